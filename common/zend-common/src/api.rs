@@ -1,4 +1,4 @@
-use crate::util;
+use crate::{codec::Encoding, util};
 use enum_convert::EnumConvert;
 use p256::{
     ecdsa,
@@ -7,11 +7,10 @@ use p256::{
         Signature,
     },
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::fmt::Display;
-use wasm_bindgen::UnwrapThrowExt;
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[serde(try_from = "String", into = "String")]
 pub struct Nonce {
     pub id: u64,
@@ -78,24 +77,59 @@ impl Into<String> for Nonce {
     }
 }
 
+/** Marks serialised Ed25519 keys and signatures. P-256 ones have no prefix, as they were around
+before other algorithms were supported. */
+const ED25519_PREFIX: &str = "ed25519:";
+
+/** A peer's public key, which doubles as its ID */
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(try_from = "String", into = "String")]
-pub struct EcdsaPublicKeyWrapper(pub ecdsa::VerifyingKey);
-impl TryFrom<String> for EcdsaPublicKeyWrapper {
+pub enum PublicKeyWrapper {
+    /** Serialised as base64 SEC1 bytes */
+    P256(ecdsa::VerifyingKey),
+    /** Serialised as `ed25519:` followed by the base64 key bytes */
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+impl PublicKeyWrapper {
+    /** Fails for signatures made with a different algorithm than the key's */
+    pub fn verify(&self, message: &[u8], signature: &SignatureWrapper) -> Result<(), ecdsa::Error> {
+        match (self, signature) {
+            (Self::P256(key), SignatureWrapper::P256(signature)) => key.verify(message, signature),
+            (Self::Ed25519(key), SignatureWrapper::Ed25519(signature)) => {
+                key.verify(message, signature)
+            }
+            _ => Err(ecdsa::Error::new()),
+        }
+    }
+}
+impl TryFrom<String> for PublicKeyWrapper {
     type Error = VerifyingKeyFromBase64Error;
     fn try_from(value: String) -> Result<Self, Self::Error> {
+        if let Some(value) = value.strip_prefix(ED25519_PREFIX) {
+            let bytes: [u8; ed25519_dalek::PUBLIC_KEY_LENGTH] = util::decode_base64(value)?
+                .try_into()
+                .map_err(|_| ecdsa::Error::new())?;
+            let key = ed25519_dalek::VerifyingKey::from_bytes(&bytes)?;
+            return Ok(Self::Ed25519(key));
+        }
         let bytes = util::decode_base64(&value)?;
-        Ok(Self(ecdsa::VerifyingKey::from_sec1_bytes(&bytes)?))
+        Ok(Self::P256(ecdsa::VerifyingKey::from_sec1_bytes(&bytes)?))
     }
 }
-impl Into<String> for EcdsaPublicKeyWrapper {
+impl Into<String> for PublicKeyWrapper {
     fn into(self) -> String {
         self.to_string()
     }
 }
-impl Display for EcdsaPublicKeyWrapper {
+impl Display for PublicKeyWrapper {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&util::encode_base64(&self.0.to_sec1_bytes()))
+        match self {
+            Self::P256(key) => f.write_str(&util::encode_base64(&key.to_sec1_bytes())),
+            Self::Ed25519(key) => {
+                f.write_str(ED25519_PREFIX)?;
+                f.write_str(&util::encode_base64(key.as_bytes()))
+            }
+        }
     }
 }
 
@@ -103,6 +137,7 @@ impl Display for EcdsaPublicKeyWrapper {
 #[enum_convert(from)]
 pub enum VerifyingKeyFromBase64Error {
     BytesFromBase64Error(base64::DecodeError),
+    // Shared by both algorithms, as ecdsa and ed25519 use the same signature crate
     KeyFromBytesError(p256::ecdsa::Error),
 }
 impl Display for VerifyingKeyFromBase64Error {
@@ -111,9 +146,13 @@ impl Display for VerifyingKeyFromBase64Error {
     }
 }
 
+/** Serialised like the `PublicKeyWrapper` of the matching algorithm */
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(try_from = "String", into = "String")]
-pub struct EcdsaSignatureWrapper(pub Signature);
+pub enum SignatureWrapper {
+    P256(Signature),
+    Ed25519(ed25519_dalek::Signature),
+}
 
 #[derive(Debug, EnumConvert)]
 #[enum_convert(from)]
@@ -126,50 +165,114 @@ impl Display for SignatureFromBase64Error {
         f.write_fmt(format_args!("{:?}", self))
     }
 }
-impl TryFrom<String> for EcdsaSignatureWrapper {
+impl TryFrom<String> for SignatureWrapper {
     type Error = SignatureFromBase64Error;
     fn try_from(value: String) -> Result<Self, Self::Error> {
+        if let Some(value) = value.strip_prefix(ED25519_PREFIX) {
+            let bytes = util::decode_base64(value)?;
+            return Ok(Self::Ed25519(ed25519_dalek::Signature::from_slice(&bytes)?));
+        }
         let bytes = util::decode_base64(&value)?;
-        Ok(Self(Signature::from_slice(&bytes.as_slice())?))
+        Ok(Self::P256(Signature::from_slice(&bytes.as_slice())?))
     }
 }
-impl Into<String> for EcdsaSignatureWrapper {
+impl Into<String> for SignatureWrapper {
     fn into(self) -> String {
-        util::encode_base64(&self.0.to_bytes())
+        match self {
+            Self::P256(signature) => util::encode_base64(&signature.to_bytes()),
+            Self::Ed25519(signature) => {
+                let bytes = signature.to_bytes();
+                format!("{}{}", ED25519_PREFIX, util::encode_base64(&bytes))
+            }
+        }
     }
 }
-impl Display for EcdsaSignatureWrapper {
+impl Display for SignatureWrapper {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(&<Self as Into<String>>::into(self.clone()))
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-#[serde(try_from = "String", into = "String")]
+/** Keys that can sign method calls */
+pub trait CallSigningKey {
+    fn sign_call(&self, message: &[u8]) -> SignatureWrapper;
+}
+impl CallSigningKey for ecdsa::SigningKey {
+    fn sign_call(&self, message: &[u8]) -> SignatureWrapper {
+        SignatureWrapper::P256(self.sign(message))
+    }
+}
+impl CallSigningKey for ed25519_dalek::SigningKey {
+    fn sign_call(&self, message: &[u8]) -> SignatureWrapper {
+        SignatureWrapper::Ed25519(self.sign(message))
+    }
+}
+
+/** Six letters followed by a checksum letter, which catches any single mistyped letter.
+Codes from before the checksum was added are still accepted where `TryFrom<String>` is used. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
 pub struct RoomId(u64);
 impl RoomId {
-    pub fn from_int(id_int: u64) -> Self {
-        assert!(id_int < 26u64.pow(6), "Kira you dumb whore");
-        Self(id_int)
+    pub const MAX: Self = Self(26u64.pow(6) - 1);
+    /** Odd and not 13, so coprime with 26 and every single substitution changes the checksum */
+    const CHECKSUM_WEIGHTS: [u64; 6] = [1, 3, 5, 7, 9, 11];
+
+    fn checksum_char(legacy_code: &str) -> char {
+        let sum: u64 = legacy_code
+            .bytes()
+            .zip(Self::CHECKSUM_WEIGHTS)
+            .map(|(char, weight)| (char - b'A') as u64 * weight)
+            .sum();
+        (b'A' + (sum % 26) as u8) as char
     }
-    pub fn get_int(self) -> u64 {
-        self.0
+    /** Parses a code, only accepting ones without a checksum if `accept_legacy` is set.
+    Meant for codes typed in by people, where a missing checksum can't catch typos. */
+    pub fn parse(value: &str, accept_legacy: bool) -> Result<Self, &'static str> {
+        if !value.is_ascii() {
+            return Err("ID contains invalid characters");
+        }
+        let value = value.to_ascii_uppercase();
+        match value.len() {
+            7 => {
+                let (legacy_code, checksum) = value.split_at(6);
+                let id = Self::from_legacy_code(legacy_code)?;
+                if !checksum.starts_with(Self::checksum_char(legacy_code)) {
+                    return Err("ID checksum does not match");
+                }
+                Ok(id)
+            }
+            6 if accept_legacy => Self::from_legacy_code(&value),
+            6 => Err("ID has no checksum"),
+            len if len < 6 => Err("ID too short"),
+            _ => Err("ID too long"),
+        }
     }
-    pub fn from_random(random: f64) -> Self {
-        assert!(random < 1.0, "Kira you dumb whore");
-        Self((random * 26u64.pow(6) as f64) as u64)
+    /** The six letters without a checksum. Room durable objects are named after this, so it
+    must never change. */
+    pub fn legacy_code(self) -> String {
+        let mut out = String::with_capacity(6);
+        // Every way of making a RoomId checks the range, so no letters are lost here
+        let mut input = self.0;
+        let mut i = 0_usize;
+        while i < 6 {
+            if input > 0 {
+                out.push((input % 26 + 65) as u8 as char);
+                input = input / 26;
+            } else {
+                out.push('A');
+            }
+            i = i + 1;
+        }
+        out.chars().rev().collect()
     }
-}
-impl TryFrom<String> for RoomId {
-    type Error = &'static str;
-    fn try_from(value: String) -> Result<Self, Self::Error> {
+    fn from_legacy_code(value: &str) -> Result<Self, &'static str> {
         let mut out_int = 0;
         let mut exponent = 5i8;
-        for mut char in value.chars() {
+        for char in value.chars() {
             if exponent < 0 {
                 return Err("ID too long");
             }
-            char.make_ascii_uppercase();
             if !char.is_ascii_uppercase() {
                 return Err("ID contains invalid characters");
             }
@@ -182,65 +285,203 @@ impl TryFrom<String> for RoomId {
         }
         Ok(Self(out_int))
     }
+
+    pub fn get_int(self) -> u64 {
+        self.0
+    }
+    /** Picks an ID uniformly, given a random number in `[0, 1)` */
+    pub fn from_random(random: f64) -> Result<Self, RoomIdOutOfRange> {
+        if !(0.0..1.0).contains(&random) {
+            return Err(RoomIdOutOfRange);
+        }
+        Self::try_from((random * (Self::MAX.0 + 1) as f64) as u64)
+    }
 }
-impl Into<String> for RoomId {
-    fn into(self) -> String {
-        let mut out = String::with_capacity(6);
-        // Some potential for subtle bugs as values that are too large to be RoomIds
-        // are silently moduloed into the required range, instead of causing an error.
-        // Implemented this way because serde does not offer a try_into macro.
-        let mut input = self.0 % 26u64.pow(6);
-        let mut i = 0_usize;
-        while i < 6 {
-            if input > 0 {
-                out.push((input % 26 + 65) as u8 as char);
-                input = input / 26;
-            } else {
-                out.push('A');
-            }
-            i = i + 1;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoomIdOutOfRange;
+impl Display for RoomIdOutOfRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Room ID out of range (maximum is {})", RoomId::MAX.0)
+    }
+}
+impl TryFrom<u64> for RoomId {
+    type Error = RoomIdOutOfRange;
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        if value > Self::MAX.0 {
+            return Err(RoomIdOutOfRange);
         }
-        out.chars().rev().collect()
+        Ok(Self(value))
+    }
+}
+/** Accepts legacy codes, so IDs sent by older clients keep working */
+impl TryFrom<String> for RoomId {
+    type Error = &'static str;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::parse(&value, true)
+    }
+}
+// Written out instead of using `into = "String"`, as that can't fail
+impl Serialize for RoomId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.0 > Self::MAX.0 {
+            return Err(serde::ser::Error::custom(RoomIdOutOfRange));
+        }
+        serializer.serialize_str(&self.to_string())
     }
 }
 impl Display for RoomId {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&<Self as Into<String>>::into(self.clone()))
+        let legacy_code = self.legacy_code();
+        write!(f, "{}{}", legacy_code, Self::checksum_char(&legacy_code))
     }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MethodCallCommonArgs {
-    pub caller_id: EcdsaPublicKeyWrapper,
+    pub caller_id: PublicKeyWrapper,
     pub nonce: Nonce,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SubscribeToRoomArgs {
     pub room_id: RoomId,
+    #[serde(default)]
+    pub filter: SubscriptionFilter,
+    /** Opts out of `PresenceEvent`s */
+    #[serde(default)]
+    pub ignore_presence: bool,
+    /** Broadcast as data from the subscriber, with the subscribe call's nonce, if the subscription
+    closes without being unsubscribed. Checked like the data of a broadcast. */
+    #[serde(default)]
+    pub last_will: Option<serde_json::Value>,
+}
+
+/** Applied by the room to broadcast data before it's sent to a subscription.
+Unicast data addressed to the subscriber is never filtered. */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SubscriptionFilter {
+    /** Only receive broadcasts from these peers */
+    #[serde(default)]
+    pub sender_ids: Option<Vec<PublicKeyWrapper>>,
+    /** Only receive broadcasts that were written to the room's history */
+    #[serde(default)]
+    pub history_only: bool,
+}
+
+/** `create_room` takes no arguments, this only exists to pair the method with its return */
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CreateRoomArgs;
+impl From<CreateRoomArgs> for MethodCallArgsVariants {
+    fn from(_: CreateRoomArgs) -> Self {
+        Self::CreateRoom
+    }
 }
 
+/** Ends a subscription made over the same connection without sending its last will */
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UnsubscribeFromRoomArgs {
+    pub room_id: RoomId,
     pub subscription_id: u64,
 }
 
+/** Replays history entries newer than `after_nonce` over the subscription, for clients that
+noticed a gap in `SubscriptionData::seq`. Replayed data gets new `seq`s, so clients should skip
+nonces they already have. Data that wasn't written to history can't be recovered. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResyncSubscriptionArgs {
+    pub room_id: RoomId,
+    pub subscription_id: u64,
+    /** The newest data received before the gap. None replays the whole history. */
+    pub after_nonce: Option<Nonce>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AddPrivilegedPeerArgs {
     pub room_id: RoomId,
-    pub allow_id: EcdsaPublicKeyWrapper,
+    pub allow_id: PublicKeyWrapper,
+}
+
+/** A peer's role in a room. Peers with any role are what used to be called privileged peers:
+they receive broadcasts and keep the room alive. */
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerRole {
+    Member,
+    Moderator,
+    Owner,
+}
+impl PeerRole {
+    /** Whether a peer with this role may change another peer's role from `current` to `new`,
+    where `None` means not having a role at all. Owners may do anything, moderators may manage
+    members, and members may only let new peers in. */
+    pub fn may_change_role(self, current: Option<PeerRole>, new: Option<PeerRole>) -> bool {
+        match self {
+            PeerRole::Owner => true,
+            PeerRole::Moderator => {
+                current.is_none_or(|v| v < PeerRole::Moderator)
+                    && new.is_none_or(|v| v < PeerRole::Moderator)
+            }
+            PeerRole::Member => current.is_none() && new == Some(PeerRole::Member),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetPeerRoleArgs {
+    pub room_id: RoomId,
+    pub peer_id: PublicKeyWrapper,
+    /** `None` removes the peer from the room */
+    pub role: Option<PeerRole>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteRoomArgs {
+    pub room_id: RoomId,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetRoomPeersArgs {
+    pub room_id: RoomId,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetRoomInfoArgs {
+    pub room_id: RoomId,
+}
+
+/** A history entry, to continue reading the history after. Nonces are only unique per sender,
+so it takes both to find the entry. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryPosition {
+    pub sender_id: PublicKeyWrapper,
+    pub nonce: Nonce,
 }
 
+/** Returns the oldest matching entries first. Timestamps are in seconds and inclusive. */
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GetRoomDataHistoryArgs {
     pub room_id: RoomId,
+    #[serde(default)]
     pub from_timestamp: u64,
+    #[serde(default)]
+    pub until_timestamp: Option<u64>,
+    /** Only returns entries the room received after this one, e.g. the last entry of the
+    previous page. It's looked for at `from_timestamp`, which should be its timestamp. If it's
+    gone from the history, entries from `from_timestamp` on are returned, so some can repeat. */
+    #[serde(default)]
+    pub after: Option<HistoryPosition>,
+    /** Only returns data from this peer */
+    #[serde(default)]
+    pub sender_id: Option<PublicKeyWrapper>,
+    /** Capped, and defaulted, by the server */
+    #[serde(default)]
+    pub limit: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeleteDataArgs {
     pub room_id: RoomId,
-    pub data_sender_id: EcdsaPublicKeyWrapper,
+    pub data_sender_id: PublicKeyWrapper,
     pub data_nonce: Nonce,
 }
 
@@ -260,12 +501,57 @@ pub struct BroadcastDataArgs {
     pub common_args: SendDataCommonArgs,
 }
 
+/** If the receiver isn't subscribed to the room, the data is queued until they next subscribe.
+Only data from peers with a role is queued, anything else is only delivered live. */
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UnicastDataArgs {
-    pub receiver_id: EcdsaPublicKeyWrapper,
+    pub receiver_id: PublicKeyWrapper,
     #[serde(flatten)]
     pub common_args: SendDataCommonArgs,
     pub make_receiver_privileged: bool,
+    /** Asks for a `DeliveryReceipt` once the data reaches one of the receiver's connections.
+    Receipts go to the sender's own subscriptions to the room. */
+    #[serde(default)]
+    pub require_ack: bool,
+}
+
+/** Sent to the subscriptions of each receiver, and to nobody else. Unlike unicast data, nothing
+is queued for receivers that aren't subscribed, which the success reports instead. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MulticastDataArgs {
+    pub receiver_ids: Vec<PublicKeyWrapper>,
+    #[serde(flatten)]
+    pub common_args: SendDataCommonArgs,
+}
+
+/** Fanned out like a broadcast, but never written to history. Meant for things like typing
+indicators, which are rate limited more tightly than regular data. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SendEphemeralArgs {
+    pub room_id: RoomId,
+    pub data: serde_json::Value,
+    /** Tells receivers how long the data stays relevant */
+    pub max_age_ms: Option<u64>,
+}
+
+/** Trades a signed call for a session key, which later calls on the same connection can be
+authenticated with instead of a signature. Can't be called with a session. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateSessionArgs {
+    /** From the `SessionChallenge` the server sent on this connection. Only usable once. */
+    pub challenge: String,
+    /** How long the session should last. Capped, and defaulted, by the server. */
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+}
+
+/** `end_session` takes no arguments, this only exists to pair the method with its return */
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EndSessionArgs;
+impl From<EndSessionArgs> for MethodCallArgsVariants {
+    fn from(_: EndSessionArgs) -> Self {
+        Self::EndSession
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, EnumConvert)]
@@ -276,11 +562,45 @@ pub enum MethodCallArgsVariants {
     CreateRoom,
     SubscribeToRoom(SubscribeToRoomArgs),
     UnsubscribeFromRoom(UnsubscribeFromRoomArgs),
+    ResyncSubscription(ResyncSubscriptionArgs),
     AddPrivilegedPeer(AddPrivilegedPeerArgs),
+    SetPeerRole(SetPeerRoleArgs),
+    DeleteRoom(DeleteRoomArgs),
+    GetRoomPeers(GetRoomPeersArgs),
+    GetRoomInfo(GetRoomInfoArgs),
     GetRoomDataHistory(GetRoomDataHistoryArgs),
     DeleteData(DeleteDataArgs),
     BroadcastData(BroadcastDataArgs),
     UnicastData(UnicastDataArgs),
+    MulticastData(MulticastDataArgs),
+    SendEphemeral(SendEphemeralArgs),
+    CreateSession(CreateSessionArgs),
+    /** Revokes the connection's session */
+    EndSession,
+}
+impl MethodCallArgsVariants {
+    /** The method's name as it appears in serialised calls */
+    pub fn method_name(&self) -> &'static str {
+        match self {
+            Self::CreateRoom => "create_room",
+            Self::SubscribeToRoom(_) => "subscribe_to_room",
+            Self::UnsubscribeFromRoom(_) => "unsubscribe_from_room",
+            Self::ResyncSubscription(_) => "resync_subscription",
+            Self::AddPrivilegedPeer(_) => "add_privileged_peer",
+            Self::SetPeerRole(_) => "set_peer_role",
+            Self::DeleteRoom(_) => "delete_room",
+            Self::GetRoomPeers(_) => "get_room_peers",
+            Self::GetRoomInfo(_) => "get_room_info",
+            Self::GetRoomDataHistory(_) => "get_room_data_history",
+            Self::DeleteData(_) => "delete_data",
+            Self::BroadcastData(_) => "broadcast_data",
+            Self::UnicastData(_) => "unicast_data",
+            Self::MulticastData(_) => "multicast_data",
+            Self::SendEphemeral(_) => "send_ephemeral",
+            Self::CreateSession(_) => "create_session",
+            Self::EndSession => "end_session",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -293,7 +613,7 @@ pub struct MethodCallContent {
 }
 impl MethodCallContent {
     pub fn new<T: Into<MethodCallArgsVariants>>(
-        caller_id: EcdsaPublicKeyWrapper,
+        caller_id: PublicKeyWrapper,
         nonce: Nonce,
         args: T,
     ) -> Self {
@@ -305,15 +625,33 @@ impl MethodCallContent {
     pub fn sign(
         self,
         call_id: u64,
-        signing_key: &ecdsa::SigningKey,
+        signing_key: &impl CallSigningKey,
     ) -> Result<SignedMethodCall, serde_json::Error> {
         let signed_call: MethodCall = self.try_into()?;
         Ok(SignedMethodCall {
             call_id,
-            signature: EcdsaSignatureWrapper(signing_key.sign(signed_call.json.as_bytes())),
+            signature: signing_key.sign_call(&signed_call.canonical),
             signed_call,
         })
     }
+    /** For calls on a connection with a session, `session_key` is from `CreateSessionSuccess` */
+    pub fn authenticate(
+        self,
+        call_id: u64,
+        session_key: &[u8],
+    ) -> Result<SessionMethodCall, serde_json::Error> {
+        use hmac::Mac;
+        let session_call: MethodCall = self.try_into()?;
+        // HMAC takes keys of any length, so this doesn't actually fail
+        let mut mac = SessionMac::new_from_slice(session_key)
+            .map_err(<serde_json::Error as serde::ser::Error>::custom)?;
+        mac.update(&session_call.canonical);
+        Ok(SessionMethodCall {
+            call_id,
+            mac: util::encode_base64(&mac.finalize().into_bytes()),
+            session_call,
+        })
+    }
 }
 /*
 impl TryFrom<serde_json::Value> for MethodCallContent {
@@ -334,17 +672,62 @@ impl TryFrom<serde_json::Value> for MethodCallContent {
     }
 } */
 
+/** Writes JSON with object keys sorted by their bytes and no whitespace, so the same value
+always produces the same bytes however it was formatted when it was received. */
+fn write_canonical_json(
+    value: &serde_json::Value,
+    out: &mut Vec<u8>,
+) -> Result<(), serde_json::Error> {
+    match value {
+        serde_json::Value::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+            out.push(b'{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                serde_json::to_writer(&mut *out, key)?;
+                out.push(b':');
+                write_canonical_json(value, out)?;
+            }
+            out.push(b'}');
+        }
+        serde_json::Value::Array(items) => {
+            out.push(b'[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(b',');
+                }
+                write_canonical_json(item, out)?;
+            }
+            out.push(b']');
+        }
+        other => serde_json::to_writer(&mut *out, other)?,
+    }
+    Ok(())
+}
+
+/** The bytes a method call's signature is made over */
+pub fn canonical_bytes(value: &serde_json::Value) -> Result<Vec<u8>, serde_json::Error> {
+    let mut out = Vec::new();
+    write_canonical_json(value, &mut out)?;
+    Ok(out)
+}
+
+/** A method call along with its canonical encoding, which is what gets signed and what is
+sent. Calls are accepted in any JSON formatting, as the canonical bytes are recomputed. */
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(try_from = "String", into = "String")]
 pub struct MethodCall {
-    json: String,
+    canonical: Vec<u8>,
     pub call: MethodCallContent,
 }
 impl TryFrom<MethodCallContent> for MethodCall {
     type Error = serde_json::Error;
     fn try_from(value: MethodCallContent) -> Result<Self, Self::Error> {
         Ok(Self {
-            json: serde_json::to_string(&value)?,
+            canonical: canonical_bytes(&serde_json::to_value(&value)?)?,
             call: value,
         })
     }
@@ -352,16 +735,17 @@ impl TryFrom<MethodCallContent> for MethodCall {
 impl TryFrom<String> for MethodCall {
     type Error = serde_json::Error;
     fn try_from(value_json: String) -> Result<Self, Self::Error> {
-        let content = serde_json::from_str(&value_json)?;
+        let value: serde_json::Value = serde_json::from_str(&value_json)?;
         Ok(Self {
-            json: value_json,
-            call: content,
+            canonical: canonical_bytes(&value)?,
+            call: serde_json::from_value(value)?,
         })
     }
 }
 impl Into<String> for MethodCall {
     fn into(self) -> String {
-        self.json
+        // Only ever made by serde_json, so this is valid UTF-8
+        String::from_utf8(self.canonical).unwrap_or_default()
     }
 }
 
@@ -376,21 +760,47 @@ pub struct SignedMethodCallPartial {
 pub struct SignedMethodCall {
     pub call_id: u64,
     pub signed_call: MethodCall,
-    signature: EcdsaSignatureWrapper,
+    signature: SignatureWrapper,
 }
 impl SignedMethodCall {
-    pub fn validate_timestamp(&self, now: u64) -> bool {
-        let timestamp = self.signed_call.call.common_arguments.nonce.timestamp;
-        // Accept timestamps from up to 10 seconds in the future and 5 minutes in the past
-        timestamp < now + 10 && timestamp > now - 5 * 60
+    pub fn validate_timestamp(&self, now: u64, config: &ProtocolConfig) -> bool {
+        config.accepts_timestamp(self.signed_call.call.common_arguments.nonce.timestamp, now)
     }
     pub fn validate_signature(&self) -> Result<(), p256::ecdsa::Error> {
         self.signed_call
             .call
             .common_arguments
             .caller_id
-            .0
-            .verify(self.signed_call.json.as_bytes(), &self.signature.0)
+            .verify(&self.signed_call.canonical, &self.signature)
+    }
+}
+
+/** HMAC-SHA256 with a session key */
+type SessionMac = hmac::Hmac<sha2::Sha256>;
+
+/** A method call authenticated with the key of the connection's session instead of a signature.
+The caller has to be the one the session was created for. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionMethodCall {
+    pub call_id: u64,
+    pub session_call: MethodCall,
+    /** Base64 HMAC-SHA256 of the call's canonical bytes */
+    mac: String,
+}
+impl SessionMethodCall {
+    pub fn validate_timestamp(&self, now: u64, config: &ProtocolConfig) -> bool {
+        config.accepts_timestamp(self.session_call.call.common_arguments.nonce.timestamp, now)
+    }
+    pub fn validate_mac(&self, session_key: &[u8]) -> bool {
+        use hmac::Mac;
+        let Ok(mac) = util::decode_base64(&self.mac) else {
+            return false;
+        };
+        let Ok(mut expected) = SessionMac::new_from_slice(session_key) else {
+            return false;
+        };
+        expected.update(&self.session_call.canonical);
+        expected.verify_slice(&mac).is_ok()
     }
 }
 
@@ -419,22 +829,158 @@ impl From<SignedMethodCallPartial> for SignedMethodCallOrPartial {
     }
 }*/
 
+/** How the server judges calls, told to clients in the `ServerHello` so they can tell whether
+their clock is too far off, and how they should keep their connection alive */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProtocolConfig {
+    /** How far in the future a call's timestamp may be */
+    pub max_clock_skew_secs: u64,
+    /** How far in the past a call's timestamp may be */
+    pub max_call_age_secs: u64,
+    /** How often clients should ping so their connection isn't closed as idle. `None` if the
+    server keeps connections alive itself, so clients don't have to. */
+    pub ping_interval_secs: Option<u64>,
+}
+/** Up to 10 seconds in the future and 5 minutes in the past, pinged every 10 seconds */
+impl Default for ProtocolConfig {
+    fn default() -> Self {
+        Self {
+            max_clock_skew_secs: 10,
+            max_call_age_secs: 5 * 60,
+            ping_interval_secs: Some(10),
+        }
+    }
+}
+impl ProtocolConfig {
+    pub fn accepts_timestamp(&self, timestamp: u64, now: u64) -> bool {
+        timestamp < now.saturating_add(self.max_clock_skew_secs)
+            && timestamp > now.saturating_sub(self.max_call_age_secs)
+    }
+    /** How long nonces have to be remembered for, with some margin for clock differences
+    between the server's isolates */
+    pub fn nonce_retention_secs(&self) -> u64 {
+        self.max_call_age_secs
+            .saturating_add(self.max_clock_skew_secs)
+            .saturating_mul(2)
+    }
+}
+
+/** Protocol versions this build understands, oldest first */
+pub const PROTOCOL_VERSIONS: &[u32] = &[1];
+/** Assumed for clients that never sent a `ClientHello` */
+pub const DEFAULT_PROTOCOL_VERSION: u32 = 1;
+
+/** Optional parts of the protocol, so clients can tell what a server supports */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProtocolFeature {
+    Cbor,
+    /** Large messages may be sent as `codec::CompressedEnvelope`s */
+    Compression,
+    DeliveryReceipts,
+    /** Ed25519 keys are accepted as caller IDs */
+    Ed25519,
+    EphemeralData,
+    Multicast,
+    Presence,
+    Sessions,
+    /** Subscription data is numbered, and subscriptions can be resynced */
+    SubscriptionSeq,
+    UnicastQueue,
+    /** Anything this build doesn't know about */
+    #[serde(other)]
+    Unknown,
+}
+
+/** The first message a client sends, declaring what it supports */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientHello {
+    pub protocol_versions: Vec<u32>,
+    #[serde(default)]
+    pub features: Vec<ProtocolFeature>,
+    /** How the server should encode everything after its hello. None keeps the current encoding. */
+    #[serde(default)]
+    pub encoding: Option<Encoding>,
+}
+/** Offers every version this build understands */
+impl Default for ClientHello {
+    fn default() -> Self {
+        Self {
+            protocol_versions: PROTOCOL_VERSIONS.to_vec(),
+            features: vec![],
+            encoding: None,
+        }
+    }
+}
+impl ClientHello {
+    /** The newest version both sides support */
+    pub fn choose_version(&self) -> Option<u32> {
+        PROTOCOL_VERSIONS
+            .iter()
+            .rev()
+            .find(|v| self.protocol_versions.contains(v))
+            .copied()
+    }
+}
+
+/** Abandons a call that hasn't returned yet, which then returns `ErrorId::Cancelled`.
+Whatever the call already did isn't undone, and calls that already returned are unaffected. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CancelCall {
+    pub call_id: u64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case", tag = "message_type")]
 #[serde(content = "message_content")]
 pub enum ClientToServerMessage {
+    Hello(ClientHello),
     Ping,
     SignedMethodCall(SignedMethodCallOrPartial),
+    /** Answered with a `SessionChallenge`, needed for `create_session` */
+    RequestSessionChallenge,
+    SessionMethodCall(SessionMethodCall),
+    CancelCall(CancelCall),
+}
+impl ClientToServerMessage {
+    /** The ID of the call this message makes, if it makes one */
+    pub fn call_id(&self) -> Option<u64> {
+        match self {
+            Self::SignedMethodCall(SignedMethodCallOrPartial::Full(call)) => Some(call.call_id),
+            Self::SignedMethodCall(SignedMethodCallOrPartial::Partial(call_id)) => Some(*call_id),
+            Self::SessionMethodCall(call) => Some(call.call_id),
+            _ => None,
+        }
+    }
+}
+impl From<CancelCall> for ClientToServerMessage {
+    fn from(value: CancelCall) -> Self {
+        Self::CancelCall(value)
+    }
 }
 impl From<SignedMethodCall> for ClientToServerMessage {
     fn from(value: SignedMethodCall) -> Self {
         Self::SignedMethodCall(SignedMethodCallOrPartial::Full(value))
     }
 }
+impl From<SessionMethodCall> for ClientToServerMessage {
+    fn from(value: SessionMethodCall) -> Self {
+        Self::SessionMethodCall(value)
+    }
+}
+
+/** Limits on how much history a room keeps. `None` means unlimited. */
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct HistoryRetention {
+    pub max_entries: Option<u64>,
+    pub max_age_secs: Option<u64>,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateRoomSuccess {
     pub room_id: RoomId,
+    pub history_retention: HistoryRetention,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -442,31 +988,177 @@ pub struct SubscribeSuccess {
     pub subscription_id: u64,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomPeerInfo {
+    pub peer_id: PublicKeyWrapper,
+    /** Same as `role.is_some()` */
+    pub privileged: bool,
+    pub role: Option<PeerRole>,
+    pub subscribed: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetRoomPeersSuccess {
+    pub peers: Vec<RoomPeerInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomInfo {
+    /** Unix timestamp in seconds. Missing for rooms created before it was recorded. */
+    pub created_at: Option<u64>,
+    pub peer_count: u64,
+    pub subscriber_count: u64,
+    pub history_entry_count: u64,
+    pub history_retention: HistoryRetention,
+    /** How long the room lives on without activity from privileged peers */
+    pub idle_timeout_secs: u64,
+    /** Maximum size of the serialised data of a single send */
+    pub max_data_bytes: u64,
+}
+
+/** `info` is missing both for rooms that don't exist and for callers that aren't members */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetRoomInfoSuccess {
+    pub info: Option<RoomInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateSessionSuccess {
+    /** Base64, only ever sent to the client this once */
+    pub session_key: String,
+    /** Unix timestamp in seconds after which the session can't be used anymore */
+    pub expires_at: u64,
+}
+
+/** Only senders with a role learn who is subscribed. For anyone else, and for rooms that don't
+exist, every receiver is reported as not reached and nothing is sent. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MulticastDataSuccess {
+    /** Receivers the data was sent to at least one subscription of */
+    pub delivered_to: Vec<PublicKeyWrapper>,
+    /** Receivers without a subscription to the room */
+    pub not_delivered_to: Vec<PublicKeyWrapper>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomDataHistoryEntry {
+    pub sender_id: PublicKeyWrapper,
+    pub nonce: Nonce,
+    /** Unix timestamp in seconds of when the room received the data */
+    pub timestamp: u64,
+    pub data: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetRoomDataHistorySuccess {
+    pub entries: Vec<RoomDataHistoryEntry>,
+    /** More entries matched than the limit allowed. Continue after the last entry, see
+    `GetRoomDataHistoryArgs::after`. */
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+/** Returned by methods that have nothing to report, serialised as `null` */
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct AckSuccess;
+impl From<AckSuccess> for MethodCallSuccess {
+    fn from(_: AckSuccess) -> Self {
+        Self::Ack
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, EnumConvert)]
 #[serde(untagged)]
 #[enum_convert(from)]
 pub enum MethodCallSuccess {
     // When deserialising, serde should attempt to deserialise to this variant
-    // first and immediately succeed, leaving the client to manually deserialise
-    // into an actual type.
+    // first and immediately succeed, as returns don't say which method they belong to.
+    // Clients then use `parse` with the method they called.
     Value(serde_json::Value),
     CreateRoom(CreateRoomSuccess),
     SubscribeToRoom(SubscribeSuccess),
+    GetRoomPeers(GetRoomPeersSuccess),
+    GetRoomInfo(GetRoomInfoSuccess),
+    GetRoomDataHistory(GetRoomDataHistorySuccess),
+    CreateSession(CreateSessionSuccess),
+    MulticastData(MulticastDataSuccess),
     Ack,
 }
+impl MethodCallSuccess {
+    /** Reads the return of a call to `M` */
+    pub fn parse<M: ApiMethod>(self) -> Result<M::Success, serde_json::Error> {
+        match self {
+            Self::Value(value) => serde_json::from_value(value),
+            other => serde_json::from_value(serde_json::to_value(other)?),
+        }
+    }
+}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/** Pairs a method's arguments with what a successful call to it returns, so handlers and
+clients can't disagree about it */
+pub trait ApiMethod: Into<MethodCallArgsVariants> {
+    type Success: Serialize + DeserializeOwned + Into<MethodCallSuccess>;
+}
+macro_rules! api_methods {
+    ($($args:ty => $success:ty,)*) => {
+        $(impl ApiMethod for $args {
+            type Success = $success;
+        })*
+    };
+}
+api_methods! {
+    CreateRoomArgs => CreateRoomSuccess,
+    SubscribeToRoomArgs => SubscribeSuccess,
+    UnsubscribeFromRoomArgs => AckSuccess,
+    ResyncSubscriptionArgs => AckSuccess,
+    AddPrivilegedPeerArgs => AckSuccess,
+    SetPeerRoleArgs => AckSuccess,
+    DeleteRoomArgs => AckSuccess,
+    GetRoomPeersArgs => GetRoomPeersSuccess,
+    GetRoomInfoArgs => GetRoomInfoSuccess,
+    GetRoomDataHistoryArgs => GetRoomDataHistorySuccess,
+    DeleteDataArgs => AckSuccess,
+    BroadcastDataArgs => AckSuccess,
+    UnicastDataArgs => AckSuccess,
+    MulticastDataArgs => MulticastDataSuccess,
+    SendEphemeralArgs => AckSuccess,
+    CreateSessionArgs => CreateSessionSuccess,
+    EndSessionArgs => AckSuccess,
+}
+
+/** Why a call failed, for clients to handle programmatically. Room methods don't report
+`NotFound` or `NotAuthorized` where that would reveal a room's existence or its members. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum ErrorId {
     InternalError,
     InvalidSignature,
     ParseError,
+    RateLimited,
+    PayloadTooLarge,
+    QueueFull,
+    InvalidPayload,
+    NotFound,
+    NotAuthorized,
+    NonceReused,
+    TimestampOutOfRange,
+    RoomFull,
+    QuotaExceeded,
+    MethodNotImplemented,
+    InvalidSession,
+    /** Returned for calls the client cancelled with `CancelCall` */
+    Cancelled,
+    /** Sent by a newer server. Clients should treat it like `InternalError`. */
+    #[serde(other)]
+    Unknown,
 }
 impl ErrorId {
     pub fn with_message(self, message: String) -> MethodCallError {
         MethodCallError {
             error_id: self,
             message: Some(message),
+            data: None,
+            details: None,
         }
     }
     pub fn with_default_message(self) -> MethodCallError {
@@ -475,12 +1167,28 @@ impl ErrorId {
             ErrorId::InternalError => "An unexpected internal error occured.",
             ErrorId::InvalidSignature => "The request was not signed correctly.",
             ErrorId::ParseError => "The request could not be parsed.",
+            ErrorId::RateLimited => "Too many requests were made in a short time.",
+            ErrorId::PayloadTooLarge => "The request's data exceeds the maximum allowed size.",
+            ErrorId::QueueFull => "The receiver has too much undelivered data queued.",
+            ErrorId::InvalidPayload => "The request's data is nested too deeply or too complex.",
+            ErrorId::NotFound => "The requested resource does not exist.",
+            ErrorId::NotAuthorized => "The caller is not allowed to do this.",
+            ErrorId::NonceReused => "The request's nonce was already used.",
+            ErrorId::TimestampOutOfRange => "The request's timestamp is out of range.",
+            ErrorId::RoomFull => "The room can't take any more peers.",
+            ErrorId::QuotaExceeded => "The caller has used up its quota.",
+            ErrorId::MethodNotImplemented => "The method is not implemented by this server.",
+            ErrorId::InvalidSession => "The connection has no session for the caller.",
+            ErrorId::Cancelled => "The call was cancelled.",
+            ErrorId::Unknown => "",
             // _ => "",
         };
         if message.is_empty() {
             MethodCallError {
                 error_id: self,
                 message: None,
+                data: None,
+                details: None,
             }
         } else {
             self.with_message(message.to_string())
@@ -488,10 +1196,38 @@ impl ErrorId {
     }
 }
 
+/** Typed data for the errors that come with some, so clients can react without parsing messages */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "data_type")]
+pub enum MethodCallErrorData {
+    RateLimited {
+        /** How long to back off before calling again */
+        retry_after_ms: u64,
+    },
+    RoomFull {
+        max_peers: u64,
+    },
+    PayloadTooLarge {
+        max_bytes: u64,
+    },
+    QuotaExceeded {
+        quota: String,
+        limit: u64,
+    },
+    /** Anything this build doesn't know about */
+    #[serde(other)]
+    Unknown,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MethodCallError {
     error_id: ErrorId,
     message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<MethodCallErrorData>,
+    /** Free-form error-specific data, for anything `data` has no variant for */
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    details: Option<serde_json::Value>,
 }
 impl From<ErrorId> for MethodCallError {
     fn from(error_id: ErrorId) -> Self {
@@ -502,6 +1238,26 @@ impl MethodCallError {
     pub fn internal() -> Self {
         ErrorId::InternalError.with_default_message()
     }
+    pub fn with_data(mut self, data: MethodCallErrorData) -> Self {
+        self.data = Some(data);
+        self
+    }
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+    pub fn error_id(&self) -> ErrorId {
+        self.error_id
+    }
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_deref()
+    }
+    pub fn data(&self) -> Option<&MethodCallErrorData> {
+        self.data.as_ref()
+    }
+    pub fn details(&self) -> Option<&serde_json::Value> {
+        self.details.as_ref()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, EnumConvert)]
@@ -516,6 +1272,9 @@ pub enum MethodCallReturnVariants {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MethodCallReturn {
     pub call_id: u64,
+    /** When the server received the call, in milliseconds since the epoch */
+    #[serde(default)]
+    pub received_at: u64,
     #[serde(flatten)]
     pub return_data: MethodCallReturnVariants,
 }
@@ -524,7 +1283,15 @@ pub struct MethodCallReturn {
 pub struct SubscriptionData {
     pub subscription_id: u64,
     pub room_id: RoomId,
-    pub sender_id: EcdsaPublicKeyWrapper,
+    /** Counts the data sent over the subscription, starting at 0 and carrying on when it's
+    resumed. Skipping a number means something was missed, see `ResyncSubscriptionArgs`. */
+    #[serde(default)]
+    pub seq: u64,
+    /** When the room received the data, in milliseconds since the epoch. Unlike the nonce's
+    timestamp, this comes from the server rather than the sender. */
+    #[serde(default)]
+    pub received_at: u64,
+    pub sender_id: PublicKeyWrapper,
     pub nonce: Nonce,
     pub data: serde_json::Value,
 }
@@ -534,41 +1301,356 @@ impl SubscriptionData {
     }
 }
 
+/** Confirms that unicast data sent with `require_ack` was forwarded to the receiver */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryReceipt {
+    pub subscription_id: u64,
+    pub room_id: RoomId,
+    pub receiver_id: PublicKeyWrapper,
+    pub nonce: Nonce,
+}
+impl DeliveryReceipt {
+    pub fn into_message(self) -> ServerToClientMessage {
+        self.into()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscriptionDataDeleted {
+    pub subscription_id: u64,
+    pub room_id: RoomId,
+    pub deleter_id: PublicKeyWrapper,
+    pub data_sender_id: PublicKeyWrapper,
+    pub data_nonce: Nonce,
+}
+impl SubscriptionDataDeleted {
+    pub fn into_message(self) -> ServerToClientMessage {
+        self.into()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomClosedReason {
+    Expired,
+    Deleted,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EphemeralData {
+    pub subscription_id: u64,
+    pub room_id: RoomId,
+    pub sender_id: PublicKeyWrapper,
+    pub data: serde_json::Value,
+    pub max_age_ms: Option<u64>,
+    /** When the room received the data, in milliseconds, as ephemeral data has no nonce */
+    pub timestamp: u64,
+}
+impl EphemeralData {
+    pub fn into_message(self) -> ServerToClientMessage {
+        self.into()
+    }
+}
+
+/** Answers a `ClientHello`. Everything after it uses the chosen version. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerHello {
+    pub protocol_version: u32,
+    pub features: Vec<ProtocolFeature>,
+    /** Used by the server from now on. The hello itself is still sent in the previous encoding. */
+    pub encoding: Encoding,
+    #[serde(default)]
+    pub protocol_config: ProtocolConfig,
+}
+impl ServerHello {
+    pub fn into_message(self) -> ServerToClientMessage {
+        self.into()
+    }
+}
+
+/** Sent right before the server closes the connection, the close code says why */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GoingAway {
+    /** How long to wait before reconnecting. None if reconnecting wouldn't help. */
+    pub retry_after_secs: Option<u64>,
+}
+impl GoingAway {
+    pub fn into_message(self) -> ServerToClientMessage {
+        self.into()
+    }
+}
+
+/** Answers `RequestSessionChallenge`. Requesting another one replaces it. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionChallenge {
+    pub challenge: String,
+}
+impl SessionChallenge {
+    pub fn into_message(self) -> ServerToClientMessage {
+        self.into()
+    }
+}
+
+/** Sent when a room stops existing. The subscription ends with it. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomClosed {
+    pub subscription_id: u64,
+    pub room_id: RoomId,
+    pub reason: RoomClosedReason,
+}
+impl RoomClosed {
+    pub fn into_message(self) -> ServerToClientMessage {
+        self.into()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceKind {
+    /** The peer was given a role in the room */
+    Joined,
+    /** The peer's role was taken away */
+    Left,
+    Subscribed,
+    Unsubscribed,
+}
+
+/** Sent to subscribers with a role in the room when another peer's presence changes */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceEvent {
+    pub subscription_id: u64,
+    pub room_id: RoomId,
+    pub peer_id: PublicKeyWrapper,
+    pub kind: PresenceKind,
+    /** Unix timestamp in seconds */
+    pub timestamp: u64,
+}
+impl PresenceEvent {
+    pub fn into_message(self) -> ServerToClientMessage {
+        self.into()
+    }
+}
+
+/** Sent after the server had to reconnect a subscription to its room. Data sent to the room
+in the meantime has been replayed from history, so only data sent without writing history
+may have been missed. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscriptionResumed {
+    pub subscription_id: u64,
+    pub room_id: RoomId,
+}
+impl SubscriptionResumed {
+    pub fn into_message(self) -> ServerToClientMessage {
+        self.into()
+    }
+}
+
+/** The server will be unavailable for a while, connections are closed when it starts */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaintenanceNotice {
+    /** In seconds since the epoch */
+    pub starts_at: u64,
+    /** None if it isn't known how long it'll take */
+    pub duration_secs: Option<u64>,
+}
+
+/** A subscription ended without its room closing, and the client has to subscribe again */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscriptionClosedNotice {
+    pub subscription_id: u64,
+    pub room_id: RoomId,
+}
+
+/** A message was dropped without being handled, as it was too large (`ErrorId::PayloadTooLarge`)
+or couldn't be parsed (`ErrorId::ParseError`). Calls in it get no return. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageRejectedNotice {
+    pub error: MethodCallError,
+}
+
+/** Sent before a quota runs out, after which calls fail with `ErrorId::QuotaExceeded` */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaWarningNotice {
+    pub quota: String,
+    pub used: u64,
+    pub limit: u64,
+}
+
+/** Something the client relies on will stop working in a later protocol version */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeprecationNotice {
+    pub feature: String,
+    /** None if no version has been picked yet */
+    pub removed_in_version: Option<u32>,
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, EnumConvert)]
+#[enum_convert(from)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "notice_type", content = "notice_content")]
+pub enum ServerNotice {
+    Maintenance(MaintenanceNotice),
+    SubscriptionClosed(SubscriptionClosedNotice),
+    MessageRejected(MessageRejectedNotice),
+    QuotaWarning(QuotaWarningNotice),
+    Deprecation(DeprecationNotice),
+    /** Serialised as a plain string, like all notices were before they had types */
+    #[serde(untagged)]
+    Text(String),
+}
+impl ServerNotice {
+    pub fn into_message(self) -> ServerToClientMessage {
+        self.into()
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, EnumConvert)]
 #[enum_convert(from)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "message_type", content = "message_content")]
 pub enum ServerToClientMessage {
+    Hello(ServerHello),
     Pong,
     MethodCallReturn(MethodCallReturn),
     SubscriptionData(SubscriptionData),
-    Info(String),
+    EphemeralData(EphemeralData),
+    DeliveryReceipt(DeliveryReceipt),
+    SubscriptionDataDeleted(SubscriptionDataDeleted),
+    RoomClosed(RoomClosed),
+    SubscriptionResumed(SubscriptionResumed),
+    PresenceEvent(PresenceEvent),
+    GoingAway(GoingAway),
+    SessionChallenge(SessionChallenge),
+    Info(ServerNotice),
 }
 impl ServerToClientMessage {
     pub fn pong() -> Self {
         Self::Pong
     }
-    pub fn call_error(call_id: u64, error_id: ErrorId, message: Option<String>) -> Self {
+    /** The room and subscription ID of messages that belong to a subscription */
+    pub fn subscription_mut(&mut self) -> Option<(RoomId, &mut u64)> {
+        Some(match self {
+            Self::SubscriptionData(v) => (v.room_id, &mut v.subscription_id),
+            Self::EphemeralData(v) => (v.room_id, &mut v.subscription_id),
+            Self::DeliveryReceipt(v) => (v.room_id, &mut v.subscription_id),
+            Self::SubscriptionDataDeleted(v) => (v.room_id, &mut v.subscription_id),
+            Self::RoomClosed(v) => (v.room_id, &mut v.subscription_id),
+            Self::SubscriptionResumed(v) => (v.room_id, &mut v.subscription_id),
+            Self::PresenceEvent(v) => (v.room_id, &mut v.subscription_id),
+            Self::Info(ServerNotice::SubscriptionClosed(v)) => (v.room_id, &mut v.subscription_id),
+            _ => return None,
+        })
+    }
+    pub fn call_error(
+        call_id: u64,
+        received_at: u64,
+        error_id: ErrorId,
+        message: Option<String>,
+    ) -> Self {
         MethodCallReturn {
             call_id,
-            return_data: MethodCallError { error_id, message }.into(),
+            received_at,
+            return_data: MethodCallError {
+                error_id,
+                message,
+                data: None,
+                details: None,
+            }
+            .into(),
         }
         .into()
     }
-    pub fn from_error(call_id: u64, error: MethodCallError) -> Self {
+    pub fn from_error(call_id: u64, received_at: u64, error: MethodCallError) -> Self {
         MethodCallReturn {
             call_id,
+            received_at,
             return_data: error.into(),
         }
         .into()
     }
-    pub fn from_success(call_id: u64, data: MethodCallSuccess) -> Self {
+    pub fn from_success(call_id: u64, received_at: u64, data: MethodCallSuccess) -> Self {
         Self::MethodCallReturn(MethodCallReturn {
             call_id,
+            received_at,
             return_data: data.into(),
         })
     }
     pub fn info(text: &str) -> Self {
-        Self::Info(text.to_string())
+        Self::Info(ServerNotice::Text(text.to_string()))
+    }
+}
+
+/** Close codes used by the server, so clients can tell closures worth retrying from fatal ones.
+Application-specific codes use the 4000-4999 range reserved for private use. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    /** Closed on purpose, e.g. completing a close the other side started */
+    Normal = 1000,
+    /** The server is going away. The runtime also uses this code during deployments. */
+    ServerShutdown = 1001,
+    /** A message was larger than the maximum message size */
+    MessageTooLarge = 1009,
+    /** The client sent something that isn't part of the protocol */
+    ProtocolError = 4000,
+    /** The client kept making calls after being rate limited */
+    RateLimited = 4001,
+    /** The client didn't send anything (not even pings) for too long */
+    IdleTimeout = 4002,
+    /** The room was deleted by a privileged peer */
+    RoomDeleted = 4003,
+    /** The room expired after being inactive */
+    RoomExpired = 4004,
+    /** The client and server have no protocol version in common */
+    UnsupportedProtocol = 4005,
+}
+impl CloseCode {
+    pub fn code(self) -> u16 {
+        self as u16
+    }
+    pub fn reason(self) -> &'static str {
+        match self {
+            CloseCode::Normal => "Normal closure",
+            CloseCode::ServerShutdown => "Server shutting down",
+            CloseCode::MessageTooLarge => "Message too large",
+            CloseCode::ProtocolError => "Protocol error",
+            CloseCode::RateLimited => "Rate limited",
+            CloseCode::IdleTimeout => "Idle timeout",
+            CloseCode::RoomDeleted => "Room deleted",
+            CloseCode::RoomExpired => "Room expired",
+            CloseCode::UnsupportedProtocol => "Unsupported protocol version",
+        }
+    }
+    /** Whether reconnecting could succeed. Rate limited clients should back off first. */
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            CloseCode::ServerShutdown | CloseCode::RateLimited | CloseCode::IdleTimeout
+        )
+    }
+}
+impl TryFrom<u16> for CloseCode {
+    type Error = ();
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        Ok(match value {
+            1000 => CloseCode::Normal,
+            1001 => CloseCode::ServerShutdown,
+            1009 => CloseCode::MessageTooLarge,
+            4000 => CloseCode::ProtocolError,
+            4001 => CloseCode::RateLimited,
+            4002 => CloseCode::IdleTimeout,
+            4003 => CloseCode::RoomDeleted,
+            4004 => CloseCode::RoomExpired,
+            4005 => CloseCode::UnsupportedProtocol,
+            _ => return Err(()),
+        })
+    }
+}
+impl From<RoomClosedReason> for CloseCode {
+    fn from(value: RoomClosedReason) -> Self {
+        match value {
+            RoomClosedReason::Deleted => CloseCode::RoomDeleted,
+            RoomClosedReason::Expired => CloseCode::RoomExpired,
+        }
     }
 }
@@ -0,0 +1,249 @@
+use crate::api::{self, CallSigningKey};
+use futures::{
+    future::{self, Either},
+    Future, Sink, SinkExt, Stream, StreamExt,
+};
+use p256::ecdsa;
+use std::collections::VecDeque;
+
+/** Anything that carries JSON encoded messages to the server and back, like a websocket's
+text frames. Lets native and wasm clients share `ApiCaller` whatever socket library they use. */
+pub trait Transport: Sink<String> + Stream<Item = String> + Unpin {}
+impl<T: Sink<String> + Stream<Item = String> + Unpin> Transport for T {}
+
+/** A key that can both sign calls and say which caller it signs for */
+pub trait CallerKey: CallSigningKey {
+    fn caller_id(&self) -> api::PublicKeyWrapper;
+}
+impl CallerKey for ecdsa::SigningKey {
+    fn caller_id(&self) -> api::PublicKeyWrapper {
+        api::PublicKeyWrapper::P256(*self.verifying_key())
+    }
+}
+impl CallerKey for ed25519_dalek::SigningKey {
+    fn caller_id(&self) -> api::PublicKeyWrapper {
+        api::PublicKeyWrapper::Ed25519(self.verifying_key())
+    }
+}
+
+/** Builds method calls, keeping track of call IDs and nonces. The clock returns seconds since
+the epoch, and is a parameter because wasm and native targets read the time differently. */
+#[derive(Debug)]
+pub struct CallBuilder<K: CallerKey> {
+    key: K,
+    clock: fn() -> u64,
+    next_call_id: u64,
+    next_nonce: api::Nonce,
+    last_time: u64,
+}
+impl<K: CallerKey> CallBuilder<K> {
+    pub fn new(key: K, clock: fn() -> u64) -> Self {
+        let time = clock();
+        Self {
+            key,
+            clock,
+            next_call_id: 0,
+            next_nonce: api::Nonce::new(time),
+            last_time: time,
+        }
+    }
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+    pub fn caller_id(&self) -> api::PublicKeyWrapper {
+        self.key.caller_id()
+    }
+    // Nonces have to increase, even if the clock goes backwards
+    fn get_time(&mut self) -> u64 {
+        let now = std::cmp::max(self.last_time, (self.clock)());
+        self.last_time = now;
+        now
+    }
+    /** The nonce the next call will have, for data that's signed together with its nonce */
+    pub fn peek_nonce(&self) -> api::Nonce {
+        self.next_nonce
+    }
+    /** Makes the following calls' nonces come after `nonce`, for keys that made calls before,
+    e.g. before the page was reloaded */
+    pub fn resume_after(&mut self, nonce: api::Nonce) {
+        if self.next_nonce > nonce {
+            return;
+        }
+        self.last_time = std::cmp::max(self.last_time, nonce.timestamp);
+        let time = self.get_time();
+        self.next_nonce = nonce.next(time);
+    }
+    pub fn next_nonce(&mut self) -> api::Nonce {
+        let time = self.get_time();
+        let nonce = self.next_nonce;
+        self.next_nonce.increment(time);
+        nonce
+    }
+    fn next_call_id(&mut self) -> u64 {
+        let call_id = self.next_call_id;
+        self.next_call_id += 1;
+        call_id
+    }
+    fn content<M: api::ApiMethod>(&mut self, args: M) -> api::MethodCallContent {
+        let nonce = self.next_nonce();
+        api::MethodCallContent::new(self.caller_id(), nonce, args)
+    }
+    /** Returns the call's ID along with it, to match the server's return to it */
+    pub fn server_call<M: api::ApiMethod>(
+        &mut self,
+        args: M,
+    ) -> Result<(u64, api::ClientToServerMessage), serde_json::Error> {
+        let call_id = self.next_call_id();
+        Ok((call_id, self.server_call_with_id(call_id, args)?))
+    }
+    /** For clients that number calls themselves, e.g. because several keys share a connection */
+    pub fn server_call_with_id<M: api::ApiMethod>(
+        &mut self,
+        call_id: u64,
+        args: M,
+    ) -> Result<api::ClientToServerMessage, serde_json::Error> {
+        Ok(self.content(args).sign(call_id, &self.key)?.into())
+    }
+    /** Like `server_call`, for connections with a session. Cheaper than signing. */
+    pub fn session_call<M: api::ApiMethod>(
+        &mut self,
+        session_key: &[u8],
+        args: M,
+    ) -> Result<(u64, api::ClientToServerMessage), serde_json::Error> {
+        let content = self.content(args);
+        let call_id = self.next_call_id();
+        let call = content.authenticate(call_id, session_key)?;
+        Ok((call_id, call.into()))
+    }
+}
+
+#[derive(Debug)]
+pub enum CallError<E> {
+    Transport(E),
+    /** The transport ended before the server returned */
+    Closed,
+    /** The server didn't return in time, see `ApiCaller::call_with_timeout`. The call may still
+    have been carried out. */
+    Timeout,
+    Encode(String),
+    /** The server returned something other than what the method returns */
+    Decode(String),
+    Method(api::MethodCallError),
+}
+impl<E: std::fmt::Display> std::fmt::Display for CallError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Transport(err) => write!(f, "Transport error: {}", err),
+            Self::Closed => write!(f, "The transport closed before the call returned"),
+            Self::Timeout => write!(f, "The call timed out"),
+            Self::Encode(err) => write!(f, "Failed to encode the call: {}", err),
+            Self::Decode(err) => write!(f, "Failed to decode the return: {}", err),
+            Self::Method(err) => write!(f, "{:?}", err),
+        }
+    }
+}
+
+/** Reads a return from the server as the return of a call to `M` */
+pub fn parse_return<M: api::ApiMethod, E>(
+    return_data: api::MethodCallReturnVariants,
+) -> Result<M::Success, CallError<E>> {
+    match return_data {
+        api::MethodCallReturnVariants::Success(success) => success
+            .parse::<M>()
+            .map_err(|err| CallError::Decode(err.to_string())),
+        api::MethodCallReturnVariants::Error(err) => Err(CallError::Method(err)),
+    }
+}
+
+/** Makes method calls over a `Transport` and waits for their returns. Anything else the server
+sends in the meantime is kept, in order, for `next_message`. */
+pub struct ApiCaller<T: Transport, K: CallerKey> {
+    transport: T,
+    builder: CallBuilder<K>,
+    session_key: Option<Vec<u8>>,
+    received: VecDeque<api::ServerToClientMessage>,
+}
+impl<T: Transport, K: CallerKey> ApiCaller<T, K> {
+    pub fn new(transport: T, builder: CallBuilder<K>) -> Self {
+        Self {
+            transport,
+            builder,
+            session_key: None,
+            received: VecDeque::new(),
+        }
+    }
+    pub fn builder(&mut self) -> &mut CallBuilder<K> {
+        &mut self.builder
+    }
+    /** Calls made afterwards are authenticated with the session instead of signed. The key is
+    from `CreateSessionSuccess`, `None` goes back to signing. */
+    pub fn set_session_key(&mut self, session_key: Option<Vec<u8>>) {
+        self.session_key = session_key;
+    }
+    pub async fn send(
+        &mut self,
+        message: &api::ClientToServerMessage,
+    ) -> Result<(), CallError<<T as Sink<String>>::Error>> {
+        let message =
+            serde_json::to_string(message).map_err(|err| CallError::Encode(err.to_string()))?;
+        self.transport
+            .send(message)
+            .await
+            .map_err(CallError::Transport)
+    }
+    // Messages that don't parse are skipped, like they are by the wasm client
+    async fn receive(&mut self) -> Option<api::ServerToClientMessage> {
+        while let Some(message) = self.transport.next().await {
+            if let Ok(message) = serde_json::from_str(&message) {
+                return Some(message);
+            }
+        }
+        None
+    }
+    /** Waits for the return for as long as the transport stays open */
+    pub async fn call<M: api::ApiMethod>(
+        &mut self,
+        args: M,
+    ) -> Result<M::Success, CallError<<T as Sink<String>>::Error>> {
+        self.call_with_timeout(args, future::pending()).await
+    }
+    /** Gives up with `CallError::Timeout` once `timeout` completes. It's a future rather than a
+    duration as native and wasm targets have different timers, e.g. a sleep from either. */
+    pub async fn call_with_timeout<M: api::ApiMethod>(
+        &mut self,
+        args: M,
+        timeout: impl Future<Output = ()>,
+    ) -> Result<M::Success, CallError<<T as Sink<String>>::Error>> {
+        let (call_id, message) = match &self.session_key {
+            Some(session_key) => self.builder.session_call(&session_key.clone(), args),
+            None => self.builder.server_call(args),
+        }
+        .map_err(|err| CallError::Encode(err.to_string()))?;
+        self.send(&message).await?;
+        let receiving = async {
+            loop {
+                match self.receive().await.ok_or(CallError::Closed)? {
+                    api::ServerToClientMessage::MethodCallReturn(call_return)
+                        if call_return.call_id == call_id =>
+                    {
+                        return parse_return::<M, _>(call_return.return_data);
+                    }
+                    other => self.received.push_back(other),
+                }
+            }
+        };
+        futures::pin_mut!(receiving, timeout);
+        match future::select(receiving, timeout).await {
+            Either::Left((result, _)) => result,
+            // Messages received until now are kept, a late return is just another message
+            Either::Right(_) => Err(CallError::Timeout),
+        }
+    }
+    /** The next message that wasn't the return of a call made with `call` */
+    pub async fn next_message(&mut self) -> Option<api::ServerToClientMessage> {
+        match self.received.pop_front() {
+            Some(message) => Some(message),
+            None => self.receive().await,
+        }
+    }
+}
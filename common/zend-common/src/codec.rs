@@ -0,0 +1,110 @@
+use crate::util;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/** Smaller messages aren't worth compressing, even when both sides support it */
+pub const COMPRESSION_THRESHOLD_BYTES: usize = 1024;
+
+/** Takes the place of a message that was encoded, then compressed with raw deflate. Only sent
+once both sides listed `ProtocolFeature::Compression` in their hellos. Its fields are always
+encoded in this order, so envelopes can be told apart by how they start, see
+`Encoding::envelope_prefix`. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompressedEnvelope {
+    pub compressed: bool,
+    /** Base64, so the envelope looks the same in every encoding */
+    pub payload: String,
+}
+
+/** How messages are encoded on the wire. Text frames always hold JSON and binary frames CBOR,
+so this only decides what each side sends. Signed calls stay embedded as canonical JSON strings,
+so they verify the same whichever encoding carried them. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Cbor,
+}
+impl std::str::FromStr for Encoding {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "json" => Encoding::Json,
+            "cbor" => Encoding::Cbor,
+            _ => return Err(()),
+        })
+    }
+}
+
+impl Encoding {
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(|err| err.to_string()),
+            Encoding::Cbor => to_cbor(value),
+        }
+    }
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Encoding::Json => serde_json::from_slice(bytes).map_err(|err| err.to_string()),
+            Encoding::Cbor => from_cbor(bytes),
+        }
+    }
+    /** Compresses the encoded message with `deflate` if it's large enough for that to help.
+    Compression is left to the caller, so this crate doesn't need a deflate implementation. */
+    pub fn encode_compressed<T: Serialize>(
+        self,
+        value: &T,
+        deflate: impl FnOnce(&[u8]) -> Vec<u8>,
+    ) -> Result<Vec<u8>, String> {
+        let bytes = self.encode(value)?;
+        if bytes.len() < COMPRESSION_THRESHOLD_BYTES {
+            return Ok(bytes);
+        }
+        let envelope = self.encode(&CompressedEnvelope {
+            compressed: true,
+            payload: util::encode_base64(&deflate(&bytes)),
+        })?;
+        Ok(if envelope.len() < bytes.len() {
+            envelope
+        } else {
+            bytes
+        })
+    }
+    /** How every `CompressedEnvelope` starts in this encoding, as `encode_compressed` makes
+    them. No other message starts with a `compressed` field. */
+    fn envelope_prefix(self) -> &'static [u8] {
+        match self {
+            Encoding::Json => br#"{"compressed":true,"#,
+            // A map of two entries, the text key of 10 bytes, then true
+            Encoding::Cbor => b"\xa2\x6acompressed\xf5",
+        }
+    }
+    /** Decodes messages whether or not they were compressed, unwrapping envelopes with `inflate`.
+    Only messages starting like an envelope are decoded as one, so others are only parsed once. */
+    pub fn decode_compressed<T: DeserializeOwned>(
+        self,
+        bytes: &[u8],
+        inflate: impl FnOnce(&[u8]) -> Result<Vec<u8>, String>,
+    ) -> Result<T, String> {
+        if !bytes.starts_with(self.envelope_prefix()) {
+            return self.decode(bytes);
+        }
+        let envelope: CompressedEnvelope = self.decode(bytes)?;
+        let deflated = util::decode_base64(&envelope.payload).map_err(|err| err.to_string())?;
+        self.decode(&inflate(&deflated)?)
+    }
+    /** Whether messages in this encoding go in binary frames */
+    pub fn is_binary(self) -> bool {
+        self == Encoding::Cbor
+    }
+}
+
+pub fn to_cbor<T: Serialize>(value: &T) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    ciborium::ser::into_writer(value, &mut bytes).map_err(|err| err.to_string())?;
+    Ok(bytes)
+}
+
+pub fn from_cbor<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+    ciborium::de::from_reader(bytes).map_err(|err| err.to_string())
+}
@@ -0,0 +1,69 @@
+use crate::api;
+use sha2::{Digest, Sha512};
+
+const FINGERPRINT_VERSION: u16 = 0;
+// Makes finding a key with a chosen fingerprint slower, like Signal's safety numbers
+const FINGERPRINT_ITERATIONS: usize = 5200;
+const GROUP_DIGITS: usize = 5;
+
+/** Thirty digits that stand for one peer's key, the same whoever it's computed by */
+fn key_fingerprint(key: &api::PublicKeyWrapper) -> String {
+    // The key's string form says which algorithm it's for, so it's hashed rather than its bytes
+    let key = key.to_string();
+    let mut hash = Sha512::new()
+        .chain_update(FINGERPRINT_VERSION.to_be_bytes())
+        .chain_update(key.as_bytes())
+        .finalize();
+    for _ in 1..FINGERPRINT_ITERATIONS {
+        hash = Sha512::new()
+            .chain_update(hash)
+            .chain_update(key.as_bytes())
+            .finalize();
+    }
+    hash[..30]
+        .chunks_exact(5)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, v| (acc << 8) | *v as u64);
+            format!("{:05}", value % 100_000)
+        })
+        .collect()
+}
+
+/** The first ten digits of the key's half of a `SafetyNumber`, to tell peers apart by at a
+glance. Far too short to verify a key with. */
+pub fn short_fingerprint(key: &api::PublicKeyWrapper) -> String {
+    let fingerprint = key_fingerprint(key);
+    format!(
+        "{} {}",
+        &fingerprint[..GROUP_DIGITS],
+        &fingerprint[GROUP_DIGITS..2 * GROUP_DIGITS]
+    )
+}
+
+/** Sixty digits for two peers to read to each other, e.g. in person or over a call, to check
+that neither of them was given a key by someone in between. Both get the same number, as the
+two keys' halves are ordered the same way for both. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafetyNumber(String);
+impl SafetyNumber {
+    pub fn new(own_key: &api::PublicKeyWrapper, peer_key: &api::PublicKeyWrapper) -> Self {
+        let mut halves = [key_fingerprint(own_key), key_fingerprint(peer_key)];
+        halves.sort();
+        Self(halves.concat())
+    }
+    pub fn digits(&self) -> &str {
+        &self.0
+    }
+    /** The digits in groups of five, which are easier to read out */
+    pub fn groups(&self) -> Vec<&str> {
+        (0..self.0.len())
+            .step_by(GROUP_DIGITS)
+            .map(|start| &self.0[start..start + GROUP_DIGITS])
+            .collect()
+    }
+}
+impl std::fmt::Display for SafetyNumber {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.groups().join(" "))
+    }
+}
@@ -4,6 +4,10 @@ pub mod _use {
     pub use web_sys;
 }
 pub mod api;
+pub mod caller;
+pub mod codec;
+pub mod fingerprint;
+pub mod logging;
 pub mod panic_hook;
 pub mod util;
 pub use enum_convert;
@@ -0,0 +1,147 @@
+use serde::Serialize;
+use std::cell::Cell;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+impl std::str::FromStr for Level {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "debug" => Level::Debug,
+            "info" => Level::Info,
+            "warn" => Level::Warn,
+            "error" => Level::Error,
+            _ => return Err(()),
+        })
+    }
+}
+
+thread_local! {
+    static MIN_LEVEL: Cell<Level> = const { Cell::new(Level::Info) };
+}
+
+/** Lines below this level are dropped. Applies to the whole isolate. */
+pub fn set_min_level(level: Level) {
+    MIN_LEVEL.with(|v| v.set(level));
+}
+
+/** Identifies what a log line belongs to, so logs can be filtered per connection or call */
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct LogContext {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connection_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub call_id: Option<u64>,
+}
+impl LogContext {
+    pub fn connection(connection_id: String) -> Self {
+        Self {
+            connection_id: Some(connection_id),
+            call_id: None,
+        }
+    }
+    pub fn with_call(&self, call_id: u64) -> Self {
+        Self {
+            call_id: Some(call_id),
+            ..self.clone()
+        }
+    }
+}
+
+/** A random hex string, unique enough to tell connections apart in logs */
+pub fn new_correlation_id() -> String {
+    let mut bytes = [0u8; 8];
+    // An all-zero ID is still better than no log line at all
+    let _ = getrandom::getrandom(&mut bytes);
+    hex::encode(bytes)
+}
+
+#[derive(Serialize)]
+struct LogLine<'a> {
+    level: Level,
+    target: &'a str,
+    #[serde(flatten)]
+    context: Option<&'a LogContext>,
+    message: &'a str,
+}
+
+/** Writes a single line of JSON to the console. Usually called through the `log_*` macros. */
+pub fn emit(level: Level, target: &str, context: Option<&LogContext>, message: &str) {
+    if level < MIN_LEVEL.with(Cell::get) {
+        return;
+    }
+    let line = LogLine {
+        level,
+        target,
+        context,
+        message,
+    };
+    let json = match serde_json::to_string(&line) {
+        Ok(json) => json,
+        Err(_) => return,
+    };
+    write_line(level, &json);
+}
+
+#[cfg(target_arch = "wasm32")]
+fn write_line(level: Level, json: &str) {
+    let json = wasm_bindgen::JsValue::from_str(json);
+    match level {
+        Level::Debug => web_sys::console::debug_1(&json),
+        Level::Info => web_sys::console::log_1(&json),
+        Level::Warn => web_sys::console::warn_1(&json),
+        Level::Error => web_sys::console::error_1(&json),
+    }
+}
+
+// There's no console off wasm, and calling into JS there panics
+#[cfg(not(target_arch = "wasm32"))]
+fn write_line(_: Level, json: &str) {
+    eprintln!("{}", json);
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! _log_with_level {
+    ($level:expr, ctx: $ctx:expr, $($arg:tt)*) => {
+        $crate::logging::emit(
+            $level,
+            ::std::module_path!(),
+            // Accepts both owned contexts and references to them
+            Some(::std::borrow::Borrow::<$crate::logging::LogContext>::borrow(&$ctx)),
+            &::std::fmt::format(format_args!($($arg)*)),
+        )
+    };
+    ($level:expr, $($arg:tt)*) => {
+        $crate::logging::emit(
+            $level,
+            ::std::module_path!(),
+            None,
+            &::std::fmt::format(format_args!($($arg)*)),
+        )
+    };
+}
+
+/** Like `log!`, but structured. Takes an optional `ctx: LogContext` before the format arguments. */
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => { $crate::_log_with_level!($crate::logging::Level::Debug, $($arg)*) };
+}
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => { $crate::_log_with_level!($crate::logging::Level::Info, $($arg)*) };
+}
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => { $crate::_log_with_level!($crate::logging::Level::Warn, $($arg)*) };
+}
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => { $crate::_log_with_level!($crate::logging::Level::Error, $($arg)*) };
+}
@@ -52,14 +52,19 @@ macro_rules! log {
         $crate::log!("")
     };
     ($($arg:tt)*) => {{
-        let arr = $crate::_use::js_sys::Array::new_with_length(3);
-        arr.set(
-            0,
-            $crate::_use::wasm_bindgen::JsValue::from_str(&format!("%c[{}:{}]", ::std::file!(), ::std::line!())),
-        );
-        arr.set(1, $crate::_use::wasm_bindgen::JsValue::from_str("font-weight: bold"));
-        let s = ::std::fmt::format(format_args!($($arg)*));
-        arr.set(2, $crate::_use::wasm_bindgen::JsValue::from_str(&s));
-        $crate::_use::web_sys::console::log(&arr);
+        #[cfg(target_arch = "wasm32")]
+        {
+            let arr = $crate::_use::js_sys::Array::new_with_length(3);
+            arr.set(
+                0,
+                $crate::_use::wasm_bindgen::JsValue::from_str(&format!("%c[{}:{}]", ::std::file!(), ::std::line!())),
+            );
+            arr.set(1, $crate::_use::wasm_bindgen::JsValue::from_str("font-weight: bold"));
+            let s = ::std::fmt::format(format_args!($($arg)*));
+            arr.set(2, $crate::_use::wasm_bindgen::JsValue::from_str(&s));
+            $crate::_use::web_sys::console::log(&arr);
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        ::std::eprintln!("[{}:{}] {}", ::std::file!(), ::std::line!(), format_args!($($arg)*));
     }};
 }
@@ -1,24 +1,63 @@
 #![allow(dead_code)]
 
-use crate::wsclient::WsApiClient;
+use crate::{
+    config::ClientConfig,
+    wsclient::{ApiClientEvent, SubscriptionEventFilter, WebSocketState, WsApiClient},
+};
 use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit};
+use events::AppEventSubscribers;
+use futures::{
+    channel::mpsc,
+    future::{self, AbortHandle},
+    stream, Stream, StreamExt,
+};
+use identity::IdentityStore;
 use std::{
+    cell::{Cell, Ref, RefCell},
+    collections::HashMap,
     fmt::Debug,
+    rc::Rc,
     time::{Duration, SystemTime},
 };
 use zend_common::{
     _use::wasm_bindgen::UnwrapThrowExt,
-    api::{self, EcdsaSignatureWrapper},
-    util,
+    api::{self, SignatureWrapper},
+    caller::{CallBuilder, CallError},
+    fingerprint::SafetyNumber,
+    log, util,
 };
 
-use p256::{
-    ecdh,
-    ecdsa::{self, signature::Verifier},
-};
+use p256::{ecdh, ecdsa};
+use rand_core::RngCore;
 use serde::{Deserialize, Serialize};
 use serde_json;
 
+mod attachments;
+mod crypto;
+mod events;
+mod identity;
+
+pub use attachments::{Attachment, AttachmentState};
+pub use events::{AppEvent, AppEventFilter, AppEventHandle};
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    rand_core::OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/** Derives the AES key of a peer-encrypted message from the ECDH shared secret */
+fn derive_peer_key(
+    shared: ecdh::SharedSecret,
+    salt: &[u8; 32],
+) -> Result<aes_gcm::Key<aes_gcm::Aes256Gcm>, &'static str> {
+    let hkdf = shared.extract::<sha2::Sha256>(Some(salt));
+    let mut okm = [0u8; 32];
+    hkdf.expand(&[], &mut okm)
+        .map_err(|_| "Failed to use ECDH shared secret as AES key material")?;
+    Ok(*aes_gcm::Key::<aes_gcm::Aes256Gcm>::from_slice(&okm))
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(try_from = "&str", into = "String")]
 struct EcdhPublicKey(pub p256::PublicKey);
@@ -47,8 +86,8 @@ struct Aes256GcmKey(pub aes_gcm::Key<aes_gcm::Aes256Gcm>);
 impl TryFrom<&str> for Aes256GcmKey {
     type Error = &'static str;
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let mut output: [u8; 12] = [0; 12];
-        util::decode_base64_slice_exact(value, 12, &mut output)?;
+        let mut output: [u8; 32] = [0; 32];
+        util::decode_base64_slice_exact(value, 32, &mut output)?;
         let key: &aes_gcm::Key<aes_gcm::Aes256Gcm> = output.as_slice().into();
         Ok(Self(*key))
     }
@@ -97,10 +136,16 @@ impl Into<String> for HkdfSalt {
 struct EncodedDataCipherRoom {
     aes_text: String,
     aes_iv: Aes256GcmIv,
+    // Which of the `RoomKeys` it's encrypted with. Missing before the key was first rotated.
+    #[serde(default)]
+    key_id: u32,
 }
 impl EncodedDataCipherRoom {
-    fn decrypt(&self, key: &Aes256GcmKey) -> Result<String, &'static str> {
-        let cipher = aes_gcm::Aes256Gcm::new(&key.0);
+    fn decrypt(&self, keys: &RoomKeys) -> Result<String, &'static str> {
+        let key = keys
+            .get(self.key_id)
+            .ok_or("Data is encrypted with a room key we don't have")?;
+        let cipher = aes_gcm::Aes256Gcm::new(key);
         String::from_utf8(
             cipher
                 .decrypt(
@@ -113,14 +158,16 @@ impl EncodedDataCipherRoom {
         )
         .map_err(|_| "Failed to utf8-decode room-encrypted ciphertext's plaintext")
     }
-    fn encrypt(key: &aes_gcm::Key<aes_gcm::Aes256Gcm>, iv: [u8; 12], plaintext: String) -> Self {
-        let cipher = Aes256Gcm::new(key);
+    fn encrypt(keys: &RoomKeys, iv: [u8; 12], plaintext: String) -> Self {
+        let (key_id, key) = keys.current();
+        let cipher = Aes256Gcm::new(&key);
         let cipher_text = cipher
             .encrypt(&iv.into(), plaintext.as_bytes())
             .unwrap_throw();
         Self {
             aes_text: util::encode_base64(&cipher_text),
             aes_iv: Aes256GcmIv(iv),
+            key_id,
         }
     }
 }
@@ -133,14 +180,11 @@ struct EncodedDataCipherPeer {
     aes_text: String,
 }
 impl EncodedDataCipherPeer {
-    fn decrypt(&self, key: &ecdh::EphemeralSecret) -> Result<String, &'static str> {
-        let shared = key.diffie_hellman(&self.ecdh_public_key.0);
-        let hkdf = shared.extract::<sha2::Sha256>(Some(&self.hkdf_salt.0));
-        let mut okm = [0u8; 32];
-        hkdf.expand(&[], &mut okm)
-            .map_err(|_| "Failed to use ECDH shared secret as AES key material")?;
-        let hkdf_derived_key: &aes_gcm::Key<aes_gcm::Aes256Gcm> = okm.as_slice().into();
-        let cipher = aes_gcm::Aes256Gcm::new(&hkdf_derived_key);
+    fn decrypt(&self, key: &p256::SecretKey) -> Result<String, &'static str> {
+        let shared =
+            ecdh::diffie_hellman(key.to_nonzero_scalar(), self.ecdh_public_key.0.as_affine());
+        let derived_key = derive_peer_key(shared, &self.hkdf_salt.0)?;
+        let cipher = aes_gcm::Aes256Gcm::new(&derived_key);
         String::from_utf8(
             cipher
                 .decrypt(
@@ -164,31 +208,49 @@ struct EncodedDataTextPlain {
 #[serde(rename_all = "snake_case", tag = "cipher_type")]
 enum CipherInfo {
     Room(EncodedDataCipherRoom),
+    // Only sent by clients from before `Ratchet`, which is read with a key that's never replaced
     Peer(EncodedDataCipherPeer),
+    Ratchet(crypto::EncodedDataCipherRatchet),
     Plain(EncodedDataTextPlain),
 }
 
+impl CipherInfo {
+    /** Encrypted with the current room key */
+    fn room(room_keys: &RoomKeys, iv: [u8; 12], call: &RoomMethodCall) -> Self {
+        let call_json = serde_json::to_string(call).unwrap_throw();
+        Self::Room(EncodedDataCipherRoom::encrypt(room_keys, iv, call_json))
+    }
+    fn plain(call: &RoomMethodCall) -> Self {
+        let plain_text = serde_json::to_string(call).unwrap_throw();
+        Self::Plain(EncodedDataTextPlain { plain_text })
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 struct CipherPart {
     cipher_info: String,
-    signature: api::EcdsaSignatureWrapper,
+    signature: api::SignatureWrapper,
 }
 impl CipherPart {
-    fn with_room_key(
-        room_key: &aes_gcm::Key<aes_gcm::Aes256Gcm>,
-        signing_key: &ecdsa::SigningKey,
-        iv: [u8; 12],
-        call: &RoomMethodCall,
+    /** Signed together with the sender, room and nonce, like `EncodedData::from_message`
+    expects. The nonce is the one of the next call `calls` makes, which has to carry this. */
+    fn new(
+        cipher_info: &CipherInfo,
+        calls: &CallBuilder<ecdsa::SigningKey>,
+        room_id: api::RoomId,
     ) -> Self {
         use p256::ecdsa::signature::Signer;
 
-        let call_json = serde_json::to_string(call).unwrap_throw();
-        let encoded = EncodedDataCipherRoom::encrypt(room_key, iv, call_json);
-        let cipher_info = CipherInfo::Room(encoded);
-        let cipher_info_json = serde_json::to_string(&cipher_info).unwrap_throw();
-
+        let cipher_info_json = serde_json::to_string(cipher_info).unwrap_throw();
+        let normalized = format!(
+            "{}&{}&{}&{}",
+            calls.caller_id().to_string(),
+            room_id.legacy_code(),
+            calls.peek_nonce().to_string(),
+            cipher_info_json
+        );
         Self {
-            signature: EcdsaSignatureWrapper(signing_key.sign(cipher_info_json.as_bytes())),
+            signature: SignatureWrapper::P256(calls.key().sign(normalized.as_bytes())),
             cipher_info: cipher_info_json,
         }
     }
@@ -196,31 +258,44 @@ impl CipherPart {
 
 struct EncodedData {
     room_id: api::RoomId,
-    sender_id: api::EcdsaPublicKeyWrapper,
+    sender_id: api::PublicKeyWrapper,
     nonce: api::Nonce,
     cipher_info: CipherInfo,
 }
 impl EncodedData {
     fn from_message(data: api::SubscriptionData) -> Result<Self, &'static str> {
+        Self::from_parts(data.room_id, data.sender_id, data.nonce, data.data)
+    }
+    fn from_history_entry(
+        room_id: api::RoomId,
+        entry: api::RoomDataHistoryEntry,
+    ) -> Result<Self, &'static str> {
+        Self::from_parts(room_id, entry.sender_id, entry.nonce, entry.data)
+    }
+    fn from_parts(
+        room_id: api::RoomId,
+        sender_id: api::PublicKeyWrapper,
+        nonce: api::Nonce,
+        data: serde_json::Value,
+    ) -> Result<Self, &'static str> {
         let cipher_part: CipherPart =
-            serde_json::from_value(data.data).map_err(|_| "Error parsing CipherPart")?;
+            serde_json::from_value(data).map_err(|_| "Error parsing CipherPart")?;
         let cipher_info: CipherInfo = serde_json::from_str(&cipher_part.cipher_info)
             .map_err(|_| "Error parsing CipherInfo")?;
         let normalized = format!(
             "{}&{}&{}&{}",
-            data.sender_id.to_string(),
-            data.room_id.to_string(),
-            data.nonce.to_string(),
+            sender_id.to_string(),
+            room_id.legacy_code(),
+            nonce.to_string(),
             cipher_part.cipher_info
         );
-        data.sender_id
-            .0
-            .verify(&normalized.as_bytes(), &cipher_part.signature.0)
+        sender_id
+            .verify(normalized.as_bytes(), &cipher_part.signature)
             .map_err(|_| "ECDSA authentication failed")?;
         Ok(Self {
-            room_id: data.room_id,
-            sender_id: data.sender_id,
-            nonce: data.nonce,
+            room_id,
+            sender_id,
+            nonce,
             cipher_info,
         })
     }
@@ -230,40 +305,181 @@ impl EncodedData {
 enum RoomMethodCall {
     AcceptJoin {
         room_key: Aes256GcmKey,
+        #[serde(default)]
+        key_id: u32,
+        // Keys from before rotations, to read the history with
+        #[serde(default)]
+        older_keys: Vec<SharedRoomKey>,
+        // Of the members the sender knows of, including itself
+        #[serde(default)]
+        member_keys: Vec<MemberKey>,
     },
     InitJoin {
         joining_id: EcdhPublicKey,
     },
     SendMessage {
         message: String,
+        #[serde(default)]
+        in_reply_to: Option<MessageRef>,
     },
     DeleteMessage {
         target_nonce: api::Nonce,
-        sender_id: api::EcdsaPublicKeyWrapper,
+        sender_id: api::PublicKeyWrapper,
     },
     ConfirmJoin {
-        joined_id: api::EcdsaPublicKeyWrapper,
+        joined_id: api::PublicKeyWrapper,
+        #[serde(default)]
+        ecdh_public_key: Option<EcdhPublicKey>,
     },
     PreventJoin {
-        denied_id: api::EcdsaPublicKeyWrapper,
+        denied_id: api::PublicKeyWrapper,
+    },
+    // Peer-encrypted to each member ahead of `RotateKey`
+    ShareKey {
+        key: SharedRoomKey,
+    },
+    // Encrypted with the new key, which room-encrypted data is sent with from now on
+    RotateKey {
+        key_id: u32,
+    },
+    SetNickname {
+        nickname: String,
+    },
+    // Room- or peer-encrypted, ahead of the file's chunks
+    FileManifest {
+        manifest: attachments::FileManifest,
+    },
+    // Plain, as chunks are encrypted with the key from the manifest
+    FileChunk {
+        chunk: attachments::FileChunk,
     },
+    // Kept in the history, so the reactions of a message are known to peers that join later
+    React {
+        target_nonce: api::Nonce,
+        target_sender: api::PublicKeyWrapper,
+        reaction: String,
+        // Takes back a reaction made before
+        #[serde(default)]
+        remove: bool,
+    },
+    // Only sent by peers that opted in, see `AppClient::set_send_read_receipts`
+    ReadUpTo {
+        nonce: api::Nonce,
+    },
+    // Room-encrypted by a peer that got the key from an invite link, see `Invite`
+    RedeemInvite {
+        ecdh_public_key: EcdhPublicKey,
+    },
+}
+
+/** Sent with `SendEphemeral` as a room-encrypted `EncodedDataCipherRoom`, which isn't signed
+like `CipherPart`. The server says who sent it, and only passes it on from peers with a role. */
+#[derive(Debug, Clone, Deserialize, Serialize)]
+enum EphemeralCall {
+    Typing { typing: bool },
+}
+
+// How often `AppClient::set_typing` tells the room we're still typing
+const TYPING_RESEND_MS: u64 = 3_000;
+// How long a peer counts as typing without saying so again
+const TYPING_EXPIRY_MS: u64 = 6_000;
+// How long after a change the identity is stored, so a burst of calls, like the chunks of a file,
+// is stored once
+const PERSIST_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct SharedRoomKey {
+    key_id: u32,
+    room_key: Aes256GcmKey,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct MemberKey {
+    peer_id: api::PublicKeyWrapper,
+    ecdh_public_key: EcdhPublicKey,
+}
+
+/** The room's keys by ID. Room-encrypted data is sent with the current one, older ones are kept
+to read what was sent before the key was rotated. */
+#[derive(Debug, Clone)]
+pub struct RoomKeys {
+    current_id: u32,
+    keys: HashMap<u32, aes_gcm::Key<aes_gcm::Aes256Gcm>>,
+}
+impl RoomKeys {
+    fn new(key_id: u32, key: aes_gcm::Key<aes_gcm::Aes256Gcm>) -> Self {
+        Self {
+            current_id: key_id,
+            keys: HashMap::from([(key_id, key)]),
+        }
+    }
+    fn current(&self) -> (u32, aes_gcm::Key<aes_gcm::Aes256Gcm>) {
+        (self.current_id, self.keys[&self.current_id])
+    }
+    fn get(&self, key_id: u32) -> Option<&aes_gcm::Key<aes_gcm::Aes256Gcm>> {
+        self.keys.get(&key_id)
+    }
+    fn insert(&mut self, key_id: u32, key: aes_gcm::Key<aes_gcm::Aes256Gcm>) {
+        self.keys.insert(key_id, key);
+    }
+    /** Fails if the key wasn't shared with us */
+    fn set_current(&mut self, key_id: u32) -> Result<(), &'static str> {
+        if !self.keys.contains_key(&key_id) {
+            return Err("Rotated to a room key we don't have");
+        }
+        self.current_id = key_id;
+        Ok(())
+    }
+    fn next_id(&self) -> u32 {
+        self.keys.keys().max().map_or(0, |v| v + 1)
+    }
+    fn shared_keys(&self) -> Vec<SharedRoomKey> {
+        self.keys
+            .iter()
+            .map(|(key_id, key)| SharedRoomKey {
+                key_id: *key_id,
+                room_key: Aes256GcmKey(*key),
+            })
+            .collect()
+    }
+    fn older_keys(&self) -> Vec<SharedRoomKey> {
+        let mut keys = self.shared_keys();
+        keys.retain(|v| v.key_id != self.current_id);
+        keys
+    }
 }
 
 struct DecodedData {
     method_call: RoomMethodCall,
     room_id: api::RoomId,
-    sender_id: api::EcdsaPublicKeyWrapper,
+    sender_id: api::PublicKeyWrapper,
     nonce: api::Nonce,
+    // Which room key it was encrypted with, if it was room-encrypted
+    room_key_id: Option<u32>,
 }
 impl DecodedData {
+    /** Room-encrypted data can't be read without `room_keys`, e.g. while joining. Reading
+    peer-encrypted data moves our session with the sender on. */
     fn from_encoded_data(
         data: EncodedData,
-        aes_key: &Aes256GcmKey,
-        ecdh_secret: &ecdh::EphemeralSecret,
+        room_keys: Option<&RoomKeys>,
+        own_id: &api::PublicKeyWrapper,
+        ecdh_secret: &p256::SecretKey,
+        ratchets: &mut crypto::Ratchets,
     ) -> Result<Self, &'static str> {
+        let room_key_id = match &data.cipher_info {
+            CipherInfo::Room(info) => Some(info.key_id),
+            _ => None,
+        };
         let info_json = match data.cipher_info {
-            CipherInfo::Room(info) => info.decrypt(aes_key)?,
+            CipherInfo::Room(info) => {
+                info.decrypt(room_keys.ok_or("No room key to decrypt with")?)?
+            }
             CipherInfo::Peer(info) => info.decrypt(ecdh_secret)?,
+            CipherInfo::Ratchet(info) => {
+                String::from_utf8(ratchets.decrypt(own_id, &data.sender_id, ecdh_secret, &info)?)
+                    .map_err(|_| "Failed to utf8-decode peer-encrypted ciphertext's plaintext")?
+            }
             CipherInfo::Plain(info) => info.plain_text,
         };
         let call: RoomMethodCall = serde_json::from_str(&info_json)
@@ -273,126 +489,2041 @@ impl DecodedData {
             room_id: data.room_id,
             sender_id: data.sender_id,
             nonce: data.nonce,
+            room_key_id,
         })
     }
 }
 
 struct JoinedRoomInfo {
-    room_key: aes_gcm::Key<aes_gcm::Aes256Gcm>,
+    room_keys: RoomKeys,
     room_id: api::RoomId,
 }
 
-#[derive(Debug)]
+/** Points at a message by who sent it and its nonce, which together are unique */
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MessageRef {
+    sender_id: api::PublicKeyWrapper,
+    nonce: api::Nonce,
+}
+impl MessageRef {
+    pub fn new(sender_id: api::PublicKeyWrapper, nonce: api::Nonce) -> Self {
+        Self { sender_id, nonce }
+    }
+    pub fn sender_id(&self) -> &api::PublicKeyWrapper {
+        &self.sender_id
+    }
+    pub fn nonce(&self) -> api::Nonce {
+        self.nonce
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct RoomTextMessage {
-    text: String,
+    // None once deleted, leaving the message in place as a tombstone
+    text: Option<String>,
     nonce: api::Nonce,
-    sender_id: api::EcdsaPublicKeyWrapper,
+    sender_id: api::PublicKeyWrapper,
+    in_reply_to: Option<MessageRef>,
+}
+impl RoomTextMessage {
+    /** None if the message was deleted */
+    pub fn text(&self) -> Option<&str> {
+        self.text.as_deref()
+    }
+    pub fn is_deleted(&self) -> bool {
+        self.text.is_none()
+    }
+    pub fn nonce(&self) -> api::Nonce {
+        self.nonce
+    }
+    pub fn sender_id(&self) -> &api::PublicKeyWrapper {
+        &self.sender_id
+    }
+    /** The message this one replies to, which may not have been received */
+    pub fn in_reply_to(&self) -> Option<&MessageRef> {
+        self.in_reply_to.as_ref()
+    }
+}
+
+/** A link that lets whoever opens it into a room, without a member having to accept them. Holds
+the room's current key in the fragment, which browsers don't send to the server. Only the
+history from when the key was last rotated can be read with it, and rotating the key makes
+links from before stop working. */
+#[derive(Debug, Clone)]
+pub struct Invite {
+    pub room_id: api::RoomId,
+    key_id: u32,
+    room_key: aes_gcm::Key<aes_gcm::Aes256Gcm>,
+}
+impl Invite {
+    /** Reads a link made by `to_url`, with any origin */
+    pub fn parse(url: &str) -> Result<Self, &'static str> {
+        let (path, fragment) = url.split_once('#').ok_or("Invite link has no room key")?;
+        let path = path.split('?').next().unwrap_or(path);
+        let (_, room_code) = path.rsplit_once("/room/").ok_or("Not an invite link")?;
+        let room_id = api::RoomId::parse(room_code.trim_end_matches('/'), true)?;
+        let mut bytes = [0u8; 36];
+        util::decode_base64_slice_exact(fragment, 36, &mut bytes)
+            .map_err(|_| "Invalid room key in invite link")?;
+        let (room_key, key_id) = bytes.split_at(32);
+        Ok(Self {
+            room_id,
+            key_id: u32::from_be_bytes(key_id.try_into().unwrap_throw()),
+            room_key: *aes_gcm::Key::<aes_gcm::Aes256Gcm>::from_slice(room_key),
+        })
+    }
+    /** `base_url` is where the app is served from, e.g. `https://example.com` */
+    pub fn to_url(&self, base_url: &str) -> String {
+        let mut bytes = self.room_key.to_vec();
+        bytes.extend_from_slice(&self.key_id.to_be_bytes());
+        format!(
+            "{}/room/{}#{}",
+            base_url.trim_end_matches('/'),
+            self.room_id,
+            util::encode_base64(&bytes)
+        )
+    }
+}
+
+/** Someone who asked to join a room we're in, see `AppClient::accept_join` */
+#[derive(Debug, Clone)]
+pub struct JoinRequest {
+    pub room_id: api::RoomId,
+    pub peer_id: api::PublicKeyWrapper,
+    ecdh_public_key: EcdhPublicKey,
 }
 
 // Valid state transitions are:
-// NoRoom -> CreatingRoom
-// NoRoom -> JoiningRoom
-// CreatingRoom -> InRoom
-// Joiningroom -> Inroom
-// InRoom -> NoRoom (By AppState reinit)
+// JoiningRoom -> LoadingHistory (Once a member sent AcceptJoin and ConfirmJoin, or only
+//                                 ConfirmJoin when joining with an invite)
+// LoadingHistory -> InRoom (Once the room's history was read, or failed to be)
+// InRoom -> LoadingHistory (After `AppClient::reconnect`, to read what was missed)
+// JoiningRoom -> Forgotten (If a member sent PreventJoin, or after `AppClient::reconnect`)
+// Rooms we created start out InRoom
 #[derive(Debug)]
-pub enum CurrentAppState {
-    NoRoom,
-    CreatingRoom,
+pub enum RoomPhase {
     JoiningRoom {
-        room_id: api::RoomId,
+        // From AcceptJoin, which comes before ConfirmJoin so the confirmation can be read, or
+        // from the invite
+        room_keys: Option<RoomKeys>,
+    },
+    // Live data waits until the history before it was read, see `AppClient::new`
+    LoadingHistory {
+        room_keys: RoomKeys,
     },
     InRoom {
-        room_id: api::RoomId,
-        room_key: aes_gcm::Key<aes_gcm::Aes256Gcm>,
+        room_keys: RoomKeys,
+    },
+}
+
+/** Whether we compared a peer's key with them some other way */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VerificationState {
+    #[default]
+    Unverified,
+    FingerprintVerified,
+}
+
+const MAX_NICKNAME_CHARS: usize = 64;
+// Enough for any emoji, made of however many code points
+const MAX_REACTION_CHARS: usize = 16;
+
+/** One reaction to a message, and who reacted with it, for the UI to show below it */
+#[derive(Debug, Clone)]
+pub struct ReactionSummary {
+    pub reaction: String,
+    pub peers: Vec<api::PublicKeyWrapper>,
+    /** Whether we're one of `peers`, e.g. so clicking it again removes ours */
+    pub own: bool,
+}
+
+/** A peer whose safety number we compared with them. Kept across rooms, as it's their key that
+was verified. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct VerifiedPeer {
+    peer_id: api::PublicKeyWrapper,
+    // The last one they set, to tell when someone else starts using it
+    nickname: Option<String>,
+}
+
+/** Something the UI should point out, see `AppClient::take_warnings` */
+#[derive(Debug, Clone)]
+pub enum RoomWarning {
+    /** A peer set the nickname of a peer we verified, but with a different key. Either the
+    verified peer lost their key and made a new one, or someone is posing as them. */
+    KeyChanged {
+        nickname: String,
+        verified_id: api::PublicKeyWrapper,
+        peer_id: api::PublicKeyWrapper,
+    },
+    /** Live data from the peer that didn't come after what it sent before, so the server sent
+    it again or out of order. It was dropped. */
+    Replayed {
+        peer_id: api::PublicKeyWrapper,
+        nonce: api::Nonce,
     },
 }
 
+/** The newest data we've seen from one peer in a room, as nonces only ever increase */
+#[derive(Debug)]
+struct SenderNonces {
+    highest: api::Nonce,
+    // Newest from the room's history. Live data up to it was already read from there, as live
+    // data queues up while the history loads.
+    history_highest: Option<api::Nonce>,
+}
+
+/** A peer of a room, as far as the join flow, presence events and its data told us */
+#[derive(Debug, Clone)]
+pub struct RosterEntry {
+    pub peer_id: api::PublicKeyWrapper,
+    /** Chosen by the peer itself, so nothing stops two peers from picking the same one */
+    pub nickname: Option<String>,
+    pub verification: VerificationState,
+    /** Has a role in the room */
+    pub member: bool,
+    /** Has a subscription to the room. Only known from presence events, which are only sent
+    to members. */
+    pub online: bool,
+}
+
+#[derive(Debug)]
+pub struct PerRoomState {
+    phase: RoomPhase,
+    messages: Vec<RoomTextMessage>,
+    attachments: Vec<Attachment>,
+    // By the sender and nonce of the message they're on, in the order they were first used
+    reactions: HashMap<(String, api::Nonce), Vec<(String, Vec<api::PublicKeyWrapper>)>>,
+    // Indices into `messages` of the replies to a message, by its sender and nonce
+    threads: HashMap<(String, api::Nonce), Vec<usize>>,
+    // Peers typing, in the order they started, until the time in milliseconds
+    typing: Vec<(api::PublicKeyWrapper, u64)>,
+    // When we last told the room we're typing, None once we told it we stopped
+    typing_sent_at: Option<u64>,
+    // Told of changes to `typing`, see `AppClient::typing_stream`
+    typing_watchers: Vec<mpsc::UnboundedSender<()>>,
+    // Not yet sent to subscribers, see `dispatch_events`
+    events: Vec<AppEvent>,
+    roster: Vec<RosterEntry>,
+    warnings: Vec<RoomWarning>,
+    // By sender
+    nonces: HashMap<String, SenderNonces>,
+    // Messages with nonces up to this one were read by us
+    read_up_to: Option<api::Nonce>,
+    // By the peers that told us, keyed by peer ID
+    read_positions: HashMap<String, (api::PublicKeyWrapper, api::Nonce)>,
+    // Waiting for us to accept or prevent them, while we're in the room
+    pending_joins: Vec<JoinRequest>,
+    // Waiting to be let in, as they have the current room key, see `admit_invited`
+    redeemed_invites: Vec<JoinRequest>,
+    // Of the other members, to send them new room keys
+    member_keys: Vec<MemberKey>,
+}
+impl PerRoomState {
+    fn new(phase: RoomPhase) -> Self {
+        Self {
+            phase,
+            messages: Vec::new(),
+            attachments: Vec::new(),
+            reactions: HashMap::new(),
+            threads: HashMap::new(),
+            typing: Vec::new(),
+            typing_sent_at: None,
+            typing_watchers: Vec::new(),
+            events: Vec::new(),
+            roster: Vec::new(),
+            warnings: Vec::new(),
+            nonces: HashMap::new(),
+            read_up_to: None,
+            read_positions: HashMap::new(),
+            pending_joins: Vec::new(),
+            redeemed_invites: Vec::new(),
+            member_keys: Vec::new(),
+        }
+    }
+    // Adds the peer if it's new to us
+    fn roster_entry(&mut self, peer_id: &api::PublicKeyWrapper) -> &mut RosterEntry {
+        let peer_key = peer_id.to_string();
+        let index = match self
+            .roster
+            .iter()
+            .position(|v| v.peer_id.to_string() == peer_key)
+        {
+            Some(index) => index,
+            None => {
+                self.roster.push(RosterEntry {
+                    peer_id: peer_id.clone(),
+                    nickname: None,
+                    verification: VerificationState::Unverified,
+                    member: false,
+                    online: false,
+                });
+                self.roster.len() - 1
+            }
+        };
+        &mut self.roster[index]
+    }
+    /** Fails for live data that isn't newer than what the sender sent before */
+    fn check_nonce(
+        &mut self,
+        sender_id: &api::PublicKeyWrapper,
+        nonce: api::Nonce,
+    ) -> Result<(), &'static str> {
+        let nonces = match self.nonces.get_mut(&sender_id.to_string()) {
+            Some(nonces) => nonces,
+            None => {
+                self.nonces.insert(
+                    sender_id.to_string(),
+                    SenderNonces {
+                        highest: nonce,
+                        history_highest: None,
+                    },
+                );
+                return Ok(());
+            }
+        };
+        if nonce > nonces.highest {
+            nonces.highest = nonce;
+            if matches!(nonces.history_highest, Some(v) if nonce > v) {
+                nonces.history_highest = None;
+            }
+            return Ok(());
+        }
+        if matches!(nonces.history_highest, Some(v) if nonce <= v) {
+            return Err("Data was already read from the history");
+        }
+        self.warnings.push(RoomWarning::Replayed {
+            peer_id: sender_id.clone(),
+            nonce,
+        });
+        Err("Data isn't newer than what its sender sent before")
+    }
+    // History entries are never rejected, as they're read after live data that may be newer
+    fn record_history_nonce(&mut self, sender_id: &api::PublicKeyWrapper, nonce: api::Nonce) {
+        let nonces = self
+            .nonces
+            .entry(sender_id.to_string())
+            .or_insert(SenderNonces {
+                highest: nonce,
+                history_highest: None,
+            });
+        nonces.highest = std::cmp::max(nonces.highest, nonce);
+        nonces.history_highest = Some(std::cmp::max(
+            nonces.history_highest.unwrap_or(nonce),
+            nonce,
+        ));
+    }
+    fn handle_presence(&mut self, event: &api::PresenceEvent) {
+        if matches!(event.kind, api::PresenceKind::Joined) {
+            self.mark_joined(event.room_id, &event.peer_id);
+        }
+        let entry = self.roster_entry(&event.peer_id);
+        match event.kind {
+            api::PresenceKind::Joined => {}
+            api::PresenceKind::Left => entry.member = false,
+            api::PresenceKind::Subscribed => entry.online = true,
+            api::PresenceKind::Unsubscribed => entry.online = false,
+        }
+        if matches!(event.kind, api::PresenceKind::Unsubscribed) {
+            self.set_typing(&event.peer_id, None);
+        }
+    }
+    // Only tells subscribers if the peer wasn't a member already
+    fn mark_joined(&mut self, room_id: api::RoomId, peer_id: &api::PublicKeyWrapper) {
+        let entry = self.roster_entry(peer_id);
+        if entry.member {
+            return;
+        }
+        entry.member = true;
+        self.events.push(AppEvent::PeerJoined {
+            room_id,
+            peer_id: peer_id.clone(),
+        });
+    }
+    /** Typing until `until`, or not typing if it's None */
+    fn set_typing(&mut self, peer_id: &api::PublicKeyWrapper, until: Option<u64>) {
+        let peer_key = peer_id.to_string();
+        let index = self.typing.iter().position(|v| v.0.to_string() == peer_key);
+        match (index, until) {
+            (None, None) => return,
+            // Keeps the peer's place, so who's typing isn't reordered each time they say so
+            (Some(index), Some(until)) => self.typing[index].1 = until,
+            (Some(index), None) => {
+                self.typing.remove(index);
+            }
+            (None, Some(until)) => self.typing.push((peer_id.clone(), until)),
+        }
+        self.typing_watchers
+            .retain(|watcher| watcher.unbounded_send(()).is_ok());
+    }
+    /** Who's typing at `now`, and how long until the next of them stops if nobody says so */
+    fn typing_peers(&mut self, now: u64) -> (Vec<api::PublicKeyWrapper>, Option<u64>) {
+        self.typing.retain(|v| v.1 > now);
+        let next_expiry = self.typing.iter().map(|v| v.1 - now).min();
+        let peers = self.typing.iter().map(|v| v.0.clone()).collect();
+        (peers, next_expiry)
+    }
+    // Keys to read data with, in every phase but the start of joining
+    fn room_keys(&self) -> Option<&RoomKeys> {
+        match &self.phase {
+            RoomPhase::InRoom { room_keys } | RoomPhase::LoadingHistory { room_keys } => {
+                Some(room_keys)
+            }
+            RoomPhase::JoiningRoom { room_keys } => room_keys.as_ref(),
+        }
+    }
+    fn forget_join_request(&mut self, peer_id: &api::PublicKeyWrapper) {
+        let peer_id = peer_id.to_string();
+        self.pending_joins
+            .retain(|v| v.peer_id.to_string() != peer_id);
+    }
+    fn remember_member(&mut self, member: MemberKey) {
+        let peer_id = member.peer_id.to_string();
+        self.member_keys
+            .retain(|v| v.peer_id.to_string() != peer_id);
+        self.member_keys.push(member);
+    }
+    fn is_member(&self, peer_id: &api::PublicKeyWrapper) -> bool {
+        let peer_id = peer_id.to_string();
+        self.member_keys
+            .iter()
+            .any(|v| v.peer_id.to_string() == peer_id)
+    }
+    /** Acts on a call made in this room. False if we were prevented from joining it, so it
+    should be forgotten. */
+    fn handle_room_call(
+        &mut self,
+        data: DecodedData,
+        own_id: &str,
+        verified_peers: &mut [VerifiedPeer],
+    ) -> bool {
+        let is_own = data.sender_id.to_string() == own_id;
+        let in_room = matches!(
+            self.phase,
+            RoomPhase::InRoom { .. } | RoomPhase::LoadingHistory { .. }
+        );
+        match data.method_call {
+            RoomMethodCall::SendMessage {
+                message,
+                in_reply_to,
+            } if in_room => {
+                // History and live data overlap while joining
+                let sender_id = data.sender_id.to_string();
+                if self
+                    .messages
+                    .iter()
+                    .any(|v| v.nonce == data.nonce && v.sender_id.to_string() == sender_id)
+                {
+                    return true;
+                }
+                self.roster_entry(&data.sender_id);
+                // Their message is what they were typing
+                self.set_typing(&data.sender_id, None);
+                if let Some(target) = &in_reply_to {
+                    self.threads
+                        .entry((target.sender_id.to_string(), target.nonce))
+                        .or_default()
+                        .push(self.messages.len());
+                }
+                let message = RoomTextMessage {
+                    text: Some(message),
+                    nonce: data.nonce,
+                    sender_id: data.sender_id,
+                    in_reply_to,
+                };
+                self.events.push(AppEvent::MessageReceived {
+                    room_id: data.room_id,
+                    message: message.clone(),
+                });
+                self.messages.push(message);
+            }
+            RoomMethodCall::SetNickname { nickname } if in_room => {
+                let nickname: String = nickname.trim().chars().take(MAX_NICKNAME_CHARS).collect();
+                let nickname = (!nickname.is_empty()).then_some(nickname);
+                let sender_id = data.sender_id.to_string();
+                for verified in verified_peers.iter_mut() {
+                    if verified.peer_id.to_string() == sender_id {
+                        verified.nickname = nickname.clone();
+                        continue;
+                    }
+                    if let (Some(verified_nickname), Some(nickname)) =
+                        (&verified.nickname, &nickname)
+                    {
+                        if verified_nickname.to_lowercase() == nickname.to_lowercase() {
+                            self.warnings.push(RoomWarning::KeyChanged {
+                                nickname: nickname.clone(),
+                                verified_id: verified.peer_id.clone(),
+                                peer_id: data.sender_id.clone(),
+                            });
+                        }
+                    }
+                }
+                self.roster_entry(&data.sender_id).nickname = nickname;
+            }
+            RoomMethodCall::FileManifest { manifest } if in_room => {
+                let sender_id = data.sender_id.to_string();
+                if self.attachments.iter().any(|v| {
+                    v.file_id() == manifest.file_id() && v.sender_id().to_string() == sender_id
+                }) {
+                    return true;
+                }
+                match Attachment::new(manifest, data.sender_id, data.nonce) {
+                    Ok(attachment) => self.attachments.push(attachment),
+                    Err(err) => log!("{}", err),
+                }
+            }
+            // Only for files whose manifest came from the same sender
+            RoomMethodCall::FileChunk { chunk } if in_room => {
+                let sender_id = data.sender_id.to_string();
+                let attachment = self.attachments.iter_mut().find(|v| {
+                    v.file_id() == chunk.file_id() && v.sender_id().to_string() == sender_id
+                });
+                if let Some(attachment) = attachment {
+                    if let Err(err) = attachment.add_chunk(chunk) {
+                        log!("{}", err);
+                    }
+                }
+            }
+            // Only the sender of a message may delete it
+            RoomMethodCall::DeleteMessage {
+                target_nonce,
+                sender_id,
+            } if in_room => {
+                let sender_id = sender_id.to_string();
+                if data.sender_id.to_string() != sender_id {
+                    return true;
+                }
+                for message in self.messages.iter_mut() {
+                    if message.nonce == target_nonce
+                        && message.sender_id.to_string() == sender_id
+                        && message.text.is_some()
+                    {
+                        message.text = None;
+                        self.events.push(AppEvent::MessageDeleted {
+                            room_id: data.room_id,
+                            sender_id: data.sender_id.clone(),
+                            nonce: target_nonce,
+                        });
+                    }
+                }
+            }
+            // Reacting twice, or taking back a reaction that wasn't made, changes nothing
+            RoomMethodCall::React {
+                target_nonce,
+                target_sender,
+                reaction,
+                remove,
+            } if in_room => {
+                if reaction.is_empty() || reaction.chars().count() > MAX_REACTION_CHARS {
+                    return true;
+                }
+                let sender_id = data.sender_id.to_string();
+                let reactions = self
+                    .reactions
+                    .entry((target_sender.to_string(), target_nonce))
+                    .or_default();
+                let index = match reactions.iter().position(|(v, _)| *v == reaction) {
+                    Some(index) => index,
+                    None if remove => return true,
+                    None => {
+                        reactions.push((reaction, Vec::new()));
+                        reactions.len() - 1
+                    }
+                };
+                let peers = &mut reactions[index].1;
+                let reacted = peers.iter().any(|v| v.to_string() == sender_id);
+                match (remove, reacted) {
+                    (false, false) => peers.push(data.sender_id),
+                    (true, true) => peers.retain(|v| v.to_string() != sender_id),
+                    _ => {}
+                }
+                reactions.retain(|(_, peers)| !peers.is_empty());
+            }
+            // Positions only ever move forward, whatever order receipts arrive in
+            RoomMethodCall::ReadUpTo { nonce } if in_room => {
+                let position = self
+                    .read_positions
+                    .entry(data.sender_id.to_string())
+                    .or_insert((data.sender_id, nonce));
+                position.1 = std::cmp::max(position.1, nonce);
+            }
+            // Only with the current key, so links made before the key was rotated are useless
+            RoomMethodCall::RedeemInvite { ecdh_public_key } if in_room && !is_own => {
+                let current_id = self.room_keys().map(|v| v.current().0);
+                if data.room_key_id.is_none() || data.room_key_id != current_id {
+                    return true;
+                }
+                self.redeemed_invites.push(JoinRequest {
+                    room_id: data.room_id,
+                    peer_id: data.sender_id,
+                    ecdh_public_key,
+                });
+            }
+            RoomMethodCall::InitJoin { joining_id } if in_room => {
+                if is_own {
+                    return true;
+                }
+                self.forget_join_request(&data.sender_id);
+                self.pending_joins.push(JoinRequest {
+                    room_id: data.room_id,
+                    peer_id: data.sender_id,
+                    ecdh_public_key: joining_id,
+                });
+            }
+            // Another member got to it first
+            RoomMethodCall::ConfirmJoin {
+                joined_id,
+                ecdh_public_key,
+            } if in_room => {
+                self.forget_join_request(&joined_id);
+                self.mark_joined(data.room_id, &joined_id);
+                if let Some(ecdh_public_key) = ecdh_public_key {
+                    self.remember_member(MemberKey {
+                        peer_id: joined_id,
+                        ecdh_public_key,
+                    });
+                }
+            }
+            RoomMethodCall::PreventJoin { denied_id } if in_room => {
+                self.forget_join_request(&denied_id)
+            }
+            // Only from members, so nobody else can slip us a key of their own
+            RoomMethodCall::ShareKey { key }
+                if in_room && (self.is_member(&data.sender_id) || is_own) =>
+            {
+                if let RoomPhase::InRoom { room_keys } | RoomPhase::LoadingHistory { room_keys } =
+                    &mut self.phase
+                {
+                    room_keys.insert(key.key_id, key.room_key.0);
+                }
+            }
+            RoomMethodCall::RotateKey { key_id }
+                if in_room && (self.is_member(&data.sender_id) || is_own) =>
+            {
+                if let RoomPhase::InRoom { room_keys } | RoomPhase::LoadingHistory { room_keys } =
+                    &mut self.phase
+                {
+                    match room_keys.set_current(key_id) {
+                        Ok(()) => self.events.push(AppEvent::KeyRotated {
+                            room_id: data.room_id,
+                            key_id,
+                        }),
+                        Err(err) => log!("{}", err),
+                    }
+                }
+            }
+            RoomMethodCall::AcceptJoin {
+                room_key,
+                key_id,
+                older_keys,
+                member_keys,
+            } => {
+                if let RoomPhase::JoiningRoom { room_keys } = &mut self.phase {
+                    let mut keys = RoomKeys::new(key_id, room_key.0);
+                    for older_key in older_keys {
+                        keys.insert(older_key.key_id, older_key.room_key.0);
+                    }
+                    *room_keys = Some(keys);
+                    for member_key in &member_keys {
+                        self.roster_entry(&member_key.peer_id).member = true;
+                    }
+                    self.member_keys = member_keys;
+                }
+            }
+            RoomMethodCall::ConfirmJoin { joined_id, .. } if joined_id.to_string() == own_id => {
+                if let RoomPhase::JoiningRoom {
+                    room_keys: Some(room_keys),
+                } = &self.phase
+                {
+                    self.phase = RoomPhase::LoadingHistory {
+                        room_keys: room_keys.clone(),
+                    };
+                    self.mark_joined(data.room_id, &joined_id);
+                }
+            }
+            RoomMethodCall::PreventJoin { denied_id } if denied_id.to_string() == own_id => {
+                if let RoomPhase::JoiningRoom { .. } = self.phase {
+                    return false;
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+}
+
 pub struct RoomState {
-    current_state: CurrentAppState,
-    ecdh_secret: ecdh::EphemeralSecret,
+    // Not an `ecdh::EphemeralSecret`, as it's stored along with the identity. Only used to start
+    // sessions with, see `crypto::Ratchets`.
+    ecdh_secret: p256::SecretKey,
     ecdh_public_key: p256::PublicKey,
-    ecdsa_verifying_key: ecdsa::VerifyingKey,
-    ecdsa_signing_key: ecdsa::SigningKey,
-    messages: Vec<RoomTextMessage>,
-    next_nonce: api::Nonce,
-    last_time: u64,
+    // Shared with the api client, which uses it to renew subscriptions after reconnecting
+    calls: Rc<RefCell<CallBuilder<ecdsa::SigningKey>>>,
+    // The rooms we're in or joining
+    rooms: HashMap<api::RoomId, PerRoomState>,
+    verified_peers: Vec<VerifiedPeer>,
+    // Our sessions for peer-encrypted data
+    ratchets: crypto::Ratchets,
+    send_read_receipts: bool,
 }
 impl Debug for RoomState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("AppState")
-            .field("current_state", &self.current_state)
-            .field("messages", &self.messages)
-            .field("next_nonce", &self.next_nonce)
-            .field("last_time", &self.last_time)
+            .field("rooms", &self.rooms)
+            .field("calls", &self.calls)
             .finish()
     }
 }
 fn get_sys_time() -> u64 {
     (js_sys::Date::now() / 1000f64) as u64
 }
+fn get_sys_time_ms() -> u64 {
+    js_sys::Date::now() as u64
+}
 impl RoomState {
     pub fn init() -> Self {
-        let ecdh_secret = ecdh::EphemeralSecret::random(&mut rand_core::OsRng);
+        Self::from_keys(
+            ecdsa::SigningKey::random(&mut rand_core::OsRng),
+            p256::SecretKey::random(&mut rand_core::OsRng),
+        )
+    }
+    fn from_keys(ecdsa_signing_key: ecdsa::SigningKey, ecdh_secret: p256::SecretKey) -> Self {
         let ecdh_public_key = ecdh_secret.public_key();
-        let ecdsa_signing_key = ecdsa::SigningKey::random(&mut rand_core::OsRng);
-        let ecdsa_verifying_key = ecdsa::VerifyingKey::from(&ecdsa_signing_key);
-        let time = get_sys_time();
         Self {
-            current_state: CurrentAppState::NoRoom,
             ecdh_secret,
             ecdh_public_key,
-            ecdsa_verifying_key,
-            ecdsa_signing_key,
-            messages: Vec::new(),
-            next_nonce: api::Nonce::new(time),
-            last_time: time,
+            calls: Rc::new(RefCell::new(CallBuilder::new(
+                ecdsa_signing_key,
+                get_sys_time,
+            ))),
+            rooms: HashMap::new(),
+            verified_peers: Vec::new(),
+            ratchets: crypto::Ratchets::default(),
+            send_read_receipts: false,
+        }
+    }
+    fn joined_room(&self, room_id: api::RoomId) -> Result<RoomKeys, AppClientError> {
+        match self.rooms.get(&room_id).map(|v| &v.phase) {
+            Some(RoomPhase::InRoom { room_keys }) => Ok(room_keys.clone()),
+            _ => Err(AppClientError::InvalidState),
+        }
+    }
+    fn take_redeemed_invites(&mut self) -> Vec<JoinRequest> {
+        self.rooms
+            .values_mut()
+            .filter(|room| matches!(room.phase, RoomPhase::InRoom { .. }))
+            .flat_map(|room| std::mem::take(&mut room.redeemed_invites))
+            .collect()
+    }
+    fn take_events(&mut self) -> Vec<AppEvent> {
+        self.rooms
+            .values_mut()
+            .flat_map(|room| std::mem::take(&mut room.events))
+            .collect()
+    }
+    // Goes to the room's subscribers along with its other events
+    fn report_error(&mut self, room_id: api::RoomId, error: String) {
+        if let Some(room) = self.rooms.get_mut(&room_id) {
+            room.events.push(AppEvent::Error {
+                room_id: Some(room_id),
+                error,
+            });
+        }
+    }
+    // For a new connection, which has to read what it missed from the history
+    fn reload_rooms(&mut self) {
+        self.rooms
+            .retain(|_, room| !matches!(room.phase, RoomPhase::JoiningRoom { .. }));
+        for room in self.rooms.values_mut() {
+            if let RoomPhase::InRoom { room_keys } = &room.phase {
+                room.phase = RoomPhase::LoadingHistory {
+                    room_keys: room_keys.clone(),
+                };
+            }
+        }
+    }
+    fn loading_rooms(&self) -> Vec<api::RoomId> {
+        self.rooms
+            .iter()
+            .filter(|(_, room)| matches!(room.phase, RoomPhase::LoadingHistory { .. }))
+            .map(|(room_id, _)| *room_id)
+            .collect()
+    }
+    /** Verifies and decrypts data received over one of our room subscriptions, then acts on it
+    in the room it's from */
+    fn handle_room_data(&mut self, data: api::SubscriptionData) -> Result<(), &'static str> {
+        let own_key = self.calls.borrow().caller_id();
+        let own_id = own_key.to_string();
+        let room_id = data.room_id;
+        let room = self
+            .rooms
+            .get_mut(&room_id)
+            .ok_or("Data isn't from a room we're in")?;
+        let encoded = EncodedData::from_message(data)?;
+        room.check_nonce(&encoded.sender_id, encoded.nonce)?;
+        let decoded = DecodedData::from_encoded_data(
+            encoded,
+            room.room_keys(),
+            &own_key,
+            &self.ecdh_secret,
+            &mut self.ratchets,
+        )?;
+        if !room.handle_room_call(decoded, &own_id, &mut self.verified_peers) {
+            self.rooms.remove(&room_id);
+        }
+        Ok(())
+    }
+    /** Peer-encrypted with our session with the peer, which is started with `their_key` if
+    there isn't one yet */
+    fn peer_cipher(
+        &mut self,
+        peer_id: &api::PublicKeyWrapper,
+        their_key: &p256::PublicKey,
+        call: &RoomMethodCall,
+    ) -> CipherInfo {
+        let own_id = self.calls.borrow().caller_id();
+        let call_json = serde_json::to_string(call).unwrap_throw();
+        CipherInfo::Ratchet(self.ratchets.encrypt(
+            &own_id,
+            peer_id,
+            their_key,
+            call_json.as_bytes(),
+        ))
+    }
+    fn verification(&self, peer_id: &api::PublicKeyWrapper) -> VerificationState {
+        let peer_id = peer_id.to_string();
+        match self
+            .verified_peers
+            .iter()
+            .any(|v| v.peer_id.to_string() == peer_id)
+        {
+            true => VerificationState::FingerprintVerified,
+            false => VerificationState::Unverified,
+        }
+    }
+    fn handle_presence(&mut self, event: &api::PresenceEvent) {
+        if let Some(room) = self.rooms.get_mut(&event.room_id) {
+            room.handle_presence(event);
+        }
+    }
+    fn handle_ephemeral(&mut self, data: api::EphemeralData) -> Result<(), &'static str> {
+        let own_id = self.calls.borrow().caller_id().to_string();
+        let room = self
+            .rooms
+            .get_mut(&data.room_id)
+            .ok_or("Data isn't from a room we're in")?;
+        if data.sender_id.to_string() == own_id {
+            return Ok(());
+        }
+        let cipher_info: EncodedDataCipherRoom =
+            serde_json::from_value(data.data).map_err(|_| "Error parsing ephemeral data")?;
+        let call_json =
+            cipher_info.decrypt(room.room_keys().ok_or("No room key to decrypt with")?)?;
+        let call: EphemeralCall = serde_json::from_str(&call_json)
+            .map_err(|_| "Failed to deserialise ephemeral call JSON")?;
+        match call {
+            EphemeralCall::Typing { typing } => {
+                // Peers can't keep themselves typing for longer than they could by saying so
+                let max_age = data
+                    .max_age_ms
+                    .map_or(TYPING_EXPIRY_MS, |v| v.min(TYPING_EXPIRY_MS));
+                let until = typing.then(|| get_sys_time_ms() + max_age);
+                room.set_typing(&data.sender_id, until);
+            }
+        }
+        Ok(())
+    }
+    /** Reads history entries like live data, oldest first. Ends loading the room's history. */
+    fn handle_history(&mut self, room_id: api::RoomId, entries: Vec<api::RoomDataHistoryEntry>) {
+        let own_key = self.calls.borrow().caller_id();
+        let own_id = own_key.to_string();
+        let room = match self.rooms.get_mut(&room_id) {
+            Some(room) => room,
+            None => return,
+        };
+        let room_keys = match &room.phase {
+            RoomPhase::LoadingHistory { room_keys } => room_keys.clone(),
+            _ => return,
+        };
+        for entry in entries {
+            let decoded = EncodedData::from_history_entry(room_id, entry).and_then(|encoded| {
+                room.record_history_nonce(&encoded.sender_id, encoded.nonce);
+                DecodedData::from_encoded_data(
+                    encoded,
+                    Some(&room_keys),
+                    &own_key,
+                    &self.ecdh_secret,
+                    &mut self.ratchets,
+                )
+            });
+            match decoded {
+                Ok(decoded) => {
+                    room.handle_room_call(decoded, &own_id, &mut self.verified_peers);
+                }
+                Err(err) => log!("Dropped history entry: {}", err),
+            }
+        }
+        if let RoomPhase::LoadingHistory { room_keys } = &room.phase {
+            room.phase = RoomPhase::InRoom {
+                room_keys: room_keys.clone(),
+            };
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AppClientError {
+    /** Not possible in the room's current `RoomPhase`, or we're not in the room */
+    InvalidState,
+    /** Larger than `attachments::MAX_FILE_BYTES` */
+    FileTooLarge,
+    Call(CallError<()>),
+}
+impl From<CallError<()>> for AppClientError {
+    fn from(value: CallError<()>) -> Self {
+        Self::Call(value)
+    }
+}
+
+/** Broadcasts the call to the room, or unicasts it to `receiver`, making it a member if it's not
+one already */
+async fn send_room_data(
+    api_client: &WsApiClient,
+    calls: &RefCell<CallBuilder<ecdsa::SigningKey>>,
+    room_id: api::RoomId,
+    receiver: Option<api::PublicKeyWrapper>,
+    cipher_info: CipherInfo,
+    write_history: bool,
+) -> Result<(), CallError<()>> {
+    // Signed with the nonce of the call made right after, before anything else can call
+    let cipher_part = CipherPart::new(&cipher_info, &calls.borrow(), room_id);
+    let common_args = api::SendDataCommonArgs {
+        room_id,
+        write_history,
+        data: serde_json::to_value(cipher_part).unwrap_throw(),
+    };
+    match receiver {
+        Some(receiver_id) => {
+            api_client
+                .call(
+                    calls,
+                    api::UnicastDataArgs {
+                        receiver_id,
+                        common_args,
+                        make_receiver_privileged: true,
+                        require_ack: false,
+                    },
+                )
+                .await?;
+        }
+        None => {
+            api_client
+                .call(calls, api::BroadcastDataArgs { common_args })
+                .await?;
         }
     }
-    fn reinit(&mut self) {
-        *self = Self::init();
+    Ok(())
+}
+
+/** Gives the peer a role, so it receives broadcasts and can read the history, then confirms it
+to the room like `AppClient::accept_join` does */
+async fn admit_invited_peer(
+    api_client: &WsApiClient,
+    calls: &RefCell<CallBuilder<ecdsa::SigningKey>>,
+    room_keys: &RoomKeys,
+    request: JoinRequest,
+) -> Result<(), CallError<()>> {
+    let add_privileged_peer = api::AddPrivilegedPeerArgs {
+        room_id: request.room_id,
+        allow_id: request.peer_id.clone(),
+    };
+    api_client.call(calls, add_privileged_peer).await?;
+    let confirm_join = RoomMethodCall::ConfirmJoin {
+        joined_id: request.peer_id,
+        ecdh_public_key: Some(request.ecdh_public_key),
+    };
+    let cipher_info = CipherInfo::room(room_keys, random_bytes(), &confirm_join);
+    send_room_data(api_client, calls, request.room_id, None, cipher_info, false).await
+}
+
+/** Lets in the peers that redeemed invites to rooms we're in. Every member that's online does,
+which does no harm, as being let in twice is the same as once. */
+async fn admit_invited(api_client: &WsApiClient, room_state: &RefCell<RoomState>) {
+    let (requests, calls) = {
+        let mut room_state = room_state.borrow_mut();
+        (room_state.take_redeemed_invites(), room_state.calls.clone())
+    };
+    for request in requests {
+        let room_keys = match room_state.borrow().joined_room(request.room_id) {
+            Ok(room_keys) => room_keys,
+            Err(_) => continue,
+        };
+        let room_id = request.room_id;
+        if let Err(err) = admit_invited_peer(api_client, &calls, &room_keys, request).await {
+            log!("Failed to let in invited peer: {:?}", err);
+            room_state
+                .borrow_mut()
+                .report_error(room_id, format!("Failed to let in invited peer: {:?}", err));
+        }
+    }
+}
+
+/** All of the room's history, oldest first */
+async fn load_history(
+    api_client: &WsApiClient,
+    calls: &RefCell<CallBuilder<ecdsa::SigningKey>>,
+    room_id: api::RoomId,
+) -> Result<Vec<api::RoomDataHistoryEntry>, CallError<()>> {
+    let mut entries: Vec<api::RoomDataHistoryEntry> = Vec::new();
+    let mut from_timestamp = 0;
+    let mut after = None;
+    let mut previous_page = 0..0;
+    loop {
+        let page = api_client
+            .call(
+                calls,
+                api::GetRoomDataHistoryArgs {
+                    room_id,
+                    from_timestamp,
+                    until_timestamp: None,
+                    after,
+                    sender_id: None,
+                    limit: None,
+                },
+            )
+            .await?;
+        let last = page.entries.last().map(|v| {
+            let position = api::HistoryPosition {
+                sender_id: v.sender_id.clone(),
+                nonce: v.nonce,
+            };
+            (v.timestamp, position)
+        });
+        // If the last entry was deleted in the meantime, entries of its second can come again
+        let page_start = entries.len();
+        for entry in page.entries {
+            let sender_id = entry.sender_id.to_string();
+            let is_known = entries[previous_page.clone()]
+                .iter()
+                .any(|v| v.nonce == entry.nonce && v.sender_id.to_string() == sender_id);
+            if !is_known {
+                entries.push(entry);
+            }
+        }
+        previous_page = page_start..entries.len();
+        // A page of nothing but repeats wouldn't get any further
+        match (page.truncated, last) {
+            (true, Some((timestamp, position))) if !previous_page.is_empty() => {
+                from_timestamp = timestamp;
+                after = Some(position);
+            }
+            _ => return Ok(entries),
+        }
     }
-    fn get_time(&mut self) -> u64 {
-        let now = std::cmp::max(self.last_time, get_sys_time());
-        self.last_time = now;
-        now
+}
+
+/** Reads the history of the rooms we're waiting for it in, see `RoomPhase::LoadingHistory` */
+async fn catch_up_on_history(api_client: &WsApiClient, room_state: &RefCell<RoomState>) {
+    let (room_ids, calls) = {
+        let room_state = room_state.borrow();
+        (room_state.loading_rooms(), room_state.calls.clone())
+    };
+    for room_id in room_ids {
+        let entries = match load_history(api_client, &calls, room_id).await {
+            Ok(entries) => entries,
+            Err(err) => {
+                log!("Failed to load room history: {:?}", err);
+                room_state
+                    .borrow_mut()
+                    .report_error(room_id, format!("Failed to load room history: {:?}", err));
+                Vec::new()
+            }
+        };
+        room_state.borrow_mut().handle_history(room_id, entries);
     }
-    fn next_nonce(&mut self) -> api::Nonce {
-        let time = self.get_time();
-        let nonce = self.next_nonce;
-        self.next_nonce.increment(time);
-        nonce
+}
+
+/** Sends subscribers the events the rooms queued up since the last time */
+fn dispatch_events(room_state: &RefCell<RoomState>, subscribers: &RefCell<AppEventSubscribers>) {
+    let events = room_state.borrow_mut().take_events();
+    let mut subscribers = subscribers.borrow_mut();
+    for event in events {
+        subscribers.dispatch(event);
     }
 }
 
 #[derive(Debug)]
 pub struct AppClient {
     api_client: WsApiClient,
-    room_state: RoomState,
-    next_call_id: u64,
+    // Shared with the task handling room data, which stops once the client is dropped
+    room_state: Rc<RefCell<RoomState>>,
+    store: Option<Rc<IdentityStore>>,
+    // Shared with the tasks too, which send them events
+    events: Rc<RefCell<AppEventSubscribers>>,
+    // Of the task sending connection state changes, see `reconnect`
+    state_task: AbortHandle,
+    // What the connection was made with
+    config: ClientConfig,
+    // Set while storing the identity is waiting for `PERSIST_DELAY`
+    persist_scheduled: Rc<Cell<bool>>,
 }
 impl AppClient {
-    pub fn new() -> Self {
+    /** Starts with a new identity, which is lost when the page is closed. Connects to the
+    server as the config says. */
+    pub fn new(config: ClientConfig) -> Self {
+        Self::with_room_state(config, RoomState::init(), None)
+    }
+    /** Carries on with the identity stored with the passphrase, and the rooms it was in, or
+    starts with a new identity that's stored from now on. Fails for a wrong passphrase. */
+    pub async fn with_stored_identity(
+        config: ClientConfig,
+        passphrase: &str,
+    ) -> Result<Self, &'static str> {
+        let (store, room_state) = IdentityStore::open(passphrase)?;
+        let room_state = room_state.unwrap_or_else(RoomState::init);
+        let client = Self::with_room_state(config, room_state, Some(Rc::new(store)));
+        client.persist();
+        client.resume_rooms().await;
+        Ok(client)
+    }
+    /** A backup of our keys and the rooms we're in, encrypted with the passphrase, for
+    `import_identity` on another device */
+    pub fn export_identity(&self, passphrase: &str) -> String {
+        identity::export_backup(&self.room_state.borrow(), passphrase)
+    }
+    /** Carries on with the identity from an `export_identity` backup, in place of ours, and
+    subscribes to the rooms it was in again. Replaces the stored identity too. */
+    pub async fn import_identity(
+        &self,
+        backup: &str,
+        passphrase: &str,
+    ) -> Result<(), &'static str> {
+        let room_state = identity::import_backup(backup, passphrase)?;
+        self.replace_room_state(room_state).await;
+        self.resume_rooms().await;
+        Ok(())
+    }
+    // Our subscriptions are renewed with the key they were made with, so they're ended while
+    // it's still ours, rather than carrying on with the identity that was replaced
+    async fn replace_room_state(&self, room_state: RoomState) {
+        self.api_client.unsubscribe_all(&self.calls()).await;
+        *self.room_state.borrow_mut() = room_state;
+        self.persist();
+    }
+    // For a restored identity or a new connection, whose rooms are all `LoadingHistory`
+    async fn resume_rooms(&self) {
+        let room_ids = self.room_state.borrow().loading_rooms();
+        for room_id in room_ids {
+            let subscribed = self
+                .subscribe_to_room(api::SubscribeToRoomArgs {
+                    room_id,
+                    filter: Default::default(),
+                    ignore_presence: false,
+                    last_will: None,
+                })
+                .await;
+            if let Err(err) = subscribed {
+                log!("Failed to resubscribe to a restored room: {:?}", err);
+                self.room_state.borrow_mut().report_error(
+                    room_id,
+                    format!("Failed to resubscribe to a restored room: {:?}", err),
+                );
+            }
+        }
+        catch_up_on_history(&self.api_client, &self.room_state).await;
+        dispatch_events(&self.room_state, &self.events);
+    }
+    /** Removes the stored identity, e.g. because its passphrase was forgotten */
+    pub fn delete_stored_identity() -> Result<(), &'static str> {
+        IdentityStore::delete()
+    }
+    /** Starts over with new keys, leaving the rooms we're in. Peers can't tell we were the
+    same. Replaces the stored identity too. */
+    pub async fn reset_identity(&self) {
+        self.replace_room_state(RoomState::init()).await;
+    }
+    /** A client with a new connection, carrying on with our identity and rooms, for when this
+    one's connection ended. Subscriptions to events carry on too. Rooms are subscribed to again
+    and their history is read, for what was missed in the meantime. Rooms still being joined
+    are forgotten, to be joined again. The new connection is made with `config`, e.g. for a
+    different server. */
+    pub async fn reconnect(&self, config: ClientConfig) -> Self {
+        // The old connection ending isn't news to subscribers
+        self.state_task.abort();
+        self.api_client.end();
+        let client = Self::with_shared_state(
+            config,
+            self.room_state.clone(),
+            self.store.clone(),
+            self.events.clone(),
+        );
+        client.room_state.borrow_mut().reload_rooms();
+        client.resume_rooms().await;
+        client
+    }
+    fn with_room_state(
+        config: ClientConfig,
+        room_state: RoomState,
+        store: Option<Rc<IdentityStore>>,
+    ) -> Self {
+        Self::with_shared_state(
+            config,
+            Rc::new(RefCell::new(room_state)),
+            store,
+            Rc::new(RefCell::new(AppEventSubscribers::default())),
+        )
+    }
+    fn with_shared_state(
+        config: ClientConfig,
+        room_state: Rc<RefCell<RoomState>>,
+        store: Option<Rc<IdentityStore>>,
+        events: Rc<RefCell<AppEventSubscribers>>,
+    ) -> Self {
+        let api_client =
+            WsApiClient::new_with_config(config.server_url(), config.ws_client_config());
+        let mut room_data =
+            api_client.receive_events(SubscriptionEventFilter::new().sub_data().custom(|event| {
+                matches!(
+                    event,
+                    ApiClientEvent::ApiMessage(
+                        api::ServerToClientMessage::PresenceEvent(_)
+                            | api::ServerToClientMessage::EphemeralData(_)
+                    )
+                )
+            }));
+        let weak_state = Rc::downgrade(&room_state);
+        let weak_events = Rc::downgrade(&events);
+        let task_client = api_client.anon_clone();
+        let task_store = store.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            while let Some(event) = room_data.next().await {
+                let message = match event {
+                    ApiClientEvent::ApiMessage(message) => message,
+                    _ => continue,
+                };
+                let (room_state, events) = match (weak_state.upgrade(), weak_events.upgrade()) {
+                    (Some(room_state), Some(events)) => (room_state, events),
+                    _ => break,
+                };
+                match message {
+                    api::ServerToClientMessage::SubscriptionData(data) => {
+                        if let Err(err) = room_state.borrow_mut().handle_room_data(data) {
+                            log!("Dropped room data: {}", err);
+                        }
+                    }
+                    api::ServerToClientMessage::PresenceEvent(event) => {
+                        room_state.borrow_mut().handle_presence(&event);
+                        dispatch_events(&room_state, &events);
+                        continue;
+                    }
+                    api::ServerToClientMessage::EphemeralData(data) => {
+                        if let Err(err) = room_state.borrow_mut().handle_ephemeral(data) {
+                            log!("Dropped ephemeral data: {}", err);
+                        }
+                        continue;
+                    }
+                    _ => continue,
+                }
+                admit_invited(&task_client, &room_state).await;
+                // Live data keeps queueing up meanwhile, so it's read after the history
+                catch_up_on_history(&task_client, &room_state).await;
+                // Joining, and new room keys, change what's stored
+                if let Some(store) = &task_store {
+                    store.save(&room_state.borrow());
+                }
+                dispatch_events(&room_state, &events);
+            }
+        });
+        let mut state_changes = api_client.state_stream().boxed_local();
+        let weak_events = Rc::downgrade(&events);
+        let (send_state_changes, state_task) = future::abortable(async move {
+            while let Some(change) = state_changes.next().await {
+                match weak_events.upgrade() {
+                    Some(events) => events
+                        .borrow_mut()
+                        .dispatch(AppEvent::ConnectionStateChanged(change)),
+                    None => break,
+                }
+            }
+        });
+        wasm_bindgen_futures::spawn_local(async move {
+            let _ = send_state_changes.await;
+        });
         Self {
-            api_client: WsApiClient::new("https://garbage.notaws"),
-            room_state: RoomState::init(),
-            next_call_id: 0,
+            api_client,
+            room_state,
+            store,
+            events,
+            state_task,
+            config,
+            persist_scheduled: Rc::new(Cell::new(false)),
         }
     }
-    pub fn make_server_method_call<T: Into<api::MethodCallArgsVariants>>(
+    /** What the client was made with, see `reconnect` */
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+    pub fn connection_state(&self) -> WebSocketState {
+        self.api_client.state()
+    }
+    /** Our key, which everything we send is signed with */
+    pub fn own_id(&self) -> api::PublicKeyWrapper {
+        self.calls().borrow().caller_id()
+    }
+    /** Events from now on that match the filter, like `WsApiClient::receive_events` */
+    pub fn receive_events(&self, filter: AppEventFilter) -> AppEventHandle {
+        let (id, receiver) = self.events.borrow_mut().subscribe(filter);
+        AppEventHandle::new(receiver, id, Rc::downgrade(&self.events))
+    }
+    // Stores the identity soon, along with whatever else changes until then
+    fn persist(&self) {
+        let store = match &self.store {
+            Some(store) => store.clone(),
+            None => return,
+        };
+        if self.persist_scheduled.replace(true) {
+            return;
+        }
+        let scheduled = self.persist_scheduled.clone();
+        let room_state = self.room_state.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            gloo_timers::future::sleep(PERSIST_DELAY).await;
+            scheduled.set(false);
+            store.save(&room_state.borrow());
+        });
+    }
+    fn calls(&self) -> Rc<RefCell<CallBuilder<ecdsa::SigningKey>>> {
+        self.room_state.borrow().calls.clone()
+    }
+    pub fn make_server_method_call<T: api::ApiMethod>(
         &mut self,
         args: T,
     ) -> api::ClientToServerMessage {
-        // let args: api::MethodCallArgsVariants = args.into();
-        let call = api::MethodCallContent::new(
-            api::EcdsaPublicKeyWrapper(self.room_state.ecdsa_verifying_key),
-            self.room_state.next_nonce(),
-            args.into(),
-        );
-        let call = call
-            .sign(self.next_call_id, &self.room_state.ecdsa_signing_key)
+        let (_, call) = self.calls().borrow_mut().server_call(args).unwrap_throw();
+        self.persist();
+        call
+    }
+    /** Like `make_server_method_call`, for connections with a session. Cheaper than signing. */
+    pub fn make_session_method_call<T: api::ApiMethod>(
+        &mut self,
+        session_key: &[u8],
+        args: T,
+    ) -> api::ClientToServerMessage {
+        let (_, call) = self
+            .calls()
+            .borrow_mut()
+            .session_call(session_key, args)
             .unwrap_throw();
-        self.next_call_id += 1;
-        call.into()
+        self.persist();
+        call
+    }
+    /** Makes a signed call and waits for the server to return */
+    pub async fn call<T: api::ApiMethod>(&self, args: T) -> Result<T::Success, CallError<()>> {
+        let result = self.api_client.call(&self.calls(), args).await;
+        // For the nonce it used up
+        self.persist();
+        result
+    }
+    /** Subscriptions made this way survive reconnects, see `WsApiClient::subscribe_to_room` */
+    pub async fn subscribe_to_room(
+        &self,
+        args: api::SubscribeToRoomArgs,
+    ) -> Result<api::SubscribeSuccess, CallError<()>> {
+        self.api_client.subscribe_to_room(self.calls(), args).await
+    }
+    /** Creates a room with a new room key and subscribes to it. Returns the room's ID, whose
+    code others join it with. */
+    pub async fn create_room(&self) -> Result<api::RoomId, AppClientError> {
+        let created = self.call(api::CreateRoomArgs).await?;
+        self.subscribe_to_room(api::SubscribeToRoomArgs {
+            room_id: created.room_id,
+            filter: Default::default(),
+            ignore_presence: false,
+            last_will: None,
+        })
+        .await?;
+        let room = PerRoomState::new(RoomPhase::InRoom {
+            room_keys: RoomKeys::new(0, random_bytes::<32>().into()),
+        });
+        self.room_state
+            .borrow_mut()
+            .rooms
+            .insert(created.room_id, room);
+        self.persist();
+        Ok(created.room_id)
+    }
+    /** Subscribes to the room and asks its members for the room key. The room is joined once
+    a member accepts and confirms it to the room. */
+    pub async fn join_room(&self, room_id: api::RoomId) -> Result<(), AppClientError> {
+        {
+            let mut room_state = self.room_state.borrow_mut();
+            if room_state.rooms.contains_key(&room_id) {
+                return Err(AppClientError::InvalidState);
+            }
+            let room = PerRoomState::new(RoomPhase::JoiningRoom { room_keys: None });
+            room_state.rooms.insert(room_id, room);
+        }
+        let result = self.request_join(room_id).await;
+        if result.is_err() {
+            self.room_state.borrow_mut().rooms.remove(&room_id);
+        }
+        result
+    }
+    /** An invite link to the room, see `Invite` */
+    pub fn invite(&self, room_id: api::RoomId) -> Result<Invite, AppClientError> {
+        let room_keys = self.room_state.borrow().joined_room(room_id)?;
+        let (key_id, room_key) = room_keys.current();
+        Ok(Invite {
+            room_id,
+            key_id,
+            room_key,
+        })
+    }
+    /** Subscribes to the room and shows the members we have its key. The first member online
+    to see that lets us in, with no one having to accept us, and the room's history is read once
+    they confirmed us to the room. Nothing happens while no member is online. */
+    pub async fn join_with_invite(&self, invite: &Invite) -> Result<(), AppClientError> {
+        let room_id = invite.room_id;
+        let room_keys = RoomKeys::new(invite.key_id, invite.room_key);
+        {
+            let mut room_state = self.room_state.borrow_mut();
+            if room_state.rooms.contains_key(&room_id) {
+                return Err(AppClientError::InvalidState);
+            }
+            let room = PerRoomState::new(RoomPhase::JoiningRoom {
+                room_keys: Some(room_keys.clone()),
+            });
+            room_state.rooms.insert(room_id, room);
+        }
+        let result = self.redeem_invite(room_id, &room_keys).await;
+        if result.is_err() {
+            self.room_state.borrow_mut().rooms.remove(&room_id);
+        }
+        result
+    }
+    async fn redeem_invite(
+        &self,
+        room_id: api::RoomId,
+        room_keys: &RoomKeys,
+    ) -> Result<(), AppClientError> {
+        self.subscribe_to_room(api::SubscribeToRoomArgs {
+            room_id,
+            filter: Default::default(),
+            ignore_presence: false,
+            last_will: None,
+        })
+        .await?;
+        let redeem_invite = RoomMethodCall::RedeemInvite {
+            ecdh_public_key: EcdhPublicKey(self.room_state.borrow().ecdh_public_key),
+        };
+        let cipher_info = CipherInfo::room(room_keys, random_bytes(), &redeem_invite);
+        self.send_room_call(room_id, None, cipher_info, false).await
+    }
+    /** Whether we're in the room, or joining it */
+    pub fn has_room(&self, room_id: api::RoomId) -> bool {
+        self.room_state.borrow().rooms.contains_key(&room_id)
+    }
+    /** The rooms we're in, not counting ones we're still joining */
+    pub fn joined_rooms(&self) -> Vec<api::RoomId> {
+        self.room_state
+            .borrow()
+            .rooms
+            .iter()
+            .filter(|(_, room)| matches!(room.phase, RoomPhase::InRoom { .. }))
+            .map(|(room_id, _)| *room_id)
+            .collect()
+    }
+    async fn request_join(&self, room_id: api::RoomId) -> Result<(), AppClientError> {
+        self.subscribe_to_room(api::SubscribeToRoomArgs {
+            room_id,
+            filter: Default::default(),
+            ignore_presence: false,
+            last_will: None,
+        })
+        .await?;
+        let init_join = RoomMethodCall::InitJoin {
+            joining_id: EcdhPublicKey(self.room_state.borrow().ecdh_public_key),
+        };
+        // Plain, as we have no key in common with the members yet
+        self.send_room_call(room_id, None, CipherInfo::plain(&init_join), false)
+            .await
+    }
+    /** Sends a message to everyone in the room, encrypted with the room key and kept in the
+    room's history. It shows up in `messages` once the server sends it back. */
+    pub async fn send_text(
+        &self,
+        room_id: api::RoomId,
+        text: String,
+    ) -> Result<(), AppClientError> {
+        self.send_message(room_id, text, None).await
+    }
+    /** Like `send_text`, for a reply to another message in the room */
+    pub async fn send_reply(
+        &self,
+        room_id: api::RoomId,
+        text: String,
+        in_reply_to: MessageRef,
+    ) -> Result<(), AppClientError> {
+        self.send_message(room_id, text, Some(in_reply_to)).await
+    }
+    async fn send_message(
+        &self,
+        room_id: api::RoomId,
+        text: String,
+        in_reply_to: Option<MessageRef>,
+    ) -> Result<(), AppClientError> {
+        let room_keys = self.room_state.borrow().joined_room(room_id)?;
+        // Peers stop showing us typing once they get the message, so typing again is sent
+        // right away
+        if let Some(room) = self.room_state.borrow_mut().rooms.get_mut(&room_id) {
+            room.typing_sent_at = None;
+        }
+        let send_message = RoomMethodCall::SendMessage {
+            message: text,
+            in_reply_to,
+        };
+        let cipher_info = CipherInfo::room(&room_keys, random_bytes(), &send_message);
+        self.send_room_call(room_id, None, cipher_info, true).await
+    }
+    /** Deletes a message from the room's history, then tells everyone in the room to delete it.
+    Members only act on that for messages of the peer asking, so others' messages are only
+    deleted from the history, if our role allows it. */
+    pub async fn delete_message(
+        &self,
+        room_id: api::RoomId,
+        nonce: api::Nonce,
+        sender_id: api::PublicKeyWrapper,
+    ) -> Result<(), AppClientError> {
+        let room_keys = self.room_state.borrow().joined_room(room_id)?;
+        self.call(api::DeleteDataArgs {
+            room_id,
+            data_sender_id: sender_id.clone(),
+            data_nonce: nonce,
+        })
+        .await?;
+        let delete_message = RoomMethodCall::DeleteMessage {
+            target_nonce: nonce,
+            sender_id,
+        };
+        let cipher_info = CipherInfo::room(&room_keys, random_bytes(), &delete_message);
+        // Nobody reading the history needs it, as the message is gone from there
+        self.send_room_call(room_id, None, cipher_info, false).await
+    }
+    /** Messages received in the room, oldest first. Deleted ones stay as tombstones. */
+    pub fn messages(&self, room_id: api::RoomId) -> Option<Ref<'_, [RoomTextMessage]>> {
+        Ref::filter_map(self.room_state.borrow(), |v| {
+            v.rooms.get(&room_id).map(|room| room.messages.as_slice())
+        })
+        .ok()
+    }
+    /** Tells the room whether we're typing, e.g. on each change of the message input. Saying
+    we're typing is only passed on every few seconds, saying we stopped only once. */
+    pub async fn set_typing(
+        &self,
+        room_id: api::RoomId,
+        typing: bool,
+    ) -> Result<(), AppClientError> {
+        let room_keys = self.room_state.borrow().joined_room(room_id)?;
+        let now = get_sys_time_ms();
+        let sent_at = {
+            let mut room_state = self.room_state.borrow_mut();
+            let room = room_state
+                .rooms
+                .get_mut(&room_id)
+                .ok_or(AppClientError::InvalidState)?;
+            let sent_at = room.typing_sent_at;
+            match (typing, sent_at) {
+                (true, Some(sent_at)) if now < sent_at + TYPING_RESEND_MS => return Ok(()),
+                (false, None) => return Ok(()),
+                _ => {}
+            }
+            room.typing_sent_at = typing.then_some(now);
+            sent_at
+        };
+        let call_json = serde_json::to_string(&EphemeralCall::Typing { typing }).unwrap_throw();
+        let cipher_info = EncodedDataCipherRoom::encrypt(&room_keys, random_bytes(), call_json);
+        let result = self
+            .call(api::SendEphemeralArgs {
+                room_id,
+                data: serde_json::to_value(cipher_info).unwrap_throw(),
+                max_age_ms: Some(TYPING_EXPIRY_MS),
+            })
+            .await;
+        if result.is_err() {
+            // So it's tried again on the next call, rather than after the throttle
+            if let Some(room) = self.room_state.borrow_mut().rooms.get_mut(&room_id) {
+                room.typing_sent_at = sent_at;
+            }
+        }
+        result?;
+        Ok(())
+    }
+    /** Who's typing in the room now, in the order they started */
+    pub fn typing(&self, room_id: api::RoomId) -> Vec<api::PublicKeyWrapper> {
+        match self.room_state.borrow_mut().rooms.get_mut(&room_id) {
+            Some(room) => room.typing_peers(get_sys_time_ms()).0,
+            None => Vec::new(),
+        }
+    }
+    /** Like `typing`, each time that changes, starting with who's typing now. Peers stop
+    typing when they say so, send a message, go offline, or haven't said they're still typing
+    in a while. Ends once we've left the room. */
+    pub fn typing_stream(
+        &self,
+        room_id: api::RoomId,
+    ) -> impl Stream<Item = Vec<api::PublicKeyWrapper>> {
+        let (watcher, changes) = mpsc::unbounded();
+        if let Some(room) = self.room_state.borrow_mut().rooms.get_mut(&room_id) {
+            room.typing_watchers.push(watcher);
+        }
+        let weak_state = Rc::downgrade(&self.room_state);
+        stream::unfold((changes, None), move |(mut changes, last)| {
+            let weak_state = weak_state.clone();
+            async move {
+                loop {
+                    let (typing, next_expiry) = {
+                        let room_state = weak_state.upgrade()?;
+                        let mut room_state = room_state.borrow_mut();
+                        let room = room_state.rooms.get_mut(&room_id)?;
+                        room.typing_peers(get_sys_time_ms())
+                    };
+                    let typing_ids: Vec<String> = typing.iter().map(|v| v.to_string()).collect();
+                    if last.as_ref() != Some(&typing_ids) {
+                        return Some((typing, (changes, Some(typing_ids))));
+                    }
+                    // Nobody says when they stopped by going quiet, so that's waited for too
+                    match next_expiry {
+                        Some(ms) => {
+                            let expired = gloo_timers::future::sleep(Duration::from_millis(ms));
+                            if let futures::future::Either::Left((None, _)) =
+                                futures::future::select(changes.next(), expired).await
+                            {
+                                return None;
+                            }
+                        }
+                        None => changes.next().await?,
+                    }
+                }
+            }
+        })
+    }
+    /** The replies to a message received so far, in the order they were received */
+    pub fn replies(&self, room_id: api::RoomId, target: &MessageRef) -> Vec<RoomTextMessage> {
+        let room_state = self.room_state.borrow();
+        let room = match room_state.rooms.get(&room_id) {
+            Some(room) => room,
+            None => return Vec::new(),
+        };
+        match room
+            .threads
+            .get(&(target.sender_id.to_string(), target.nonce))
+        {
+            Some(indices) => indices.iter().map(|v| room.messages[*v].clone()).collect(),
+            None => Vec::new(),
+        }
+    }
+    /** Reacts to the message with e.g. an emoji, or takes back our reaction if `remove` is set */
+    pub async fn react(
+        &self,
+        room_id: api::RoomId,
+        target_nonce: api::Nonce,
+        target_sender: api::PublicKeyWrapper,
+        reaction: String,
+        remove: bool,
+    ) -> Result<(), AppClientError> {
+        let room_keys = self.room_state.borrow().joined_room(room_id)?;
+        let react = RoomMethodCall::React {
+            target_nonce,
+            target_sender,
+            reaction,
+            remove,
+        };
+        let cipher_info = CipherInfo::room(&room_keys, random_bytes(), &react);
+        self.send_room_call(room_id, None, cipher_info, true).await
+    }
+    /** The reactions to a message, in the order they were first used */
+    pub fn reactions(
+        &self,
+        room_id: api::RoomId,
+        target_nonce: api::Nonce,
+        target_sender: &api::PublicKeyWrapper,
+    ) -> Vec<ReactionSummary> {
+        let own_id = self.calls().borrow().caller_id().to_string();
+        let room_state = self.room_state.borrow();
+        let reactions = room_state.rooms.get(&room_id).and_then(|room| {
+            room.reactions
+                .get(&(target_sender.to_string(), target_nonce))
+        });
+        match reactions {
+            Some(reactions) => reactions
+                .iter()
+                .map(|(reaction, peers)| ReactionSummary {
+                    reaction: reaction.clone(),
+                    peers: peers.clone(),
+                    own: peers.iter().any(|v| v.to_string() == own_id),
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+    /** Whether `mark_read` tells the room how far we've read. Off unless turned on. */
+    pub fn set_send_read_receipts(&self, send: bool) {
+        self.room_state.borrow_mut().send_read_receipts = send;
+        self.persist();
+    }
+    /** Marks the room's messages read up to the one with `nonce`, and tells the room if read
+    receipts are turned on. Marking an older message read does nothing. */
+    pub async fn mark_read(
+        &self,
+        room_id: api::RoomId,
+        nonce: api::Nonce,
+    ) -> Result<(), AppClientError> {
+        let room_keys = self.room_state.borrow().joined_room(room_id)?;
+        let send_read_receipts = {
+            let mut room_state = self.room_state.borrow_mut();
+            let room = room_state
+                .rooms
+                .get_mut(&room_id)
+                .ok_or(AppClientError::InvalidState)?;
+            if room.read_up_to.map_or(false, |v| v >= nonce) {
+                return Ok(());
+            }
+            room.read_up_to = Some(nonce);
+            room_state.send_read_receipts
+        };
+        self.persist();
+        if !send_read_receipts {
+            return Ok(());
+        }
+        let read_up_to = RoomMethodCall::ReadUpTo { nonce };
+        let cipher_info = CipherInfo::room(&room_keys, random_bytes(), &read_up_to);
+        self.send_room_call(room_id, None, cipher_info, false).await
+    }
+    /** Messages from others that came after what we marked read, and weren't deleted */
+    pub fn unread_count(&self, room_id: api::RoomId) -> usize {
+        let own_id = self.calls().borrow().caller_id().to_string();
+        let room_state = self.room_state.borrow();
+        let room = match room_state.rooms.get(&room_id) {
+            Some(room) => room,
+            None => return 0,
+        };
+        room.messages
+            .iter()
+            .filter(|v| !v.is_deleted() && v.sender_id.to_string() != own_id)
+            .filter(|v| {
+                room.read_up_to
+                    .map_or(true, |read_up_to| v.nonce > read_up_to)
+            })
+            .count()
+    }
+    /** The peers that told us they read the message with `nonce`, or one after it */
+    pub fn seen_by(&self, room_id: api::RoomId, nonce: api::Nonce) -> Vec<api::PublicKeyWrapper> {
+        match self.room_state.borrow().rooms.get(&room_id) {
+            Some(room) => room
+                .read_positions
+                .values()
+                .filter(|(_, read_up_to)| *read_up_to >= nonce)
+                .map(|(peer_id, _)| peer_id.clone())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+    /** Files sent to the room, oldest first, including those still being received */
+    pub fn attachments(&self, room_id: api::RoomId) -> Option<Ref<'_, [Attachment]>> {
+        Ref::filter_map(self.room_state.borrow(), |v| {
+            v.rooms
+                .get(&room_id)
+                .map(|room| room.attachments.as_slice())
+        })
+        .ok()
+    }
+    /** Sends a file in chunks, encrypted with a key of its own that's sent ahead of them. To
+    everyone in the room and its history, or only to `receiver`, which has to be a member. */
+    pub async fn send_file(
+        &self,
+        room_id: api::RoomId,
+        receiver: Option<api::PublicKeyWrapper>,
+        name: &str,
+        mime_type: &str,
+        bytes: &[u8],
+    ) -> Result<(), AppClientError> {
+        if bytes.len() > attachments::MAX_FILE_BYTES {
+            return Err(AppClientError::FileTooLarge);
+        }
+        let room_keys = self.room_state.borrow().joined_room(room_id)?;
+        let (manifest, chunks) = attachments::split_file(name, mime_type, bytes);
+        let send_manifest = RoomMethodCall::FileManifest { manifest };
+        let cipher_info = match &receiver {
+            Some(receiver) => {
+                let mut room_state = self.room_state.borrow_mut();
+                let receiver_id = receiver.to_string();
+                let ecdh_public_key = room_state
+                    .rooms
+                    .get(&room_id)
+                    .and_then(|room| {
+                        room.member_keys
+                            .iter()
+                            .find(|v| v.peer_id.to_string() == receiver_id)
+                    })
+                    .map(|v| v.ecdh_public_key.0)
+                    .ok_or(AppClientError::InvalidState)?;
+                room_state.peer_cipher(receiver, &ecdh_public_key, &send_manifest)
+            }
+            None => CipherInfo::room(&room_keys, random_bytes(), &send_manifest),
+        };
+        let write_history = receiver.is_none();
+        self.send_room_call(room_id, receiver.clone(), cipher_info, write_history)
+            .await?;
+        for chunk in chunks {
+            let send_chunk = RoomMethodCall::FileChunk { chunk };
+            let cipher_info = CipherInfo::plain(&send_chunk);
+            self.send_room_call(room_id, receiver.clone(), cipher_info, write_history)
+                .await?;
+        }
+        Ok(())
+    }
+    /** Everyone we know of in the room, including ourselves once we set a nickname */
+    pub fn roster(&self, room_id: api::RoomId) -> Vec<RosterEntry> {
+        let room_state = self.room_state.borrow();
+        match room_state.rooms.get(&room_id) {
+            Some(room) => room
+                .roster
+                .iter()
+                .map(|entry| RosterEntry {
+                    verification: room_state.verification(&entry.peer_id),
+                    ..entry.clone()
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+    /** For comparing with what the peer's client shows, to verify their key */
+    pub fn safety_number(&self, peer_id: &api::PublicKeyWrapper) -> SafetyNumber {
+        SafetyNumber::new(&self.calls().borrow().caller_id(), peer_id)
+    }
+    /** Marks the peer verified in every room, once its safety number was compared with them.
+    Its nickname is remembered from the rooms we saw it in, so we're warned if another key
+    takes it. */
+    pub fn mark_verified(&self, peer_id: &api::PublicKeyWrapper) {
+        let mut room_state = self.room_state.borrow_mut();
+        let peer_key = peer_id.to_string();
+        room_state
+            .verified_peers
+            .retain(|v| v.peer_id.to_string() != peer_key);
+        let nickname = room_state
+            .rooms
+            .values()
+            .flat_map(|room| room.roster.iter())
+            .find(|v| v.peer_id.to_string() == peer_key && v.nickname.is_some())
+            .and_then(|v| v.nickname.clone());
+        room_state.verified_peers.push(VerifiedPeer {
+            peer_id: peer_id.clone(),
+            nickname,
+        });
+        drop(room_state);
+        self.persist();
+    }
+    pub fn unmark_verified(&self, peer_id: &api::PublicKeyWrapper) {
+        let peer_id = peer_id.to_string();
+        self.room_state
+            .borrow_mut()
+            .verified_peers
+            .retain(|v| v.peer_id.to_string() != peer_id);
+        self.persist();
+    }
+    /** Warnings about the room since this was last called, oldest first */
+    pub fn take_warnings(&self, room_id: api::RoomId) -> Vec<RoomWarning> {
+        match self.room_state.borrow_mut().rooms.get_mut(&room_id) {
+            Some(room) => std::mem::take(&mut room.warnings),
+            None => Vec::new(),
+        }
+    }
+    /** Tells everyone in the room what to call us. Kept in the room's history, so peers that
+    join later know it too. */
+    pub async fn set_nickname(
+        &self,
+        room_id: api::RoomId,
+        nickname: String,
+    ) -> Result<(), AppClientError> {
+        let room_keys = self.room_state.borrow().joined_room(room_id)?;
+        let set_nickname = RoomMethodCall::SetNickname { nickname };
+        let cipher_info = CipherInfo::room(&room_keys, random_bytes(), &set_nickname);
+        self.send_room_call(room_id, None, cipher_info, true).await
+    }
+    pub fn pending_joins(&self, room_id: api::RoomId) -> Vec<JoinRequest> {
+        match self.room_state.borrow().rooms.get(&room_id) {
+            Some(room) => room.pending_joins.clone(),
+            None => Vec::new(),
+        }
+    }
+    /** Sends the room key to the peer, encrypted to its ECDH key, which also makes it a member
+    of the room, then tells everyone in the room that it joined */
+    pub async fn accept_join(&self, request: &JoinRequest) -> Result<(), AppClientError> {
+        let room_id = request.room_id;
+        let (room_keys, mut member_keys) = self.take_join_request(request)?;
+        let (key_id, room_key) = room_keys.current();
+        member_keys.push(MemberKey {
+            peer_id: self.calls().borrow().caller_id(),
+            ecdh_public_key: EcdhPublicKey(self.room_state.borrow().ecdh_public_key),
+        });
+        let accept_join = RoomMethodCall::AcceptJoin {
+            room_key: Aes256GcmKey(room_key),
+            key_id,
+            older_keys: room_keys.older_keys(),
+            member_keys,
+        };
+        let cipher_info = self.room_state.borrow_mut().peer_cipher(
+            &request.peer_id,
+            &request.ecdh_public_key.0,
+            &accept_join,
+        );
+        self.send_room_call(room_id, Some(request.peer_id.clone()), cipher_info, false)
+            .await?;
+        let confirm_join = RoomMethodCall::ConfirmJoin {
+            joined_id: request.peer_id.clone(),
+            ecdh_public_key: Some(request.ecdh_public_key.clone()),
+        };
+        let cipher_info = CipherInfo::room(&room_keys, random_bytes(), &confirm_join);
+        self.send_room_call(room_id, None, cipher_info, false).await
+    }
+    /** Tells the peer it won't be let in, and everyone in the room not to let it in either */
+    pub async fn prevent_join(&self, request: &JoinRequest) -> Result<(), AppClientError> {
+        let room_id = request.room_id;
+        let (room_keys, _) = self.take_join_request(request)?;
+        let prevent_join = RoomMethodCall::PreventJoin {
+            denied_id: request.peer_id.clone(),
+        };
+        // The peer isn't a member, so it doesn't receive broadcasts
+        let cipher_info = self.room_state.borrow_mut().peer_cipher(
+            &request.peer_id,
+            &request.ecdh_public_key.0,
+            &prevent_join,
+        );
+        self.send_room_call(room_id, Some(request.peer_id.clone()), cipher_info, false)
+            .await?;
+        let cipher_info = CipherInfo::room(&room_keys, random_bytes(), &prevent_join);
+        self.send_room_call(room_id, None, cipher_info, false).await
+    }
+    /** Replaces the room key, sending the new one to every remaining member encrypted to its
+    ECDH key, so peers that were removed can't read what's sent from now on. Older keys are kept
+    for the history. Returns the members whose ECDH key we don't know, which didn't get it. */
+    pub async fn rotate_room_key(
+        &self,
+        room_id: api::RoomId,
+    ) -> Result<Vec<api::PublicKeyWrapper>, AppClientError> {
+        let mut room_keys = self.room_state.borrow().joined_room(room_id)?;
+        let own_id = self.calls().borrow().caller_id().to_string();
+        let peers = self.call(api::GetRoomPeersArgs { room_id }).await?.peers;
+        let members: Vec<_> = peers
+            .into_iter()
+            .filter(|v| v.privileged && v.peer_id.to_string() != own_id)
+            .map(|v| v.peer_id)
+            .collect();
+        let member_keys = {
+            let mut room_state = self.room_state.borrow_mut();
+            let room = room_state
+                .rooms
+                .get_mut(&room_id)
+                .ok_or(AppClientError::InvalidState)?;
+            let member_ids: Vec<_> = members.iter().map(|v| v.to_string()).collect();
+            room.member_keys
+                .retain(|v| member_ids.contains(&v.peer_id.to_string()));
+            room.member_keys.clone()
+        };
+        let key = SharedRoomKey {
+            key_id: room_keys.next_id(),
+            room_key: Aes256GcmKey(random_bytes::<32>().into()),
+        };
+        let mut unreachable = Vec::new();
+        for member in members {
+            let member_id = member.to_string();
+            let ecdh_public_key = match member_keys
+                .iter()
+                .find(|v| v.peer_id.to_string() == member_id)
+            {
+                Some(member_key) => &member_key.ecdh_public_key,
+                None => {
+                    unreachable.push(member);
+                    continue;
+                }
+            };
+            let share_key = RoomMethodCall::ShareKey { key: key.clone() };
+            let cipher_info =
+                self.room_state
+                    .borrow_mut()
+                    .peer_cipher(&member, &ecdh_public_key.0, &share_key);
+            self.send_room_call(room_id, Some(member), cipher_info, false)
+                .await?;
+        }
+        room_keys.insert(key.key_id, key.room_key.0);
+        room_keys.set_current(key.key_id).unwrap_throw();
+        // Switched to right away, as every member got the key before the broadcast
+        if let Some(RoomPhase::InRoom {
+            room_keys: state_keys,
+        }) = self
+            .room_state
+            .borrow_mut()
+            .rooms
+            .get_mut(&room_id)
+            .map(|v| &mut v.phase)
+        {
+            state_keys.insert(key.key_id, key.room_key.0);
+            state_keys.set_current(key.key_id).unwrap_throw();
+        }
+        let rotate_key = RoomMethodCall::RotateKey { key_id: key.key_id };
+        let cipher_info = CipherInfo::room(&room_keys, random_bytes(), &rotate_key);
+        self.send_room_call(room_id, None, cipher_info, false)
+            .await?;
+        Ok(unreachable)
+    }
+    /** Takes the peer's role in the room, then replaces the room key with `rotate_room_key`, as
+    it still has the old one. Returns the members that didn't get the new key. */
+    pub async fn remove_member(
+        &self,
+        room_id: api::RoomId,
+        peer_id: &api::PublicKeyWrapper,
+    ) -> Result<Vec<api::PublicKeyWrapper>, AppClientError> {
+        self.room_state.borrow().joined_room(room_id)?;
+        self.call(api::SetPeerRoleArgs {
+            room_id,
+            peer_id: peer_id.clone(),
+            role: None,
+        })
+        .await?;
+        if let Some(room) = self.room_state.borrow_mut().rooms.get_mut(&room_id) {
+            room.roster_entry(peer_id).member = false;
+        }
+        self.rotate_room_key(room_id).await
+    }
+    // The room's keys and the ECDH keys of its members, to let the peer in with
+    fn take_join_request(
+        &self,
+        request: &JoinRequest,
+    ) -> Result<(RoomKeys, Vec<MemberKey>), AppClientError> {
+        let mut room_state = self.room_state.borrow_mut();
+        let room_keys = room_state.joined_room(request.room_id)?;
+        let room = room_state.rooms.get_mut(&request.room_id).unwrap_throw();
+        room.forget_join_request(&request.peer_id);
+        Ok((room_keys, room.member_keys.clone()))
+    }
+    async fn send_room_call(
+        &self,
+        room_id: api::RoomId,
+        receiver: Option<api::PublicKeyWrapper>,
+        cipher_info: CipherInfo,
+        write_history: bool,
+    ) -> Result<(), AppClientError> {
+        let result = send_room_data(
+            &self.api_client,
+            &self.calls(),
+            room_id,
+            receiver,
+            cipher_info,
+            write_history,
+        )
+        .await;
+        // For the nonce it used up
+        self.persist();
+        Ok(result?)
     }
 }
@@ -0,0 +1,205 @@
+use super::{random_bytes, Aes256GcmIv, Aes256GcmKey};
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zend_common::{_use::wasm_bindgen::UnwrapThrowExt, api, log, util};
+
+/** Plain bytes per chunk. Chunks are sent base64 encoded in a JSON string, which the server
+limits to 16 KiB. */
+const CHUNK_BYTES: usize = 8 * 1024;
+pub(super) const MAX_FILE_BYTES: usize = 8 * 1024 * 1024;
+
+/** What a file is, and the key its chunks are encrypted with. Sent room- or peer-encrypted ahead
+of the chunks, so only those who got it can read them. */
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(super) struct FileManifest {
+    file_id: String,
+    name: String,
+    mime_type: String,
+    size: u64,
+    chunk_count: u32,
+    // Of the whole file, checked once every chunk is there
+    sha256: String,
+    file_key: Aes256GcmKey,
+}
+impl FileManifest {
+    pub(super) fn file_id(&self) -> &str {
+        &self.file_id
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(super) struct FileChunk {
+    file_id: String,
+    index: u32,
+    aes_iv: Aes256GcmIv,
+    aes_text: String,
+}
+impl FileChunk {
+    pub(super) fn file_id(&self) -> &str {
+        &self.file_id
+    }
+}
+
+/** Encrypts the file with a new key, giving the manifest and the chunks to send after it */
+pub(super) fn split_file(
+    name: &str,
+    mime_type: &str,
+    bytes: &[u8],
+) -> (FileManifest, Vec<FileChunk>) {
+    let file_id = util::encode_base64(&random_bytes::<16>());
+    let file_key: aes_gcm::Key<Aes256Gcm> = random_bytes::<32>().into();
+    let cipher = Aes256Gcm::new(&file_key);
+    let chunks: Vec<_> = bytes
+        .chunks(CHUNK_BYTES)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let iv = random_bytes();
+            let cipher_text = cipher.encrypt(&iv.into(), chunk).unwrap_throw();
+            FileChunk {
+                file_id: file_id.clone(),
+                index: index as u32,
+                aes_iv: Aes256GcmIv(iv),
+                aes_text: util::encode_base64(&cipher_text),
+            }
+        })
+        .collect();
+    let manifest = FileManifest {
+        file_id,
+        name: name.into(),
+        mime_type: mime_type.into(),
+        size: bytes.len() as u64,
+        chunk_count: chunks.len() as u32,
+        sha256: util::encode_base64(&Sha256::digest(bytes)),
+        file_key: Aes256GcmKey(file_key),
+    };
+    (manifest, chunks)
+}
+
+fn blob_url(bytes: &[u8], mime_type: &str) -> Result<String, &'static str> {
+    let parts = js_sys::Array::of1(&js_sys::Uint8Array::from(bytes));
+    let mut options = web_sys::BlobPropertyBag::new();
+    options.type_(mime_type);
+    let blob = web_sys::Blob::new_with_u8_array_sequence_and_options(&parts, &options)
+        .map_err(|_| "Failed to create a blob of the file")?;
+    web_sys::Url::create_object_url_with_blob(&blob)
+        .map_err(|_| "Failed to create a URL for the file")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AttachmentState {
+    Receiving {
+        received: u32,
+        total: u32,
+    },
+    /** The URL stays valid while the page is open */
+    Complete {
+        blob_url: String,
+    },
+    /** The chunks didn't add up to the file the manifest describes */
+    Corrupt,
+}
+
+/** A file sent to a room, as far as its chunks were received */
+#[derive(Debug)]
+pub struct Attachment {
+    manifest: FileManifest,
+    sender_id: api::PublicKeyWrapper,
+    nonce: api::Nonce,
+    // Decrypted, until every chunk is there and they're put together
+    chunks: Vec<Option<Vec<u8>>>,
+    state: AttachmentState,
+}
+impl Attachment {
+    pub(super) fn new(
+        manifest: FileManifest,
+        sender_id: api::PublicKeyWrapper,
+        nonce: api::Nonce,
+    ) -> Result<Self, &'static str> {
+        let expected_chunks = (manifest.size as usize).div_ceil(CHUNK_BYTES);
+        if manifest.size as usize > MAX_FILE_BYTES
+            || manifest.chunk_count as usize != expected_chunks
+        {
+            return Err("File manifest has an invalid size");
+        }
+        let mut attachment = Self {
+            chunks: vec![None; manifest.chunk_count as usize],
+            state: AttachmentState::Receiving {
+                received: 0,
+                total: manifest.chunk_count,
+            },
+            manifest,
+            sender_id,
+            nonce,
+        };
+        // Empty files have nothing to wait for
+        attachment.finish_if_complete();
+        Ok(attachment)
+    }
+    pub(super) fn file_id(&self) -> &str {
+        &self.manifest.file_id
+    }
+    pub fn name(&self) -> &str {
+        &self.manifest.name
+    }
+    pub fn mime_type(&self) -> &str {
+        &self.manifest.mime_type
+    }
+    pub fn size(&self) -> u64 {
+        self.manifest.size
+    }
+    pub fn sender_id(&self) -> &api::PublicKeyWrapper {
+        &self.sender_id
+    }
+    /** Of the manifest */
+    pub fn nonce(&self) -> api::Nonce {
+        self.nonce
+    }
+    pub fn state(&self) -> &AttachmentState {
+        &self.state
+    }
+    /** Chunks received twice, e.g. from both the history and live, are ignored */
+    pub(super) fn add_chunk(&mut self, chunk: FileChunk) -> Result<(), &'static str> {
+        if !matches!(self.state, AttachmentState::Receiving { .. }) {
+            return Ok(());
+        }
+        let slot = self
+            .chunks
+            .get_mut(chunk.index as usize)
+            .ok_or("File chunk index is out of range")?;
+        if slot.is_some() {
+            return Ok(());
+        }
+        let cipher_text = util::decode_base64(&chunk.aes_text)
+            .map_err(|_| "Failed to decode file chunk base64")?;
+        let plain_text = Aes256Gcm::new(&self.manifest.file_key.0)
+            .decrypt((&chunk.aes_iv.0).into(), cipher_text.as_slice())
+            .map_err(|_| "Failed to decrypt file chunk")?;
+        *slot = Some(plain_text);
+        self.finish_if_complete();
+        Ok(())
+    }
+    fn finish_if_complete(&mut self) {
+        let received = self.chunks.iter().filter(|v| v.is_some()).count() as u32;
+        if received < self.manifest.chunk_count {
+            self.state = AttachmentState::Receiving {
+                received,
+                total: self.manifest.chunk_count,
+            };
+            return;
+        }
+        let bytes: Vec<u8> = self.chunks.drain(..).flatten().flatten().collect();
+        let sha256 = util::encode_base64(&Sha256::digest(&bytes));
+        if bytes.len() as u64 != self.manifest.size || sha256 != self.manifest.sha256 {
+            self.state = AttachmentState::Corrupt;
+            return;
+        }
+        self.state = match blob_url(&bytes, &self.manifest.mime_type) {
+            Ok(blob_url) => AttachmentState::Complete { blob_url },
+            Err(err) => {
+                log!("{}", err);
+                AttachmentState::Corrupt
+            }
+        };
+    }
+}
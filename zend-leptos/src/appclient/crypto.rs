@@ -0,0 +1,376 @@
+use super::{random_bytes, Aes256GcmIv, EcdhPublicKey};
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit};
+use hkdf::Hkdf;
+use p256::ecdh;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use zend_common::{_use::wasm_bindgen::UnwrapThrowExt, api, util};
+
+// How far ahead of the last message of a chain the next one may be, and how many keys of the
+// messages in between are kept, in case they arrive late
+const MAX_SKIPPED_KEYS: usize = 64;
+// How many of the first ratchet keys of sessions peers started with us are remembered
+const MAX_INITIAL_KEYS: usize = 1024;
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(try_from = "&str", into = "String")]
+struct ChainKey([u8; 32]);
+impl TryFrom<&str> for ChainKey {
+    type Error = &'static str;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut output: [u8; 32] = [0; 32];
+        util::decode_base64_slice_exact(value, 32, &mut output)?;
+        Ok(Self(output))
+    }
+}
+impl Into<String> for ChainKey {
+    fn into(self) -> String {
+        util::encode_base64(&self.0)
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(try_from = "&str", into = "String")]
+struct RatchetSecret(p256::SecretKey);
+impl TryFrom<&str> for RatchetSecret {
+    type Error = &'static str;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let bytes = util::decode_base64(value).map_err(|_| "Base64 decode error")?;
+        let secret =
+            p256::SecretKey::from_slice(&bytes).map_err(|_| "Couldn't decode bytes as p256 key")?;
+        Ok(Self(secret))
+    }
+}
+impl Into<String> for RatchetSecret {
+    fn into(self) -> String {
+        util::encode_base64(&self.0.to_bytes())
+    }
+}
+impl RatchetSecret {
+    fn random() -> Self {
+        Self(p256::SecretKey::random(&mut rand_core::OsRng))
+    }
+    fn diffie_hellman(&self, public_key: &p256::PublicKey) -> ecdh::SharedSecret {
+        ecdh::diffie_hellman(self.0.to_nonzero_scalar(), public_key.as_affine())
+    }
+}
+
+/** Mixes a new ECDH shared secret into the root key, giving the next root key and the key of a
+new chain */
+fn root_step(root_key: &ChainKey, shared: ecdh::SharedSecret) -> (ChainKey, ChainKey) {
+    let hkdf = Hkdf::<Sha256>::new(Some(&root_key.0), shared.raw_secret_bytes());
+    let mut okm = [0u8; 64];
+    hkdf.expand(b"zend ratchet root", &mut okm).unwrap_throw();
+    let mut next_root = [0u8; 32];
+    let mut chain = [0u8; 32];
+    next_root.copy_from_slice(&okm[..32]);
+    chain.copy_from_slice(&okm[32..]);
+    (ChainKey(next_root), ChainKey(chain))
+}
+
+/** The chain key of the next message, and the AES key of this one. Neither leads back to the
+chain key they're derived from. */
+fn chain_step(chain_key: &ChainKey) -> (ChainKey, aes_gcm::Key<Aes256Gcm>) {
+    let hkdf = Hkdf::<Sha256>::from_prk(&chain_key.0).unwrap_throw();
+    let mut next = [0u8; 32];
+    let mut message_key = [0u8; 32];
+    hkdf.expand(b"zend ratchet chain", &mut next).unwrap_throw();
+    hkdf.expand(b"zend ratchet message", &mut message_key)
+        .unwrap_throw();
+    (ChainKey(next), message_key.into())
+}
+
+// Sessions of the same two peers start from the same root, whichever of them started it
+fn initial_root(a: &str, b: &str) -> ChainKey {
+    let (first, second) = match a < b {
+        true => (a, b),
+        false => (b, a),
+    };
+    let hash = Sha256::new()
+        .chain_update(b"zend ratchet")
+        .chain_update(first.as_bytes())
+        .chain_update(second.as_bytes())
+        .finalize();
+    ChainKey(hash.into())
+}
+
+/** Peer-encrypted data, sent with the sender's session with the receiver. Signed along with the
+rest of the `CipherInfo`, so none of it can be changed by the server. */
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(super) struct EncodedDataCipherRatchet {
+    // The sender's current ratchet key, replaced whenever it hears back from the receiver
+    ratchet_key: EcdhPublicKey,
+    // Of the message in the sender's current chain
+    index: u32,
+    // Length of the sender's chain before the current one, so messages missing from its end can
+    // be skipped
+    previous_count: u32,
+    // The session is new, and the first ratchet key was combined with the receiver's long-lived
+    // ECDH key, since the sender hasn't heard back yet
+    initial: bool,
+    aes_iv: Aes256GcmIv,
+    aes_text: String,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct Chain {
+    key: ChainKey,
+    index: u32,
+}
+impl Chain {
+    fn new(key: ChainKey) -> Self {
+        Self { key, index: 0 }
+    }
+    fn next_message_key(&mut self) -> aes_gcm::Key<Aes256Gcm> {
+        let (next, message_key) = chain_step(&self.key);
+        self.key = next;
+        self.index += 1;
+        message_key
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct SkippedKey {
+    ratchet_key: String,
+    index: u32,
+    key: ChainKey,
+}
+
+/** One side of a double ratchet with a peer. Every time one side hears back from the other, it
+makes a new ECDH key pair, so keys that were used up can be forgotten. Someone who gets hold of
+a session's current state can't read what was sent before. */
+#[derive(Clone, Deserialize, Serialize)]
+struct Session {
+    root_key: ChainKey,
+    own_secret: RatchetSecret,
+    their_key: Option<EcdhPublicKey>,
+    send_chain: Option<Chain>,
+    receive_chain: Option<Chain>,
+    previous_count: u32,
+    // Keys of messages that were skipped over, oldest first
+    skipped: Vec<SkippedKey>,
+    // We started the session and haven't heard back, so what we send is still marked initial
+    awaiting_reply: bool,
+}
+impl Session {
+    /** For sending first, to the peer's long-lived ECDH key */
+    fn initiate(root_key: ChainKey, their_key: &p256::PublicKey) -> Self {
+        let own_secret = RatchetSecret::random();
+        let (root_key, send_chain) = root_step(&root_key, own_secret.diffie_hellman(their_key));
+        Self {
+            root_key,
+            own_secret,
+            their_key: Some(EcdhPublicKey(*their_key)),
+            send_chain: Some(Chain::new(send_chain)),
+            receive_chain: None,
+            previous_count: 0,
+            skipped: Vec::new(),
+            awaiting_reply: true,
+        }
+    }
+    /** For the first message of a session the peer started, with our long-lived ECDH key */
+    fn respond(root_key: ChainKey, own_secret: &p256::SecretKey) -> Self {
+        Self {
+            root_key,
+            own_secret: RatchetSecret(own_secret.clone()),
+            their_key: None,
+            send_chain: None,
+            receive_chain: None,
+            previous_count: 0,
+            skipped: Vec::new(),
+            awaiting_reply: false,
+        }
+    }
+    fn encrypt(&mut self, plain_text: &[u8]) -> EncodedDataCipherRatchet {
+        let chain = self
+            .send_chain
+            .as_mut()
+            .expect_throw("Sessions can send once they know the peer's key");
+        let index = chain.index;
+        let message_key = chain.next_message_key();
+        let iv = random_bytes();
+        let cipher_text = Aes256Gcm::new(&message_key)
+            .encrypt(&iv.into(), plain_text)
+            .unwrap_throw();
+        EncodedDataCipherRatchet {
+            ratchet_key: EcdhPublicKey(self.own_secret.0.public_key()),
+            index,
+            previous_count: self.previous_count,
+            initial: self.awaiting_reply,
+            aes_iv: Aes256GcmIv(iv),
+            aes_text: util::encode_base64(&cipher_text),
+        }
+    }
+    // Keeps the keys of the receive chain's messages up to `until`
+    fn skip_to(&mut self, until: u32) -> Result<(), &'static str> {
+        let ratchet_key: String = match &self.their_key {
+            Some(their_key) => their_key.clone().into(),
+            None => return Ok(()),
+        };
+        let chain = match &mut self.receive_chain {
+            Some(chain) => chain,
+            None => return Ok(()),
+        };
+        if until.saturating_sub(chain.index) as usize > MAX_SKIPPED_KEYS {
+            return Err("Too many peer-encrypted messages are missing");
+        }
+        while chain.index < until {
+            let index = chain.index;
+            let message_key = chain.next_message_key();
+            self.skipped.push(SkippedKey {
+                ratchet_key: ratchet_key.clone(),
+                index,
+                key: ChainKey(message_key.into()),
+            });
+        }
+        let excess = self.skipped.len().saturating_sub(MAX_SKIPPED_KEYS);
+        self.skipped.drain(..excess);
+        Ok(())
+    }
+    // The peer heard back from us and made a new key pair, so we make one too
+    fn ratchet(
+        &mut self,
+        their_key: &EcdhPublicKey,
+        previous_count: u32,
+    ) -> Result<(), &'static str> {
+        self.skip_to(previous_count)?;
+        let (root_key, receive_chain) =
+            root_step(&self.root_key, self.own_secret.diffie_hellman(&their_key.0));
+        self.own_secret = RatchetSecret::random();
+        let (root_key, send_chain) =
+            root_step(&root_key, self.own_secret.diffie_hellman(&their_key.0));
+        self.previous_count = self.send_chain.as_ref().map(|v| v.index).unwrap_or(0);
+        self.root_key = root_key;
+        self.their_key = Some(their_key.clone());
+        self.receive_chain = Some(Chain::new(receive_chain));
+        self.send_chain = Some(Chain::new(send_chain));
+        Ok(())
+    }
+    fn message_key(
+        &mut self,
+        data: &EncodedDataCipherRatchet,
+    ) -> Result<aes_gcm::Key<Aes256Gcm>, &'static str> {
+        let ratchet_key: String = data.ratchet_key.clone().into();
+        if let Some(position) = self
+            .skipped
+            .iter()
+            .position(|v| v.ratchet_key == ratchet_key && v.index == data.index)
+        {
+            return Ok(self.skipped.remove(position).key.0.into());
+        }
+        let is_current = match &self.their_key {
+            Some(their_key) => Into::<String>::into(their_key.clone()) == ratchet_key,
+            None => false,
+        };
+        if !is_current {
+            self.ratchet(&data.ratchet_key, data.previous_count)?;
+        }
+        self.skip_to(data.index)?;
+        let chain = self
+            .receive_chain
+            .as_mut()
+            .ok_or("No chain to receive peer-encrypted data with")?;
+        if chain.index != data.index {
+            return Err("Peer-encrypted data was already received, or is too old");
+        }
+        Ok(chain.next_message_key())
+    }
+    fn decrypt(&mut self, data: &EncodedDataCipherRatchet) -> Result<Vec<u8>, &'static str> {
+        let message_key = self.message_key(data)?;
+        let cipher_text = util::decode_base64(&data.aes_text)
+            .map_err(|_| "Failed to decode ciphertext base64")?;
+        let plain_text = Aes256Gcm::new(&message_key)
+            .decrypt((&data.aes_iv.0).into(), cipher_text.as_slice())
+            .map_err(|_| "Failed to decrypt peer-encrypted ciphertext")?;
+        self.awaiting_reply = false;
+        Ok(plain_text)
+    }
+}
+
+/** Our sessions with other peers, for sending and receiving peer-encrypted data with forward
+secrecy. A session starts from the receiver's long-lived ECDH key, like a member key or the key
+of a join request, and leaves it behind as soon as the receiver answers. */
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub(super) struct Ratchets {
+    sessions: HashMap<String, Session>,
+    // First ratchet keys of the sessions peers started with us, oldest first, so none of them
+    // starts a session twice
+    #[serde(default)]
+    initial_keys: VecDeque<String>,
+}
+impl std::fmt::Debug for Ratchets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ratchets")
+            .field("sessions", &self.sessions.len())
+            .finish()
+    }
+}
+impl Ratchets {
+    /** Starts a session with the peer if there isn't one, with `their_key` as its ECDH key */
+    pub(super) fn encrypt(
+        &mut self,
+        own_id: &api::PublicKeyWrapper,
+        peer_id: &api::PublicKeyWrapper,
+        their_key: &p256::PublicKey,
+        plain_text: &[u8],
+    ) -> EncodedDataCipherRatchet {
+        let peer_id = peer_id.to_string();
+        self.sessions
+            .entry(peer_id.clone())
+            .or_insert_with(|| {
+                Session::initiate(initial_root(&own_id.to_string(), &peer_id), their_key)
+            })
+            .encrypt(plain_text)
+    }
+    /** Only changes the session with the sender if the data could be decrypted, so data the
+    server made up doesn't break it. Data marked initial only starts a new session if there is
+    none yet, or ours is still waiting for a reply, and only once per ratchet key. Otherwise an
+    initial message sent again would start the session over, and with it keys that were already
+    used up. */
+    pub(super) fn decrypt(
+        &mut self,
+        own_id: &api::PublicKeyWrapper,
+        sender_id: &api::PublicKeyWrapper,
+        own_secret: &p256::SecretKey,
+        data: &EncodedDataCipherRatchet,
+    ) -> Result<Vec<u8>, &'static str> {
+        let own_id = own_id.to_string();
+        let sender_id = sender_id.to_string();
+        let existing = self.sessions.get(&sender_id);
+        let continues_session = existing
+            .and_then(|v| v.their_key.clone())
+            .map(|v| Into::<String>::into(v) == Into::<String>::into(data.ratchet_key.clone()))
+            .unwrap_or(false);
+        let starts_session = data.initial && !continues_session;
+        let mut session = match (starts_session, existing) {
+            (false, Some(session)) => session.clone(),
+            (false, None) => return Err("No session with the sender of peer-encrypted data"),
+            (true, Some(session)) if !session.awaiting_reply => {
+                return Err("Peer-encrypted data tried to start over a session in use")
+            }
+            (true, existing) => {
+                let ratchet_key: String = data.ratchet_key.clone().into();
+                if self.initial_keys.contains(&ratchet_key) {
+                    return Err("Peer-encrypted data tried to start a session a second time");
+                }
+                let mut fresh = Session::respond(initial_root(&own_id, &sender_id), own_secret);
+                // Both of us started a session before hearing from the other. The one started by
+                // the peer with the lower ID is kept by both.
+                if existing.is_some() && own_id < sender_id {
+                    return fresh.decrypt(data);
+                }
+                fresh
+            }
+        };
+        let plain_text = session.decrypt(data)?;
+        if starts_session {
+            self.initial_keys.push_back(data.ratchet_key.clone().into());
+            if self.initial_keys.len() > MAX_INITIAL_KEYS {
+                self.initial_keys.pop_front();
+            }
+        }
+        self.sessions.insert(sender_id, session);
+        Ok(plain_text)
+    }
+}
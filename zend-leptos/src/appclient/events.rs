@@ -0,0 +1,194 @@
+use super::RoomTextMessage;
+use crate::wsclient::StateChange;
+use futures::{channel::mpsc, Stream, StreamExt};
+use std::{
+    cell::RefCell,
+    pin::Pin,
+    rc::Weak,
+    task::{Context, Poll},
+};
+use zend_common::api;
+
+/** Something that changed, for the UI to update from instead of reading `AppClient`'s state
+over and over, see `AppClient::receive_events` */
+#[derive(Debug, Clone)]
+pub enum AppEvent {
+    /** Messages read from the history while joining are received too, oldest first */
+    MessageReceived {
+        room_id: api::RoomId,
+        message: RoomTextMessage,
+    },
+    MessageDeleted {
+        room_id: api::RoomId,
+        sender_id: api::PublicKeyWrapper,
+        nonce: api::Nonce,
+    },
+    /** When a peer becomes a member, whether the server or a member told us first. Includes us. */
+    PeerJoined {
+        room_id: api::RoomId,
+        peer_id: api::PublicKeyWrapper,
+    },
+    /** Room-encrypted data is sent with the key from now on */
+    KeyRotated {
+        room_id: api::RoomId,
+        key_id: u32,
+    },
+    ConnectionStateChanged(StateChange),
+    /** Something that failed in the background, e.g. loading a room's history, rather than in a
+    call the UI made and got the error of */
+    Error {
+        room_id: Option<api::RoomId>,
+        error: String,
+    },
+}
+impl AppEvent {
+    /** The room the event is about, if it's about one */
+    pub fn room_id(&self) -> Option<api::RoomId> {
+        match self {
+            Self::MessageReceived { room_id, .. }
+            | Self::MessageDeleted { room_id, .. }
+            | Self::PeerJoined { room_id, .. }
+            | Self::KeyRotated { room_id, .. } => Some(*room_id),
+            Self::ConnectionStateChanged(_) => None,
+            Self::Error { room_id, .. } => *room_id,
+        }
+    }
+}
+
+/** Which `AppEvent`s a subscription yields. Like `SubscriptionEventFilter`, it matches nothing
+until something is added. */
+#[derive(Debug, Clone, Default)]
+pub struct AppEventFilter {
+    messages: bool,
+    peers_joined: bool,
+    key_rotations: bool,
+    connection: bool,
+    errors: bool,
+    room_id: Option<api::RoomId>,
+}
+impl AppEventFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn any(self) -> Self {
+        self.messages()
+            .peers_joined()
+            .key_rotations()
+            .connection()
+            .errors()
+    }
+    /** Received and deleted */
+    pub fn messages(mut self) -> Self {
+        self.messages = true;
+        self
+    }
+    pub fn peers_joined(mut self) -> Self {
+        self.peers_joined = true;
+        self
+    }
+    pub fn key_rotations(mut self) -> Self {
+        self.key_rotations = true;
+        self
+    }
+    pub fn connection(mut self) -> Self {
+        self.connection = true;
+        self
+    }
+    pub fn errors(mut self) -> Self {
+        self.errors = true;
+        self
+    }
+    /** Leaves out events about other rooms. Events about no room in particular still match. */
+    pub fn for_room(mut self, room_id: api::RoomId) -> Self {
+        self.room_id = Some(room_id);
+        self
+    }
+    fn matches(&self, event: &AppEvent) -> bool {
+        let kind = match event {
+            AppEvent::MessageReceived { .. } | AppEvent::MessageDeleted { .. } => self.messages,
+            AppEvent::PeerJoined { .. } => self.peers_joined,
+            AppEvent::KeyRotated { .. } => self.key_rotations,
+            AppEvent::ConnectionStateChanged(_) => self.connection,
+            AppEvent::Error { .. } => self.errors,
+        };
+        let room = match (self.room_id, event.room_id()) {
+            (Some(room_id), Some(event_room_id)) => room_id == event_room_id,
+            _ => true,
+        };
+        kind && room
+    }
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    id: usize,
+    filter: AppEventFilter,
+    sender: mpsc::UnboundedSender<AppEvent>,
+}
+
+/** Who's subscribed to an `AppClient`'s events. Shared with its tasks, which send them. */
+#[derive(Debug, Default)]
+pub(super) struct AppEventSubscribers {
+    next_id: usize,
+    subscribers: Vec<Subscriber>,
+}
+impl AppEventSubscribers {
+    pub(super) fn subscribe(
+        &mut self,
+        filter: AppEventFilter,
+    ) -> (usize, mpsc::UnboundedReceiver<AppEvent>) {
+        let (sender, receiver) = mpsc::unbounded();
+        let id = self.next_id;
+        self.next_id += 1;
+        self.subscribers.push(Subscriber { id, filter, sender });
+        (id, receiver)
+    }
+    fn unsubscribe(&mut self, id: usize) {
+        self.subscribers.retain(|v| v.id != id);
+    }
+    pub(super) fn dispatch(&mut self, event: AppEvent) {
+        // Subscribers whose handle was dropped are forgotten along the way
+        self.subscribers.retain(|subscriber| {
+            !subscriber.filter.matches(&event)
+                || subscriber.sender.unbounded_send(event.clone()).is_ok()
+        });
+    }
+}
+
+/** Yields the events matched by its filter until dropped, which unsubscribes, like
+`EventSubscriptionHandle`. Ends once the `AppClient` is dropped. */
+#[derive(Debug)]
+pub struct AppEventHandle {
+    receiver: mpsc::UnboundedReceiver<AppEvent>,
+    id: usize,
+    subscribers: Weak<RefCell<AppEventSubscribers>>,
+}
+impl AppEventHandle {
+    pub(super) fn new(
+        receiver: mpsc::UnboundedReceiver<AppEvent>,
+        id: usize,
+        subscribers: Weak<RefCell<AppEventSubscribers>>,
+    ) -> Self {
+        Self {
+            receiver,
+            id,
+            subscribers,
+        }
+    }
+}
+impl Stream for AppEventHandle {
+    type Item = AppEvent;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.receiver.size_hint()
+    }
+}
+impl Drop for AppEventHandle {
+    fn drop(&mut self) {
+        if let Some(subscribers) = self.subscribers.upgrade() {
+            subscribers.borrow_mut().unsubscribe(self.id);
+        }
+    }
+}
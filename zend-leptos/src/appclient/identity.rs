@@ -0,0 +1,256 @@
+use super::{
+    crypto::Ratchets, random_bytes, Aes256GcmIv, MemberKey, PerRoomState, RoomKeys, RoomPhase,
+    RoomState, SharedRoomKey, VerifiedPeer,
+};
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit};
+use p256::ecdsa;
+use serde::{Deserialize, Serialize};
+use zend_common::{_use::wasm_bindgen::UnwrapThrowExt, api, log, util};
+
+const STORAGE_KEY: &str = "zend_identity";
+const PBKDF2_ROUNDS: u32 = 600_000;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredRoom {
+    room_id: api::RoomId,
+    key_id: u32,
+    room_keys: Vec<SharedRoomKey>,
+    member_keys: Vec<MemberKey>,
+    #[serde(default)]
+    read_up_to: Option<api::Nonce>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredIdentity {
+    signing_key: String,
+    ecdh_secret: String,
+    // The key's calls have to carry on with newer nonces
+    next_nonce: api::Nonce,
+    #[serde(default)]
+    rooms: Vec<StoredRoom>,
+    #[serde(default)]
+    verified_peers: Vec<VerifiedPeer>,
+    #[serde(default)]
+    ratchets: Ratchets,
+    #[serde(default)]
+    send_read_receipts: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EncryptedIdentity {
+    pbkdf2_salt: String,
+    aes_iv: Aes256GcmIv,
+    aes_text: String,
+}
+
+// Argon2 rather than PBKDF2, as backups leave the device
+#[derive(Debug, Serialize, Deserialize)]
+struct IdentityBackup {
+    argon2_salt: String,
+    aes_iv: Aes256GcmIv,
+    aes_text: String,
+}
+
+fn local_storage() -> Result<web_sys::Storage, &'static str> {
+    web_sys::window()
+        .ok_or("No window")?
+        .local_storage()
+        .ok()
+        .flatten()
+        .ok_or("localStorage isn't available")
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> aes_gcm::Key<Aes256Gcm> {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<sha2::Sha256>(passphrase.as_bytes(), salt, PBKDF2_ROUNDS, &mut key);
+    key.into()
+}
+
+fn derive_backup_key(passphrase: &str, salt: &[u8]) -> aes_gcm::Key<Aes256Gcm> {
+    let mut key = [0u8; 32];
+    argon2::Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .unwrap_throw();
+    key.into()
+}
+
+fn encrypt_identity(
+    key: &aes_gcm::Key<Aes256Gcm>,
+    room_state: &RoomState,
+) -> (Aes256GcmIv, String) {
+    let identity = StoredIdentity::from_room_state(room_state);
+    let plain_text = serde_json::to_vec(&identity).unwrap_throw();
+    let iv = random_bytes();
+    let cipher_text = Aes256Gcm::new(key)
+        .encrypt(&iv.into(), plain_text.as_slice())
+        .unwrap_throw();
+    (Aes256GcmIv(iv), util::encode_base64(&cipher_text))
+}
+
+fn decrypt_identity(
+    key: &aes_gcm::Key<Aes256Gcm>,
+    iv: &Aes256GcmIv,
+    text: &str,
+) -> Result<RoomState, &'static str> {
+    let cipher_text = util::decode_base64(text).map_err(|_| "Invalid stored identity")?;
+    let plain_text = Aes256Gcm::new(key)
+        .decrypt((&iv.0).into(), cipher_text.as_slice())
+        .map_err(|_| "Wrong passphrase")?;
+    let identity: StoredIdentity =
+        serde_json::from_slice(&plain_text).map_err(|_| "Error parsing the stored identity")?;
+    identity.into_room_state()
+}
+
+/** Our keys and the rooms we're in, encrypted with the passphrase, to carry on with on another
+device, see `import_backup` */
+pub(super) fn export_backup(room_state: &RoomState, passphrase: &str) -> String {
+    let salt: [u8; 16] = random_bytes();
+    let key = derive_backup_key(passphrase, &salt);
+    let (aes_iv, aes_text) = encrypt_identity(&key, room_state);
+    let backup = IdentityBackup {
+        argon2_salt: util::encode_base64(&salt),
+        aes_iv,
+        aes_text,
+    };
+    util::encode_base64(serde_json::to_string(&backup).unwrap_throw().as_bytes())
+}
+
+pub(super) fn import_backup(backup: &str, passphrase: &str) -> Result<RoomState, &'static str> {
+    let backup: IdentityBackup = util::decode_base64(backup.trim())
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or("Invalid backup")?;
+    let salt = util::decode_base64(&backup.argon2_salt).map_err(|_| "Invalid backup")?;
+    let key = derive_backup_key(passphrase, &salt);
+    decrypt_identity(&key, &backup.aes_iv, &backup.aes_text)
+}
+
+/** Keeps our keys, and the rooms we're in, in localStorage across page loads, encrypted with a
+key derived from a passphrase. Deriving it is slow on purpose, so it's only done when opening. */
+pub struct IdentityStore {
+    key: aes_gcm::Key<Aes256Gcm>,
+    salt: [u8; 32],
+}
+impl std::fmt::Debug for IdentityStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("IdentityStore")
+    }
+}
+impl IdentityStore {
+    /** Reads what was stored with the passphrase, if anything was. Fails for a wrong passphrase,
+    rather than starting over and replacing what was stored. */
+    pub(super) fn open(passphrase: &str) -> Result<(Self, Option<RoomState>), &'static str> {
+        let stored = local_storage()?
+            .get_item(STORAGE_KEY)
+            .map_err(|_| "Failed to read localStorage")?;
+        let encrypted: EncryptedIdentity = match stored {
+            Some(stored) => {
+                serde_json::from_str(&stored).map_err(|_| "Error parsing the stored identity")?
+            }
+            None => {
+                let salt = random_bytes();
+                let key = derive_key(passphrase, &salt);
+                return Ok((Self { key, salt }, None));
+            }
+        };
+        let mut salt = [0u8; 32];
+        util::decode_base64_slice_exact(&encrypted.pbkdf2_salt, 32, &mut salt)?;
+        let key = derive_key(passphrase, &salt);
+        let room_state = decrypt_identity(&key, &encrypted.aes_iv, &encrypted.aes_text)?;
+        Ok((Self { key, salt }, Some(room_state)))
+    }
+    pub(super) fn save(&self, room_state: &RoomState) {
+        let (aes_iv, aes_text) = encrypt_identity(&self.key, room_state);
+        let encrypted = EncryptedIdentity {
+            pbkdf2_salt: util::encode_base64(&self.salt),
+            aes_iv,
+            aes_text,
+        };
+        let encrypted = serde_json::to_string(&encrypted).unwrap_throw();
+        let result = local_storage().and_then(|storage| {
+            storage
+                .set_item(STORAGE_KEY, &encrypted)
+                .map_err(|_| "Failed to write localStorage")
+        });
+        if let Err(err) = result {
+            log!("Failed to store identity: {}", err);
+        }
+    }
+    /** For when the passphrase was forgotten */
+    pub(super) fn delete() -> Result<(), &'static str> {
+        local_storage()?
+            .remove_item(STORAGE_KEY)
+            .map_err(|_| "Failed to write localStorage")
+    }
+}
+
+impl StoredRoom {
+    fn into_room(self) -> Result<(api::RoomId, PerRoomState), &'static str> {
+        let mut room_keys: Option<RoomKeys> = None;
+        for key in self.room_keys {
+            match &mut room_keys {
+                Some(room_keys) => room_keys.insert(key.key_id, key.room_key.0),
+                None => room_keys = Some(RoomKeys::new(key.key_id, key.room_key.0)),
+            }
+        }
+        let mut room_keys = room_keys.ok_or("No stored room keys")?;
+        room_keys.set_current(self.key_id)?;
+        // Messages aren't stored, so the history is read again
+        let mut room = PerRoomState::new(RoomPhase::LoadingHistory { room_keys });
+        room.member_keys = self.member_keys;
+        room.read_up_to = self.read_up_to;
+        Ok((self.room_id, room))
+    }
+}
+
+impl StoredIdentity {
+    fn from_room_state(room_state: &RoomState) -> Self {
+        let calls = room_state.calls.borrow();
+        // Joining isn't resumed, as the members may have answered in the meantime
+        let rooms = room_state
+            .rooms
+            .iter()
+            .filter_map(|(room_id, room)| match &room.phase {
+                RoomPhase::InRoom { room_keys } | RoomPhase::LoadingHistory { room_keys } => {
+                    Some(StoredRoom {
+                        room_id: *room_id,
+                        key_id: room_keys.current().0,
+                        room_keys: room_keys.shared_keys(),
+                        member_keys: room.member_keys.clone(),
+                        read_up_to: room.read_up_to,
+                    })
+                }
+                RoomPhase::JoiningRoom { .. } => None,
+            })
+            .collect();
+        Self {
+            signing_key: util::encode_base64(&calls.key().to_bytes()),
+            ecdh_secret: util::encode_base64(&room_state.ecdh_secret.to_bytes()),
+            next_nonce: calls.peek_nonce(),
+            rooms,
+            verified_peers: room_state.verified_peers.clone(),
+            ratchets: room_state.ratchets.clone(),
+            send_read_receipts: room_state.send_read_receipts,
+        }
+    }
+    fn into_room_state(self) -> Result<RoomState, &'static str> {
+        let signing_key = util::decode_base64(&self.signing_key)
+            .ok()
+            .and_then(|bytes| ecdsa::SigningKey::from_slice(&bytes).ok())
+            .ok_or("Invalid stored signing key")?;
+        let ecdh_secret = util::decode_base64(&self.ecdh_secret)
+            .ok()
+            .and_then(|bytes| p256::SecretKey::from_slice(&bytes).ok())
+            .ok_or("Invalid stored ECDH key")?;
+        let mut room_state = RoomState::from_keys(signing_key, ecdh_secret);
+        room_state.calls.borrow_mut().resume_after(self.next_nonce);
+        for room in self.rooms {
+            let (room_id, room) = room.into_room()?;
+            room_state.rooms.insert(room_id, room);
+        }
+        room_state.verified_peers = self.verified_peers;
+        room_state.ratchets = self.ratchets;
+        room_state.send_read_receipts = self.send_read_receipts;
+        Ok(room_state)
+    }
+}
@@ -0,0 +1,5 @@
+// The web worker for clients configured with `WsClientConfig::with_worker`
+fn main() {
+    zend_common::set_panic_hook!();
+    zend_leptos::run_worker();
+}
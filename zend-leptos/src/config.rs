@@ -0,0 +1,111 @@
+use crate::wsclient::WsClientConfig;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use zend_common::log;
+
+const STORAGE_KEY: &str = "zend_config";
+/** Where `wrangler dev` serves the worker */
+pub const DEFAULT_SERVER_URL: &str = "ws://localhost:8787";
+
+/** When `WsApiClient` tries to connect again after losing its connection, see
+`WsClientConfig` */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReconnectPolicy {
+    pub reconnect: bool,
+    /** The wait after the first failed attempt, doubling with every further one */
+    pub initial_backoff_secs: u64,
+    pub max_backoff_secs: u64,
+    /** Consecutive failed attempts to give up after, if any */
+    pub max_attempts: Option<u32>,
+}
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            reconnect: true,
+            initial_backoff_secs: 5,
+            max_backoff_secs: 60,
+            max_attempts: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotificationPrefs {
+    /** Only shown with the browser's permission too */
+    pub enabled: bool,
+    /** Otherwise notifications only tell who sent something, for screens others can see */
+    pub show_text: bool,
+}
+impl Default for NotificationPrefs {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            show_text: true,
+        }
+    }
+}
+
+/** The client's settings, kept in localStorage across page loads. Missing or unreadable
+settings are the defaults, so older saved configs still load. */
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientConfig {
+    /** In place of `DEFAULT_SERVER_URL` */
+    pub server_url: Option<String>,
+    pub reconnect: ReconnectPolicy,
+    pub notifications: NotificationPrefs,
+}
+impl ClientConfig {
+    /** What was saved last, or the defaults */
+    pub fn load() -> Self {
+        let stored = local_storage().and_then(|storage| {
+            storage
+                .get_item(STORAGE_KEY)
+                .map_err(|_| "Failed to read localStorage")
+        });
+        match stored {
+            Ok(Some(stored)) => serde_json::from_str(&stored).unwrap_or_else(|_| {
+                log!("Error parsing the stored config, using the defaults");
+                Self::default()
+            }),
+            Ok(None) => Self::default(),
+            Err(err) => {
+                log!("Failed to load the config: {}", err);
+                Self::default()
+            }
+        }
+    }
+    pub fn save(&self) -> Result<(), &'static str> {
+        let config = serde_json::to_string(self).map_err(|_| "Failed to serialize the config")?;
+        local_storage()?
+            .set_item(STORAGE_KEY, &config)
+            .map_err(|_| "Failed to write localStorage")
+    }
+    pub fn server_url(&self) -> &str {
+        self.server_url.as_deref().unwrap_or(DEFAULT_SERVER_URL)
+    }
+    pub fn ws_client_config(&self) -> WsClientConfig {
+        let reconnect = &self.reconnect;
+        WsClientConfig::new()
+            .with_reconnect(reconnect.reconnect)
+            .with_initial_backoff(Duration::from_secs(reconnect.initial_backoff_secs))
+            .with_max_backoff(Duration::from_secs(reconnect.max_backoff_secs))
+            .with_max_reconnect_attempts(reconnect.max_attempts)
+    }
+    /** Whether a client made with this config connects the same way as with `other`. If not,
+    changing from one to the other only applies after reconnecting. */
+    pub fn connects_like(&self, other: &Self) -> bool {
+        self.server_url() == other.server_url() && self.reconnect == other.reconnect
+    }
+}
+
+fn local_storage() -> Result<web_sys::Storage, &'static str> {
+    web_sys::window()
+        .ok_or("No window")?
+        .local_storage()
+        .ok()
+        .flatten()
+        .ok_or("localStorage isn't available")
+}
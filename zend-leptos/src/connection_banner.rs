@@ -0,0 +1,57 @@
+use crate::{signals::use_client, wsclient::WebSocketState};
+use leptos::*;
+use std::time::Duration;
+
+/** How the connection to the server is doing, with a way to start over with a new one, so
+nobody types into a session that's no longer there without knowing */
+#[component]
+pub fn ConnectionBanner(cx: Scope) -> impl IntoView {
+    let signals = use_client(cx);
+    let connection = signals.connection;
+    // Counts down to the next attempt from when it was announced
+    let (remaining, set_remaining) = create_signal(cx, 0u64);
+    create_effect(cx, move |_| {
+        if let Some(retry_after) = connection.get().retry_after {
+            set_remaining.set(retry_after);
+        }
+    });
+    let tick = move || set_remaining.update(|v| *v = v.saturating_sub(1));
+    if let Ok(interval) = set_interval(tick, Duration::from_secs(1)) {
+        on_cleanup(cx, move || interval.clear());
+    }
+
+    let (retrying, set_retrying) = create_signal(cx, false);
+    let on_retry = move |_: ev::MouseEvent| {
+        set_retrying.set(true);
+        let signals = signals.clone();
+        spawn_local(async move {
+            signals.reconnect().await;
+            set_retrying.set(false);
+        });
+    };
+    let state = move || connection.get().state;
+
+    view! { cx,
+        <div class="connection-banner" class:connected=move || state() == WebSocketState::Connected>
+            <span>
+                {move || match state() {
+                    WebSocketState::Connected => "Connected".to_string(),
+                    WebSocketState::Reconnecting => {
+                        format!("Reconnecting in {}s…", remaining.get())
+                    }
+                    WebSocketState::Ended => "Connection ended".to_string(),
+                }}
+            </span>
+            {move || {
+                let on_retry = on_retry.clone();
+                (state() != WebSocketState::Connected).then(|| {
+                    view! { cx,
+                        <button on:click=on_retry prop:disabled=move || retrying.get()>
+                            "Retry"
+                        </button>
+                    }
+                })
+            }}
+        </div>
+    }
+}
@@ -0,0 +1,76 @@
+use crate::signals::use_client;
+use leptos::*;
+use leptos_router::*;
+use std::rc::Rc;
+use zend_common::api;
+
+/** Creating a room, or going to one by its code or an invite's QR code */
+#[component]
+pub fn Home(cx: Scope) -> impl IntoView {
+    let signals = use_client(cx);
+    let (creating, set_creating) = create_signal(cx, false);
+    let (create_error, set_create_error) = create_signal(cx, None::<String>);
+    let create_navigate = Rc::new(use_navigate(cx));
+    let on_create = move |_| {
+        set_creating.set(true);
+        set_create_error.set(None);
+        let client = signals.client();
+        let navigate = create_navigate.clone();
+        spawn_local(async move {
+            let result = match client.create_room().await {
+                // Leaves this page, so nothing here is updated afterwards
+                Ok(room_id) => navigate(&format!("/room/{}", room_id), Default::default())
+                    .map_err(|err| format!("{:?}", err)),
+                Err(err) => Err(format!("Failed to create a room: {:?}", err)),
+            };
+            if let Err(err) = result {
+                set_create_error.set(Some(err));
+                set_creating.set(false);
+            }
+        });
+    };
+
+    let (code, set_code) = create_signal(cx, String::new());
+    let (join_error, set_join_error) = create_signal(cx, None::<String>);
+    let join_navigate = use_navigate(cx);
+    let on_join = move |ev: ev::SubmitEvent| {
+        ev.prevent_default();
+        // Checked here, so typos don't get as far as asking a room that isn't there
+        match api::RoomId::try_from(code.get().trim().to_string()) {
+            Ok(room_id) => {
+                set_join_error.set(None);
+                if let Err(err) = join_navigate(&format!("/room/{}", room_id), Default::default()) {
+                    set_join_error.set(Some(format!("{:?}", err)));
+                }
+            }
+            Err(err) => set_join_error.set(Some(err.to_string())),
+        }
+    };
+
+    view! { cx,
+        <div class="home">
+            <section>
+                <h2>"Create a room"</h2>
+                <button on:click=on_create prop:disabled=move || creating.get()>
+                    "Create room"
+                </button>
+                {move || create_error.get().map(|err| view! { cx, <p class="error">{err}</p> })}
+            </section>
+            <section>
+                <h2>"Join a room"</h2>
+                <form on:submit=on_join>
+                    <input
+                        type="text"
+                        placeholder="Room code"
+                        prop:value=move || code.get()
+                        on:input=move |ev| set_code.set(event_target_value(&ev))
+                    />
+                    <button type="submit">"Join room"</button>
+                </form>
+                {move || join_error.get().map(|err| view! { cx, <p class="error">{err}</p> })}
+                <A href="/scan">"Scan an invite"</A>
+            </section>
+            <A href="/settings">"Settings"</A>
+        </div>
+    }
+}
@@ -1,37 +1,44 @@
 use leptos::*;
 use leptos_router::*;
 mod appclient;
+mod config;
+mod connection_banner;
+mod home;
+mod notifications;
+mod qr;
+mod room_view;
+mod settings;
+mod signals;
 mod util;
 mod wsclient;
-use zend_common::{_use::wasm_bindgen::UnwrapThrowExt, api, debug_log_pretty};
+mod wsworker;
+use connection_banner::{ConnectionBanner, ConnectionBannerProps};
+use home::{Home, HomeProps};
+use notifications::{MessageNotifications, MessageNotificationsProps};
+use qr::{ScanInvite, ScanInviteProps};
+use room_view::{RoomView, RoomViewProps};
+use settings::{Settings, SettingsProps};
+use std::rc::Rc;
+pub use wsworker::run_worker;
 
 #[component]
 pub fn App(cx: Scope) -> impl IntoView {
-    // let _ws = wsclient::WsApiClient::new("ws://localhost:8787");
-    let mut client = appclient::AppClient::new();
-    // debug_log_pretty!(client);
-    let message = client.make_server_method_call(api::SubscribeToRoomArgs {
-        room_id: api::RoomId::from_int(0),
-    });
-    let json = serde_json::to_string(&message);
-    debug_log_pretty!(json);
-    let message = client.make_server_method_call(api::BroadcastDataArgs {
-        common_args: api::SendDataCommonArgs {
-            room_id: api::RoomId::from_int(0),
-            write_history: false,
-            data: serde_json::from_str("\"\"").unwrap_throw(),
-        },
-    });
-    let json = serde_json::to_string(&message);
-    debug_log_pretty!(json);
+    let client = appclient::AppClient::new(config::ClientConfig::load());
+    provide_context(cx, signals::ClientSignals::new(cx, Rc::new(client)));
 
     view! { cx,
-        <Router>
-            <Routes>
-                <Route path="/" view=|cx| view! { cx, <div></div> }/>
-                <Route path="/room/:id" view=|cx| view! { cx, <div></div> }/>
-                <Route path="/*any" view=|cx| view! { cx, <Redirect path="/"/> }/>
-            </Routes>
-        </Router>
+        <>
+            <ConnectionBanner/>
+            <Router>
+                <MessageNotifications/>
+                <Routes>
+                    <Route path="/" view=|cx| view! { cx, <Home/> }/>
+                    <Route path="/scan" view=|cx| view! { cx, <ScanInvite/> }/>
+                    <Route path="/room/:id" view=|cx| view! { cx, <RoomView/> }/>
+                    <Route path="/settings" view=|cx| view! { cx, <Settings/> }/>
+                    <Route path="/*any" view=|cx| view! { cx, <Redirect path="/"/> }/>
+                </Routes>
+            </Router>
+        </>
     }
 }
@@ -0,0 +1,187 @@
+use crate::{
+    appclient::{AppEvent, AppEventFilter, RoomTextMessage},
+    config::NotificationPrefs,
+    signals::{use_client, ClientSignals},
+};
+use futures::{future, StreamExt};
+use leptos::*;
+use leptos_router::*;
+use web_sys::{Notification, NotificationOptions, NotificationPermission};
+use zend_common::{
+    _use::wasm_bindgen::{closure::Closure, JsCast},
+    api, fingerprint, log,
+};
+
+// Older messages arrive when catching up on a room's history, which isn't news
+const MAX_AGE_SECS: u64 = 60;
+
+fn is_supported() -> bool {
+    js_sys::Reflect::has(&window(), &"Notification".into()).unwrap_or(false)
+}
+
+async fn request_permission() -> bool {
+    let promise = match Notification::request_permission() {
+        Ok(promise) => promise,
+        Err(_) => return false,
+    };
+    match wasm_bindgen_futures::JsFuture::from(promise).await {
+        Ok(permission) => permission.as_string().as_deref() == Some("granted"),
+        Err(_) => false,
+    }
+}
+
+/** Whether a message is worth interrupting for: someone else's, new, and sent while the tab
+can't be seen. Permission alone isn't opting in, as it outlives turning notifications off. */
+fn should_notify(signals: &ClientSignals, message: &RoomTextMessage) -> bool {
+    let now = (js_sys::Date::now() / 1000.0) as u64;
+    signals.config.with(|config| config.notifications.enabled)
+        && Notification::permission() == NotificationPermission::Granted
+        && document().hidden()
+        && message.nonce().timestamp + MAX_AGE_SECS >= now
+        && message.sender_id().to_string() != signals.client().own_id().to_string()
+}
+
+fn notify(
+    signals: &ClientSignals,
+    room_id: api::RoomId,
+    message: &RoomTextMessage,
+    navigate: impl Fn(&str, NavigateOptions) -> Result<(), NavigationError> + 'static,
+) {
+    let sender_id = message.sender_id().to_string();
+    let nickname = signals
+        .client()
+        .roster(room_id)
+        .into_iter()
+        .find(|v| v.peer_id.to_string() == sender_id)
+        .and_then(|v| v.nickname);
+    let fingerprint = fingerprint::short_fingerprint(message.sender_id());
+    let title = match nickname {
+        Some(nickname) => format!("{} ({})", nickname, fingerprint),
+        None => fingerprint,
+    };
+    let mut options = NotificationOptions::new();
+    if signals.config.with(|config| config.notifications.show_text) {
+        options.body(message.text().unwrap_or_default());
+    }
+    // A room's newer messages replace its older ones, rather than piling up
+    options.tag(&room_id.to_string());
+    let notification = match Notification::new_with_options(&title, &options) {
+        Ok(notification) => notification,
+        Err(err) => {
+            log!("Failed to show a notification: {:?}", err);
+            return;
+        }
+    };
+    let clicked = notification.clone();
+    let on_click = Closure::once_into_js(move || {
+        clicked.close();
+        let _ = window().focus();
+        if let Err(err) = navigate(&format!("/room/{}", room_id), Default::default()) {
+            log!("Failed to open the room: {:?}", err);
+        }
+    });
+    notification.set_onclick(Some(on_click.unchecked_ref()));
+}
+
+/** Notifications for messages that arrive while the tab is hidden, if they're turned on, see
+`NotificationSettings`. Clicking one opens its room. Has to be inside the `Router`, for that. */
+#[component]
+pub fn MessageNotifications(cx: Scope) -> impl IntoView {
+    let signals = use_client(cx);
+    let navigate = use_navigate(cx);
+    // Subscriptions carry on after reconnecting, like those of `ClientSignals`
+    let mut events = signals
+        .client()
+        .receive_events(AppEventFilter::new().messages());
+    let (follow_events, abort_handle) = future::abortable(async move {
+        while let Some(event) = events.next().await {
+            if let AppEvent::MessageReceived { room_id, message } = event {
+                if should_notify(&signals, &message) {
+                    notify(&signals, room_id, &message, navigate.clone());
+                }
+            }
+        }
+    });
+    spawn_local(async move {
+        let _ = follow_events.await;
+    });
+    on_cleanup(cx, move || abort_handle.abort());
+}
+
+/** Opting in to notifications, asking for the browser's permission when turning them on */
+#[component]
+pub fn NotificationSettings(cx: Scope) -> impl IntoView {
+    let signals = use_client(cx);
+    let config = signals.config;
+    let enabled = move || {
+        config.with(|config| config.notifications.enabled)
+            && Notification::permission() == NotificationPermission::Granted
+    };
+    // Blocking can't be undone from here, only in the browser's settings
+    let (blocked, set_blocked) = create_signal(
+        cx,
+        is_supported() && Notification::permission() == NotificationPermission::Denied,
+    );
+    let (error, set_error) = create_signal(cx, None::<String>);
+    let save = move |signals: ClientSignals, prefs: NotificationPrefs| {
+        let mut new_config = config.get();
+        new_config.notifications = prefs;
+        spawn_local(async move {
+            let result = signals.apply_config(new_config).await;
+            set_error.set(result.err().map(|err| err.to_string()));
+        });
+    };
+
+    let toggle_signals = signals.clone();
+    let on_toggle = move |_: ev::MouseEvent| {
+        let mut prefs = config.with(|config| config.notifications.clone());
+        let signals = toggle_signals.clone();
+        if enabled() {
+            prefs.enabled = false;
+            save(signals, prefs);
+            return;
+        }
+        spawn_local(async move {
+            if request_permission().await {
+                prefs.enabled = true;
+                save(signals, prefs);
+            } else {
+                set_blocked.set(Notification::permission() == NotificationPermission::Denied);
+            }
+        });
+    };
+    let on_show_text = move |ev: ev::Event| {
+        let mut prefs = config.with(|config| config.notifications.clone());
+        prefs.show_text = event_target_checked(&ev);
+        save(signals.clone(), prefs);
+    };
+
+    if !is_supported() {
+        return view! { cx, <p>"This browser can't show notifications"</p> }.into_view(cx);
+    }
+    view! { cx,
+        <div class="notification-settings">
+            <button on:click=on_toggle prop:disabled=move || blocked.get()>
+                {move || match enabled() {
+                    true => "Stop notifications",
+                    false => "Notify me of new messages",
+                }}
+            </button>
+            {move || {
+                blocked.get().then(|| {
+                    view! { cx, <span>"Notifications are blocked by the browser"</span> }
+                })
+            }}
+            <label>
+                <input
+                    type="checkbox"
+                    prop:checked=move || config.with(|config| config.notifications.show_text)
+                    on:change=on_show_text
+                />
+                "Show what messages say"
+            </label>
+            {move || error.get().map(|err| view! { cx, <p class="error">{err}</p> })}
+        </div>
+    }
+    .into_view(cx)
+}
@@ -0,0 +1,152 @@
+use crate::{appclient::Invite, signals::use_client};
+use leptos::*;
+use leptos_router::*;
+use qrcode::{render::svg, QrCode};
+use std::{cell::Cell, rc::Rc, time::Duration};
+use zend_common::_use::wasm_bindgen::{JsCast, JsValue};
+
+// Often enough to feel instant, rarely enough not to keep a phone busy
+const SCAN_INTERVAL: Duration = Duration::from_millis(300);
+
+/** The link as a QR code, to show to someone who's there in person */
+pub fn qr_svg(url: &str) -> Option<String> {
+    let code = QrCode::new(url.as_bytes()).ok()?;
+    Some(code.render::<svg::Color>().min_dimensions(200, 200).build())
+}
+
+/** The back camera if there is one, as facing the user makes pointing at another screen hard */
+async fn open_camera() -> Result<web_sys::MediaStream, &'static str> {
+    let media_devices = window()
+        .navigator()
+        .media_devices()
+        .map_err(|_| "There's no camera access in this browser")?;
+    let video = js_sys::Object::new();
+    js_sys::Reflect::set(&video, &"facingMode".into(), &"environment".into())
+        .map_err(|_| "Failed to ask for the camera")?;
+    let mut constraints = web_sys::MediaStreamConstraints::new();
+    constraints.video(&video);
+    let stream = media_devices
+        .get_user_media_with_constraints(&constraints)
+        .map_err(|_| "Failed to ask for the camera")?;
+    wasm_bindgen_futures::JsFuture::from(stream)
+        .await
+        .map_err(|_| "No camera, or it wasn't allowed")?
+        .dyn_into()
+        .map_err(|_| "Failed to open the camera")
+}
+
+fn close_camera(stream: &web_sys::MediaStream) {
+    for track in stream.get_tracks().iter() {
+        if let Ok(track) = track.dyn_into::<web_sys::MediaStreamTrack>() {
+            track.stop();
+        }
+    }
+}
+
+/** The text of the first QR code in the video's current frame */
+fn scan_frame(
+    video: &web_sys::HtmlVideoElement,
+    canvas: &web_sys::HtmlCanvasElement,
+) -> Result<Option<String>, JsValue> {
+    let (width, height) = (video.video_width(), video.video_height());
+    // Until the first frame arrives
+    if width == 0 || height == 0 {
+        return Ok(None);
+    }
+    canvas.set_width(width);
+    canvas.set_height(height);
+    let context: web_sys::CanvasRenderingContext2d = canvas
+        .get_context("2d")?
+        .ok_or("No 2d canvas context")?
+        .dyn_into()?;
+    context.draw_image_with_html_video_element(video, 0.0, 0.0)?;
+    let rgba = context
+        .get_image_data(0.0, 0.0, width as f64, height as f64)?
+        .data();
+    let luma: Vec<u8> = rgba
+        .chunks_exact(4)
+        .map(|v| ((v[0] as u32 * 299 + v[1] as u32 * 587 + v[2] as u32 * 114) / 1000) as u8)
+        .collect();
+    let width = width as usize;
+    let mut image = rqrr::PreparedImage::prepare_from_greyscale(width, height as usize, |x, y| {
+        luma[y * width + x]
+    });
+    Ok(image
+        .detect_grids()
+        .into_iter()
+        .find_map(|grid| grid.decode().ok())
+        .map(|(_, content)| content))
+}
+
+/** Joins a room by pointing the camera at the QR code of an invite link, e.g. on a member's
+screen */
+#[component]
+pub fn ScanInvite(cx: Scope) -> impl IntoView {
+    let signals = use_client(cx);
+    let navigate = use_navigate(cx);
+    let video = create_node_ref::<html::Video>(cx);
+    let (status, set_status) = create_signal(cx, "Opening the camera…".to_string());
+    // Scanning stops once the page is left
+    let stopped = Rc::new(Cell::new(false));
+    let cleanup_stopped = stopped.clone();
+    on_cleanup(cx, move || cleanup_stopped.set(true));
+
+    spawn_local(async move {
+        let stream = match open_camera().await {
+            Ok(stream) => stream,
+            Err(err) => {
+                set_status.set(err.to_string());
+                return;
+            }
+        };
+        let canvas = document()
+            .create_element("canvas")
+            .ok()
+            .and_then(|v| v.dyn_into::<web_sys::HtmlCanvasElement>().ok());
+        let (video, canvas) = match (video.get(), canvas) {
+            (Some(video), Some(canvas)) if !stopped.get() => (video, canvas),
+            _ => {
+                close_camera(&stream);
+                return;
+            }
+        };
+        video.set_src_object(Some(&stream));
+        set_status.set("Point the camera at an invite's QR code".to_string());
+        while !stopped.get() {
+            gloo_timers::future::sleep(SCAN_INTERVAL).await;
+            let content = match scan_frame(&video, &canvas) {
+                Ok(Some(content)) => content,
+                Ok(None) => continue,
+                Err(err) => {
+                    set_status.set(format!("Failed to read the camera: {:?}", err));
+                    break;
+                }
+            };
+            let invite = match Invite::parse(&content) {
+                Ok(invite) => invite,
+                Err(err) => {
+                    set_status.set(format!("{}, keep looking", err));
+                    continue;
+                }
+            };
+            if let Err(err) = signals.client().join_with_invite(&invite).await {
+                set_status.set(format!("Failed to join with the invite: {:?}", err));
+                break;
+            }
+            close_camera(&stream);
+            if let Err(err) = navigate(&format!("/room/{}", invite.room_id), Default::default()) {
+                set_status.set(format!("{:?}", err));
+            }
+            return;
+        }
+        close_camera(&stream);
+    });
+
+    view! { cx,
+        <div class="scan-invite">
+            <video node_ref=video autoplay=true playsinline=true muted=true></video>
+            <p class="status">{move || status.get()}</p>
+            <A href="/">"Back"</A>
+        </div>
+    }
+}
@@ -0,0 +1,231 @@
+use crate::{
+    appclient::{Invite, RoomTextMessage, RosterEntry},
+    qr::qr_svg,
+    signals::use_client,
+};
+use leptos::*;
+use leptos_router::*;
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use zend_common::{api, fingerprint};
+
+// Slow to compute on purpose, see `fingerprint`, so each sender's is only computed once
+#[derive(Debug, Clone, Default)]
+struct Fingerprints(Rc<RefCell<HashMap<String, String>>>);
+impl Fingerprints {
+    fn get(&self, peer_id: &api::PublicKeyWrapper) -> String {
+        self.0
+            .borrow_mut()
+            .entry(peer_id.to_string())
+            .or_insert_with(|| fingerprint::short_fingerprint(peer_id))
+            .clone()
+    }
+}
+
+/** Seconds since the epoch, as the time of day where the browser is */
+fn format_time(timestamp: u64) -> String {
+    let date = js_sys::Date::new(&(timestamp as f64 * 1000.0).into());
+    date.to_locale_time_string("default").into()
+}
+
+/** The room whose code is in the path. Joins it if we're not in it yet, with the invite in the
+link's fragment if there is one, or by asking its members to let us in. */
+#[component]
+pub fn RoomView(cx: Scope) -> impl IntoView {
+    let params = use_params_map(cx);
+    let room_id = move || {
+        params.with(|params| {
+            let code = params.get("id").ok_or("No room code")?;
+            api::RoomId::parse(code, true)
+        })
+    };
+    move || match room_id() {
+        Ok(room_id) => view! { cx, <Room room_id=room_id/> }.into_view(cx),
+        Err(err) => view! { cx, <p class="error">{err}</p> }.into_view(cx),
+    }
+}
+
+#[component]
+fn Room(cx: Scope, room_id: api::RoomId) -> impl IntoView {
+    let signals = use_client(cx);
+    let room = signals.room(room_id);
+    let joined_rooms = signals.joined_rooms;
+    let joined = move || joined_rooms.with(|rooms| rooms.contains(&room_id));
+    let (join_error, set_join_error) = create_signal(cx, None::<String>);
+    if !signals.client().has_room(room_id) {
+        let client = signals.client();
+        let invite = window()
+            .location()
+            .href()
+            .ok()
+            .filter(|href| href.contains('#'))
+            .map(|href| Invite::parse(&href));
+        spawn_local(async move {
+            let result = match invite {
+                Some(Ok(invite)) if invite.room_id == room_id => client
+                    .join_with_invite(&invite)
+                    .await
+                    .map_err(|err| format!("Failed to join with the invite: {:?}", err)),
+                Some(Ok(_)) => Err("The invite is for another room".to_string()),
+                Some(Err(err)) => Err(err.to_string()),
+                None => client
+                    .join_room(room_id)
+                    .await
+                    .map_err(|err| format!("Failed to ask to join: {:?}", err)),
+            };
+            if let Err(err) = result {
+                set_join_error.set(Some(err));
+            }
+        });
+    }
+
+    // For members to pass on, as anyone with it can get in
+    let invite_signals = signals.clone();
+    let invite_url = move || {
+        let invite = invite_signals.client().invite(room_id).ok()?;
+        let origin = window().location().origin().ok()?;
+        Some(invite.to_url(&origin))
+    };
+
+    let roster = room.roster;
+    let nickname = move |peer_id: &api::PublicKeyWrapper| {
+        let peer_id = peer_id.to_string();
+        roster.with(|roster| {
+            roster
+                .iter()
+                .find(|v| v.peer_id.to_string() == peer_id)
+                .and_then(|v| v.nickname.clone())
+        })
+    };
+    let fingerprints = Fingerprints::default();
+
+    let (member_status, set_member_status) = create_signal(cx, None::<Result<String, String>>);
+    let own_id = signals.client().own_id().to_string();
+    let member_signals = signals.clone();
+    let member_fingerprints = fingerprints.clone();
+    let member_view = move |cx, entry: RosterEntry| {
+        let peer_id = entry.peer_id.clone();
+        let name = entry
+            .nickname
+            .unwrap_or_else(|| member_fingerprints.get(&peer_id));
+        let signals = member_signals.clone();
+        // Removing a member also gives everyone else a new room key, see `remove_member`
+        let on_remove = move |_: ev::MouseEvent| {
+            let signals = signals.clone();
+            let peer_id = peer_id.clone();
+            spawn_local(async move {
+                let status = match signals.client().remove_member(room_id, &peer_id).await {
+                    Ok(unreachable) if unreachable.is_empty() => Ok("Removed".to_string()),
+                    Ok(unreachable) => Ok(format!(
+                        "Removed, but {} members didn't get the new room key",
+                        unreachable.len()
+                    )),
+                    Err(err) => Err(format!("Failed to remove: {:?}", err)),
+                };
+                signals.refresh();
+                set_member_status.set(Some(status));
+            });
+        };
+        let removable = entry.member && entry.peer_id.to_string() != own_id;
+        view! { cx,
+            <li class="member">
+                <span class="sender">{name}</span>
+                {removable.then(|| view! { cx, <button on:click=on_remove>"Remove"</button> })}
+            </li>
+        }
+    };
+
+    let message_view = move |cx, message: RoomTextMessage| {
+        let sender_id = message.sender_id().clone();
+        let fingerprint = fingerprints.get(&sender_id);
+        let text = message.text().unwrap_or("Message deleted").to_string();
+        view! { cx,
+            <li class="message">
+                <span class="sender">{move || nickname(&sender_id)}</span>
+                <span class="fingerprint">{fingerprint}</span>
+                <time>{format_time(message.nonce().timestamp)}</time>
+                <p class:deleted=message.is_deleted()>{text}</p>
+            </li>
+        }
+    };
+
+    let (text, set_text) = create_signal(cx, String::new());
+    let (send_error, set_send_error) = create_signal(cx, None::<String>);
+    let on_submit = move |ev: ev::SubmitEvent| {
+        ev.prevent_default();
+        let message = text.get();
+        if message.trim().is_empty() {
+            return;
+        }
+        set_text.set(String::new());
+        set_send_error.set(None);
+        let client = signals.client();
+        spawn_local(async move {
+            if let Err(err) = client.send_text(room_id, message).await {
+                set_send_error.set(Some(format!("Failed to send: {:?}", err)));
+            }
+        });
+    };
+
+    view! { cx,
+        <div class="room">
+            <h1>"Room " {room_id.to_string()}</h1>
+            {move || {
+                if !joined() {
+                    return None;
+                }
+                let url = invite_url()?;
+                // The link is still there if it's too long for a QR code
+                let qr = qr_svg(&url)
+                    .map(|svg| view! { cx, <div class="invite-qr" inner_html=svg></div> });
+                Some(view! { cx,
+                    <>
+                        <p class="invite">"Invite link: " <a href=url.clone()>{url}</a></p>
+                        {qr}
+                    </>
+                })
+            }}
+            {move || join_error.get().map(|err| view! { cx, <p class="error">{err}</p> })}
+            {move || {
+                (!joined() && join_error.get().is_none())
+                    .then(|| view! { cx, <p class="status">"Waiting to be let in…"</p> })
+            }}
+            <ul class="roster">
+                <For
+                    each=move || roster.get()
+                    key=|entry| (entry.peer_id.to_string(), entry.nickname.clone(), entry.member)
+                    view=member_view
+                />
+            </ul>
+            {move || match member_status.get() {
+                Some(Ok(status)) => Some(view! { cx, <p class="status">{status}</p> }),
+                Some(Err(err)) => Some(view! { cx, <p class="error">{err}</p> }),
+                None => None,
+            }}
+            <ol class="messages">
+                <For
+                    each=move || room.messages.get()
+                    // Deleting a message changes it in place, so it's rendered again
+                    key=|message| {
+                        (
+                            message.sender_id().to_string(),
+                            message.nonce().to_string(),
+                            message.is_deleted(),
+                        )
+                    }
+                    view=message_view
+                />
+            </ol>
+            <form on:submit=on_submit>
+                <input
+                    type="text"
+                    placeholder="Message"
+                    prop:value=move || text.get()
+                    prop:disabled=move || !joined()
+                    on:input=move |ev| set_text.set(event_target_value(&ev))
+                />
+                <button type="submit" prop:disabled=move || !joined()>"Send"</button>
+            </form>
+            {move || send_error.get().map(|err| view! { cx, <p class="error">{err}</p> })}
+        </div>
+    }
+}
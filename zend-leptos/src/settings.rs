@@ -0,0 +1,276 @@
+use crate::{
+    config::{ClientConfig, ReconnectPolicy, DEFAULT_SERVER_URL},
+    notifications::{NotificationSettings, NotificationSettingsProps},
+    signals::{use_client, ClientSignals},
+};
+use leptos::*;
+use leptos_router::*;
+use zend_common::fingerprint;
+
+/** The client's config, and what to do with our identity */
+#[component]
+pub fn Settings(cx: Scope) -> impl IntoView {
+    view! { cx,
+        <div class="settings">
+            <section>
+                <h2>"Connection"</h2>
+                <ConnectionSettings/>
+            </section>
+            <section>
+                <h2>"Notifications"</h2>
+                <NotificationSettings/>
+            </section>
+            <section>
+                <h2>"Identity"</h2>
+                <IdentitySettings/>
+            </section>
+            <A href="/">"Back"</A>
+        </div>
+    }
+}
+
+/** A whole number, or none if left empty */
+fn parse_optional(value: &str, what: &str) -> Result<Option<u32>, String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| format!("{} has to be a whole number", what))
+}
+
+/** Read from the form, as the config it describes */
+fn read_config(
+    config: ClientConfig,
+    server_url: &str,
+    reconnect: bool,
+    initial_backoff: &str,
+    max_backoff: &str,
+    max_attempts: &str,
+) -> Result<ClientConfig, String> {
+    let defaults = ReconnectPolicy::default();
+    let server_url = server_url.trim();
+    Ok(ClientConfig {
+        server_url: (!server_url.is_empty()).then(|| server_url.to_string()),
+        reconnect: ReconnectPolicy {
+            reconnect,
+            initial_backoff_secs: parse_optional(initial_backoff, "The first wait")?
+                .map_or(defaults.initial_backoff_secs, u64::from),
+            max_backoff_secs: parse_optional(max_backoff, "The longest wait")?
+                .map_or(defaults.max_backoff_secs, u64::from),
+            max_attempts: parse_optional(max_attempts, "The number of attempts")?,
+        },
+        ..config
+    })
+}
+
+#[component]
+fn ConnectionSettings(cx: Scope) -> impl IntoView {
+    let signals = use_client(cx);
+    let config = signals.config.get();
+    let (server_url, set_server_url) =
+        create_signal(cx, config.server_url.clone().unwrap_or_default());
+    let (reconnect, set_reconnect) = create_signal(cx, config.reconnect.reconnect);
+    let (initial_backoff, set_initial_backoff) =
+        create_signal(cx, config.reconnect.initial_backoff_secs.to_string());
+    let (max_backoff, set_max_backoff) =
+        create_signal(cx, config.reconnect.max_backoff_secs.to_string());
+    let (max_attempts, set_max_attempts) = create_signal(
+        cx,
+        config
+            .reconnect
+            .max_attempts
+            .map(|v| v.to_string())
+            .unwrap_or_default(),
+    );
+    let (status, set_status) = create_signal(cx, None::<Result<&'static str, String>>);
+
+    let on_submit = move |ev: ev::SubmitEvent| {
+        ev.prevent_default();
+        let new_config = read_config(
+            signals.config.get(),
+            &server_url.get(),
+            reconnect.get(),
+            &initial_backoff.get(),
+            &max_backoff.get(),
+            &max_attempts.get(),
+        );
+        let new_config = match new_config {
+            Ok(new_config) => new_config,
+            Err(err) => {
+                set_status.set(Some(Err(err)));
+                return;
+            }
+        };
+        let signals = signals.clone();
+        spawn_local(async move {
+            // Takes effect with a new connection, which `apply_config` makes if needed
+            let result = signals.apply_config(new_config).await;
+            set_status.set(Some(result.map(|_| "Saved").map_err(|err| err.to_string())));
+        });
+    };
+
+    view! { cx,
+        <form on:submit=on_submit>
+            <label>
+                "Server "
+                <input
+                    type="url"
+                    placeholder=DEFAULT_SERVER_URL
+                    prop:value=move || server_url.get()
+                    on:input=move |ev| set_server_url.set(event_target_value(&ev))
+                />
+            </label>
+            <label>
+                <input
+                    type="checkbox"
+                    prop:checked=move || reconnect.get()
+                    on:change=move |ev| set_reconnect.set(event_target_checked(&ev))
+                />
+                "Reconnect after losing the connection"
+            </label>
+            <label>
+                "First wait, in seconds "
+                <input
+                    type="number"
+                    min="0"
+                    prop:value=move || initial_backoff.get()
+                    on:input=move |ev| set_initial_backoff.set(event_target_value(&ev))
+                />
+            </label>
+            <label>
+                "Longest wait, in seconds "
+                <input
+                    type="number"
+                    min="0"
+                    prop:value=move || max_backoff.get()
+                    on:input=move |ev| set_max_backoff.set(event_target_value(&ev))
+                />
+            </label>
+            <label>
+                "Attempts before giving up "
+                <input
+                    type="number"
+                    min="1"
+                    placeholder="No limit"
+                    prop:value=move || max_attempts.get()
+                    on:input=move |ev| set_max_attempts.set(event_target_value(&ev))
+                />
+            </label>
+            <button type="submit">"Save"</button>
+            {move || match status.get() {
+                Some(Ok(status)) => Some(view! { cx, <p class="status">{status}</p> }),
+                Some(Err(err)) => Some(view! { cx, <p class="error">{err}</p> }),
+                None => None,
+            }}
+        </form>
+    }
+}
+
+fn own_fingerprint(signals: &ClientSignals) -> String {
+    fingerprint::short_fingerprint(&signals.client().own_id())
+}
+
+/** Exporting, importing and resetting our identity, see `AppClient::export_identity` */
+#[component]
+fn IdentitySettings(cx: Scope) -> impl IntoView {
+    let signals = use_client(cx);
+    let (fingerprint, set_fingerprint) = create_signal(cx, own_fingerprint(&signals));
+    let (status, set_status) = create_signal(cx, None::<Result<&'static str, String>>);
+
+    let (export_passphrase, set_export_passphrase) = create_signal(cx, String::new());
+    let (backup, set_backup) = create_signal(cx, None::<String>);
+    let export_signals = signals.clone();
+    let on_export = move |ev: ev::SubmitEvent| {
+        ev.prevent_default();
+        let passphrase = export_passphrase.get();
+        if passphrase.is_empty() {
+            set_status.set(Some(Err("The backup needs a passphrase".to_string())));
+            return;
+        }
+        set_backup.set(Some(export_signals.client().export_identity(&passphrase)));
+        set_status.set(None);
+    };
+
+    let (import_backup, set_import_backup) = create_signal(cx, String::new());
+    let (import_passphrase, set_import_passphrase) = create_signal(cx, String::new());
+    let import_signals = signals.clone();
+    let on_import = move |ev: ev::SubmitEvent| {
+        ev.prevent_default();
+        let signals = import_signals.clone();
+        spawn_local(async move {
+            let result = signals
+                .client()
+                .import_identity(&import_backup.get(), &import_passphrase.get())
+                .await;
+            match result {
+                Ok(()) => {
+                    signals.refresh();
+                    set_fingerprint.set(own_fingerprint(&signals));
+                    set_import_backup.set(String::new());
+                    set_import_passphrase.set(String::new());
+                    set_status.set(Some(Ok("Imported")));
+                }
+                Err(err) => set_status.set(Some(Err(err.to_string()))),
+            }
+        });
+    };
+
+    let on_reset = move |_: ev::MouseEvent| {
+        // Our rooms can't be gotten back into without someone letting us in again
+        let confirmed = window()
+            .confirm_with_message("Start over with a new identity, leaving every room?")
+            .unwrap_or(false);
+        if !confirmed {
+            return;
+        }
+        let signals = signals.clone();
+        spawn_local(async move {
+            signals.client().reset_identity().await;
+            signals.refresh();
+            set_fingerprint.set(own_fingerprint(&signals));
+            set_backup.set(None);
+            set_status.set(Some(Ok("Started over with a new identity")));
+        });
+    };
+
+    view! { cx,
+        <>
+            <p>"Your fingerprint: " <span class="fingerprint">{move || fingerprint.get()}</span></p>
+            <form on:submit=on_export>
+                <input
+                    type="password"
+                    placeholder="Passphrase for the backup"
+                    prop:value=move || export_passphrase.get()
+                    on:input=move |ev| set_export_passphrase.set(event_target_value(&ev))
+                />
+                <button type="submit">"Export"</button>
+            </form>
+            {move || {
+                backup.get().map(|backup| view! { cx, <textarea readonly=true>{backup}</textarea> })
+            }}
+            <form on:submit=on_import>
+                <textarea
+                    placeholder="Backup"
+                    prop:value=move || import_backup.get()
+                    on:input=move |ev| set_import_backup.set(event_target_value(&ev))
+                ></textarea>
+                <input
+                    type="password"
+                    placeholder="Passphrase of the backup"
+                    prop:value=move || import_passphrase.get()
+                    on:input=move |ev| set_import_passphrase.set(event_target_value(&ev))
+                />
+                <button type="submit">"Import"</button>
+            </form>
+            <button on:click=on_reset>"Start over with a new identity"</button>
+            {move || match status.get() {
+                Some(Ok(status)) => Some(view! { cx, <p class="status">{status}</p> }),
+                Some(Err(err)) => Some(view! { cx, <p class="error">{err}</p> }),
+                None => None,
+            }}
+        </>
+    }
+}
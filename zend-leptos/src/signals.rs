@@ -0,0 +1,161 @@
+use crate::{
+    appclient::{AppClient, AppEvent, AppEventFilter, RoomTextMessage, RosterEntry},
+    config::ClientConfig,
+    wsclient::StateChange,
+};
+use futures::{future, StreamExt};
+use leptos::*;
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use zend_common::{_use::wasm_bindgen::UnwrapThrowExt, api};
+
+/** A room's messages and members, kept up to date by `ClientSignals` */
+#[derive(Debug, Clone, Copy)]
+pub struct RoomSignals {
+    pub messages: RwSignal<Vec<RoomTextMessage>>,
+    pub roster: RwSignal<Vec<RosterEntry>>,
+}
+
+/** `AppClient`'s state as signals, updated from its events, so components can render it
+without subscribing to anything themselves. `App` provides it to every component, see
+`use_client`. */
+#[derive(Debug, Clone)]
+pub struct ClientSignals {
+    cx: Scope,
+    // Replaced by `reconnect`
+    client: Rc<RefCell<Rc<AppClient>>>,
+    /** As last saved, see `apply_config` */
+    pub config: RwSignal<ClientConfig>,
+    pub connection: RwSignal<StateChange>,
+    /** Rooms whose history was read, in no particular order */
+    pub joined_rooms: RwSignal<Vec<api::RoomId>>,
+    /** What last failed in the background, see `AppEvent::Error` */
+    pub last_error: RwSignal<Option<String>>,
+    // Made the first time a room is asked for, as only rooms that are rendered need them
+    rooms: Rc<RefCell<HashMap<api::RoomId, RoomSignals>>>,
+}
+impl ClientSignals {
+    /** Follows the client's events until `cx` is disposed of */
+    pub fn new(cx: Scope, client: Rc<AppClient>) -> Self {
+        let signals = Self {
+            cx,
+            config: create_rw_signal(cx, client.config().clone()),
+            connection: create_rw_signal(
+                cx,
+                StateChange {
+                    state: client.connection_state(),
+                    retry_after: None,
+                },
+            ),
+            joined_rooms: create_rw_signal(cx, client.joined_rooms()),
+            last_error: create_rw_signal(cx, None),
+            rooms: Rc::new(RefCell::new(HashMap::new())),
+            client: Rc::new(RefCell::new(client)),
+        };
+        // Subscriptions carry on after reconnecting, so this lasts as long as the signals
+        let mut events = signals.client().receive_events(AppEventFilter::new().any());
+        let task_signals = signals.clone();
+        // The task holds on to the client, so it's stopped rather than left to end by itself
+        let (follow_events, abort_handle) = future::abortable(async move {
+            while let Some(event) = events.next().await {
+                task_signals.handle_event(event);
+            }
+        });
+        spawn_local(async move {
+            let _ = follow_events.await;
+        });
+        on_cleanup(cx, move || abort_handle.abort());
+        signals
+    }
+    /** For calls, e.g. to send a message. Their effects show up in the signals. Not to be held
+    on to, as it's replaced after reconnecting. */
+    pub fn client(&self) -> Rc<AppClient> {
+        self.client.borrow().clone()
+    }
+    /** Starts over with a new connection, made with the current config, see
+    `AppClient::reconnect` */
+    pub async fn reconnect(&self) {
+        let client = self.client().reconnect(self.config.get()).await;
+        *self.client.borrow_mut() = Rc::new(client);
+        self.connection.set(StateChange {
+            state: self.client().connection_state(),
+            retry_after: None,
+        });
+        self.refresh();
+    }
+    /** Saves the config, then reconnects if the connection should be made differently now */
+    pub async fn apply_config(&self, config: ClientConfig) -> Result<(), &'static str> {
+        config.save()?;
+        let reconnect = !config.connects_like(self.client().config());
+        self.config.set(config);
+        if reconnect {
+            self.reconnect().await;
+        }
+        Ok(())
+    }
+    /** Reads everything again, for changes without events, like importing an identity */
+    pub fn refresh(&self) {
+        self.joined_rooms.set(self.client().joined_rooms());
+        // Copied out, as rendering what's set here can ask for other rooms
+        let rooms: Vec<_> = self.rooms.borrow().iter().map(|(k, v)| (*k, *v)).collect();
+        for (room_id, room) in rooms {
+            room.messages.set(self.read_messages(room_id));
+            room.roster.set(self.client().roster(room_id));
+        }
+    }
+    pub fn room(&self, room_id: api::RoomId) -> RoomSignals {
+        if let Some(room) = self.rooms.borrow().get(&room_id) {
+            return *room;
+        }
+        let room = RoomSignals {
+            messages: create_rw_signal(self.cx, self.read_messages(room_id)),
+            roster: create_rw_signal(self.cx, self.client().roster(room_id)),
+        };
+        self.rooms.borrow_mut().insert(room_id, room);
+        room
+    }
+    fn read_messages(&self, room_id: api::RoomId) -> Vec<RoomTextMessage> {
+        self.client()
+            .messages(room_id)
+            .map(|messages| messages.to_vec())
+            .unwrap_or_default()
+    }
+    fn handle_event(&self, event: AppEvent) {
+        match &event {
+            AppEvent::ConnectionStateChanged(change) => self.connection.set(*change),
+            AppEvent::Error { error, .. } => self.last_error.set(Some(error.clone())),
+            _ => {}
+        }
+        let room_id = match event.room_id() {
+            Some(room_id) => room_id,
+            None => return,
+        };
+        // Rooms become joined once their history was read, which has no event of its own
+        self.joined_rooms.set(self.client().joined_rooms());
+        let room = match self.rooms.borrow().get(&room_id) {
+            Some(room) => *room,
+            None => return,
+        };
+        match event {
+            AppEvent::MessageReceived { message, .. } => room.messages.update(|messages| {
+                // It's already there if the room's signals were made after it was received
+                let sender_id = message.sender_id().to_string();
+                if !messages
+                    .iter()
+                    .any(|v| v.nonce() == message.nonce() && v.sender_id().to_string() == sender_id)
+                {
+                    messages.push(message);
+                }
+            }),
+            // Deleted messages stay in place as tombstones, which are read again
+            AppEvent::MessageDeleted { .. } => room.messages.set(self.read_messages(room_id)),
+            _ => {}
+        }
+        // Nicknames and who's online change without events of their own, so this catches up
+        room.roster.set(self.client().roster(room_id));
+    }
+}
+
+/** The signals `App` provides */
+pub fn use_client(cx: Scope) -> ClientSignals {
+    use_context::<ClientSignals>(cx).expect_throw("ClientSignals weren't provided")
+}
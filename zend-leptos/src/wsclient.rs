@@ -1,24 +1,289 @@
-use crate::util::*;
-use futures::{channel::mpsc, future, stream::StreamExt};
+use crate::{util::*, wsworker::WorkerBridge};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{self, FutureExt},
+    stream::{self, Stream, StreamExt},
+};
+use serde::{Deserialize, Serialize};
 use std::{
     cell::{Cell, RefCell},
-    rc::Rc,
+    collections::{HashMap, HashSet, VecDeque},
+    ops::ControlFlow,
+    pin::Pin,
+    rc::{Rc, Weak},
+    task::{Context, Poll, Waker},
     time::Duration,
 };
-use web_sys::WebSocket;
-use ws_stream_wasm::{WsMessage, WsMeta, WsStream};
-use zend_common::{api, log};
+use transport::{Connection, Frame, Transport, WsStreamTransport};
+use zend_common::{
+    api,
+    caller::{self, CallBuilder, CallError, CallerKey},
+    codec::Encoding,
+    log,
+};
+
+#[cfg(test)]
+mod tests;
+pub mod transport;
+
+/** How long `WsApiClient::close` waits for queued messages to be sent and the client to end */
+pub const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/** How long `WsApiClient::call` waits for the server to return */
+pub const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(10);
+/** How long after a call timed out its return is still recognized as `ApiClientEvent::LateReturn` */
+pub const LATE_RETURN_GRACE_PERIOD: Duration = Duration::from_secs(60);
+/** How many events a subscriber can fall behind by before its overflow policy applies */
+pub const EVENT_QUEUE_CAPACITY: usize = 256;
+/** How often the server is pinged, which is also how often the latency is measured, unless the
+server asks for another interval in its hello */
+pub const PING_INTERVAL: Duration = Duration::from_secs(10);
+/** How many round trips `WsApiClient::average_rtt` averages over */
+pub const RTT_SAMPLES: usize = 10;
+
+/** What happens to messages sent while the outgoing queue is full */
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QueueOverflowPolicy {
+    /** The new message isn't queued and `send_message` fails */
+    Reject,
+    /** The oldest queued message is dropped to make room */
+    DropOldest,
+}
+
+/** Messages sent while the client is reconnecting are queued, and sent in order once it's
+connected again. A capacity of 0 turns queueing off. */
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct OutgoingQueueConfig {
+    pub capacity: usize,
+    pub overflow: QueueOverflowPolicy,
+}
+impl Default for OutgoingQueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 64,
+            overflow: QueueOverflowPolicy::Reject,
+        }
+    }
+}
+
+/** Paces a client's outgoing messages with a token bucket, to stay below the server's rate
+limit: up to `burst` messages are sent right away, then `per_second`. Messages over the limit
+wait, in order, and `ApiClientEvent::SendingDelayed` says how many are waiting. */
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+/** What `WsClientConfig::with_dedup_window` did so far, see `WsApiClient::dedup_stats` */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DedupStats {
+    /** Subscription data that was checked, duplicates included */
+    pub checked: u64,
+    pub dropped: u64,
+}
+
+/** How `WsApiClient` connects and reconnects. The defaults are what it always did. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WsClientConfig {
+    encoding: Encoding,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    jitter: f64,
+    connect_timeout: Duration,
+    idle_timeout: Duration,
+    max_reconnect_attempts: Option<u32>,
+    reconnect: bool,
+    outgoing_queue: OutgoingQueueConfig,
+    shared_socket: bool,
+    worker_url: Option<String>,
+    rate_limit: Option<RateLimitConfig>,
+    dedup_window: Option<usize>,
+    #[serde(skip)]
+    transport: Option<Rc<dyn Transport>>,
+}
+impl Default for WsClientConfig {
+    fn default() -> Self {
+        Self {
+            encoding: Encoding::Json,
+            initial_backoff: Duration::from_secs(5),
+            max_backoff: Duration::from_secs(60),
+            jitter: 0.0,
+            connect_timeout: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(30),
+            max_reconnect_attempts: None,
+            reconnect: true,
+            outgoing_queue: OutgoingQueueConfig::default(),
+            shared_socket: true,
+            worker_url: None,
+            rate_limit: None,
+            dedup_window: None,
+            transport: None,
+        }
+    }
+}
+#[allow(dead_code)]
+impl WsClientConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /** Asked for in the client's hello */
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+    /** The wait after the first failed attempt, doubling with every further one */
+    pub fn with_initial_backoff(mut self, backoff: Duration) -> Self {
+        self.initial_backoff = backoff;
+        self
+    }
+    pub fn with_max_backoff(mut self, backoff: Duration) -> Self {
+        self.max_backoff = backoff;
+        self
+    }
+    /** Shortens each wait by a random fraction of up to this much, between 0 and 1, so clients
+    that lost their connections together don't all come back at once */
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+    /** Connections that receive nothing for this long are considered dead. The server sends
+    keepalives, so this only has to be longer than its keepalive interval. */
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+    /** Consecutive failed attempts after which the client gives up and ends */
+    pub fn with_max_reconnect_attempts(mut self, attempts: Option<u32>) -> Self {
+        self.max_reconnect_attempts = attempts;
+        self
+    }
+    /** If false, the client ends as soon as it loses its connection or fails to connect */
+    pub fn with_reconnect(mut self, reconnect: bool) -> Self {
+        self.reconnect = reconnect;
+        self
+    }
+    pub fn with_outgoing_queue(mut self, config: OutgoingQueueConfig) -> Self {
+        self.outgoing_queue = config;
+        self
+    }
+    /** If true, clients for the same URL share one websocket. Each still has its own event
+    subscriptions, queue and room subscriptions, and gets only the returns of its own calls and
+    the data of its own subscriptions. The socket is set up with the config of the client that
+    opened it, so the encoding and reconnection settings of the others are ignored. */
+    pub fn with_shared_socket(mut self, shared: bool) -> Self {
+        self.shared_socket = shared;
+        self
+    }
+    /** Keeps the socket in a web worker loaded from this URL, usually
+    `wsworker::DEFAULT_WORKER_URL`, which also parses what the server sends. The client works the
+    same either way. If the worker can't be started, the socket is kept on this thread. */
+    pub fn with_worker(mut self, worker_url: Option<String>) -> Self {
+        self.worker_url = worker_url;
+        self
+    }
+    /** Per client, even if the socket is shared */
+    pub fn with_rate_limit(mut self, rate_limit: Option<RateLimitConfig>) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+    /** Drops subscription data with the same sender and nonce as any of the last `window` data
+    received for the same subscription, which resubscribing or resyncing can deliver again */
+    pub fn with_dedup_window(mut self, window: Option<usize>) -> Self {
+        self.dedup_window = window;
+        self
+    }
+    /** Connects with this instead of a websocket, like a `LoopbackTransport` in tests. Sockets
+    with their own transport are never shared or moved to a worker. */
+    pub fn with_transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Some(Rc::new(transport));
+        self
+    }
+
+    /** How long to wait after the given number of consecutive failed attempts */
+    fn backoff(&self, failed_attempts: u32) -> Duration {
+        let doublings = failed_attempts.saturating_sub(1).min(31);
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << doublings)
+            .min(self.max_backoff);
+        backoff.mul_f64(1.0 - self.jitter * js_sys::Math::random())
+    }
+}
+
+/** A change of `WsApiClient::state()`, as yielded by `WsApiClient::state_stream()` */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateChange {
+    pub state: WebSocketState,
+    /** Seconds until the next connection attempt, while reconnecting */
+    pub retry_after: Option<u64>,
+}
+
+/** What happens to events for a subscriber whose queue is full. Unless the subscriber is closed,
+it's told how many events it missed with an `ApiClientEvent::Lagged` where they would have been. */
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EventOverflowPolicy {
+    /** The oldest queued event is dropped to make room */
+    DropOldest,
+    /** The new event is dropped */
+    #[default]
+    DropNewest,
+    /** The subscriber gets the events already queued, then a `Lagged(1)`, then nothing more */
+    CloseSubscriber,
+}
 
 #[derive(Debug, Clone)]
 pub enum ApiClientEvent {
     Connected,
     Reconnecting(u64),
     ApiMessage(api::ServerToClientMessage),
+    /** The round trip time of a ping */
+    Latency(Duration),
+    /** This many messages are waiting for the rate limit. 0 once they have all been sent. */
+    SendingDelayed(usize),
+    /** The return of a call that timed out arrived after all, with this call ID. Follows the
+    return itself, which nobody is waiting for anymore. Hints at slow server paths. */
+    LateReturn(u64),
     Ended,
+    /** This many events were dropped because the subscriber didn't keep up. Never matched by
+    filters, only sent to subscribers that missed something. */
+    Lagged(u64),
+}
+
+struct EventPredicate(Box<dyn Fn(&ApiClientEvent) -> bool>);
+impl std::fmt::Debug for EventPredicate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EventPredicate")
+    }
+}
+// Closures can't be compared, so no two are considered the same filter
+impl PartialEq for EventPredicate {
+    fn eq(&self, _other: &Self) -> bool {
+        false
+    }
+}
+
+struct OutgoingHook(Box<dyn FnMut(&mut api::ClientToServerMessage) -> ControlFlow<()>>);
+impl std::fmt::Debug for OutgoingHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("OutgoingHook")
+    }
+}
+
+struct IncomingHook(Box<dyn FnMut(&api::ServerToClientMessage)>);
+impl std::fmt::Debug for IncomingHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("IncomingHook")
+    }
 }
 
 #[allow(dead_code)]
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq)]
 enum SubscriptionEventFilterItem {
     Any,
     Connected,
@@ -27,7 +292,11 @@ enum SubscriptionEventFilterItem {
     ApiSubscriptionData(Option<u64>), // Optionally specify subscription ID
     ApiPong,
     ApiInfo,
+    Latency,
+    SendingDelayed,
+    LateReturn,
     Ended,
+    Custom(EventPredicate),
 }
 impl Into<Vec<Self>> for SubscriptionEventFilterItem {
     fn into(self) -> Vec<Self> {
@@ -74,15 +343,35 @@ impl SubscriptionEventFilter {
     add_filter_fn!(sub_data, ApiSubscriptionData(None));
     add_filter_fn!(pong, ApiPong);
     add_filter_fn!(info, ApiInfo);
+    add_filter_fn!(latency, Latency);
+    add_filter_fn!(sending_delayed, SendingDelayed);
+    add_filter_fn!(late_return, LateReturn);
     add_filter_fn!(ended, Ended);
+    /** Matches whatever the predicate returns true for, like subscription data from one room
+    only. It's called for every event while subscribed, so it should be cheap. */
+    pub fn custom(self, predicate: impl Fn(&ApiClientEvent) -> bool + 'static) -> Self {
+        self.add_filter_item(SubscriptionEventFilterItem::Custom(EventPredicate(
+            Box::new(predicate),
+        )))
+    }
 }
 
+/** Yields the events matched by its filter until dropped, which unsubscribes */
 #[derive(Debug)]
 pub struct EventSubscriptionHandle {
-    pub receiver: mpsc::Receiver<ApiClientEvent>,
+    pub receiver: EventReceiver,
     id: usize,
     api_client: WsApiClient,
 }
+impl Stream for EventSubscriptionHandle {
+    type Item = ApiClientEvent;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.receiver.size_hint()
+    }
+}
 impl Drop for EventSubscriptionHandle {
     fn drop(&mut self) {
         self.api_client.unregister_event_subscription(self.id);
@@ -94,9 +383,16 @@ pub enum AwaitEventError {
     Timeout,
     EventsEmpty,
 }
+/** Why `WsApiClient::ready` gave up */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadyError {
+    /** The client ended before it connected, e.g. after running out of reconnection attempts */
+    Ended,
+    Timeout,
+}
 #[derive(Debug)]
 pub struct AwaitEventHandle {
-    receiver: mpsc::Receiver<ApiClientEvent>,
+    receiver: EventReceiver,
     id: usize,
     api_client: WsApiClient,
     timeout: Option<Duration>,
@@ -128,13 +424,287 @@ impl Drop for AwaitEventHandle {
 
 #[derive(Debug)]
 struct WsApiClientInner {
-    ws: WsRefCellWrap,
+    socket: Rc<SharedSocket>,
     event_subscriptions: RefCell<Vec<EventSubscription>>,
     next_event_subscription_id: Cell<usize>,
-    ws_state: Cell<WebSocketState>,
+    // Set once this client has ended, which can be before the socket does
+    ended: Cell<bool>,
+    // Calls sent by this client that haven't returned yet, so their returns only come here
+    pending_calls: RefCell<HashSet<u64>>,
+    // Calls that timed out, and when to stop expecting their returns, in milliseconds
+    timed_out_calls: RefCell<HashMap<u64, f64>>,
+    // Renewed after every reconnect, see `subscribe_to_room`
+    active_subscriptions: RefCell<Vec<ActiveSubscription>>,
+    // Sent after our hello once connected, encoded then as the encoding may change in between
+    outgoing_queue: RefCell<VecDeque<api::ClientToServerMessage>>,
+    outgoing_queue_config: Cell<OutgoingQueueConfig>,
+    // Measured by the pinger task, most recent last
+    rtt_samples: RefCell<VecDeque<Duration>>,
+    // The token bucket of `RateLimitConfig`, refilled whenever it's looked at
+    rate_limit: Cell<Option<RateLimitConfig>>,
+    tokens: Cell<f64>,
+    tokens_updated_at: Cell<f64>,
+    // Messages waiting for a token, with the encoding to send them in if not the negotiated one
+    paced_queue: RefCell<VecDeque<(api::ClientToServerMessage, Option<Encoding>)>>,
+    pacer_running: Cell<bool>,
+    // Recently received subscription data, if duplicates are dropped
+    dedup: RefCell<Option<DedupWindow>>,
+    // Run in the order they were added, see `add_outgoing_hook` and `add_incoming_hook`
+    outgoing_hooks: RefCell<Vec<OutgoingHook>>,
+    incoming_hooks: RefCell<Vec<IncomingHook>>,
+    // Set by `close`, after which nothing new is sent
+    closing: Cell<bool>,
+    // Resolves once this client's last event has been delivered
+    event_task_done: RefCell<Option<oneshot::Receiver<()>>>,
+    event_task_finished: RefCell<Option<oneshot::Sender<()>>>,
     clones: Cell<usize>,
 }
 
+thread_local! {
+    // Sockets that new clients can share, by URL
+    static SHARED_SOCKETS: RefCell<HashMap<String, Weak<SharedSocket>>> = Default::default();
+}
+
+/** A websocket and what was negotiated over it. Its events are handled once, then passed on to
+every client using it. */
+#[derive(Debug)]
+struct SharedSocket {
+    backend: SocketBackend,
+    ws_state: Cell<WebSocketState>,
+    // When the current connection was made, in milliseconds since the epoch
+    connected_at: Cell<Option<u64>>,
+    // Set once the last client ended, so no new client joins in the meantime
+    ending: Cell<bool>,
+    // Chosen by the server in answer to our hello, once per connection
+    protocol_version: Cell<Option<u32>>,
+    // Asked for by the server in its hello, `None` if it keeps the connection alive itself
+    ping_interval: Cell<Option<Duration>>,
+    // Asked for in our hello, and used in both directions once the server confirms it
+    preferred_encoding: Encoding,
+    encoding: Cell<Encoding>,
+    // Offered in our hello, and used in both directions if the server supports it too
+    compression: Cell<bool>,
+    // Shared by every client on this socket, so returns can't be mixed up
+    next_call_id: Cell<u64>,
+    clients: RefCell<Vec<Weak<WsApiClientInner>>>,
+}
+impl SharedSocket {
+    /** Joins the shared socket for the URL if there is one, or opens a new one */
+    fn open(url: &str, config: WsClientConfig) -> Rc<Self> {
+        let shared = config.shared_socket && config.transport.is_none();
+        if shared {
+            let existing = SHARED_SOCKETS.with(|v| v.borrow().get(url).and_then(Weak::upgrade));
+            if let Some(socket) = existing {
+                if !socket.ending.get() && socket.ws_state.get() != WebSocketState::Ended {
+                    return socket;
+                }
+            }
+        }
+        let preferred_encoding = config.encoding;
+        let backend = SocketBackend::new(url, config);
+        let socket = Rc::new(Self {
+            backend,
+            ws_state: Cell::new(WebSocketState::Reconnecting),
+            connected_at: Cell::new(None),
+            ending: Cell::new(false),
+            protocol_version: Cell::new(None),
+            ping_interval: Cell::new(Some(PING_INTERVAL)),
+            preferred_encoding,
+            encoding: Cell::new(Encoding::Json),
+            compression: Cell::new(false),
+            next_call_id: Cell::new(0),
+            clients: RefCell::new(Vec::new()),
+        });
+        if shared {
+            SHARED_SOCKETS.with(|v| {
+                let mut sockets = v.borrow_mut();
+                sockets.retain(|_, v| v.strong_count() > 0);
+                sockets.insert(url.into(), Rc::downgrade(&socket));
+            });
+        }
+        // Keeps the socket alive until it ends, which happens once its last client ends
+        let task_socket = Rc::clone(&socket);
+        wasm_bindgen_futures::spawn_local(async move {
+            match &task_socket.backend {
+                SocketBackend::Direct(ws) => {
+                    let mut events = ws;
+                    while let Some(event) = events.next().await {
+                        if let Some(event) = task_socket.handle_event(event) {
+                            task_socket.dispatch(event);
+                        }
+                    }
+                }
+                // Already handled by the worker's own socket
+                SocketBackend::Worker(worker) => {
+                    while let Some(event) = worker.next_event().await {
+                        match &event {
+                            ApiClientEvent::Connected => {
+                                task_socket.set_state(WebSocketState::Connected)
+                            }
+                            ApiClientEvent::Reconnecting(_) => {
+                                task_socket.set_state(WebSocketState::Reconnecting)
+                            }
+                            ApiClientEvent::Ended => task_socket.set_state(WebSocketState::Ended),
+                            ApiClientEvent::ApiMessage(api::ServerToClientMessage::Hello(
+                                hello,
+                            )) => task_socket.handle_hello(hello),
+                            _ => {}
+                        }
+                        task_socket.dispatch(event);
+                    }
+                }
+            }
+            for client in task_socket.clients() {
+                client.finish();
+            }
+            log!("event handler task ended");
+        });
+        socket
+    }
+
+    fn attach(&self, client: &Rc<WsApiClientInner>) {
+        self.clients.borrow_mut().push(Rc::downgrade(client));
+    }
+
+    fn detach(&self, client: &Rc<WsApiClientInner>) {
+        self.clients
+            .borrow_mut()
+            .retain(|v| v.strong_count() > 0 && !std::ptr::eq(v.as_ptr(), Rc::as_ptr(client)));
+    }
+
+    // Anonymous, so dropping them doesn't end anything
+    fn clients(&self) -> Vec<WsApiClient> {
+        self.clients
+            .borrow()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|inner| WsApiClient { inner, anon: true })
+            .collect()
+    }
+
+    fn end(&self) {
+        self.ending.set(true);
+        match &self.backend {
+            SocketBackend::Direct(ws) => ws.end(),
+            SocketBackend::Worker(worker) => worker.end(),
+        }
+    }
+
+    fn set_state(&self, state: WebSocketState) {
+        self.ws_state.set(state);
+        self.connected_at.set(match state {
+            WebSocketState::Connected => Some(js_sys::Date::now() as u64),
+            _ => None,
+        });
+        if state == WebSocketState::Ended {
+            SHARED_SOCKETS.with(|v| {
+                v.borrow_mut()
+                    .retain(|_, v| v.strong_count() > 0 && !std::ptr::eq(v.as_ptr(), self))
+            });
+        }
+    }
+
+    fn next_call_id(&self) -> u64 {
+        let call_id = self.next_call_id.get();
+        self.next_call_id.set(call_id + 1);
+        call_id
+    }
+
+    // Binary encodings are sent as binary frames, text encodings as text frames
+    fn send(&self, message: &api::ClientToServerMessage, encoding: Encoding) -> Result<(), ()> {
+        let ws = match &self.backend {
+            SocketBackend::Direct(ws) => ws,
+            SocketBackend::Worker(worker) => return worker.send(message, encoding.is_binary()),
+        };
+        let message = match self.compression.get() {
+            true => encoding.encode_compressed(message, deflate),
+            false => encoding.encode(message),
+        };
+        let message = match message {
+            Ok(v) => v,
+            Err(_) => return Err(()),
+        };
+        if encoding.is_binary() {
+            ws.send_bytes(&message);
+        } else {
+            // Text encodings always produce valid UTF-8
+            ws.send(&String::from_utf8_lossy(&message));
+        }
+        return Ok(());
+    }
+
+    /** Updates what this socket knows about its connection. Returns what clients are told. */
+    fn handle_event(&self, event: WrappedSocketEvent) -> Option<ApiClientEvent> {
+        use WrappedSocketEvent::*;
+        Some(match event {
+            Connected => {
+                self.set_state(WebSocketState::Connected);
+                self.protocol_version.set(None);
+                self.ping_interval.set(Some(PING_INTERVAL));
+                self.encoding.set(Encoding::Json);
+                self.compression.set(false);
+                let hello = api::ClientToServerMessage::Hello(api::ClientHello {
+                    encoding: Some(self.preferred_encoding),
+                    features: vec![api::ProtocolFeature::Compression],
+                    ..Default::default()
+                });
+                let _ = self.send(&hello, Encoding::Json);
+                ApiClientEvent::Connected
+            }
+            Reconnecting(v) => {
+                self.set_state(WebSocketState::Reconnecting);
+                self.protocol_version.set(None);
+                self.ping_interval.set(Some(PING_INTERVAL));
+                self.encoding.set(Encoding::Json);
+                self.compression.set(false);
+                ApiClientEvent::Reconnecting(v)
+            }
+            Ended(_) => {
+                self.set_state(WebSocketState::Ended);
+                ApiClientEvent::Ended
+            }
+
+            TextMessage(_) | BinaryMessage(_) => {
+                let protocol_version = self.protocol_version.get();
+                let message =
+                    parse_server_message(&event, protocol_version, self.compression.get())?;
+                if let api::ServerToClientMessage::Hello(hello) = &message {
+                    self.handle_hello(hello);
+                    self.encoding.set(hello.encoding);
+                    self.compression
+                        .set(hello.features.contains(&api::ProtocolFeature::Compression));
+                }
+                ApiClientEvent::ApiMessage(message)
+            }
+        })
+    }
+
+    fn handle_hello(&self, hello: &api::ServerHello) {
+        self.protocol_version.set(Some(hello.protocol_version));
+        let ping_interval = hello.protocol_config.ping_interval_secs;
+        self.ping_interval
+            .set(ping_interval.map(Duration::from_secs));
+    }
+
+    /** Call returns and subscription data go only to the client they belong to. Anything no
+    client claims, like pongs or returns of calls sent with made up IDs, goes to all of them. */
+    fn dispatch(&self, mut event: ApiClientEvent) {
+        let clients = self.clients();
+        let owner = match &mut event {
+            ApiClientEvent::ApiMessage(message) => clients.iter().find(|v| v.claims(message)),
+            _ => None,
+        };
+        match owner {
+            Some(client) => handle_client_event(event, client),
+            None => {
+                for client in &clients {
+                    handle_client_event(event.clone(), client);
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct WsApiClient {
     inner: Rc<WsApiClientInner>,
@@ -145,39 +715,53 @@ pub struct WsApiClient {
 #[allow(dead_code)]
 impl WsApiClient {
     pub fn new(url: &str) -> Self {
+        Self::new_with_encoding(url, Encoding::Json)
+    }
+
+    pub fn new_with_encoding(url: &str, encoding: Encoding) -> Self {
+        Self::new_with_config(url, WsClientConfig::new().with_encoding(encoding))
+    }
+
+    pub fn new_with_config(url: &str, config: WsClientConfig) -> Self {
         let event_subscriptions = RefCell::new(Vec::<EventSubscription>::new());
-        let ws = WsRefCellWrap::new(url, Some(Duration::from_secs(30)));
-        let ws_state = Cell::new(WebSocketState::Reconnecting);
+        let outgoing_queue_config = config.outgoing_queue;
+        let rate_limit = config.rate_limit;
+        let dedup = config.dedup_window.map(DedupWindow::new);
+        let socket = SharedSocket::open(url, config);
+        let (event_task_finished, event_task_done) = oneshot::channel();
         let next_event_subscription_id = Cell::new(0usize);
         let data = WsApiClientInner {
-            ws,
+            socket,
             event_subscriptions,
             next_event_subscription_id,
-            ws_state,
+            ended: Cell::new(false),
+            pending_calls: RefCell::new(HashSet::new()),
+            timed_out_calls: RefCell::new(HashMap::new()),
+            active_subscriptions: RefCell::new(Vec::new()),
+            outgoing_queue: RefCell::new(VecDeque::new()),
+            outgoing_queue_config: Cell::new(outgoing_queue_config),
+            rtt_samples: RefCell::new(VecDeque::with_capacity(RTT_SAMPLES)),
+            rate_limit: Cell::new(rate_limit),
+            tokens: Cell::new(rate_limit.map_or(0.0, |v| v.burst as f64)),
+            tokens_updated_at: Cell::new(js_sys::Date::now()),
+            paced_queue: RefCell::new(VecDeque::new()),
+            pacer_running: Cell::new(false),
+            dedup: RefCell::new(dedup),
+            outgoing_hooks: RefCell::new(Vec::new()),
+            incoming_hooks: RefCell::new(Vec::new()),
+            closing: Cell::new(false),
+            event_task_done: RefCell::new(Some(event_task_done)),
+            event_task_finished: RefCell::new(Some(event_task_finished)),
             clones: Cell::new(1),
         };
         let new_client = Self {
             inner: Rc::new(data),
             anon: false,
         };
+        new_client.inner.socket.attach(&new_client.inner);
         // These clones are "anonymous" because they don't count towards the "clones" counter
         // in inner.
         let client = new_client.anon_clone();
-        wasm_bindgen_futures::spawn_local(async move {
-            while let Some(event) = client.inner.ws.next_event().await {
-                handle_event(event, &client);
-            }
-            client
-                .inner
-                .event_subscriptions
-                .borrow_mut()
-                .iter_mut()
-                .for_each(|v| {
-                    v.sender.close_channel();
-                });
-            log!("event handler task ended");
-        });
-        let client = new_client.anon_clone();
         wasm_bindgen_futures::spawn_local(async move {
             loop {
                 match client.await_state(WebSocketState::Connected).await {
@@ -186,11 +770,63 @@ impl WsApiClient {
                         zend_common::log!()
                     } // Ws was already connected or became connected after some time
                 }
+                // The server says how often to ping in its hello, which follows connecting
+                if client.inner.socket.protocol_version.get().is_none() {
+                    let hello = client.get_event_handle_timeout(
+                        SubscriptionEventFilter::new()
+                            .custom(|v| {
+                                matches!(
+                                    v,
+                                    ApiClientEvent::ApiMessage(api::ServerToClientMessage::Hello(
+                                        _
+                                    ))
+                                )
+                            })
+                            .reconnecting()
+                            .ended(),
+                        PING_INTERVAL,
+                    );
+                    match hello.await_event().await {
+                        Ok(ApiClientEvent::Reconnecting(_)) => continue,
+                        Ok(ApiClientEvent::Ended) | Err(AwaitEventError::EventsEmpty) => break,
+                        // Servers that don't answer hellos are pinged at the default interval
+                        Ok(_) | Err(AwaitEventError::Timeout) => {}
+                    }
+                }
+                let interval = match client.inner.socket.ping_interval.get() {
+                    Some(v) => v,
+                    // Nothing to do until the connection is lost
+                    None => match client.await_state(WebSocketState::Reconnecting).await {
+                        Ok(_) => continue,
+                        Err(_) => break,
+                    },
+                };
+                // Pings aren't numbered, but the server answers them in order
+                let pong = client.get_event_handle_timeout(
+                    SubscriptionEventFilter::new().pong().reconnecting().ended(),
+                    interval,
+                );
+                let sent_at = js_sys::Date::now();
                 let _ = client.send_message(&api::ClientToServerMessage::Ping);
                 zend_common::log!();
+                match pong.await_event().await {
+                    Ok(ApiClientEvent::ApiMessage(_)) => {
+                        let rtt_ms = (js_sys::Date::now() - sent_at).max(0.0);
+                        client.record_rtt(Duration::from_secs_f64(rtt_ms / 1000.0));
+                    }
+                    Ok(ApiClientEvent::Reconnecting(_)) => continue,
+                    Ok(_) | Err(AwaitEventError::EventsEmpty) => break,
+                    // Either the connection is dead, which the idle timeout will notice, or slow
+                    Err(AwaitEventError::Timeout) => continue,
+                }
+                let elapsed =
+                    Duration::from_secs_f64((js_sys::Date::now() - sent_at).max(0.0) / 1000.0);
 
                 match client
-                    .await_state_with_timeout(WebSocketState::Reconnecting, Duration::from_secs(10))
+                    .await_state_with_timeout(
+                        WebSocketState::Reconnecting,
+                        interval.saturating_sub(elapsed),
+                    )
                     .await
                 {
                     Ok(_) => continue, // Ws entered reconnecting state
@@ -202,25 +838,368 @@ impl WsApiClient {
             }
             log!("pinger task ended");
         });
+        let client = new_client.anon_clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            let mut events =
+                client.receive_events(SubscriptionEventFilter::new().connected().info());
+            while let Some(event) = events.next().await {
+                match event {
+                    ApiClientEvent::Connected => client.renew_subscriptions().await,
+                    // The server lost this subscription but kept the connection
+                    ApiClientEvent::ApiMessage(api::ServerToClientMessage::Info(
+                        api::ServerNotice::SubscriptionClosed(closed),
+                    )) => {
+                        if let Err(err) = client
+                            .renew_subscription(closed.room_id, closed.subscription_id)
+                            .await
+                        {
+                            log!(
+                                "failed to renew subscription {}: {:?}",
+                                closed.subscription_id,
+                                err
+                            );
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            log!("resubscriber task ended");
+        });
         new_client
     }
 
+    /** The round trip time of the most recent ping */
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.inner.rtt_samples.borrow().back().copied()
+    }
+
+    /** The average round trip time of the last `RTT_SAMPLES` pings */
+    pub fn average_rtt(&self) -> Option<Duration> {
+        let samples = self.inner.rtt_samples.borrow();
+        let total: Duration = samples.iter().sum();
+        (!samples.is_empty()).then(|| total / samples.len() as u32)
+    }
+
+    pub fn state(&self) -> WebSocketState {
+        self.ws_state()
+    }
+
+    /** When the current connection was made, in milliseconds since the epoch. `None` while not
+    connected. */
+    pub fn connected_since(&self) -> Option<u64> {
+        match self.ws_state() {
+            WebSocketState::Connected => self.inner.socket.connected_at.get(),
+            _ => None,
+        }
+    }
+
+    /** Resolves once the client is connected, right away if it already is. Meant for waiting
+    for the first connection when starting up. */
+    pub async fn ready(&self, timeout: Option<Duration>) -> Result<(), ReadyError> {
+        let states = vec![WebSocketState::Connected, WebSocketState::Ended];
+        let result = match timeout {
+            Some(timeout) => self.await_state_with_timeout(states, timeout).await,
+            None => self
+                .await_state(states)
+                .await
+                .map_err(|_| AwaitEventError::EventsEmpty),
+        };
+        match result {
+            Err(AwaitEventError::Timeout) => Err(ReadyError::Timeout),
+            // Connected, even if the connection dropped again since
+            _ if self.ws_state() != WebSocketState::Ended => Ok(()),
+            _ => Err(ReadyError::Ended),
+        }
+    }
+
+    /** Every change of state from now on, starting with the next. Each reconnection attempt is
+    a change too, so a countdown to the next one can be shown. Ends once the client has ended. */
+    pub fn state_stream(&self) -> impl Stream<Item = StateChange> {
+        let handle = self.receive_events(
+            SubscriptionEventFilter::new()
+                .connected()
+                .reconnecting()
+                .ended(),
+        );
+        stream::unfold(Some(handle), |handle| async move {
+            let mut handle = handle?;
+            loop {
+                let change = match handle.next().await? {
+                    ApiClientEvent::Connected => StateChange {
+                        state: WebSocketState::Connected,
+                        retry_after: None,
+                    },
+                    ApiClientEvent::Reconnecting(secs) => StateChange {
+                        state: WebSocketState::Reconnecting,
+                        retry_after: Some(secs),
+                    },
+                    ApiClientEvent::Ended => {
+                        return Some((
+                            StateChange {
+                                state: WebSocketState::Ended,
+                                retry_after: None,
+                            },
+                            None,
+                        ))
+                    }
+                    // Missed changes are skipped, the next one says what the state is now
+                    _ => continue,
+                };
+                return Some((change, Some(handle)));
+            }
+        })
+    }
+
+    /** Ends this client. The socket is closed unless other clients still share it. */
     pub fn end(&self) {
-        self.inner.ws.end();
+        let socket = &self.inner.socket;
+        let others = socket
+            .clients()
+            .iter()
+            .any(|v| !Rc::ptr_eq(&v.inner, &self.inner) && !v.inner.ended.get());
+        if !others {
+            socket.end();
+            return;
+        }
+        socket.detach(&self.inner);
+        handle_client_event(ApiClientEvent::Ended, self);
     }
 
+    /** Ends the client without losing what was already sent: new messages are rejected, queued
+    ones are sent (after reconnecting, if need be), and the connection is closed normally.
+    Resolves once every subscriber has received `Ended`. If that takes longer than the timeout,
+    the client is ended anyway and this errors. */
+    pub async fn close(self, timeout: Duration) -> Result<(), AwaitEventError> {
+        self.inner.closing.set(true);
+        let deadline = js_sys::Date::now() + timeout.as_secs_f64() * 1000.0;
+        let remaining =
+            || Duration::from_secs_f64(((deadline - js_sys::Date::now()) / 1000.0).max(0.0));
+        let mut result = Ok(());
+        if !self.inner.outgoing_queue.borrow().is_empty() {
+            // The queue is flushed as soon as the client connects
+            result = self
+                .await_state_with_timeout(
+                    vec![WebSocketState::Connected, WebSocketState::Ended],
+                    remaining(),
+                )
+                .await;
+        }
+        if result.is_ok() && !self.inner.paced_queue.borrow().is_empty() {
+            let sent = SubscriptionEventFilter::new()
+                .custom(|event| matches!(event, ApiClientEvent::SendingDelayed(0)))
+                .ended();
+            result = self
+                .get_event_handle_timeout(sent, remaining())
+                .await_event()
+                .await
+                .map(|_| ());
+        }
+        // Anything sent before is still sent before the close frame
+        self.end();
+        let done = self.inner.event_task_done.borrow_mut().take();
+        if let Some(done) = done {
+            if future_or_timeout(done, remaining()).await.is_none() {
+                result = Err(AwaitEventError::Timeout);
+            }
+        }
+        result
+    }
+
+    /** Queues the message instead while reconnecting, see `OutgoingQueueConfig` */
     pub fn send_message(&self, message: &api::ClientToServerMessage) -> Result<(), ()> {
-        let message = match serde_json::to_string(message) {
-            Ok(v) => v,
-            Err(_) => return Err(()),
+        if self.inner.closing.get() {
+            return Err(());
+        }
+        let message = match self.intercept_outgoing(message) {
+            Some(v) => v,
+            None => return Ok(()),
         };
-        self.inner.ws.send(&message);
-        return Ok(());
+        let message = &message;
+        self.track_call(message);
+        match self.ws_state() {
+            WebSocketState::Connected => self.send_paced(message, None),
+            WebSocketState::Reconnecting => self.queue_message(message),
+            WebSocketState::Ended => Err(()),
+        }
+    }
+
+    /** Sends the message as a binary (CBOR) frame, whatever encoding was negotiated. The server
+    reads binary frames as CBOR either way. Messages queued while reconnecting are sent in the
+    negotiated encoding, like all queued messages. */
+    pub fn send_message_binary(&self, message: &api::ClientToServerMessage) -> Result<(), ()> {
+        if self.inner.closing.get() {
+            return Err(());
+        }
+        let message = match self.intercept_outgoing(message) {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let message = &message;
+        self.track_call(message);
+        match self.ws_state() {
+            WebSocketState::Connected => self.send_paced(message, Some(Encoding::Cbor)),
+            WebSocketState::Reconnecting => self.queue_message(message),
+            WebSocketState::Ended => Err(()),
+        }
+    }
+
+    /** Messages already waiting are sent at the new rate, or right away if it's `None` */
+    pub fn set_rate_limit(&self, rate_limit: Option<RateLimitConfig>) {
+        self.refill_tokens();
+        self.inner.rate_limit.set(rate_limit);
+        if let Some(rate_limit) = rate_limit {
+            let tokens = self.inner.tokens.get().min(rate_limit.burst as f64);
+            self.inner.tokens.set(tokens);
+        }
+        if !self.inner.paced_queue.borrow().is_empty() {
+            self.start_pacer();
+        }
+    }
+
+    /** How many messages haven't been handed to the socket yet, whether they wait for the
+    connection or for the rate limit */
+    pub fn pending_len(&self) -> usize {
+        self.inner.outgoing_queue.borrow().len() + self.inner.paced_queue.borrow().len()
+    }
+
+    /** `None` unless duplicates are dropped, see `WsClientConfig::with_dedup_window` */
+    pub fn dedup_stats(&self) -> Option<DedupStats> {
+        self.inner.dedup.borrow().as_ref().map(|v| v.stats)
+    }
+
+    pub fn set_outgoing_queue_config(&self, config: OutgoingQueueConfig) {
+        self.inner.outgoing_queue_config.set(config);
+        let mut queue = self.inner.outgoing_queue.borrow_mut();
+        let excess = queue.len().saturating_sub(config.capacity);
+        queue.drain(..excess);
+    }
+
+    /** How many messages are waiting to be sent once the client is connected */
+    pub fn pending_message_count(&self) -> usize {
+        self.inner.outgoing_queue.borrow().len()
+    }
+
+    pub fn pending_messages(&self) -> Vec<api::ClientToServerMessage> {
+        self.inner.outgoing_queue.borrow().iter().cloned().collect()
+    }
+
+    /** Drops every queued message, returning them */
+    pub fn clear_pending_messages(&self) -> Vec<api::ClientToServerMessage> {
+        self.inner.outgoing_queue.borrow_mut().drain(..).collect()
+    }
+
+    /** Runs on every message sent with this client before it's sent or queued, after the hooks
+    added before it. The hook can change the message, or return `ControlFlow::Break` to drop it,
+    in which case sending it still succeeds. Messages the hook sends itself skip the hooks. */
+    pub fn add_outgoing_hook(
+        &self,
+        hook: impl FnMut(&mut api::ClientToServerMessage) -> ControlFlow<()> + 'static,
+    ) {
+        let hook = OutgoingHook(Box::new(hook));
+        self.inner.outgoing_hooks.borrow_mut().push(hook);
+    }
+
+    /** Runs on every message from the server that's for this client, after the hooks added
+    before it and before any subscriber sees the message */
+    pub fn add_incoming_hook(&self, hook: impl FnMut(&api::ServerToClientMessage) + 'static) {
+        let hook = IncomingHook(Box::new(hook));
+        self.inner.incoming_hooks.borrow_mut().push(hook);
+    }
+
+    pub fn clear_hooks(&self) {
+        self.inner.outgoing_hooks.borrow_mut().clear();
+        self.inner.incoming_hooks.borrow_mut().clear();
+    }
+
+    /** Signs a call with `calls`' key, sends it and waits for the return. Fails with
+    `CallError::Closed` if the connection drops first, as the server abandons the call then. */
+    pub async fn call<M: api::ApiMethod, K: CallerKey>(
+        &self,
+        calls: &RefCell<CallBuilder<K>>,
+        args: M,
+    ) -> Result<M::Success, CallError<()>> {
+        self.call_with_timeout(calls, args, DEFAULT_CALL_TIMEOUT)
+            .await
+    }
+
+    pub async fn call_with_timeout<M: api::ApiMethod, K: CallerKey>(
+        &self,
+        calls: &RefCell<CallBuilder<K>>,
+        args: M,
+        timeout: Duration,
+    ) -> Result<M::Success, CallError<()>> {
+        let call_id = self.next_call_id();
+        let message = calls
+            .borrow_mut()
+            .server_call_with_id(call_id, args)
+            .map_err(|err| CallError::Encode(err.to_string()))?;
+        let return_data = self.send_call(call_id, &message, timeout).await?;
+        caller::parse_return::<M, _>(return_data)
+    }
+
+    /** Like `call` for `SubscribeToRoomArgs`, but the subscription is renewed whenever the client
+    reconnects. Its data keeps arriving with the subscription ID returned here, even though the
+    server assigns a new one each time. */
+    pub async fn subscribe_to_room<K: CallerKey + 'static>(
+        &self,
+        calls: Rc<RefCell<CallBuilder<K>>>,
+        args: api::SubscribeToRoomArgs,
+    ) -> Result<api::SubscribeSuccess, CallError<()>> {
+        let success = self.call(&calls, args.clone()).await?;
+        self.inner
+            .active_subscriptions
+            .borrow_mut()
+            .push(ActiveSubscription {
+                room_id: args.room_id,
+                local_id: success.subscription_id,
+                server_id: Some(success.subscription_id),
+                make_call: Box::new(move |call_id| {
+                    calls
+                        .borrow_mut()
+                        .server_call_with_id(call_id, args.clone())
+                }),
+            });
+        Ok(success)
+    }
+
+    /** Stops renewing a subscription made with `subscribe_to_room`, e.g. after unsubscribing */
+    pub fn forget_subscription(&self, room_id: api::RoomId, subscription_id: u64) {
+        self.inner
+            .active_subscriptions
+            .borrow_mut()
+            .retain(|v| v.room_id != room_id || v.local_id != subscription_id);
+    }
+
+    /** Unsubscribes from every subscription made with `subscribe_to_room`, and stops renewing
+    them, e.g. before the key they were made with is replaced. Only that key can end them. */
+    pub async fn unsubscribe_all<K: CallerKey>(&self, calls: &RefCell<CallBuilder<K>>) {
+        let subscriptions = std::mem::take(&mut *self.inner.active_subscriptions.borrow_mut());
+        for subscription in subscriptions {
+            // Subscriptions being renewed are gone on the server already
+            let subscription_id = match subscription.server_id {
+                Some(subscription_id) => subscription_id,
+                None => continue,
+            };
+            let args = api::UnsubscribeFromRoomArgs {
+                room_id: subscription.room_id,
+                subscription_id,
+            };
+            if let Err(err) = self.call(calls, args).await {
+                log!(
+                    "Failed to unsubscribe from room {}: {:?}",
+                    subscription.room_id,
+                    err
+                );
+            }
+        }
     }
 
     pub fn get_event_handle(&self, filter: SubscriptionEventFilter) -> AwaitEventHandle {
-        let (id, receiver) =
-            self.register_event_subscription(EventSubscriptionType::Once, filter.inner);
+        let (id, receiver) = self.register_event_subscription(
+            EventSubscriptionType::Once,
+            filter.inner,
+            EventOverflowPolicy::default(),
+        );
         AwaitEventHandle {
             receiver,
             id,
@@ -234,8 +1213,11 @@ impl WsApiClient {
         filter: SubscriptionEventFilter,
         timeout: Duration,
     ) -> AwaitEventHandle {
-        let (id, receiver) =
-            self.register_event_subscription(EventSubscriptionType::Once, filter.inner);
+        let (id, receiver) = self.register_event_subscription(
+            EventSubscriptionType::Once,
+            filter.inner,
+            EventOverflowPolicy::default(),
+        );
         AwaitEventHandle {
             receiver,
             id,
@@ -245,35 +1227,388 @@ impl WsApiClient {
     }
 
     pub fn receive_events(&self, filter: SubscriptionEventFilter) -> EventSubscriptionHandle {
-        let (id, receiver) =
-            self.register_event_subscription(EventSubscriptionType::Persistent, filter.inner);
+        self.receive_events_with_overflow(filter, EventOverflowPolicy::default())
+    }
+
+    /** Like `receive_events`, choosing what happens when more than `EVENT_QUEUE_CAPACITY`
+    events are waiting to be received */
+    pub fn receive_events_with_overflow(
+        &self,
+        filter: SubscriptionEventFilter,
+        overflow: EventOverflowPolicy,
+    ) -> EventSubscriptionHandle {
+        let (id, receiver) = self.register_event_subscription(
+            EventSubscriptionType::Persistent,
+            filter.inner,
+            overflow,
+        );
         EventSubscriptionHandle {
             receiver,
             id,
             api_client: self.anon_clone(),
         }
     }
+
+    /** The data sent over a subscription, e.g. one made with `subscribe_to_room`, until the
+    client ends. Data missed because it wasn't received in time is skipped without notice. */
+    pub fn room_data_stream(
+        &self,
+        subscription_id: u64,
+    ) -> impl Stream<Item = api::SubscriptionData> {
+        let events =
+            self.receive_events(SubscriptionEventFilter::new().sub_data_for_id(subscription_id));
+        events.filter_map(|event| {
+            future::ready(match event {
+                ApiClientEvent::ApiMessage(api::ServerToClientMessage::SubscriptionData(data)) => {
+                    Some(data)
+                }
+                _ => None,
+            })
+        })
+    }
 }
 
 // Implementation Details
 impl WsApiClient {
-    fn anon_clone(&self) -> Self {
+    /** A clone that doesn't keep the client from ending when the others are dropped */
+    pub(crate) fn anon_clone(&self) -> Self {
         Self {
             inner: Rc::clone(&self.inner),
             anon: true,
         }
     }
 
+    fn ws_state(&self) -> WebSocketState {
+        match self.inner.ended.get() {
+            true => WebSocketState::Ended,
+            false => self.inner.socket.ws_state.get(),
+        }
+    }
+
+    fn send_now(
+        &self,
+        message: &api::ClientToServerMessage,
+        encoding: Option<Encoding>,
+    ) -> Result<(), ()> {
+        let socket = &self.inner.socket;
+        socket.send(message, encoding.unwrap_or_else(|| socket.encoding.get()))
+    }
+
+    /** Sends the message if the rate limit allows it, or has the pacer send it later */
+    fn send_paced(
+        &self,
+        message: &api::ClientToServerMessage,
+        encoding: Option<Encoding>,
+    ) -> Result<(), ()> {
+        if self.inner.rate_limit.get().is_none() {
+            return self.send_now(message, encoding);
+        }
+        // Messages already waiting go first
+        if self.inner.paced_queue.borrow().is_empty() && self.take_token() {
+            return self.send_now(message, encoding);
+        }
+        let pending = {
+            let mut queue = self.inner.paced_queue.borrow_mut();
+            queue.push_back((message.clone(), encoding));
+            queue.len()
+        };
+        dispatch_event(ApiClientEvent::SendingDelayed(pending), self);
+        self.start_pacer();
+        Ok(())
+    }
+
+    fn refill_tokens(&self) {
+        let now = js_sys::Date::now();
+        let elapsed_secs = ((now - self.inner.tokens_updated_at.get()) / 1000.0).max(0.0);
+        self.inner.tokens_updated_at.set(now);
+        if let Some(rate_limit) = self.inner.rate_limit.get() {
+            let tokens = self.inner.tokens.get() + elapsed_secs * rate_limit.per_second;
+            self.inner.tokens.set(tokens.min(rate_limit.burst as f64));
+        }
+    }
+
+    fn take_token(&self) -> bool {
+        self.refill_tokens();
+        let tokens = self.inner.tokens.get();
+        if tokens < 1.0 {
+            return false;
+        }
+        self.inner.tokens.set(tokens - 1.0);
+        true
+    }
+
+    /** How long until the next token, assuming there is a rate limit */
+    fn time_until_token(&self) -> Duration {
+        let per_second = self.inner.rate_limit.get().map_or(1.0, |v| v.per_second);
+        let missing = (1.0 - self.inner.tokens.get()).max(0.0);
+        Duration::from_secs_f64((missing / per_second.max(f64::EPSILON)).min(60.0))
+    }
+
+    /** Sends what waits for the rate limit as tokens come in. Stops while not connected, and
+    is started again after connecting. */
+    fn start_pacer(&self) {
+        if self.inner.pacer_running.replace(true) {
+            return;
+        }
+        let client = self.anon_clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            loop {
+                if client.ws_state() != WebSocketState::Connected {
+                    break;
+                }
+                if client.inner.paced_queue.borrow().is_empty() {
+                    dispatch_event(ApiClientEvent::SendingDelayed(0), &client);
+                    break;
+                }
+                if client.inner.rate_limit.get().is_some() && !client.take_token() {
+                    gloo_timers::future::sleep(client.time_until_token()).await;
+                    continue;
+                }
+                let next = client.inner.paced_queue.borrow_mut().pop_front();
+                if let Some((message, encoding)) = next {
+                    let _ = client.send_now(&message, encoding);
+                }
+            }
+            client.inner.pacer_running.set(false);
+        });
+    }
+
+    /** The message as the outgoing hooks left it, or `None` if one of them dropped it */
+    fn intercept_outgoing(
+        &self,
+        message: &api::ClientToServerMessage,
+    ) -> Option<api::ClientToServerMessage> {
+        let mut message = message.clone();
+        // Already borrowed if a hook is sending something itself
+        let mut hooks = match self.inner.outgoing_hooks.try_borrow_mut() {
+            Ok(v) => v,
+            Err(_) => return Some(message),
+        };
+        for hook in hooks.iter_mut() {
+            if (hook.0)(&mut message).is_break() {
+                return None;
+            }
+        }
+        Some(message)
+    }
+
+    fn run_incoming_hooks(&self, message: &api::ServerToClientMessage) {
+        if let Ok(mut hooks) = self.inner.incoming_hooks.try_borrow_mut() {
+            for hook in hooks.iter_mut() {
+                (hook.0)(message);
+            }
+        }
+    }
+
+    /** Whether the message is subscription data that was already received */
+    fn is_duplicate(&self, message: &api::ServerToClientMessage) -> bool {
+        let data = match message {
+            api::ServerToClientMessage::SubscriptionData(v) => v,
+            _ => return false,
+        };
+        match self.inner.dedup.borrow_mut().as_mut() {
+            Some(dedup) => {
+                dedup.check((data.subscription_id, data.sender_id.to_string(), data.nonce))
+            }
+            None => false,
+        }
+    }
+
+    fn track_call(&self, message: &api::ClientToServerMessage) {
+        if let Some(call_id) = message.call_id() {
+            self.inner.pending_calls.borrow_mut().insert(call_id);
+        }
+    }
+
+    /** Whether a message from the shared socket is for this client only, see `dispatch` */
+    fn claims(&self, message: &mut api::ServerToClientMessage) -> bool {
+        if let api::ServerToClientMessage::MethodCallReturn(call_return) = message {
+            return self
+                .inner
+                .pending_calls
+                .borrow_mut()
+                .remove(&call_return.call_id);
+        }
+        match message.subscription_mut() {
+            Some((room_id, subscription_id)) => self
+                .inner
+                .active_subscriptions
+                .borrow()
+                .iter()
+                .any(|v| v.room_id == room_id && v.server_id == Some(*subscription_id)),
+            None => false,
+        }
+    }
+
+    /** Closes every event subscription, after this client's last event */
+    fn finish(&self) {
+        self.inner.ended.set(true);
+        for subscription in self.inner.event_subscriptions.borrow_mut().iter_mut() {
+            subscription.queue.borrow_mut().close();
+        }
+        if let Some(finished) = self.inner.event_task_finished.borrow_mut().take() {
+            let _ = finished.send(());
+        }
+    }
+
+    fn queue_message(&self, message: &api::ClientToServerMessage) -> Result<(), ()> {
+        let config = self.inner.outgoing_queue_config.get();
+        let mut queue = self.inner.outgoing_queue.borrow_mut();
+        if queue.len() >= config.capacity {
+            if config.capacity == 0 || config.overflow == QueueOverflowPolicy::Reject {
+                return Err(());
+            }
+            queue.pop_front();
+        }
+        queue.push_back(message.clone());
+        Ok(())
+    }
+
+    fn flush_outgoing_queue(&self) {
+        // Sent before, so they go first
+        if !self.inner.paced_queue.borrow().is_empty() {
+            self.start_pacer();
+        }
+        let queue: Vec<_> = self.inner.outgoing_queue.borrow_mut().drain(..).collect();
+        for message in queue {
+            let _ = self.send_paced(&message, None);
+        }
+    }
+
+    fn record_rtt(&self, rtt: Duration) {
+        {
+            let mut samples = self.inner.rtt_samples.borrow_mut();
+            if samples.len() >= RTT_SAMPLES {
+                samples.pop_front();
+            }
+            samples.push_back(rtt);
+        }
+        dispatch_event(ApiClientEvent::Latency(rtt), self);
+    }
+
+    fn next_call_id(&self) -> u64 {
+        self.inner.socket.next_call_id()
+    }
+
+    async fn send_call(
+        &self,
+        call_id: u64,
+        message: &api::ClientToServerMessage,
+        timeout: Duration,
+    ) -> Result<api::MethodCallReturnVariants, CallError<()>> {
+        // Listening before sending, so the return can't arrive in between
+        let handle = self.get_event_handle_timeout(
+            SubscriptionEventFilter::new()
+                .call_return_for_id(call_id)
+                .reconnecting()
+                .ended(),
+            timeout,
+        );
+        self.send_message(message).map_err(CallError::Transport)?;
+        match handle.await_event().await {
+            Ok(ApiClientEvent::ApiMessage(api::ServerToClientMessage::MethodCallReturn(
+                call_return,
+            ))) => Ok(call_return.return_data),
+            Ok(_) | Err(AwaitEventError::EventsEmpty) => Err(CallError::Closed),
+            Err(AwaitEventError::Timeout) => {
+                self.record_timed_out_call(call_id);
+                Err(CallError::Timeout)
+            }
+        }
+    }
+
+    // The call stays in `pending_calls` until then, so a late return still comes to this client
+    fn record_timed_out_call(&self, call_id: u64) {
+        let now = js_sys::Date::now();
+        let mut pending = self.inner.pending_calls.borrow_mut();
+        let mut timed_out = self.inner.timed_out_calls.borrow_mut();
+        timed_out.retain(|call_id, expires_at| {
+            let keep = *expires_at > now;
+            if !keep {
+                pending.remove(call_id);
+            }
+            keep
+        });
+        let expires_at = now + LATE_RETURN_GRACE_PERIOD.as_secs_f64() * 1000.0;
+        timed_out.insert(call_id, expires_at);
+    }
+
+    /** Whether a return is for a call that timed out, which it then no longer is */
+    fn is_late_return(&self, call_id: u64) -> bool {
+        let expires_at = self.inner.timed_out_calls.borrow_mut().remove(&call_id);
+        expires_at.map_or(false, |v| v > js_sys::Date::now())
+    }
+
+    /** Subscribes again to everything from `subscribe_to_room`, after connecting */
+    async fn renew_subscriptions(&self) {
+        let subscriptions: Vec<(api::RoomId, u64)> = self
+            .inner
+            .active_subscriptions
+            .borrow()
+            .iter()
+            .map(|v| (v.room_id, v.local_id))
+            .collect();
+        for (room_id, local_id) in subscriptions {
+            match self.renew_subscription(room_id, local_id).await {
+                Ok(()) => {}
+                // Disconnected again, the next connect starts over
+                Err(CallError::Closed) => return,
+                Err(err) => log!("failed to renew subscription {}: {:?}", local_id, err),
+            }
+        }
+    }
+
+    /** Subscribes again to one subscription from `subscribe_to_room`, by the ID it was made with.
+    Does nothing if it was forgotten in the meantime. */
+    async fn renew_subscription(
+        &self,
+        room_id: api::RoomId,
+        local_id: u64,
+    ) -> Result<(), CallError<()>> {
+        let find = |active: &[ActiveSubscription]| {
+            active
+                .iter()
+                .position(|v| v.room_id == room_id && v.local_id == local_id)
+        };
+        let call_id = self.next_call_id();
+        // Ref only held while signing, no .await occurs in between
+        let message = {
+            let mut active = self.inner.active_subscriptions.borrow_mut();
+            match find(&active) {
+                Some(index) => {
+                    // Data for the old ID is gone for good, so it's no longer remapped
+                    active[index].server_id = None;
+                    (active[index].make_call)(call_id)
+                }
+                None => return Ok(()),
+            }
+        };
+        let message = message.map_err(|err| CallError::Encode(err.to_string()))?;
+        let success = self
+            .send_call(call_id, &message, DEFAULT_CALL_TIMEOUT)
+            .await
+            .and_then(caller::parse_return::<api::SubscribeToRoomArgs, _>)?;
+        let mut active = self.inner.active_subscriptions.borrow_mut();
+        if let Some(index) = find(&active) {
+            active[index].server_id = Some(success.subscription_id);
+        }
+        Ok(())
+    }
+
     fn register_event_subscription(
         &self,
         subscriber_type: EventSubscriptionType,
         event_filters: Vec<SubscriptionEventFilterItem>,
-    ) -> (usize, mpsc::Receiver<ApiClientEvent>) {
-        let (mut sender, receiver) = mpsc::channel::<ApiClientEvent>(256);
+        overflow: EventOverflowPolicy,
+    ) -> (usize, EventReceiver) {
+        let queue = Rc::new(RefCell::new(EventQueue::default()));
+        let receiver = EventReceiver {
+            queue: Rc::clone(&queue),
+        };
         let id_cell = &self.inner.next_event_subscription_id;
         let id = id_cell.get();
-        if self.inner.clones.get() < 1 {
-            sender.close_channel();
+        if self.inner.clones.get() < 1 || self.inner.ended.get() {
+            queue.borrow_mut().close();
             return (id, receiver);
         }
         self.inner
@@ -281,7 +1616,8 @@ impl WsApiClient {
             .borrow_mut()
             .push(EventSubscription {
                 event_filters,
-                sender,
+                queue,
+                overflow,
                 subscriber_type,
                 id,
             });
@@ -299,7 +1635,7 @@ impl WsApiClient {
     }
 
     fn await_state_common(&self, states: Vec<WebSocketState>) -> Option<SubscriptionEventFilter> {
-        let current_state = self.inner.ws_state.get();
+        let current_state = self.ws_state();
         if states.iter().any(|v| *v == current_state) {
             return None;
         }
@@ -368,30 +1704,56 @@ impl Drop for WsApiClient {
     }
 }
 
-fn handle_event(event: WrappedSocketEvent, client: &WsApiClient) {
-    let event = {
-        use WrappedSocketEvent::*;
-        match event {
-            Connected => {
-                client.inner.ws_state.set(WebSocketState::Connected);
-                ApiClientEvent::Connected
-            }
-            Reconnecting(v) => {
-                client.inner.ws_state.set(WebSocketState::Reconnecting);
-                ApiClientEvent::Reconnecting(v)
+/** Does what the client has to on an event from its socket, then passes it on to subscribers */
+fn handle_client_event(mut event: ApiClientEvent, client: &WsApiClient) {
+    if client.inner.ended.get() {
+        return;
+    }
+    match &mut event {
+        // Sent after the socket's hello
+        ApiClientEvent::Connected => client.flush_outgoing_queue(),
+        ApiClientEvent::Reconnecting(_) => {
+            // Server side subscriptions and calls end with the connection
+            for subscription in client.inner.active_subscriptions.borrow_mut().iter_mut() {
+                subscription.server_id = None;
             }
-            Ended(_) => {
-                client.inner.ws_state.set(WebSocketState::Ended);
-                ApiClientEvent::Ended
+            client.inner.pending_calls.borrow_mut().clear();
+            client.inner.timed_out_calls.borrow_mut().clear();
+        }
+        ApiClientEvent::Ended => {
+            client.inner.ended.set(true);
+            client.inner.outgoing_queue.borrow_mut().clear();
+            client.inner.paced_queue.borrow_mut().clear();
+        }
+        ApiClientEvent::ApiMessage(message) => {
+            remap_subscription_id(client, message);
+            if client.is_duplicate(message) {
+                return;
             }
-
-            TextMessage(msg) => ApiClientEvent::ApiMessage(match serde_json::from_str(&msg) {
-                Ok(v) => v,
-                Err(_) => return,
-            }),
-            BinaryMessage(_) => return,
+            client.run_incoming_hooks(message);
         }
-    };
+        _ => {}
+    }
+    let late_return =
+        match &event {
+            ApiClientEvent::ApiMessage(api::ServerToClientMessage::MethodCallReturn(
+                call_return,
+            )) if client.is_late_return(call_return.call_id) => Some(call_return.call_id),
+            _ => None,
+        };
+    let ended = matches!(event, ApiClientEvent::Ended);
+    dispatch_event(event, client);
+    if let Some(call_id) = late_return {
+        log!("call {} returned after timing out", call_id);
+        dispatch_event(ApiClientEvent::LateReturn(call_id), client);
+    }
+    if ended {
+        client.finish();
+    }
+}
+
+/** Sends an event to every subscriber whose filters match it */
+fn dispatch_event(event: ApiClientEvent, client: &WsApiClient) {
     // Ref only held until end of loop iteration, before which no .await occurs
     let mut subscribers = client.inner.event_subscriptions.borrow_mut();
     let mut i = 0;
@@ -408,15 +1770,21 @@ fn handle_event(event: WrappedSocketEvent, client: &WsApiClient) {
             i = i + 1;
             continue;
         }
-        if let Err(err) = subscriber.sender.try_send(event.clone()) {
-            if err.is_disconnected() {
-                subscribers.swap_remove(i);
-                // Do not increment index here because swap_remove just moved a subscriber to current index
-                continue;
-            }
+        // The receiver was dropped
+        if Rc::strong_count(&subscriber.queue) < 2 {
+            subscribers.swap_remove(i);
+            // Do not increment index here because swap_remove just moved a subscriber to current index
+            continue;
+        }
+        let overflow = subscriber.overflow;
+        let delivered = subscriber.queue.borrow_mut().push(event.clone(), overflow);
+        if !delivered {
+            subscriber.queue.borrow_mut().close();
+            subscribers.swap_remove(i);
+            continue;
         }
         if let EventSubscriptionType::Once = subscriber.subscriber_type {
-            subscriber.sender.close_channel();
+            subscriber.queue.borrow_mut().close();
             subscribers.swap_remove(i);
             // Do not increment index here because swap_remove just moved a subscriber to current index
             continue;
@@ -425,6 +1793,55 @@ fn handle_event(event: WrappedSocketEvent, client: &WsApiClient) {
     }
 }
 
+/** Gives messages for renewed subscriptions the ID the subscription had when it was made */
+fn remap_subscription_id(client: &WsApiClient, message: &mut api::ServerToClientMessage) {
+    let is_room_closed = matches!(message, api::ServerToClientMessage::RoomClosed(_));
+    let (room_id, subscription_id) = match message.subscription_mut() {
+        Some(v) => v,
+        None => return,
+    };
+    let mut active = client.inner.active_subscriptions.borrow_mut();
+    let index = match active
+        .iter()
+        .position(|v| v.room_id == room_id && v.server_id == Some(*subscription_id))
+    {
+        Some(v) => v,
+        None => return,
+    };
+    *subscription_id = active[index].local_id;
+    // There is nothing left to subscribe to
+    if is_room_closed {
+        active.swap_remove(index);
+    }
+}
+
+fn deflate(bytes: &[u8]) -> Vec<u8> {
+    miniz_oxide::deflate::compress_to_vec(bytes, 6)
+}
+
+fn inflate(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    miniz_oxide::inflate::decompress_to_vec(bytes).map_err(|err| format!("{:?}", err))
+}
+
+// Messages before the server's hello are parsed as the version servers without a handshake speak.
+// Text frames are always JSON and binary frames CBOR, whichever encoding was negotiated.
+fn parse_server_message(
+    event: &WrappedSocketEvent,
+    protocol_version: Option<u32>,
+    compression: bool,
+) -> Option<api::ServerToClientMessage> {
+    let (encoding, bytes) = match event {
+        WrappedSocketEvent::TextMessage(msg) => (Encoding::Json, msg.as_bytes()),
+        WrappedSocketEvent::BinaryMessage(msg) => (Encoding::Cbor, msg.as_slice()),
+        _ => return None,
+    };
+    match protocol_version.unwrap_or(api::DEFAULT_PROTOCOL_VERSION) {
+        1 if compression => encoding.decode_compressed(bytes, inflate).ok(),
+        1 => encoding.decode(bytes).ok(),
+        _ => None,
+    }
+}
+
 fn event_is_matched_by_any_filter(
     event: &ApiClientEvent,
     filters: &Vec<SubscriptionEventFilterItem>,
@@ -493,6 +1910,16 @@ fn event_is_matched_by_any_filter(
         Reconnecting => {
             match_event!(Reconnecting(_))
         }
+        Latency => {
+            match_event!(Latency(_))
+        }
+        SendingDelayed => {
+            match_event!(SendingDelayed(_))
+        }
+        LateReturn => {
+            match_event!(LateReturn(_))
+        }
+        Custom(predicate) => (predicate.0)(event),
         Ended => {
             match_event!(Ended)
         }
@@ -500,7 +1927,7 @@ fn event_is_matched_by_any_filter(
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum WebSocketState {
+pub enum WebSocketState {
     Connected,
     Reconnecting,
     Ended,
@@ -517,14 +1944,191 @@ enum EventSubscriptionType {
     Persistent,
 }
 
+struct ActiveSubscription {
+    room_id: api::RoomId,
+    // The ID from the first subscribe, which messages are remapped to
+    local_id: u64,
+    // None while reconnecting, until the renewed subscription's ID is known
+    server_id: Option<u64>,
+    // Signs a new subscribe call with the given call ID
+    make_call: Box<dyn FnMut(u64) -> Result<api::ClientToServerMessage, serde_json::Error>>,
+}
+impl std::fmt::Debug for ActiveSubscription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ActiveSubscription")
+            .field("room_id", &self.room_id)
+            .field("local_id", &self.local_id)
+            .field("server_id", &self.server_id)
+            .finish()
+    }
+}
+
 #[derive(Debug)]
 struct EventSubscription {
     event_filters: Vec<SubscriptionEventFilterItem>,
-    sender: mpsc::Sender<ApiClientEvent>,
+    queue: Rc<RefCell<EventQueue>>,
+    overflow: EventOverflowPolicy,
     subscriber_type: EventSubscriptionType,
     id: usize,
 }
 
+// Subscription ID, sender and nonce
+type DedupKey = (u64, String, api::Nonce);
+
+/** The most recent subscription data received, oldest first */
+#[derive(Debug)]
+struct DedupWindow {
+    capacity: usize,
+    seen: HashSet<DedupKey>,
+    order: VecDeque<DedupKey>,
+    stats: DedupStats,
+}
+impl DedupWindow {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            seen: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            stats: DedupStats::default(),
+        }
+    }
+    /** Whether the data is in the window, adding it if it's not */
+    fn check(&mut self, key: DedupKey) -> bool {
+        self.stats.checked += 1;
+        if self.seen.contains(&key) {
+            self.stats.dropped += 1;
+            return true;
+        }
+        if self.capacity == 0 {
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(key.clone());
+        self.order.push_back(key);
+        false
+    }
+}
+
+/** Events waiting to be received by a subscriber, shared with its `EventReceiver` */
+#[derive(Debug, Default)]
+struct EventQueue {
+    events: VecDeque<ApiClientEvent>,
+    // Not counting Lagged events, which don't take up capacity
+    len: usize,
+    closed: bool,
+    waker: Option<Waker>,
+}
+impl EventQueue {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+    fn close(&mut self) {
+        self.closed = true;
+        self.wake();
+    }
+    fn pop(&mut self) -> Option<ApiClientEvent> {
+        let event = self.events.pop_front()?;
+        if !matches!(event, ApiClientEvent::Lagged(_)) {
+            self.len -= 1;
+        }
+        Some(event)
+    }
+    /** Returns false if the subscriber has to be closed */
+    fn push(&mut self, event: ApiClientEvent, overflow: EventOverflowPolicy) -> bool {
+        if self.closed {
+            return false;
+        }
+        if self.len >= EVENT_QUEUE_CAPACITY {
+            match overflow {
+                EventOverflowPolicy::DropOldest => {
+                    // A Lagged at the front counts the events dropped before this one
+                    let mut missed = 0;
+                    while let Some(dropped) = self.pop() {
+                        match dropped {
+                            ApiClientEvent::Lagged(n) => missed += n,
+                            _ => {
+                                missed += 1;
+                                break;
+                            }
+                        }
+                    }
+                    self.events.push_front(ApiClientEvent::Lagged(missed));
+                }
+                EventOverflowPolicy::DropNewest => {
+                    match self.events.back_mut() {
+                        Some(ApiClientEvent::Lagged(n)) => *n += 1,
+                        _ => self.events.push_back(ApiClientEvent::Lagged(1)),
+                    }
+                    self.wake();
+                    return true;
+                }
+                EventOverflowPolicy::CloseSubscriber => {
+                    self.events.push_back(ApiClientEvent::Lagged(1));
+                    return false;
+                }
+            }
+        }
+        self.events.push_back(event);
+        self.len += 1;
+        self.wake();
+        true
+    }
+}
+
+/** Receives the events of one subscription, see `EventSubscriptionHandle` */
+#[derive(Debug)]
+pub struct EventReceiver {
+    queue: Rc<RefCell<EventQueue>>,
+}
+impl Stream for EventReceiver {
+    type Item = ApiClientEvent;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut queue = self.queue.borrow_mut();
+        if let Some(event) = queue.pop() {
+            return Poll::Ready(Some(event));
+        }
+        if queue.closed {
+            return Poll::Ready(None);
+        }
+        queue.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let queue = self.queue.borrow();
+        let len = queue.events.len();
+        (len, queue.closed.then_some(len))
+    }
+}
+
+/** Where a `SharedSocket`'s websocket lives */
+#[derive(Debug)]
+enum SocketBackend {
+    Direct(WsRefCellWrap),
+    /** Sends and receives messages as they are, negotiating the encoding itself */
+    Worker(WorkerBridge),
+}
+impl SocketBackend {
+    fn new(url: &str, config: WsClientConfig) -> Self {
+        let worker_url = config
+            .worker_url
+            .as_ref()
+            .filter(|_| config.transport.is_none());
+        if let Some(worker_url) = worker_url {
+            match WorkerBridge::new(worker_url, url, &config) {
+                Ok(worker) => return Self::Worker(worker),
+                Err(err) => log!("failed to start the websocket worker: {:?}", err),
+            }
+        }
+        Self::Direct(WsRefCellWrap::new(url, config))
+    }
+}
+
 #[derive(Debug)]
 enum WrappedSocketEvent {
     Connected,
@@ -535,98 +2139,170 @@ enum WrappedSocketEvent {
     Ended(&'static str),
 }
 
+/** What a `WebSocketWrap` is waiting for. Each state owns its futures, so a poll can be dropped
+at any point and the next one picks up where it left off. */
+enum WrapState {
+    /** Connects on the next poll */
+    Disconnected,
+    /** Waiting out the backoff before the next attempt */
+    Waiting(gloo_timers::future::TimeoutFuture),
+    Connecting {
+        connect: future::LocalBoxFuture<'static, Result<Box<dyn Connection>, String>>,
+        timeout: gloo_timers::future::TimeoutFuture,
+    },
+    /** The idle timer restarts with every message */
+    Open {
+        connection: Box<dyn Connection>,
+        idle: gloo_timers::future::TimeoutFuture,
+    },
+    Finished,
+}
+impl std::fmt::Debug for WrapState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Disconnected => "Disconnected",
+            Self::Waiting(_) => "Waiting",
+            Self::Connecting { .. } => "Connecting",
+            Self::Open { .. } => "Open",
+            Self::Finished => "Finished",
+        })
+    }
+}
+
 #[derive(Debug)]
 struct WebSocketWrap {
-    finished: bool,
+    state: WrapState,
     url: String,
-    ws: Option<WsStream>,
-    retry_after: u64,
-    close_timeout: Duration,
+    transport: Rc<dyn Transport>,
+    // Consecutive failed attempts, and how long to wait before the next one
+    failed_attempts: u32,
+    retry_after: Duration,
+    config: WsClientConfig,
 }
 impl WebSocketWrap {
-    fn new(url: &str, close_timeout: Option<Duration>) -> Self {
+    fn new(url: &str, config: WsClientConfig) -> Self {
         Self {
-            finished: false,
+            state: WrapState::Disconnected,
             url: url.into(),
-            ws: None,
-            retry_after: 0,
-            close_timeout: close_timeout.unwrap_or(Duration::MAX),
+            transport: match &config.transport {
+                Some(transport) => Rc::clone(transport),
+                None => Rc::new(WsStreamTransport),
+            },
+            failed_attempts: 0,
+            retry_after: Duration::ZERO,
+            config,
         }
     }
 
-    async fn connect(&mut self) -> Result<WsStream, &'static str> {
-        let connect_future = Box::pin(WsMeta::connect(&self.url, None));
-        let timeout_future = gloo_timers::future::sleep(Duration::from_secs(5));
-        let select = future::select(connect_future, timeout_future).await;
-        let (_, wsio) = match select {
-            future::Either::Left((value, _)) => value.map_err(|_| "WsErr")?,
-            future::Either::Right(_) => return Err("Timeout"),
-        };
-        Ok(wsio)
+    fn connection(&self) -> Option<&dyn Connection> {
+        match &self.state {
+            WrapState::Open { connection, .. } => Some(connection.as_ref()),
+            _ => None,
+        }
     }
 
-    async fn next_event(&mut self) -> Option<WrappedSocketEvent> {
-        if self.finished {
-            return None;
+    fn finish(&mut self) {
+        if let Some(connection) = self.connection() {
+            connection.close(api::CloseCode::Normal.code());
+        }
+        self.state = WrapState::Finished;
+    }
+
+    fn failed_to_connect(&mut self) -> WrappedSocketEvent {
+        self.failed_attempts += 1;
+        let gave_up = self
+            .config
+            .max_reconnect_attempts
+            .map_or(false, |max| self.failed_attempts > max);
+        if !self.config.reconnect || gave_up {
+            self.state = WrapState::Finished;
+            return WrappedSocketEvent::Ended("Failed to connect");
+        }
+        self.retry_after = self.config.backoff(self.failed_attempts);
+        self.state = WrapState::Waiting(gloo_timers::future::sleep(self.retry_after));
+        WrappedSocketEvent::Reconnecting(self.retry_after.as_secs())
+    }
+
+    /** The first attempt after losing a connection is made right away */
+    fn disconnected(&mut self, reason: &'static str) -> WrappedSocketEvent {
+        if !self.config.reconnect {
+            self.state = WrapState::Finished;
+            return WrappedSocketEvent::Ended(reason);
         }
-        if let Some(wsio) = &mut self.ws {
-            let timeout_future = gloo_timers::future::sleep(self.close_timeout);
-            let next_result = match future::select(wsio.next(), timeout_future).await {
-                future::Either::Left((v, _)) => v,
-                future::Either::Right(_) => {
-                    if let Some(wsio) = self.ws.take() {
-                        wsio.wrapped()
-                            .close()
-                            .expect("Something went wrong when closing a websocket connection");
+        self.state = WrapState::Disconnected;
+        WrappedSocketEvent::Reconnecting(self.retry_after.as_secs())
+    }
+}
+impl Stream for WebSocketWrap {
+    type Item = WrappedSocketEvent;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            match &mut this.state {
+                WrapState::Finished => return Poll::Ready(None),
+                WrapState::Disconnected => {
+                    this.state = WrapState::Connecting {
+                        connect: this.transport.connect(&this.url),
+                        timeout: gloo_timers::future::sleep(this.config.connect_timeout),
+                    };
+                }
+                WrapState::Waiting(timer) => {
+                    futures::ready!(timer.poll_unpin(cx));
+                    this.state = WrapState::Disconnected;
+                }
+                WrapState::Connecting { connect, timeout } => {
+                    if let Poll::Ready(result) = connect.poll_unpin(cx) {
+                        return Poll::Ready(Some(match result {
+                            Ok(connection) => {
+                                this.failed_attempts = 0;
+                                this.retry_after = Duration::ZERO;
+                                this.state = WrapState::Open {
+                                    connection,
+                                    idle: gloo_timers::future::sleep(this.config.idle_timeout),
+                                };
+                                WrappedSocketEvent::Connected
+                            }
+                            Err(_) => this.failed_to_connect(),
+                        }));
                     }
-                    return Some(WrappedSocketEvent::Reconnecting(self.retry_after));
+                    futures::ready!(timeout.poll_unpin(cx));
+                    return Poll::Ready(Some(this.failed_to_connect()));
+                }
+                WrapState::Open { connection, idle } => {
+                    match connection.poll_next_unpin(cx) {
+                        Poll::Ready(Some(frame)) => {
+                            *idle = gloo_timers::future::sleep(this.config.idle_timeout);
+                            return Poll::Ready(Some(match frame {
+                                Frame::Text(text) => WrappedSocketEvent::TextMessage(text),
+                                Frame::Binary(bytes) => WrappedSocketEvent::BinaryMessage(bytes),
+                            }));
+                        }
+                        Poll::Ready(None) => {
+                            return Poll::Ready(Some(this.disconnected("Connection lost")))
+                        }
+                        Poll::Pending => {}
+                    }
+                    futures::ready!(idle.poll_unpin(cx));
+                    connection.close(api::CloseCode::Normal.code());
+                    return Poll::Ready(Some(this.disconnected("Idle timeout")));
                 }
-            };
-            if let Some(msg) = next_result {
-                return Some(match msg {
-                    WsMessage::Text(msg) => WrappedSocketEvent::TextMessage(msg),
-                    WsMessage::Binary(msg) => WrappedSocketEvent::BinaryMessage(msg),
-                });
-            };
-            self.ws.take();
-            return Some(WrappedSocketEvent::Reconnecting(self.retry_after));
-        }
-        if self.retry_after > 0 {
-            gloo_timers::future::sleep(Duration::from_secs(self.retry_after)).await;
-            // Exponential backoff maxing out at 60 seconds
-            self.retry_after = if self.retry_after * 2 > 60 {
-                60
-            } else {
-                self.retry_after * 2
-            };
-        } else {
-            self.retry_after = 5;
-        }
-        Some(match self.connect().await {
-            Ok(new) => {
-                self.retry_after = 0;
-                let _ = self.ws.insert(new);
-                WrappedSocketEvent::Connected
             }
-            Err(_err) => WrappedSocketEvent::Reconnecting(self.retry_after),
-        })
+        }
     }
 }
 
+/** Lets the socket be shared by reference. The cells are only borrowed for the duration of a
+poll, so tasks can poll it concurrently (each event goes to one of them) or drop a poll halfway. */
 #[derive(Debug)]
 struct WsRefCellWrap {
     ws_wrap: RefCell<WebSocketWrap>,
-    ws_copy: RefCell<Option<WebSocket>>,
-    ended: Cell<bool>,
     end_channel: (RefCell<mpsc::Sender<()>>, RefCell<mpsc::Receiver<()>>),
 }
 impl WsRefCellWrap {
-    fn new(url: &str, close_timeout: Option<Duration>) -> Self {
+    fn new(url: &str, config: WsClientConfig) -> Self {
         let (sender, receiver) = mpsc::channel(0);
         Self {
-            ws_wrap: RefCell::new(WebSocketWrap::new(url, close_timeout)),
-            ws_copy: RefCell::new(None),
-            ended: Cell::new(false),
+            ws_wrap: RefCell::new(WebSocketWrap::new(url, config)),
             end_channel: (RefCell::new(sender), RefCell::new(receiver)),
         }
     }
@@ -634,49 +2310,27 @@ impl WsRefCellWrap {
         let _ = self.end_channel.0.borrow_mut().try_send(());
     }
     fn send(&self, s: &str) {
-        let ws = self.ws_copy.borrow();
-        if let Some(ref ws) = *ws {
-            let _ = ws.send_with_str(s);
+        if let Some(connection) = self.ws_wrap.borrow().connection() {
+            connection.send_text(s);
         }
     }
-    async fn next_event(&self) -> Option<WrappedSocketEvent> {
-        if self.ended.get() {
-            return None;
+    fn send_bytes(&self, bytes: &[u8]) {
+        if let Some(connection) = self.ws_wrap.borrow().connection() {
+            connection.send_bytes(bytes);
         }
-        let mut wrap = self
-            .ws_wrap
-            .try_borrow_mut()
-            .expect("You ran next_event() twice at the same time. Don't do that :(");
-
-        let mut recv = self.end_channel.1.borrow_mut();
-        let next_event_future = Box::pin(wrap.next_event());
-        let end_future = recv.next();
-        let event = match future::select(next_event_future, end_future).await {
-            future::Either::Left((ev, _)) => ev?,
-            future::Either::Right(_) => WrappedSocketEvent::Ended("End() called"),
-        };
-        use WrappedSocketEvent::*;
-        match event {
-            Connected => {
-                let mut ws = self.ws_copy.borrow_mut();
-                if let Some(new) = &wrap.ws {
-                    let _ = ws.insert(new.wrapped().clone());
-                }
-            }
-            Reconnecting(_) => {
-                let mut ws = self.ws_copy.borrow_mut();
-                ws.take();
-            }
-            Ended(_) => {
-                self.ended.set(true);
-                let ws = self.ws_copy.borrow_mut().take();
-                if let Some(ref ws) = ws {
-                    let _ = ws.close();
-                    wrap.finished = true;
-                }
-            }
-            _ => {}
+    }
+}
+impl Stream for &WsRefCellWrap {
+    type Item = WrappedSocketEvent;
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut wrap = self.ws_wrap.borrow_mut();
+        if let WrapState::Finished = wrap.state {
+            return Poll::Ready(None);
         }
-        Some(event)
+        if let Poll::Ready(_) = self.end_channel.1.borrow_mut().poll_next_unpin(cx) {
+            wrap.finish();
+            return Poll::Ready(Some(WrappedSocketEvent::Ended("End() called")));
+        }
+        wrap.poll_next_unpin(cx)
     }
 }
@@ -0,0 +1,211 @@
+use super::{
+    transport::{LoopbackPeer, LoopbackServer, LoopbackTransport},
+    WebSocketState, WsApiClient, WsClientConfig,
+};
+use futures::{future, StreamExt};
+use p256::ecdsa;
+use serde_json::json;
+use std::{cell::RefCell, rc::Rc, time::Duration};
+use wasm_bindgen_test::*;
+use zend_common::{
+    api,
+    caller::{CallBuilder, CallError},
+    codec::Encoding,
+};
+
+fn loopback_client(config: WsClientConfig) -> (WsApiClient, LoopbackServer) {
+    let (transport, server) = LoopbackTransport::new();
+    let client = WsApiClient::new_with_config("loopback", config.with_transport(transport));
+    (client, server)
+}
+
+fn calls() -> Rc<RefCell<CallBuilder<ecdsa::SigningKey>>> {
+    let key = ecdsa::SigningKey::random(&mut rand_core::OsRng);
+    Rc::new(RefCell::new(CallBuilder::new(key, || 1_700_000_000)))
+}
+
+fn room(id: u64) -> api::RoomId {
+    api::RoomId::try_from(id).unwrap()
+}
+
+fn subscribe_args(room_id: api::RoomId) -> api::SubscribeToRoomArgs {
+    api::SubscribeToRoomArgs {
+        room_id,
+        filter: Default::default(),
+        ignore_presence: false,
+        last_will: None,
+    }
+}
+
+/** The ID of the next call the client makes, skipping its hello and pings */
+async fn next_call_id(peer: &mut LoopbackPeer) -> u64 {
+    loop {
+        let message = peer.next_message().await.expect("The client disconnected");
+        if let Some(call_id) = message.call_id() {
+            return call_id;
+        }
+    }
+}
+
+fn send_return(peer: &LoopbackPeer, call_id: u64, value: serde_json::Value) {
+    let call_return = api::MethodCallReturn {
+        call_id,
+        received_at: 0,
+        return_data: api::MethodCallReturnVariants::Success(api::MethodCallSuccess::Value(value)),
+    };
+    peer.send_message(
+        &api::ServerToClientMessage::MethodCallReturn(call_return),
+        Encoding::Json,
+    )
+    .unwrap();
+}
+
+fn send_data(peer: &LoopbackPeer, subscription_id: u64, room_id: api::RoomId, data: &str) {
+    let data = api::SubscriptionData {
+        subscription_id,
+        room_id,
+        seq: 0,
+        received_at: 0,
+        sender_id: calls().borrow().caller_id(),
+        nonce: api::Nonce::new(1_700_000_000),
+        data: json!(data),
+    };
+    peer.send_message(&data.into_message(), Encoding::Json)
+        .unwrap();
+}
+
+// Lets the client's tasks catch up with what was sent to it
+async fn settle() {
+    gloo_timers::future::sleep(Duration::from_millis(20)).await;
+}
+
+/** Subscribes to `room_id`, with the server returning `subscription_id` */
+async fn subscribe(
+    client: &WsApiClient,
+    server: &mut LoopbackServer,
+    room_id: api::RoomId,
+    subscription_id: u64,
+) -> LoopbackPeer {
+    let subscribing = client.subscribe_to_room(calls(), subscribe_args(room_id));
+    let serving = async {
+        let mut peer = server.next().await.unwrap();
+        let call_id = next_call_id(&mut peer).await;
+        send_return(
+            &peer,
+            call_id,
+            json!({ "subscription_id": subscription_id }),
+        );
+        peer
+    };
+    let (success, peer) = future::join(subscribing, serving).await;
+    assert_eq!(success.unwrap().subscription_id, subscription_id);
+    peer
+}
+
+#[wasm_bindgen_test]
+async fn subscription_data_only_goes_to_its_subscription() {
+    let (client, mut server) = loopback_client(WsClientConfig::new());
+    let peer = subscribe(&client, &mut server, room(1), 7).await;
+    let mut data = client.room_data_stream(7).boxed_local();
+    send_data(&peer, 8, room(1), "someone else's");
+    send_data(&peer, 7, room(1), "ours");
+    let received = data.next().await.unwrap();
+    assert_eq!(received.subscription_id, 7);
+    assert_eq!(received.data, json!("ours"));
+}
+
+#[wasm_bindgen_test]
+async fn renewed_subscriptions_keep_their_first_id() {
+    let config = WsClientConfig::new().with_initial_backoff(Duration::ZERO);
+    let (client, mut server) = loopback_client(config);
+    let peer = subscribe(&client, &mut server, room(1), 7).await;
+    let mut data = client.room_data_stream(7).boxed_local();
+    // Losing the connection makes the client connect again and renew the subscription
+    drop(peer);
+    let mut peer = server.next().await.unwrap();
+    let call_id = next_call_id(&mut peer).await;
+    send_return(&peer, call_id, json!({ "subscription_id": 9 }));
+    settle().await;
+    send_data(&peer, 9, room(1), "after reconnecting");
+    let received = data.next().await.unwrap();
+    assert_eq!(received.subscription_id, 7);
+    assert_eq!(received.data, json!("after reconnecting"));
+}
+
+#[wasm_bindgen_test]
+fn backoff_doubles_up_to_the_maximum() {
+    let config = WsClientConfig::new()
+        .with_initial_backoff(Duration::from_secs(1))
+        .with_max_backoff(Duration::from_secs(10));
+    let backoffs: Vec<u64> = (1..=6).map(|v| config.backoff(v).as_secs()).collect();
+    assert_eq!(backoffs, [1, 2, 4, 8, 10, 10]);
+    assert_eq!(config.backoff(u32::MAX), Duration::from_secs(10));
+}
+
+#[wasm_bindgen_test]
+fn jitter_only_shortens_the_backoff() {
+    let config = WsClientConfig::new()
+        .with_initial_backoff(Duration::from_secs(8))
+        .with_max_backoff(Duration::from_secs(8))
+        .with_jitter(0.5);
+    for _ in 0..20 {
+        let backoff = config.backoff(1);
+        assert!(backoff <= Duration::from_secs(8));
+        assert!(backoff >= Duration::from_secs(4));
+    }
+}
+
+#[wasm_bindgen_test]
+async fn gives_up_after_the_maximum_attempts() {
+    let config = WsClientConfig::new()
+        .with_initial_backoff(Duration::ZERO)
+        .with_max_reconnect_attempts(Some(2));
+    let (client, server) = loopback_client(config);
+    server.refuse_connections(true);
+    let mut states = client.state_stream().boxed_local();
+    while let Some(change) = states.next().await {
+        if change.state == WebSocketState::Ended {
+            break;
+        }
+    }
+    assert_eq!(client.state(), WebSocketState::Ended);
+}
+
+#[wasm_bindgen_test]
+async fn returns_go_to_the_calls_they_answer() {
+    let (client, mut server) = loopback_client(WsClientConfig::new());
+    let calls = calls();
+    let first = client.call(&calls, subscribe_args(room(1)));
+    let second = client.call(&calls, subscribe_args(room(2)));
+    let serving = async {
+        let mut peer = server.next().await.unwrap();
+        let first_id = next_call_id(&mut peer).await;
+        let second_id = next_call_id(&mut peer).await;
+        // Returns of calls nobody made are ignored, and the others can come in any order
+        send_return(
+            &peer,
+            first_id.max(second_id) + 100,
+            json!({ "subscription_id": 0 }),
+        );
+        send_return(&peer, second_id, json!({ "subscription_id": 2 }));
+        send_return(&peer, first_id, json!({ "subscription_id": 1 }));
+        peer
+    };
+    let (first, second, _peer) = future::join3(first, second, serving).await;
+    assert_eq!(first.unwrap().subscription_id, 1);
+    assert_eq!(second.unwrap().subscription_id, 2);
+}
+
+#[wasm_bindgen_test]
+async fn calls_time_out_without_a_return() {
+    let (client, mut server) = loopback_client(WsClientConfig::new());
+    let calls = calls();
+    let call = client.call_with_timeout(&calls, subscribe_args(room(1)), Duration::from_millis(50));
+    let serving = async {
+        let mut peer = server.next().await.unwrap();
+        next_call_id(&mut peer).await;
+        peer
+    };
+    let (result, _peer) = future::join(call, serving).await;
+    assert!(matches!(result, Err(CallError::Timeout)));
+}
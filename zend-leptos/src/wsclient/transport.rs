@@ -0,0 +1,229 @@
+use futures::{
+    channel::mpsc,
+    future::{self, FutureExt},
+    stream::{Stream, StreamExt},
+};
+use std::{
+    cell::Cell,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
+use ws_stream_wasm::{WsMessage, WsMeta, WsStream};
+use zend_common::{api, codec::Encoding};
+
+/** A websocket frame. Text frames hold JSON and binary frames CBOR. */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/** How a `WsApiClient` reaches the server, see `WsClientConfig::with_transport`.
+`WsStreamTransport` opens real websockets, `LoopbackTransport` has the page play the server. */
+pub trait Transport: std::fmt::Debug {
+    /** Failing is handled like failing to open a websocket, so the client tries again later */
+    fn connect(
+        &self,
+        url: &str,
+    ) -> future::LocalBoxFuture<'static, Result<Box<dyn Connection>, String>>;
+}
+
+/** A connection made by a `Transport`, yielding what the server sends until it's lost */
+pub trait Connection: Stream<Item = Frame> + Unpin {
+    fn send_text(&self, text: &str);
+    fn send_bytes(&self, bytes: &[u8]);
+    fn close(&self, code: u16);
+}
+
+/** The default transport */
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WsStreamTransport;
+impl Transport for WsStreamTransport {
+    fn connect(
+        &self,
+        url: &str,
+    ) -> future::LocalBoxFuture<'static, Result<Box<dyn Connection>, String>> {
+        let url = url.to_owned();
+        async move {
+            let (_, wsio) = WsMeta::connect(url, None)
+                .await
+                .map_err(|err| err.to_string())?;
+            Ok(Box::new(WsStreamConnection(wsio)) as Box<dyn Connection>)
+        }
+        .boxed_local()
+    }
+}
+
+struct WsStreamConnection(WsStream);
+impl Stream for WsStreamConnection {
+    type Item = Frame;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_next_unpin(cx).map(|message| {
+            message.map(|message| match message {
+                WsMessage::Text(text) => Frame::Text(text),
+                WsMessage::Binary(bytes) => Frame::Binary(bytes),
+            })
+        })
+    }
+}
+impl Connection for WsStreamConnection {
+    fn send_text(&self, text: &str) {
+        let _ = self.0.wrapped().send_with_str(text);
+    }
+    fn send_bytes(&self, bytes: &[u8]) {
+        let _ = self.0.wrapped().send_with_u8_array(bytes);
+    }
+    fn close(&self, code: u16) {
+        let _ = self.0.wrapped().close_with_code(code);
+    }
+}
+
+/** Connects to a `LoopbackServer` in the same page instead of a server, so what the client does
+can be tested without one. The URL is ignored. */
+#[derive(Debug, Clone)]
+pub struct LoopbackTransport {
+    connections: mpsc::UnboundedSender<LoopbackPeer>,
+    refuse: Rc<Cell<bool>>,
+}
+#[allow(dead_code)]
+impl LoopbackTransport {
+    pub fn new() -> (Self, LoopbackServer) {
+        let (sender, receiver) = mpsc::unbounded();
+        let refuse = Rc::new(Cell::new(false));
+        let transport = Self {
+            connections: sender,
+            refuse: Rc::clone(&refuse),
+        };
+        let server = LoopbackServer {
+            connections: receiver,
+            refuse,
+        };
+        (transport, server)
+    }
+}
+impl Transport for LoopbackTransport {
+    fn connect(
+        &self,
+        _url: &str,
+    ) -> future::LocalBoxFuture<'static, Result<Box<dyn Connection>, String>> {
+        if self.refuse.get() {
+            return future::ready(Err("Connection refused".into())).boxed_local();
+        }
+        let (to_client, from_server) = mpsc::unbounded();
+        let (to_server, from_client) = mpsc::unbounded();
+        let close_code = Rc::new(Cell::new(None));
+        let peer = LoopbackPeer {
+            to_client,
+            from_client,
+            close_code: Rc::clone(&close_code),
+        };
+        let connection = LoopbackConnection {
+            from_server,
+            to_server,
+            close_code,
+        };
+        let result = match self.connections.unbounded_send(peer) {
+            Ok(_) => Ok(Box::new(connection) as Box<dyn Connection>),
+            Err(_) => Err("The loopback server was dropped".into()),
+        };
+        future::ready(result).boxed_local()
+    }
+}
+
+/** Yields the server's end of every connection made with its `LoopbackTransport` */
+#[derive(Debug)]
+pub struct LoopbackServer {
+    connections: mpsc::UnboundedReceiver<LoopbackPeer>,
+    refuse: Rc<Cell<bool>>,
+}
+#[allow(dead_code)]
+impl LoopbackServer {
+    /** Connection attempts fail while set, as if the server was down */
+    pub fn refuse_connections(&self, refuse: bool) {
+        self.refuse.set(refuse);
+    }
+}
+impl Stream for LoopbackServer {
+    type Item = LoopbackPeer;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.connections.poll_next_unpin(cx)
+    }
+}
+
+/** The server's end of a loopback connection, yielding what the client sends. Dropping it loses
+the connection, as far as the client can tell. */
+#[derive(Debug)]
+pub struct LoopbackPeer {
+    to_client: mpsc::UnboundedSender<Frame>,
+    from_client: mpsc::UnboundedReceiver<Frame>,
+    close_code: Rc<Cell<Option<u16>>>,
+}
+#[allow(dead_code)]
+impl LoopbackPeer {
+    pub fn send_frame(&self, frame: Frame) {
+        let _ = self.to_client.unbounded_send(frame);
+    }
+    /** Sent uncompressed, so compression shouldn't be agreed on in the hello */
+    pub fn send_message(
+        &self,
+        message: &api::ServerToClientMessage,
+        encoding: Encoding,
+    ) -> Result<(), String> {
+        let bytes = encoding.encode(message)?;
+        self.send_frame(match encoding.is_binary() {
+            true => Frame::Binary(bytes),
+            false => Frame::Text(String::from_utf8_lossy(&bytes).into_owned()),
+        });
+        Ok(())
+    }
+    /** The next message from the client, skipping frames that don't parse. Like `send_message`,
+    only works while the connection is uncompressed. */
+    pub async fn next_message(&mut self) -> Option<api::ClientToServerMessage> {
+        while let Some(frame) = self.next().await {
+            let message = match frame {
+                Frame::Text(text) => Encoding::Json.decode(text.as_bytes()),
+                Frame::Binary(bytes) => Encoding::Cbor.decode(&bytes),
+            };
+            if let Ok(message) = message {
+                return Some(message);
+            }
+        }
+        None
+    }
+    /** What the client closed the connection with, if it did */
+    pub fn close_code(&self) -> Option<u16> {
+        self.close_code.get()
+    }
+}
+impl Stream for LoopbackPeer {
+    type Item = Frame;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.from_client.poll_next_unpin(cx)
+    }
+}
+
+struct LoopbackConnection {
+    from_server: mpsc::UnboundedReceiver<Frame>,
+    to_server: mpsc::UnboundedSender<Frame>,
+    close_code: Rc<Cell<Option<u16>>>,
+}
+impl Stream for LoopbackConnection {
+    type Item = Frame;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.from_server.poll_next_unpin(cx)
+    }
+}
+impl Connection for LoopbackConnection {
+    fn send_text(&self, text: &str) {
+        let _ = self.to_server.unbounded_send(Frame::Text(text.into()));
+    }
+    fn send_bytes(&self, bytes: &[u8]) {
+        let _ = self.to_server.unbounded_send(Frame::Binary(bytes.to_vec()));
+    }
+    // Ends the peer's stream, like the server seeing the close frame
+    fn close(&self, code: u16) {
+        self.close_code.set(Some(code));
+        self.to_server.close_channel();
+    }
+}
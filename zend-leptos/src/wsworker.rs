@@ -0,0 +1,210 @@
+use crate::wsclient::{
+    ApiClientEvent, EventSubscriptionHandle, SubscriptionEventFilter, WsApiClient, WsClientConfig,
+    DEFAULT_CLOSE_TIMEOUT,
+};
+use futures::{channel::mpsc, lock::Mutex, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+use zend_common::{
+    _use::wasm_bindgen::{prelude::*, JsCast},
+    api, log,
+};
+
+/** Where index.html has trunk put the loader of the `ws_worker` binary */
+#[allow(dead_code)]
+pub const DEFAULT_WORKER_URL: &str = "./ws_worker_loader.js";
+
+/** From a client to its worker */
+#[derive(Debug, Serialize, Deserialize)]
+enum WorkerCommand {
+    Connect {
+        url: String,
+        config: WsClientConfig,
+    },
+    Send {
+        message: api::ClientToServerMessage,
+        binary: bool,
+    },
+    End,
+}
+
+/** From a worker to its client. Only what the client can't work out itself. */
+#[derive(Debug, Serialize, Deserialize)]
+enum WorkerEvent {
+    Connected,
+    Reconnecting(u64),
+    Message(api::ServerToClientMessage),
+    Ended,
+}
+
+// Structured clones keep numbers as JS numbers, which can't hold every u64
+fn to_js<T: Serialize>(value: &T) -> Result<JsValue, serde_wasm_bindgen::Error> {
+    let serializer = serde_wasm_bindgen::Serializer::new()
+        .serialize_large_number_types_as_bigints(true)
+        .serialize_maps_as_objects(true);
+    value.serialize(&serializer)
+}
+
+/** The client's side of a worker running its socket, see `WsClientConfig::with_worker` */
+#[derive(Debug)]
+pub struct WorkerBridge {
+    worker: web_sys::Worker,
+    // Callers of `next_event` wait for their turn, in order
+    events: Mutex<mpsc::UnboundedReceiver<ApiClientEvent>>,
+    ended: Cell<bool>,
+    // Kept for as long as the worker may call them
+    _on_message: Closure<dyn FnMut(web_sys::MessageEvent)>,
+    _on_error: Closure<dyn FnMut(web_sys::Event)>,
+}
+impl WorkerBridge {
+    pub fn new(worker_url: &str, url: &str, config: &WsClientConfig) -> Result<Self, JsValue> {
+        let worker = web_sys::Worker::new(worker_url)?;
+        let (sender, events) = mpsc::unbounded();
+        let message_sender = sender.clone();
+        let on_message = Closure::<dyn FnMut(web_sys::MessageEvent)>::new(
+            move |message: web_sys::MessageEvent| {
+                let event = match serde_wasm_bindgen::from_value(message.data()) {
+                    Ok(v) => v,
+                    Err(err) => {
+                        log!("unreadable message from the websocket worker: {}", err);
+                        return;
+                    }
+                };
+                let _ = message_sender.unbounded_send(match event {
+                    WorkerEvent::Connected => ApiClientEvent::Connected,
+                    WorkerEvent::Reconnecting(secs) => ApiClientEvent::Reconnecting(secs),
+                    WorkerEvent::Message(message) => ApiClientEvent::ApiMessage(message),
+                    WorkerEvent::Ended => ApiClientEvent::Ended,
+                });
+            },
+        );
+        // A worker that failed to load or crashed won't say it ended
+        let on_error = Closure::<dyn FnMut(web_sys::Event)>::new(move |_: web_sys::Event| {
+            log!("the websocket worker failed");
+            let _ = sender.unbounded_send(ApiClientEvent::Ended);
+        });
+        worker.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        worker.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+        let bridge = Self {
+            worker,
+            events: Mutex::new(events),
+            ended: Cell::new(false),
+            _on_message: on_message,
+            _on_error: on_error,
+        };
+        bridge.post(&WorkerCommand::Connect {
+            url: url.into(),
+            config: config.clone(),
+        })?;
+        Ok(bridge)
+    }
+
+    fn post(&self, command: &WorkerCommand) -> Result<(), JsValue> {
+        self.worker.post_message(&to_js(command)?)
+    }
+
+    /** The worker encodes the message as negotiated, or as CBOR if `binary` */
+    pub fn send(&self, message: &api::ClientToServerMessage, binary: bool) -> Result<(), ()> {
+        let command = WorkerCommand::Send {
+            message: message.clone(),
+            binary,
+        };
+        self.post(&command).map_err(|_| ())
+    }
+
+    /** The worker closes its socket and answers with `Ended`, after which it's terminated */
+    pub fn end(&self) {
+        if self.post(&WorkerCommand::End).is_err() {
+            self.worker.terminate();
+        }
+    }
+
+    pub async fn next_event(&self) -> Option<ApiClientEvent> {
+        let mut events = self.events.lock().await;
+        // Checked after waiting, as the caller before may have gotten the last event
+        if self.ended.get() {
+            return None;
+        }
+        let event = events.next().await;
+        if matches!(event, None | Some(ApiClientEvent::Ended)) {
+            self.ended.set(true);
+            self.worker.terminate();
+        }
+        event
+    }
+}
+
+/** Runs in the worker, see src/bin/ws_worker.rs. Connects a `WsApiClient` when told to and
+passes on everything it receives, parsed. */
+pub fn run_worker() {
+    let scope: web_sys::DedicatedWorkerGlobalScope = js_sys::global().unchecked_into();
+    let worker_scope = scope.clone();
+    let client: Rc<RefCell<Option<WsApiClient>>> = Default::default();
+    let on_message =
+        Closure::<dyn FnMut(web_sys::MessageEvent)>::new(move |message: web_sys::MessageEvent| {
+            let command = match serde_wasm_bindgen::from_value(message.data()) {
+                Ok(v) => v,
+                Err(err) => {
+                    log!("unreadable message from the websocket client: {}", err);
+                    return;
+                }
+            };
+            match command {
+                WorkerCommand::Connect { url, config } => {
+                    let config = config.with_worker(None).with_shared_socket(false);
+                    let ws_client = WsApiClient::new_with_config(&url, config);
+                    let events = ws_client.receive_events(SubscriptionEventFilter::new().any());
+                    wasm_bindgen_futures::spawn_local(forward_events(worker_scope.clone(), events));
+                    client.borrow_mut().replace(ws_client);
+                }
+                WorkerCommand::Send { message, binary } => {
+                    if let Some(ws_client) = &*client.borrow() {
+                        let _ = match binary {
+                            true => ws_client.send_message_binary(&message),
+                            false => ws_client.send_message(&message),
+                        };
+                    }
+                }
+                WorkerCommand::End => {
+                    let ws_client = client.borrow_mut().take();
+                    if let Some(ws_client) = ws_client {
+                        wasm_bindgen_futures::spawn_local(async move {
+                            let _ = ws_client.close(DEFAULT_CLOSE_TIMEOUT).await;
+                        });
+                    }
+                }
+            }
+        });
+    scope.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+    // Needed until the worker is terminated
+    on_message.forget();
+}
+
+async fn forward_events(
+    scope: web_sys::DedicatedWorkerGlobalScope,
+    mut events: EventSubscriptionHandle,
+) {
+    while let Some(event) = events.next().await {
+        let event = match event {
+            ApiClientEvent::Connected => WorkerEvent::Connected,
+            ApiClientEvent::Reconnecting(secs) => WorkerEvent::Reconnecting(secs),
+            ApiClientEvent::ApiMessage(message) => WorkerEvent::Message(message),
+            ApiClientEvent::Ended => WorkerEvent::Ended,
+            // The client measures latency itself, and a lag here isn't the client's
+            _ => continue,
+        };
+        let ended = matches!(event, WorkerEvent::Ended);
+        match to_js(&event) {
+            Ok(event) => {
+                let _ = scope.post_message(&event);
+            }
+            Err(err) => log!("failed to pass on an event: {}", err),
+        }
+        if ended {
+            break;
+        }
+    }
+}
@@ -0,0 +1,2 @@
+importScripts("./ws_worker.js");
+wasm_bindgen("./ws_worker_bg.wasm");
@@ -1,6 +1,4 @@
-export { default } from '../build/worker/shim.mjs'
-export { Room } from './room'
-export { Peer } from './peer'
+export { default, Room, Peer, Connection } from '../build/worker/shim.mjs'
 
 /**
  * Welcome to Cloudflare Workers! This is your first worker.
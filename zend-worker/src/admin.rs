@@ -0,0 +1,122 @@
+use crate::{
+    metrics, peer_api,
+    room_api::{self, IntoRequest},
+};
+use worker::{self as w};
+use zend_common::api;
+
+/** Compares without returning early, so response times don't reveal how much of a token matched */
+fn tokens_match(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+/** The admin API is disabled entirely unless the ADMIN_TOKEN secret is set */
+fn is_authorized(req: &w::Request, env: &w::Env) -> w::Result<bool> {
+    let token = match env.secret("ADMIN_TOKEN") {
+        Ok(token) => token.to_string(),
+        Err(_) => return Ok(false),
+    };
+    if token.is_empty() {
+        return Ok(false);
+    }
+    let header = req.headers().get("Authorization")?.unwrap_or_default();
+    Ok(header
+        .strip_prefix("Bearer ")
+        .map_or(false, |given| tokens_match(given, &token)))
+}
+
+fn get_room_stub(ctx: &w::RouteContext<()>) -> w::Result<Option<w::Stub>> {
+    let room_id = match ctx.param("id").map(|v| api::RoomId::try_from(v.clone())) {
+        Some(Ok(room_id)) => room_id,
+        _ => return Ok(None),
+    };
+    Ok(Some(
+        ctx.env
+            .durable_object("ROOM")?
+            .id_from_name(&room_id.legacy_code())?
+            .get_stub()?,
+    ))
+}
+
+/** Keys are standard base64, so they have to be percent-encoded to fit into a path */
+fn get_peer_stub(ctx: &w::RouteContext<()>) -> w::Result<Option<w::Stub>> {
+    let key = match ctx.param("key") {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    let key: String = match js_sys::decode_uri_component(key) {
+        Ok(key) => key.into(),
+        Err(_) => return Ok(None),
+    };
+    let peer_id = match api::PublicKeyWrapper::try_from(key) {
+        Ok(peer_id) => peer_id,
+        Err(_) => return Ok(None),
+    };
+    Ok(Some(
+        ctx.env
+            .durable_object("PEER")?
+            .id_from_name(&peer_id.to_string())?
+            .get_stub()?,
+    ))
+}
+
+macro_rules! require_authorization {
+    ($req:expr, $ctx:expr) => {
+        if !is_authorized(&$req, &$ctx.env)? {
+            return w::Response::error("Unauthorized", 401);
+        }
+    };
+}
+
+/** Only covers the isolate serving the request, as the response's `scope` says, see
+`metrics::init` */
+pub async fn get_metrics(req: w::Request, ctx: w::RouteContext<()>) -> w::Result<w::Response> {
+    require_authorization!(req, ctx);
+    w::Response::from_json(&metrics::snapshot())
+}
+
+pub async fn get_room(req: w::Request, ctx: w::RouteContext<()>) -> w::Result<w::Response> {
+    require_authorization!(req, ctx);
+    match get_room_stub(&ctx)? {
+        Some(stub) => {
+            stub.fetch_with_request(room_api::InspectMessage {}.into_request()?)
+                .await
+        }
+        None => w::Response::error("Invalid room ID", 400),
+    }
+}
+
+pub async fn delete_room(req: w::Request, ctx: w::RouteContext<()>) -> w::Result<w::Response> {
+    require_authorization!(req, ctx);
+    // Without a deleter, the room skips its privilege check
+    let request = room_api::DeleteMessage { deleter_id: None }.into_request()?;
+    match get_room_stub(&ctx)? {
+        Some(stub) => stub.fetch_with_request(request).await,
+        None => w::Response::error("Invalid room ID", 400),
+    }
+}
+
+pub async fn get_peer(req: w::Request, ctx: w::RouteContext<()>) -> w::Result<w::Response> {
+    require_authorization!(req, ctx);
+    let request = peer_api::make_request(&peer_api::ToPeerMessage::Inspect)?;
+    match get_peer_stub(&ctx)? {
+        Some(stub) => stub.fetch_with_request(request).await,
+        None => w::Response::error("Invalid peer key", 400),
+    }
+}
+
+pub async fn reset_peer_nonces(
+    req: w::Request,
+    ctx: w::RouteContext<()>,
+) -> w::Result<w::Response> {
+    require_authorization!(req, ctx);
+    let request = peer_api::make_request(&peer_api::ToPeerMessage::ResetNonces)?;
+    match get_peer_stub(&ctx)? {
+        Some(stub) => stub.fetch_with_request(request).await,
+        None => w::Response::error("Invalid peer key", 400),
+    }
+}
@@ -0,0 +1,85 @@
+use std::str::FromStr;
+use worker as w;
+use zend_common::{api, logging};
+
+/** Reads and parses an `Env` var, falling back to `default` if it's missing or invalid */
+pub fn var_or<T: FromStr>(env: &w::Env, name: &str, default: T) -> T {
+    env.var(name)
+        .ok()
+        .and_then(|v| v.to_string().parse().ok())
+        .unwrap_or(default)
+}
+
+/** Maximum size in bytes of a single websocket message from a client */
+pub fn max_message_bytes(env: &w::Env) -> usize {
+    var_or(env, "MAX_MESSAGE_BYTES", 64 * 1024)
+}
+
+/** Maximum size in bytes of the serialised `data` of a send method */
+pub fn max_data_bytes(env: &w::Env) -> usize {
+    var_or(env, "MAX_DATA_BYTES", 48 * 1024)
+}
+
+/** Maximum nesting of arrays and objects in the `data` of a send method */
+pub fn max_data_depth(env: &w::Env) -> usize {
+    var_or(env, "MAX_DATA_DEPTH", 16)
+}
+
+/** Maximum length in bytes of any string or object key in the `data` of a send method */
+pub fn max_data_string_bytes(env: &w::Env) -> usize {
+    var_or(env, "MAX_DATA_STRING_BYTES", 16 * 1024)
+}
+
+/** Maximum number of object keys in the `data` of a send method, counted across all objects */
+pub fn max_data_keys(env: &w::Env) -> usize {
+    var_or(env, "MAX_DATA_KEYS", 256)
+}
+
+/** Maximum number of entries returned by a single history query */
+pub fn max_history_query_entries(env: &w::Env) -> usize {
+    var_or(env, "MAX_HISTORY_QUERY_ENTRIES", 100)
+}
+
+/** Maximum number of receivers of a single multicast */
+pub fn max_multicast_receivers(env: &w::Env) -> usize {
+    var_or(env, "MAX_MULTICAST_RECEIVERS", 64)
+}
+
+/** How often the server checks client connections and sends them a heartbeat */
+pub fn keepalive_interval_secs(env: &w::Env) -> u64 {
+    var_or(env, "KEEPALIVE_INTERVAL_SECS", 20)
+}
+
+/** Client connections that haven't sent anything for this long are closed. 0 leaves them open,
+kept alive by the keepalive alone. */
+pub fn connection_idle_timeout_secs(env: &w::Env) -> u64 {
+    var_or(env, "CONNECTION_IDLE_TIMEOUT_SECS", 60)
+}
+
+/** The window call timestamps have to be in, from MAX_CLOCK_SKEW_SECS and MAX_CALL_AGE_SECS,
+and how often clients have to ping to stay within the idle timeout */
+pub fn protocol_config(env: &w::Env) -> api::ProtocolConfig {
+    let default = api::ProtocolConfig::default();
+    let idle_timeout_secs = connection_idle_timeout_secs(env);
+    api::ProtocolConfig {
+        max_clock_skew_secs: var_or(env, "MAX_CLOCK_SKEW_SECS", default.max_clock_skew_secs),
+        max_call_age_secs: var_or(env, "MAX_CALL_AGE_SECS", default.max_call_age_secs),
+        // Often enough that a late ping or two doesn't get the connection closed
+        ping_interval_secs: (idle_timeout_secs > 0).then(|| (idle_timeout_secs / 3).max(1)),
+    }
+}
+
+/** How long sessions last if the client doesn't say */
+pub fn session_ttl_secs(env: &w::Env) -> u64 {
+    var_or(env, "SESSION_TTL_SECS", 60 * 60)
+}
+
+/** Longest session a client can ask for */
+pub fn max_session_ttl_secs(env: &w::Env) -> u64 {
+    var_or(env, "MAX_SESSION_TTL_SECS", 24 * 60 * 60)
+}
+
+/** Applies LOG_LEVEL (debug, info, warn or error) to this isolate */
+pub fn init_logging(env: &w::Env) {
+    logging::set_min_level(var_or(env, "LOG_LEVEL", logging::Level::Info));
+}
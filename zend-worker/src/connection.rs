@@ -0,0 +1,320 @@
+use crate::{
+    config, metrics,
+    rate_limit::RateLimiter,
+    session::Session,
+    websocket::{
+        self, ClientFrame, ClientSocket, InFlightCalls, SessionState, SubscriptionRegistry,
+        WebSocketExt,
+    },
+};
+use serde::{Deserialize, Serialize};
+use std::{cell::RefCell, rc::Rc};
+use wasm_bindgen::{prelude::*, JsCast};
+use worker::{self as w, durable_object, DurableObject};
+use zend_common::{
+    api,
+    codec::Encoding,
+    log_debug, log_info, log_warn,
+    logging::{self, LogContext},
+};
+
+// The hibernation parts of the runtime API, which worker doesn't have bindings for (yet)
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(extends = js_sys::Object)]
+    type HibernationState;
+    #[wasm_bindgen(method, catch, js_name = acceptWebSocket)]
+    fn accept_web_socket(this: &HibernationState, ws: &web_sys::WebSocket) -> Result<(), JsValue>;
+    #[wasm_bindgen(method, js_name = getWebSockets)]
+    fn get_web_sockets(this: &HibernationState) -> js_sys::Array;
+    #[wasm_bindgen(method, getter)]
+    fn storage(this: &HibernationState) -> HibernationStorage;
+
+    // Only what's needed for alarms, as w::Storage can't be made from the state we keep
+    #[wasm_bindgen(extends = js_sys::Object)]
+    type HibernationStorage;
+    #[wasm_bindgen(method, catch, js_name = setAlarm)]
+    fn set_alarm(
+        this: &HibernationStorage,
+        scheduled_time: f64,
+    ) -> Result<js_sys::Promise, JsValue>;
+
+    #[wasm_bindgen(extends = js_sys::Object)]
+    type HibernatableWebSocket;
+    #[wasm_bindgen(method, catch, js_name = serializeAttachment)]
+    fn serialize_attachment(this: &HibernatableWebSocket, value: &JsValue) -> Result<(), JsValue>;
+    #[wasm_bindgen(method, js_name = deserializeAttachment)]
+    fn deserialize_attachment(this: &HibernatableWebSocket) -> JsValue;
+}
+
+/** Per-connection state that has to survive the object being evicted while hibernating */
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConnectionAttachment {
+    /** Included in every log line about this connection */
+    #[serde(default)]
+    pub connection_id: String,
+    pub connected_at: u64,
+    /** When the client last sent anything, in milliseconds */
+    pub last_active: u64,
+    /** Chosen with the `encoding` query parameter when connecting, or in the client's hello */
+    #[serde(default)]
+    pub encoding: Encoding,
+    /** Negotiated with the client's hello, if it sent one */
+    #[serde(default)]
+    pub protocol_version: Option<u32>,
+    #[serde(default)]
+    pub compression: bool,
+    #[serde(default)]
+    pub session: Option<Session>,
+    #[serde(default)]
+    pub session_challenge: Option<String>,
+}
+impl ConnectionAttachment {
+    fn log_context(&self) -> LogContext {
+        LogContext::connection(self.connection_id.clone())
+    }
+    fn get(ws: &web_sys::WebSocket) -> Option<Self> {
+        let json = ws
+            .unchecked_ref::<HibernatableWebSocket>()
+            .deserialize_attachment()
+            .as_string()?;
+        serde_json::from_str(&json).ok()
+    }
+    fn set(&self, ws: &web_sys::WebSocket) -> w::Result<()> {
+        let json = serde_json::to_string(self)?;
+        ws.unchecked_ref::<HibernatableWebSocket>()
+            .serialize_attachment(&JsValue::from_str(&json))?;
+        Ok(())
+    }
+}
+
+/** Terminates a single client websocket. Its event handlers are stateless, so the
+runtime can evict the object while the connection is idle without dropping it. */
+#[durable_object]
+pub struct Connection {
+    state: HibernationState,
+    env: Rc<w::Env>,
+    // Recreated when waking up from hibernation, which is fine as the buckets would have refilled
+    rate_limiter: Rc<RefCell<RateLimiter>>,
+    // Open subscriptions keep the object from being evicted, so this doesn't have to survive that
+    subscriptions: Rc<RefCell<SubscriptionRegistry>>,
+    // Same as subscriptions, as calls in flight keep the object from being evicted too
+    calls: Rc<RefCell<InFlightCalls>>,
+    // Shared by messages handled at the same time, so none of them undoes another's changes.
+    // Restored from the attachment after waking up from hibernation.
+    session_state: Option<Rc<RefCell<SessionState>>>,
+}
+
+impl Connection {
+    async fn schedule_keepalive(&self) -> w::Result<()> {
+        let interval_ms = config::keepalive_interval_secs(&self.env) * 1000;
+        let scheduled_time = (w::Date::now().as_millis() + interval_ms) as f64;
+        let promise = self.state.storage().set_alarm(scheduled_time)?;
+        wasm_bindgen_futures::JsFuture::from(promise).await?;
+        Ok(())
+    }
+}
+
+#[durable_object]
+impl DurableObject for Connection {
+    fn new(state: w::State, env: w::Env) -> Self {
+        config::init_logging(&env);
+        metrics::init(&env);
+        Self {
+            state: state._inner().unchecked_into(),
+            rate_limiter: Rc::new(RefCell::new(RateLimiter::from_env(&env))),
+            subscriptions: Default::default(),
+            calls: Default::default(),
+            session_state: None,
+            env: Rc::new(env),
+        }
+    }
+
+    async fn fetch(&mut self, req: w::Request) -> w::Result<w::Response> {
+        let encoding = req
+            .url()?
+            .query_pairs()
+            .find(|(key, _)| key == "encoding")
+            .and_then(|(_, value)| value.parse().ok())
+            .unwrap_or_default();
+        let pair = w::WebSocketPair::new()?;
+        let server: &web_sys::WebSocket = pair.server.as_ref();
+        self.state.accept_web_socket(server)?;
+        let now = w::Date::now().as_millis();
+        let attachment = ConnectionAttachment {
+            connection_id: logging::new_correlation_id(),
+            connected_at: now,
+            last_active: now,
+            encoding,
+            protocol_version: None,
+            compression: false,
+            session: None,
+            session_challenge: None,
+        };
+        attachment.set(server)?;
+        log_info!(ctx: attachment.log_context(), "Websocket connected");
+        self.schedule_keepalive().await?;
+        w::Response::from_websocket(pair.client)
+    }
+
+    async fn alarm(&mut self) -> w::Result<w::Response> {
+        let now = w::Date::now().as_millis();
+        let timeout_ms = config::connection_idle_timeout_secs(&self.env) * 1000;
+        let mut any_open = false;
+        for ws in self.state.get_web_sockets().iter() {
+            let ws: web_sys::WebSocket = ws.unchecked_into();
+            let attachment = ConnectionAttachment::get(&ws);
+            let last_active = attachment.as_ref().map_or(0, |v| v.last_active);
+            let encoding = attachment.as_ref().map(|v| v.encoding).unwrap_or_default();
+            let protocol_version = attachment.as_ref().and_then(|v| v.protocol_version);
+            let compression = attachment.as_ref().map_or(false, |v| v.compression);
+            let ws = ClientSocket::new(ws.into(), encoding, protocol_version)
+                .with_compression(compression);
+            if timeout_ms > 0 && now.saturating_sub(last_active) > timeout_ms {
+                let log_ctx = attachment.map(|v| v.log_context()).unwrap_or_default();
+                log_info!(ctx: log_ctx, "Closing idle websocket");
+                // Reconnecting right away is fine, the client just has to use the connection
+                ws.go_away(api::CloseCode::IdleTimeout, Some(0));
+                self.subscriptions.borrow_mut().close_all();
+                continue;
+            }
+            // Gives clients (and anything in between) a reason to consider the connection alive
+            ws.nfsendj(&api::ServerToClientMessage::info("keepalive"));
+            any_open = true;
+        }
+        if any_open {
+            self.schedule_keepalive().await?;
+        }
+        w::Response::empty()
+    }
+}
+
+#[wasm_bindgen]
+impl Connection {
+    #[wasm_bindgen(js_name = webSocketMessage)]
+    pub fn websocket_message(
+        &mut self,
+        ws: web_sys::WebSocket,
+        message: JsValue,
+    ) -> js_sys::Promise {
+        let mut attachment = ConnectionAttachment::get(&ws);
+        let log_ctx = attachment
+            .as_ref()
+            .map(|v| v.log_context())
+            .unwrap_or_default();
+        let frame = if let Some(text) = message.as_string() {
+            ClientFrame::Text(text)
+        } else if message.is_instance_of::<js_sys::ArrayBuffer>() {
+            ClientFrame::Binary(js_sys::Uint8Array::new(&message).to_vec())
+        } else {
+            log_debug!(ctx: log_ctx, "Ignored a message of unknown type");
+            return js_sys::Promise::resolve(&JsValue::UNDEFINED);
+        };
+        let encoding = attachment.as_ref().map(|v| v.encoding).unwrap_or_default();
+        let protocol_version = attachment.as_ref().and_then(|v| v.protocol_version);
+        let compression = attachment.as_ref().map_or(false, |v| v.compression);
+        let session_state = self
+            .session_state
+            .get_or_insert_with(|| {
+                Rc::new(RefCell::new(SessionState {
+                    session: attachment.as_ref().and_then(|v| v.session.clone()),
+                    challenge: attachment
+                        .as_ref()
+                        .and_then(|v| v.session_challenge.clone()),
+                }))
+            })
+            .clone();
+        if let Some(attachment) = &mut attachment {
+            attachment.last_active = w::Date::now().as_millis();
+            if let Err(err) = attachment.set(&ws) {
+                log_warn!(ctx: log_ctx, "Failed to update connection attachment. {}", err);
+            }
+        }
+        let env = self.env.clone();
+        let rate_limiter = self.rate_limiter.clone();
+        let subscriptions = self.subscriptions.clone();
+        let calls = self.calls.clone();
+        let client = Rc::new(
+            ClientSocket::new(ws.clone().into(), encoding, protocol_version)
+                .with_compression(compression)
+                .with_session_state(session_state),
+        );
+        wasm_bindgen_futures::future_to_promise(async move {
+            websocket::handle_message(
+                env,
+                frame,
+                client.clone(),
+                rate_limiter,
+                subscriptions,
+                calls,
+                log_ctx.clone(),
+            )
+            .await;
+            // Stored so the connection is handled the same way after waking up from hibernation.
+            // Read again, and only what this message changed is written, as other messages may
+            // have been handled in the meantime.
+            let session_state = client.changed_session_state();
+            let hello_state_changed = client.protocol_version() != protocol_version
+                || client.encoding() != encoding
+                || client.compression() != compression;
+            if hello_state_changed || session_state.is_some() {
+                if let Some(mut attachment) = ConnectionAttachment::get(&ws) {
+                    if hello_state_changed {
+                        attachment.protocol_version = client.protocol_version();
+                        attachment.encoding = client.encoding();
+                        attachment.compression = client.compression();
+                    }
+                    if let Some((session, session_challenge)) = session_state {
+                        attachment.session = session;
+                        attachment.session_challenge = session_challenge;
+                    }
+                    if let Err(err) = attachment.set(&ws) {
+                        log_warn!(ctx: log_ctx, "Failed to store the connection state. {}", err);
+                    }
+                }
+            }
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    #[wasm_bindgen(js_name = webSocketClose)]
+    pub fn websocket_close(
+        &mut self,
+        ws: web_sys::WebSocket,
+        code: u16,
+        reason: String,
+        _was_clean: bool,
+    ) -> js_sys::Promise {
+        let attachment = ConnectionAttachment::get(&ws);
+        let log_ctx = attachment
+            .as_ref()
+            .map(|v| v.log_context())
+            .unwrap_or_default();
+        let connected_for_ms = attachment.map_or(0, |v| {
+            w::Date::now().as_millis().saturating_sub(v.connected_at)
+        });
+        log_info!(
+            ctx: log_ctx,
+            "Websocket closed ({}, {:?}) after {}ms",
+            code,
+            reason,
+            connected_for_ms
+        );
+        // Complete the closing handshake from our side
+        w::WebSocket::from(ws).close_with(api::CloseCode::Normal);
+        self.subscriptions.borrow_mut().close_all();
+        self.calls.borrow_mut().cancel_all();
+        js_sys::Promise::resolve(&JsValue::UNDEFINED)
+    }
+
+    #[wasm_bindgen(js_name = webSocketError)]
+    pub fn websocket_error(&mut self, ws: web_sys::WebSocket, error: JsValue) -> js_sys::Promise {
+        let log_ctx = ConnectionAttachment::get(&ws)
+            .map(|v| v.log_context())
+            .unwrap_or_default();
+        log_warn!(ctx: log_ctx, "Error in websocket: {:?}", error);
+        self.subscriptions.borrow_mut().close_all();
+        self.calls.borrow_mut().cancel_all();
+        js_sys::Promise::resolve(&JsValue::UNDEFINED)
+    }
+}
@@ -0,0 +1,76 @@
+use crate::{
+    peer_api,
+    room_api::{self, IntoRequest},
+};
+use serde::Serialize;
+use worker::{self as w};
+use zend_common::log_warn;
+
+/** Object name used for echo requests. It never gets any storage, so it costs next to nothing. */
+const HEALTH_CHECK_OBJECT_NAME: &str = "healthz";
+
+#[derive(Serialize)]
+struct DependencyStatus {
+    ok: bool,
+    latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct HealthStatus {
+    ok: bool,
+    room: DependencyStatus,
+    peer: DependencyStatus,
+}
+
+async fn echo(env: &w::Env, binding: &str, request: w::Request) -> w::Result<()> {
+    let stub = env
+        .durable_object(binding)?
+        .id_from_name(HEALTH_CHECK_OBJECT_NAME)?
+        .get_stub()?;
+    let mut response = stub.fetch_with_request(request).await?;
+    match serde_json::from_str::<bool>(&response.text().await?)? {
+        true => Ok(()),
+        false => Err("Unexpected echo response".into()),
+    }
+}
+
+async fn check_dependency(
+    env: &w::Env,
+    binding: &str,
+    request: w::Result<w::Request>,
+) -> DependencyStatus {
+    let start = w::Date::now().as_millis();
+    let result = match request {
+        Ok(request) => echo(env, binding, request).await,
+        Err(err) => Err(err),
+    };
+    let latency_ms = w::Date::now().as_millis().saturating_sub(start);
+    if let Err(err) = &result {
+        log_warn!("Health check of {} failed. {}", binding, err);
+    }
+    DependencyStatus {
+        ok: result.is_ok(),
+        latency_ms,
+        error: result.err().map(|err| err.to_string()),
+    }
+}
+
+/** Reaches both durable object namespaces, so misconfigured bindings show up as unhealthy */
+pub async fn healthz(_req: w::Request, ctx: w::RouteContext<()>) -> w::Result<w::Response> {
+    let room = check_dependency(&ctx.env, "ROOM", room_api::EchoMessage {}.into_request()).await;
+    let peer = check_dependency(
+        &ctx.env,
+        "PEER",
+        peer_api::make_request(&peer_api::ToPeerMessage::Echo),
+    )
+    .await;
+    let status = HealthStatus {
+        ok: room.ok && peer.ok,
+        room,
+        peer,
+    };
+    let status_code = if status.ok { 200 } else { 503 };
+    Ok(w::Response::from_json(&status)?.with_status(status_code))
+}
@@ -1,5 +1,14 @@
+mod admin;
+mod config;
+mod connection;
+mod health;
+mod metrics;
+mod peer;
 mod peer_api;
+mod rate_limit;
+mod room;
 mod room_api;
+mod session;
 mod websocket;
 mod websocket_api_handlers;
 
@@ -10,22 +19,30 @@ thread_local!(static HOOK_SET: Cell<bool> = Cell::new(false));
 
 #[event(fetch)]
 async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
+    config::init_logging(&env);
     HOOK_SET.with(|is_set| {
         if !is_set.get() {
-            zend_common::log!("Set panic hook :3");
+            zend_common::log_debug!("Set panic hook :3");
             std::panic::set_hook(Box::new(|v: &std::panic::PanicInfo| {
-                zend_common::log!("Rust panicked qwq\n{}", v);
+                zend_common::log_error!("Rust panicked qwq\n{}", v);
             }));
             is_set.set(true);
         }
     });
     if req.headers().get("Upgrade")? == Some("websocket".to_string()) {
-        let pair = WebSocketPair::new()?;
-        let server = pair.server;
-        server.accept()?;
-        wasm_bindgen_futures::spawn_local(websocket::handle_ws_server(env, server));
-        Response::from_websocket(pair.client)
+        // Each client connection gets its own durable object, which lets idle connections hibernate
+        let stub = env.durable_object("CONNECTION")?.unique_id()?.get_stub()?;
+        stub.fetch_with_request(req).await
     } else {
-        Response::from_html("OK")
+        Router::new()
+            .get("/", |_, _| Response::from_html("OK"))
+            .get_async("/healthz", health::healthz)
+            .get_async("/metrics", admin::get_metrics)
+            .get_async("/admin/rooms/:id", admin::get_room)
+            .delete_async("/admin/rooms/:id", admin::delete_room)
+            .get_async("/admin/peers/:key", admin::get_peer)
+            .delete_async("/admin/peers/:key/nonces", admin::reset_peer_nonces)
+            .run(req, env)
+            .await
     }
 }
@@ -0,0 +1,162 @@
+use serde::Serialize;
+use std::{cell::RefCell, collections::BTreeMap};
+use wasm_bindgen::{prelude::*, JsCast};
+use worker as w;
+use zend_common::log_warn;
+
+/** How often accumulated metrics are written to Analytics Engine, if it's bound */
+const FLUSH_INTERVAL_MS: u64 = 60 * 1000;
+/** Upper bounds of the histogram buckets, the last bucket catches everything above */
+const BUCKET_BOUNDS: [f64; 10] = [1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0];
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(extends = js_sys::Object)]
+    type AnalyticsEngineDataset;
+    #[wasm_bindgen(method, catch, js_name = writeDataPoint)]
+    fn write_data_point(this: &AnalyticsEngineDataset, point: &JsValue) -> Result<(), JsValue>;
+}
+
+#[derive(Serialize, Clone)]
+struct Histogram {
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+    buckets: [u64; BUCKET_BOUNDS.len() + 1],
+}
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            buckets: [0; BUCKET_BOUNDS.len() + 1],
+        }
+    }
+    fn observe(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        let bucket = BUCKET_BOUNDS.partition_point(|bound| *bound < value);
+        self.buckets[bucket] += 1;
+    }
+}
+
+/** Everything recorded by this isolate since it started, or since the last flush */
+#[derive(Serialize, Default)]
+struct Registry {
+    counters: BTreeMap<String, u64>,
+    histograms: BTreeMap<String, Histogram>,
+    /** When counting started over, so readers know what the counts cover */
+    #[serde(rename = "since_ms")]
+    last_flush_ms: u64,
+}
+
+thread_local! {
+    static REGISTRY: RefCell<Registry> = RefCell::new(Registry::default());
+    static DATASET: RefCell<Option<AnalyticsEngineDataset>> = RefCell::new(None);
+}
+
+/** Looks up the optional METRICS Analytics Engine binding. Without it, metrics are only
+available through the /metrics route, which only sees the isolate serving it. */
+pub fn init(env: &w::Env) {
+    let env: &JsValue = env.as_ref();
+    let dataset = js_sys::Reflect::get(env, &JsValue::from_str("METRICS"))
+        .ok()
+        .filter(|v| !v.is_undefined());
+    DATASET.with(|v| *v.borrow_mut() = dataset.map(JsCast::unchecked_into));
+    REGISTRY.with(|registry| {
+        let mut registry = registry.borrow_mut();
+        if registry.last_flush_ms == 0 {
+            registry.last_flush_ms = w::Date::now().as_millis();
+        }
+    });
+}
+
+/** Metric names carry their labels, like `method_calls{method=create_room}` */
+pub fn increment(name: &str) {
+    REGISTRY.with(|registry| {
+        *registry
+            .borrow_mut()
+            .counters
+            .entry(name.to_string())
+            .or_default() += 1;
+    });
+    flush_if_due();
+}
+
+pub fn observe(name: &str, value: f64) {
+    REGISTRY.with(|registry| {
+        registry
+            .borrow_mut()
+            .histograms
+            .entry(name.to_string())
+            .or_insert_with(Histogram::new)
+            .observe(value);
+    });
+    flush_if_due();
+}
+
+/** What this isolate counted since `since_ms`, labelled as such. Other isolates count their own,
+and only the Analytics Engine dataset adds them all up. */
+pub fn snapshot() -> serde_json::Value {
+    let mut snapshot =
+        REGISTRY.with(|registry| serde_json::to_value(&*registry.borrow()).unwrap_or_default());
+    if let Some(snapshot) = snapshot.as_object_mut() {
+        snapshot.insert("scope".to_string(), "isolate".into());
+    }
+    snapshot
+}
+
+fn write_point(dataset: &AnalyticsEngineDataset, name: &str, doubles: &[f64]) {
+    let point = serde_json::json!({
+        "indexes": [name],
+        "blobs": [name],
+        "doubles": doubles,
+    });
+    let result =
+        js_sys::JSON::parse(&point.to_string()).and_then(|point| dataset.write_data_point(&point));
+    if let Err(err) = result {
+        log_warn!("Failed to write a metrics data point. {:?}", err);
+    }
+}
+
+fn flush_if_due() {
+    let now = w::Date::now().as_millis();
+    DATASET.with(|dataset| {
+        let dataset = dataset.borrow();
+        let dataset = match dataset.as_ref() {
+            Some(dataset) => dataset,
+            None => return,
+        };
+        REGISTRY.with(|registry| {
+            let mut registry = registry.borrow_mut();
+            if now.saturating_sub(registry.last_flush_ms) < FLUSH_INTERVAL_MS {
+                return;
+            }
+            let registry = std::mem::replace(
+                &mut *registry,
+                Registry {
+                    last_flush_ms: now,
+                    ..Default::default()
+                },
+            );
+            for (name, value) in registry.counters {
+                write_point(dataset, &name, &[value as f64]);
+            }
+            for (name, histogram) in registry.histograms {
+                let mut doubles = vec![
+                    histogram.count as f64,
+                    histogram.sum,
+                    histogram.min,
+                    histogram.max,
+                ];
+                doubles.extend(histogram.buckets.iter().map(|v| *v as f64));
+                write_point(dataset, &name, &doubles);
+            }
+        });
+    });
+}
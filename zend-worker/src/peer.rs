@@ -0,0 +1,136 @@
+use crate::{config, peer_api::ToPeerMessage};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use worker::{self as w, durable_object, DurableObject};
+use zend_common::api;
+
+/** How many of the most recent nonces are remembered individually */
+const MAX_RECENT_NONCES: usize = 256;
+/** Added to the nonce retention before records are cleaned up */
+const CLEANUP_DELAY_MARGIN_SECS: u64 = 60;
+
+#[derive(Serialize, Deserialize, Default)]
+struct NonceRecord {
+    /** Every nonce at or below the watermark counts as used. Only ever increases. */
+    watermark: Option<api::Nonce>,
+    /** Sorted in ascending order, all above the watermark */
+    recent: Vec<api::Nonce>,
+}
+impl NonceRecord {
+    fn is_used(&self, nonce: api::Nonce) -> bool {
+        self.watermark.map_or(false, |watermark| nonce <= watermark)
+            || self.recent.binary_search(&nonce).is_ok()
+    }
+    fn raise_watermark(&mut self, nonce: api::Nonce) {
+        self.watermark = Some(match self.watermark {
+            Some(watermark) => std::cmp::max(watermark, nonce),
+            None => nonce,
+        });
+    }
+    fn insert(&mut self, nonce: api::Nonce) {
+        if let Err(index) = self.recent.binary_search(&nonce) {
+            self.recent.insert(index, nonce);
+        }
+        while self.recent.len() > MAX_RECENT_NONCES {
+            let evicted = self.recent.remove(0);
+            self.raise_watermark(evicted);
+        }
+    }
+    /** Nonces older than `retention_secs` are rejected by the timestamp check anyway and need
+    not be remembered */
+    fn prune(&mut self, now: u64, retention_secs: u64) {
+        let cutoff = now.saturating_sub(retention_secs);
+        let expired = self.recent.partition_point(|v| v.timestamp <= cutoff);
+        if let Some(newest_expired) = self.recent.drain(..expired).last() {
+            self.raise_watermark(newest_expired);
+        }
+    }
+    fn is_expired(&self, now: u64, retention_secs: u64) -> bool {
+        let cutoff = now.saturating_sub(retention_secs);
+        self.recent.is_empty()
+            && self
+                .watermark
+                .map_or(true, |watermark| watermark.timestamp <= cutoff)
+    }
+}
+
+fn get_time() -> u64 {
+    w::Date::now().as_millis() / 1000
+}
+
+#[durable_object]
+pub struct Peer {
+    state: w::State,
+    nonce_retention_secs: u64,
+}
+
+impl Peer {
+    fn cleanup_delay(&self) -> Duration {
+        Duration::from_secs(self.nonce_retention_secs + CLEANUP_DELAY_MARGIN_SECS)
+    }
+
+    async fn get_nonce_record(&self) -> NonceRecord {
+        // Storage::get errors for missing keys, which we treat the same as an empty record
+        self.state
+            .storage()
+            .get("nonce_record")
+            .await
+            .unwrap_or_default()
+    }
+
+    async fn check_nonce_is_used(&self, nonce: api::Nonce) -> w::Result<bool> {
+        let mut record = self.get_nonce_record().await;
+        if record.is_used(nonce) {
+            return Ok(true);
+        }
+        record.prune(get_time(), self.nonce_retention_secs);
+        record.insert(nonce);
+        let mut storage = self.state.storage();
+        storage.put("nonce_record", record).await?;
+        storage.set_alarm(self.cleanup_delay()).await?;
+        Ok(false)
+    }
+}
+
+#[durable_object]
+impl DurableObject for Peer {
+    fn new(state: w::State, env: w::Env) -> Self {
+        config::init_logging(&env);
+        Self {
+            state,
+            nonce_retention_secs: config::protocol_config(&env).nonce_retention_secs(),
+        }
+    }
+
+    async fn fetch(&mut self, mut req: w::Request) -> w::Result<w::Response> {
+        let message: ToPeerMessage = req.json().await?;
+        match message {
+            ToPeerMessage::Echo => w::Response::from_json(&true),
+            ToPeerMessage::CheckNonceIsUsed(message) => {
+                w::Response::from_json(&self.check_nonce_is_used(message.nonce).await?)
+            }
+            ToPeerMessage::Inspect => w::Response::from_json(&self.get_nonce_record().await),
+            ToPeerMessage::ResetNonces => {
+                let mut storage = self.state.storage();
+                storage.delete("nonce_record").await?;
+                storage.delete_alarm().await?;
+                w::Response::from_json(&true)
+            }
+        }
+    }
+
+    async fn alarm(&mut self) -> w::Result<w::Response> {
+        let now = get_time();
+        let mut record = self.get_nonce_record().await;
+        record.prune(now, self.nonce_retention_secs);
+        let mut storage = self.state.storage();
+        if record.is_expired(now, self.nonce_retention_secs) {
+            // Anything this record could still reject is rejected by the timestamp check
+            storage.delete("nonce_record").await?;
+        } else {
+            storage.put("nonce_record", record).await?;
+            storage.set_alarm(self.cleanup_delay()).await?;
+        }
+        w::Response::empty()
+    }
+}
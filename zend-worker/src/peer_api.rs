@@ -1,18 +1,23 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use worker as w;
 use zend_common::api;
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct CheckNonceMessage {
-    #[serde(flatten)]
     pub nonce: api::Nonce,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "message_type")]
 pub enum ToPeerMessage {
+    /** Answered without touching storage, used by health checks */
+    Echo,
     CheckNonceIsUsed(CheckNonceMessage),
+    /** Admin API only */
+    Inspect,
+    /** Admin API only. Forgets every nonce this peer has used. */
+    ResetNonces,
 }
 
 pub fn make_request(message: &ToPeerMessage) -> Result<w::Request, w::Error> {
@@ -0,0 +1,201 @@
+use crate::config;
+use std::{cell::RefCell, collections::HashMap};
+use worker as w;
+
+/** Caller buckets are pruned once the per-isolate map grows beyond this */
+const MAX_TRACKED_CALLERS: usize = 4096;
+/** Connections are closed after this many messages in a row were too large or unparseable */
+const MAX_REJECTED_MESSAGES: u32 = 5;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    /** Maximum number of calls that can be made in a burst */
+    pub burst: f64,
+    /** Sustained number of calls per second */
+    pub per_sec: f64,
+}
+impl RateLimitConfig {
+    /** Reads `{prefix}_BURST` and `{prefix}_PER_SEC`, falling back to the given defaults */
+    pub fn from_env(env: &w::Env, prefix: &str, default: Self) -> Self {
+        Self {
+            burst: config::var_or(env, &format!("{}_BURST", prefix), default.burst),
+            per_sec: config::var_or(env, &format!("{}_PER_SEC", prefix), default.per_sec),
+        }
+    }
+    /** How long an exhausted bucket takes to allow another call, rounded up */
+    pub fn ms_per_call(&self) -> u64 {
+        (1000.0 / self.per_sec).ceil() as u64
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill_ms: u64,
+}
+impl TokenBucket {
+    fn full(config: &RateLimitConfig, now_ms: u64) -> Self {
+        Self {
+            tokens: config.burst,
+            last_refill_ms: now_ms,
+        }
+    }
+    fn refill(&mut self, config: &RateLimitConfig, now_ms: u64) {
+        let elapsed_secs = now_ms.saturating_sub(self.last_refill_ms) as f64 / 1000.0;
+        self.tokens = f64::min(config.burst, self.tokens + elapsed_secs * config.per_sec);
+        self.last_refill_ms = now_ms;
+    }
+    fn try_take(&mut self, config: &RateLimitConfig, now_ms: u64) -> bool {
+        self.refill(config, now_ms);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+    fn is_full(&self, config: &RateLimitConfig, now_ms: u64) -> bool {
+        let mut bucket = *self;
+        bucket.refill(config, now_ms);
+        bucket.tokens >= config.burst
+    }
+}
+
+/** Takes from the key's bucket, pruning full buckets first if too many keys are tracked */
+fn try_take_keyed(
+    buckets: &mut HashMap<String, TokenBucket>,
+    config: &RateLimitConfig,
+    key: &str,
+    now_ms: u64,
+) -> bool {
+    if buckets.len() >= MAX_TRACKED_CALLERS {
+        buckets.retain(|_, bucket| !bucket.is_full(config, now_ms));
+    }
+    buckets
+        .entry(key.to_string())
+        .or_insert_with(|| TokenBucket::full(config, now_ms))
+        .try_take(config, now_ms)
+}
+
+/** One bucket per key, for limits that are enforced by whoever owns the limiter */
+#[derive(Debug)]
+pub struct KeyedRateLimiter {
+    config: RateLimitConfig,
+    buckets: HashMap<String, TokenBucket>,
+}
+impl KeyedRateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /** Suggested to callers rejected by `check` */
+    pub fn retry_ms(&self) -> u64 {
+        self.config.ms_per_call()
+    }
+
+    pub fn check(&mut self, key: &str) -> bool {
+        try_take_keyed(
+            &mut self.buckets,
+            &self.config,
+            key,
+            w::Date::now().as_millis(),
+        )
+    }
+}
+
+thread_local! {
+    // Shared by all connections handled by this isolate, so opening more connections
+    // doesn't multiply a caller's allowance (at least within one isolate).
+    static CALLER_BUCKETS: RefCell<HashMap<String, TokenBucket>> = RefCell::new(HashMap::new());
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    connection_config: RateLimitConfig,
+    caller_config: RateLimitConfig,
+    connection_bucket: TokenBucket,
+    /** Calls rejected since the last accepted one */
+    rejected_in_a_row: u32,
+    /** Messages dropped since the last one that could be parsed */
+    rejected_messages_in_a_row: u32,
+}
+impl RateLimiter {
+    pub fn from_env(env: &w::Env) -> Self {
+        let connection_config = RateLimitConfig::from_env(
+            env,
+            "RATE_LIMIT_CONNECTION",
+            RateLimitConfig {
+                burst: 30.0,
+                per_sec: 10.0,
+            },
+        );
+        let caller_config = RateLimitConfig::from_env(
+            env,
+            "RATE_LIMIT_CALLER",
+            RateLimitConfig {
+                burst: 20.0,
+                per_sec: 5.0,
+            },
+        );
+        Self {
+            connection_config,
+            caller_config,
+            connection_bucket: TokenBucket::full(&connection_config, w::Date::now().as_millis()),
+            rejected_in_a_row: 0,
+            rejected_messages_in_a_row: 0,
+        }
+    }
+
+    /** Checked for every call before anything else, as it doesn't require a verified caller */
+    pub fn check_connection(&mut self) -> bool {
+        let now_ms = w::Date::now().as_millis();
+        let allowed = self
+            .connection_bucket
+            .try_take(&self.connection_config, now_ms);
+        self.rejected_in_a_row = if allowed {
+            0
+        } else {
+            self.rejected_in_a_row.saturating_add(1)
+        };
+        allowed
+    }
+
+    /** True once a whole burst worth of calls was rejected without the client backing off */
+    pub fn is_ignored(&self) -> bool {
+        self.rejected_in_a_row as f64 >= self.connection_config.burst
+    }
+
+    /** Counts a message that was too large or couldn't be parsed, or resets the count if it was
+    fine. True once too many were rejected in a row, as the client isn't going to get it right. */
+    pub fn check_message(&mut self, accepted: bool) -> bool {
+        self.rejected_messages_in_a_row = match accepted {
+            true => 0,
+            false => self.rejected_messages_in_a_row.saturating_add(1),
+        };
+        self.rejected_messages_in_a_row >= MAX_REJECTED_MESSAGES
+    }
+
+    /** How long it takes for an exhausted connection bucket to fill up again */
+    pub fn retry_after_secs(&self) -> u64 {
+        (self.connection_config.burst / self.connection_config.per_sec).ceil() as u64
+    }
+
+    /** Suggested to clients whose calls were rejected by `check_connection` */
+    pub fn connection_retry_ms(&self) -> u64 {
+        self.connection_config.ms_per_call()
+    }
+    /** Suggested to clients whose calls were rejected by `check_caller` */
+    pub fn caller_retry_ms(&self) -> u64 {
+        self.caller_config.ms_per_call()
+    }
+
+    /** Should only be checked once the caller's signature has been verified */
+    pub fn check_caller(&self, caller_id: &str) -> bool {
+        let now_ms = w::Date::now().as_millis();
+        let config = &self.caller_config;
+        CALLER_BUCKETS
+            .with(|buckets| try_take_keyed(&mut buckets.borrow_mut(), config, caller_id, now_ms))
+    }
+}
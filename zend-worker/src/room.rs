@@ -0,0 +1,1517 @@
+use crate::{
+    config, metrics,
+    rate_limit::{KeyedRateLimiter, RateLimitConfig},
+    room_api::{self, FromRoomMessage, FromSubscriberMessage, ToRoomMessage},
+    websocket::WebSocketExt,
+};
+use futures::{future, StreamExt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, VecDeque},
+    rc::Rc,
+    time::Duration,
+};
+use worker::{self as w, durable_object, DurableObject};
+use zend_common::{api, log_warn};
+
+/** Used when ROOM_IDLE_TIMEOUT_SECS is not configured */
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 20 * 60;
+/** Used when HISTORY_MAX_ENTRIES is not configured */
+const DEFAULT_HISTORY_MAX_ENTRIES: u64 = 1000;
+/** Used when HISTORY_MAX_AGE_SECS is not configured */
+const DEFAULT_HISTORY_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+/** Used when HISTORY_MAX_BYTES is not configured */
+const DEFAULT_HISTORY_MAX_BYTES: u64 = 1024 * 1024;
+/** History is stored in chunks of up to this many entries, see `HistoryChunk` */
+const HISTORY_CHUNK_MAX_ENTRIES: usize = 64;
+/** Below the storage value size limit, with room for one more entry of the largest size */
+const HISTORY_CHUNK_MAX_BYTES: usize = 64 * 1024;
+/** How often history is compacted while a room with a maximum history age is idle */
+const COMPACTION_INTERVAL_MS: u64 = 10 * 60 * 1000;
+/** Used when UNICAST_QUEUE_MAX_ENTRIES is not configured */
+const DEFAULT_UNICAST_QUEUE_MAX_ENTRIES: u64 = 100;
+/** Used when UNICAST_QUEUE_MAX_BYTES is not configured, below the storage value size limit */
+const DEFAULT_UNICAST_QUEUE_MAX_BYTES: u64 = 96 * 1024;
+/** Used when UNICAST_QUEUE_TTL_SECS is not configured */
+const DEFAULT_UNICAST_QUEUE_TTL_SECS: u64 = 24 * 60 * 60;
+/** The oldest unacknowledged deliveries are forgotten beyond this */
+const MAX_PENDING_ACKS: usize = 1024;
+
+#[derive(Serialize, Deserialize)]
+struct HistoryEntry {
+    receiver_id: Option<String>,
+    /** Set instead of `receiver_id` for multicast data */
+    #[serde(default)]
+    receiver_ids: Option<Vec<String>>,
+    timestamp: u64,
+    /** In milliseconds. Missing for entries stored before it was recorded. */
+    #[serde(default)]
+    received_at: Option<u64>,
+    data: serde_json::Value,
+    sender_id: String,
+    nonce: api::Nonce,
+}
+impl HistoryEntry {
+    /** None for data sent to everyone with a role */
+    fn is_addressed_to(&self, peer_id: &str) -> Option<bool> {
+        match (&self.receiver_id, &self.receiver_ids) {
+            (Some(receiver_id), _) => Some(receiver_id == peer_id),
+            (None, Some(receiver_ids)) => Some(receiver_ids.iter().any(|v| v == peer_id)),
+            (None, None) => None,
+        }
+    }
+    fn received_at_ms(&self) -> u64 {
+        // The sender's timestamp was checked against the server's clock when it was sent
+        self.received_at.unwrap_or(self.timestamp * 1000)
+    }
+    /** Roughly what it takes up in storage, as part of a list */
+    fn stored_size(&self) -> w::Result<usize> {
+        Ok(serde_json::to_string(self)?.len() + 1)
+    }
+}
+
+/** Where a part of the history is stored, and what's in it. The history is a list of these,
+oldest first, so reading or compacting part of it only loads the chunks involved. */
+#[derive(Clone, Serialize, Deserialize)]
+struct HistoryChunk {
+    id: u64,
+    entries: usize,
+    bytes: usize,
+    /** Of its first and last entry, in milliseconds */
+    first_received_at: u64,
+    last_received_at: u64,
+}
+impl HistoryChunk {
+    fn new(id: u64, entry: &HistoryEntry) -> w::Result<Self> {
+        Ok(Self {
+            id,
+            entries: 1,
+            bytes: entry.stored_size()?,
+            first_received_at: entry.received_at_ms(),
+            last_received_at: entry.received_at_ms(),
+        })
+    }
+    fn fits(&self, size: usize) -> bool {
+        self.entries < HISTORY_CHUNK_MAX_ENTRIES && self.bytes + size <= HISTORY_CHUNK_MAX_BYTES
+    }
+}
+
+fn history_chunk_key(id: u64) -> String {
+    format!("history/{}", id)
+}
+
+/** Changes to the history, see `Room::write_history` */
+struct HistoryWrite {
+    chunks: Vec<HistoryChunk>,
+    /** The contents of chunks that changed */
+    changed: Vec<(u64, Vec<HistoryEntry>)>,
+    /** Keys of chunks that are gone */
+    deleted: Vec<String>,
+}
+impl HistoryWrite {
+    fn new(chunks: Vec<HistoryChunk>) -> Self {
+        Self {
+            chunks,
+            changed: Vec::new(),
+            deleted: Vec::new(),
+        }
+    }
+    fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.deleted.is_empty()
+    }
+    fn change(&mut self, id: u64, entries: Vec<HistoryEntry>) {
+        self.changed.retain(|(v, _)| *v != id);
+        self.changed.push((id, entries));
+    }
+    fn delete(&mut self, id: u64) {
+        self.changed.retain(|(v, _)| *v != id);
+        self.deleted.push(history_chunk_key(id));
+    }
+    /** Appends to the last chunk, whose entries are `last_entries` unless they changed already,
+    if the entry fits. Otherwise to a new chunk. */
+    fn append(&mut self, last_entries: Vec<HistoryEntry>, entry: HistoryEntry) -> w::Result<()> {
+        let size = entry.stored_size()?;
+        match self.chunks.last_mut() {
+            Some(last) if last.fits(size) => {
+                last.entries += 1;
+                last.bytes += size;
+                last.last_received_at = entry.received_at_ms();
+                let id = last.id;
+                let mut entries = match self.changed.iter().position(|(v, _)| *v == id) {
+                    Some(index) => self.changed.remove(index).1,
+                    None => last_entries,
+                };
+                entries.push(entry);
+                self.changed.push((id, entries));
+            }
+            last => {
+                let id = last.map_or(0, |v| v.id + 1);
+                self.chunks.push(HistoryChunk::new(id, &entry)?);
+                self.change(id, vec![entry]);
+            }
+        }
+        Ok(())
+    }
+}
+
+/** Where replaying continues after `cursor`. History is kept in the order the room received it,
+so that's right after the entry itself, or after everything received by then if it's gone. */
+fn history_position_after(history: &[HistoryEntry], cursor: &room_api::HistoryCursor) -> usize {
+    let sender_id = cursor.sender_id.to_string();
+    match history
+        .iter()
+        .rposition(|v| v.nonce == cursor.nonce && v.sender_id == sender_id)
+    {
+        Some(index) => index + 1,
+        None => history.partition_point(|v| v.received_at_ms() <= cursor.received_at),
+    }
+}
+
+struct Subscription {
+    socket: w::WebSocket,
+    subscriber_id: String,
+    subscription_id: u64,
+    filter: BroadcastFilter,
+    /** Whether the subscriber has a role, kept up to date when roles change */
+    privileged: bool,
+    ignore_presence: bool,
+    /** The `seq` of the next data sent over this subscription */
+    next_seq: u64,
+    /** Cleared when the subscriber unsubscribes */
+    last_will: Option<room_api::LastWill>,
+}
+impl Subscription {
+    fn send_data(&mut self, mut data: room_api::SubscriptionDataMessage) -> w::Result<()> {
+        data.seq = self.next_seq;
+        self.next_seq += 1;
+        self.socket
+            .send_with_str(serde_json::to_string(&FromRoomMessage::Data(data))?)
+    }
+
+    /** Sends the history entries that the subscriber would have received live */
+    fn replay_history(&mut self, history: impl IntoIterator<Item = HistoryEntry>) -> w::Result<()> {
+        for entry in history {
+            let visible = match entry.is_addressed_to(&self.subscriber_id) {
+                Some(addressed) => addressed,
+                // Everything in the history was sent with write_history set
+                None => self.privileged && self.filter.accepts(&entry.sender_id, true),
+            };
+            if !visible {
+                continue;
+            }
+            let sender_id = match api::PublicKeyWrapper::try_from(entry.sender_id) {
+                Ok(sender_id) => sender_id,
+                Err(_) => continue,
+            };
+            self.send_data(room_api::SubscriptionDataMessage {
+                seq: 0,
+                received_at: entry.received_at_ms(),
+                sender_id,
+                nonce: entry.nonce,
+                data: entry.data,
+                require_ack: false,
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/** Unicast data kept for a receiver without a live subscription */
+#[derive(Serialize, Deserialize)]
+struct QueuedUnicast {
+    sender_id: api::PublicKeyWrapper,
+    nonce: api::Nonce,
+    data: serde_json::Value,
+    write_history: bool,
+    require_ack: bool,
+    /** In milliseconds */
+    queued_at: u64,
+}
+
+fn unicast_queue_key(receiver_id: &str) -> String {
+    format!("unicast_queue/{}", receiver_id)
+}
+
+/** Unicast data sent with `require_ack` that no subscription of the receiver forwarded yet */
+struct PendingAck {
+    sender_id: String,
+    receiver_id: String,
+    nonce: api::Nonce,
+}
+
+/** `api::SubscriptionFilter`, with peer IDs in the form they're stored and compared in */
+struct BroadcastFilter {
+    sender_ids: Option<Vec<String>>,
+    history_only: bool,
+}
+impl BroadcastFilter {
+    fn accepts(&self, sender_id: &str, write_history: bool) -> bool {
+        (write_history || !self.history_only)
+            && self
+                .sender_ids
+                .as_ref()
+                .map_or(true, |ids| ids.iter().any(|v| v == sender_id))
+    }
+}
+impl From<api::SubscriptionFilter> for BroadcastFilter {
+    fn from(value: api::SubscriptionFilter) -> Self {
+        Self {
+            sender_ids: value
+                .sender_ids
+                .map(|ids| ids.iter().map(ToString::to_string).collect()),
+            history_only: value.history_only,
+        }
+    }
+}
+
+/** Serialises like `api::RoomPeerInfo`, without having to parse every stored peer ID */
+#[derive(Serialize)]
+struct PeerInfo {
+    peer_id: String,
+    privileged: bool,
+    role: Option<api::PeerRole>,
+    subscribed: bool,
+}
+
+/** Keyed by peer ID. Every peer with a role counts as privileged. */
+type PeerRoles = BTreeMap<String, api::PeerRole>;
+
+/** Everything the admin API gets to see about a room, short of the history's contents */
+#[derive(Serialize)]
+struct RoomInspection {
+    peer_roles: PeerRoles,
+    history_entries: usize,
+    history_retention: api::HistoryRetention,
+    expires_at: u64,
+    subscriptions: Vec<SubscriptionInspection>,
+}
+#[derive(Serialize)]
+struct SubscriptionInspection {
+    subscriber_id: String,
+    subscription_id: u64,
+}
+
+/** Keys are only included when present, so absent values don't overwrite stored ones */
+#[derive(Serialize, Default)]
+struct RoomStorageUpdate {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    peer_roles: Option<PeerRoles>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    history_chunks: Option<Vec<HistoryChunk>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    history_retention: Option<api::HistoryRetention>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created_at: Option<u64>,
+}
+
+async fn get_or_default<T: DeserializeOwned + Default>(storage: &w::Storage, key: &str) -> T {
+    // Storage::get errors for missing keys, which we treat the same as an empty value
+    storage.get(key).await.unwrap_or_default()
+}
+
+fn send_to<F: Fn(&Subscription) -> bool>(
+    subscriptions: &RefCell<Vec<Subscription>>,
+    message: &FromRoomMessage,
+    filter: F,
+) -> w::Result<()> {
+    let json = serde_json::to_string(message)?;
+    let subscriptions = subscriptions.borrow();
+    for sub in subscriptions.iter().filter(|sub| filter(sub)) {
+        if let Err(err) = sub.socket.send_with_str(&json) {
+            log_warn!(
+                "Failed to send to subscription {}. {}",
+                sub.subscription_id,
+                err
+            );
+        }
+    }
+    Ok(())
+}
+
+/** Like `send_to`, but every subscription numbers the data on its own */
+fn send_data_to<F: Fn(&Subscription) -> bool>(
+    subscriptions: &RefCell<Vec<Subscription>>,
+    data: room_api::SubscriptionDataMessage,
+    filter: F,
+) {
+    let mut recipients = 0;
+    for sub in subscriptions.borrow_mut().iter_mut() {
+        if !filter(sub) {
+            continue;
+        }
+        recipients += 1;
+        if let Err(err) = sub.send_data(data.clone()) {
+            log_warn!(
+                "Failed to send to subscription {}. {}",
+                sub.subscription_id,
+                err
+            );
+        }
+    }
+    metrics::observe("room_fanout", recipients as f64);
+}
+
+/** Peers without a role don't get to learn who is in the room, same as with listing peers */
+fn send_presence(
+    subscriptions: &RefCell<Vec<Subscription>>,
+    peer_id: &api::PublicKeyWrapper,
+    kind: api::PresenceKind,
+) {
+    let peer_id_string = peer_id.to_string();
+    let message = FromRoomMessage::Presence(room_api::PresenceMessage {
+        peer_id: peer_id.clone(),
+        kind,
+        timestamp: w::Date::now().as_millis() / 1000,
+    });
+    let result = send_to(subscriptions, &message, |sub| {
+        sub.privileged && !sub.ignore_presence && sub.subscriber_id != peer_id_string
+    });
+    if let Err(err) = result {
+        log_warn!("Failed to send a presence event. {}", err);
+    }
+}
+
+/** Sends a receipt to the sender's subscriptions, for the first ack of a pending delivery only */
+fn acknowledge_delivery(
+    subscriptions: &RefCell<Vec<Subscription>>,
+    pending_acks: &RefCell<VecDeque<PendingAck>>,
+    receiver: &api::PublicKeyWrapper,
+    ack: room_api::AckMessage,
+) {
+    let receiver_id = receiver.to_string();
+    let sender_id = ack.sender_id.to_string();
+    let mut pending = pending_acks.borrow_mut();
+    let index = pending.iter().position(|v| {
+        v.receiver_id == receiver_id && v.sender_id == sender_id && v.nonce == ack.nonce
+    });
+    match index {
+        Some(index) => pending.remove(index),
+        None => return,
+    };
+    drop(pending);
+    let receipt = FromRoomMessage::DeliveryReceipt(room_api::DeliveryReceiptMessage {
+        receiver_id: receiver.clone(),
+        nonce: ack.nonce,
+    });
+    if let Err(err) = send_to(subscriptions, &receipt, |sub| {
+        sub.subscriber_id == sender_id
+    }) {
+        log_warn!("Failed to send a delivery receipt. {}", err);
+    }
+}
+
+fn bool_response(value: bool) -> w::Result<w::Response> {
+    w::Response::from_json(&value)
+}
+
+#[durable_object]
+pub struct Room {
+    state: w::State,
+    env: w::Env,
+    subscriptions: Rc<RefCell<Vec<Subscription>>>,
+    /** Only kept in memory, as receipts can't outlive the subscriptions they'd be sent to */
+    pending_acks: Rc<RefCell<VecDeque<PendingAck>>>,
+    /** Per sender, lost when the object is evicted, which only ever resets it to full */
+    ephemeral_limiter: KeyedRateLimiter,
+}
+
+impl Room {
+    async fn get_peer_roles(&self) -> PeerRoles {
+        let storage = self.state.storage();
+        if let Ok(roles) = storage.get("peer_roles").await {
+            return roles;
+        }
+        // Rooms from before roles existed only have a list of equally privileged peers,
+        // who all keep every right they had
+        let privileged_peers: Vec<String> = get_or_default(&storage, "privileged_peers").await;
+        privileged_peers
+            .into_iter()
+            .map(|peer_id| (peer_id, api::PeerRole::Owner))
+            .collect()
+    }
+
+    async fn get_privileged_peers(&self) -> Vec<String> {
+        self.get_peer_roles().await.into_keys().collect()
+    }
+
+    /** Moves rooms that stored their history as a single value over to chunks first */
+    async fn get_history_chunks(&self) -> w::Result<Vec<HistoryChunk>> {
+        if let Ok(chunks) = self.state.storage().get("history_chunks").await {
+            return Ok(chunks);
+        }
+        let legacy: Vec<HistoryEntry> =
+            get_or_default(&self.state.storage(), "message_history").await;
+        let mut write = HistoryWrite::new(Vec::new());
+        for entry in legacy {
+            write.append(Vec::new(), entry)?;
+        }
+        write.deleted.push("message_history".to_string());
+        let chunks = write.chunks.clone();
+        self.write_history(write).await?;
+        Ok(chunks)
+    }
+
+    async fn get_history_chunk(&self, id: u64) -> Vec<HistoryEntry> {
+        get_or_default(&self.state.storage(), &history_chunk_key(id)).await
+    }
+
+    /** The entries of every chunk with entries the room received since `since`, in
+    milliseconds. Entries of those chunks from before then are included too. */
+    async fn get_history_since(&self, since: u64) -> w::Result<Vec<HistoryEntry>> {
+        let mut history = Vec::new();
+        for chunk in self.get_history_chunks().await? {
+            if chunk.last_received_at >= since {
+                history.extend(self.get_history_chunk(chunk.id).await);
+            }
+        }
+        Ok(history)
+    }
+
+    async fn get_history(&self) -> w::Result<Vec<HistoryEntry>> {
+        self.get_history_since(0).await
+    }
+
+    async fn get_history_entry_count(&self) -> w::Result<usize> {
+        Ok(self
+            .get_history_chunks()
+            .await?
+            .iter()
+            .map(|v| v.entries)
+            .sum())
+    }
+
+    async fn get_history_retention(&self) -> api::HistoryRetention {
+        // Rooms created before retention was configurable get the current defaults
+        match self.state.storage().get("history_retention").await {
+            Ok(retention) => retention,
+            Err(_) => self.default_history_retention(),
+        }
+    }
+
+    fn default_history_retention(&self) -> api::HistoryRetention {
+        // A limit of 0 disables that limit
+        let limit = |name: &str, default: u64| {
+            Some(config::var_or(&self.env, name, default)).filter(|v| *v > 0)
+        };
+        api::HistoryRetention {
+            max_entries: limit("HISTORY_MAX_ENTRIES", DEFAULT_HISTORY_MAX_ENTRIES),
+            max_age_secs: limit("HISTORY_MAX_AGE_SECS", DEFAULT_HISTORY_MAX_AGE_SECS),
+        }
+    }
+
+    fn history_max_bytes(&self) -> usize {
+        config::var_or(&self.env, "HISTORY_MAX_BYTES", DEFAULT_HISTORY_MAX_BYTES) as usize
+    }
+
+    /** Appends to the history and compacts it in the same pass, to be written with
+    `write_history` */
+    async fn append_history(&self, entry: HistoryEntry) -> w::Result<HistoryWrite> {
+        let mut write = HistoryWrite::new(self.get_history_chunks().await?);
+        let size = entry.stored_size()?;
+        let last_entries = match write.chunks.last() {
+            Some(last) if last.fits(size) => self.get_history_chunk(last.id).await,
+            _ => Vec::new(),
+        };
+        write.append(last_entries, entry)?;
+        self.compact_history(&mut write).await?;
+        Ok(write)
+    }
+
+    /** Drops the oldest entries while any are too old, there are more than the maximum count,
+    or the whole history doesn't fit in HISTORY_MAX_BYTES. Only the chunks that entries are
+    dropped from are loaded. */
+    async fn compact_history(&self, write: &mut HistoryWrite) -> w::Result<()> {
+        let retention = self.get_history_retention().await;
+        let max_bytes = self.history_max_bytes();
+        let cutoff = retention
+            .max_age_secs
+            .map(|v| w::Date::now().as_millis().saturating_sub(v * 1000));
+        let mut entries: usize = write.chunks.iter().map(|v| v.entries).sum();
+        let mut bytes: usize = write.chunks.iter().map(|v| v.bytes).sum();
+        let is_over = |entries: usize, bytes: usize, oldest: u64| {
+            retention
+                .max_entries
+                .map_or(false, |v| entries > v as usize)
+                || bytes > max_bytes
+                || cutoff.map_or(false, |v| oldest < v)
+        };
+        while let Some(first) = write.chunks.first().cloned() {
+            if !is_over(entries, bytes, first.first_received_at) {
+                break;
+            }
+            let mut chunk_entries = match write.changed.iter().find(|(id, _)| *id == first.id) {
+                Some((_, v)) => v.clone(),
+                None => self.get_history_chunk(first.id).await,
+            };
+            let mut dropped = 0;
+            let mut dropped_bytes = 0;
+            for entry in &chunk_entries {
+                if !is_over(entries, bytes, entry.received_at_ms()) {
+                    break;
+                }
+                let size = entry.stored_size()?;
+                dropped += 1;
+                dropped_bytes += size;
+                entries -= 1;
+                bytes = bytes.saturating_sub(size);
+            }
+            chunk_entries.drain(..dropped);
+            match chunk_entries.first() {
+                Some(new_first) => {
+                    let chunk = &mut write.chunks[0];
+                    chunk.entries = chunk_entries.len();
+                    chunk.bytes = chunk.bytes.saturating_sub(dropped_bytes);
+                    chunk.first_received_at = new_first.received_at_ms();
+                    write.change(first.id, chunk_entries);
+                    break;
+                }
+                None => {
+                    // In case the chunk had fewer entries than listed, e.g. as it went missing
+                    entries = entries.saturating_sub(first.entries - dropped);
+                    bytes = bytes.saturating_sub(first.bytes.saturating_sub(dropped_bytes));
+                    write.chunks.remove(0);
+                    write.delete(first.id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /** Removes the entry from whichever chunk it's in, looking at the newest first. False if
+    there is no such entry. */
+    async fn delete_history_entry(&self, sender_id: &str, nonce: api::Nonce) -> w::Result<bool> {
+        let mut write = HistoryWrite::new(self.get_history_chunks().await?);
+        for index in (0..write.chunks.len()).rev() {
+            let id = write.chunks[index].id;
+            let mut entries = self.get_history_chunk(id).await;
+            let position = entries
+                .iter()
+                .position(|v| v.nonce == nonce && v.sender_id == sender_id);
+            let removed = match position {
+                Some(position) => entries.remove(position),
+                None => continue,
+            };
+            match (entries.first(), entries.last()) {
+                (Some(first), Some(last)) => {
+                    let chunk = &mut write.chunks[index];
+                    chunk.entries = entries.len();
+                    chunk.bytes = chunk.bytes.saturating_sub(removed.stored_size()?);
+                    chunk.first_received_at = first.received_at_ms();
+                    chunk.last_received_at = last.received_at_ms();
+                    write.change(id, entries);
+                }
+                _ => {
+                    write.chunks.remove(index);
+                    write.delete(id);
+                }
+            }
+            self.write_history(write).await?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /** Writes the changed chunks and the list of chunks without awaiting anything in between,
+    so storage writes them all at once, along with other writes started alongside */
+    async fn write_history(&self, write: HistoryWrite) -> w::Result<()> {
+        let chunk_writes = write.changed.into_iter().map(|(id, entries)| {
+            let mut storage = self.state.storage();
+            async move { storage.put(&history_chunk_key(id), entries).await }
+        });
+        let mut storage = self.state.storage();
+        let mut delete_storage = self.state.storage();
+        let deleted = write.deleted;
+        futures::try_join!(
+            future::try_join_all(chunk_writes),
+            storage.put("history_chunks", write.chunks),
+            async move {
+                match deleted.is_empty() {
+                    true => Ok(0),
+                    false => delete_storage.delete_multiple(deleted).await,
+                }
+            },
+        )?;
+        Ok(())
+    }
+
+    /** Unexpired queued unicasts, oldest first. None if nothing is stored for the receiver. */
+    async fn get_unicast_queue(&self, receiver_id: &str) -> Option<Vec<QueuedUnicast>> {
+        let ttl_secs = config::var_or(
+            &self.env,
+            "UNICAST_QUEUE_TTL_SECS",
+            DEFAULT_UNICAST_QUEUE_TTL_SECS,
+        );
+        let cutoff = w::Date::now().as_millis().saturating_sub(ttl_secs * 1000);
+        let mut queue: Vec<QueuedUnicast> = self
+            .state
+            .storage()
+            .get(&unicast_queue_key(receiver_id))
+            .await
+            .ok()?;
+        queue.retain(|v| v.queued_at >= cutoff);
+        Some(queue)
+    }
+
+    fn unicast_queue_fits(&self, queue: &[QueuedUnicast]) -> w::Result<bool> {
+        let max_entries = config::var_or(
+            &self.env,
+            "UNICAST_QUEUE_MAX_ENTRIES",
+            DEFAULT_UNICAST_QUEUE_MAX_ENTRIES,
+        );
+        let max_bytes = config::var_or(
+            &self.env,
+            "UNICAST_QUEUE_MAX_BYTES",
+            DEFAULT_UNICAST_QUEUE_MAX_BYTES,
+        );
+        Ok(queue.len() as u64 <= max_entries
+            && serde_json::to_string(queue)?.len() as u64 <= max_bytes)
+    }
+
+    fn track_pending_ack(&self, sender_id: String, receiver_id: String, nonce: api::Nonce) {
+        let mut pending_acks = self.pending_acks.borrow_mut();
+        if pending_acks.len() >= MAX_PENDING_ACKS {
+            pending_acks.pop_front();
+        }
+        pending_acks.push_back(PendingAck {
+            sender_id,
+            receiver_id,
+            nonce,
+        });
+    }
+
+    async fn get_next_sub_id(&self) -> w::Result<u64> {
+        let mut storage = self.state.storage();
+        let next = match storage.get::<u64>("subscription_id").await {
+            Ok(next) => next,
+            // Randomly initialise subscription ID and restrict to same range as initial choice
+            // to avoid leaking information about the existence of rooms
+            Err(_) => {
+                let mut random = [0u8; 4];
+                getrandom::getrandom(&mut random)
+                    .map_err(|e| w::Error::RustError(e.to_string()))?;
+                u32::from_be_bytes(random) as u64
+            }
+        };
+        storage
+            .put("subscription_id", (next + 1) % (1 << 32))
+            .await?;
+        Ok(next)
+    }
+
+    async fn exists(&self) -> bool {
+        !self.get_privileged_peers().await.is_empty()
+    }
+
+    fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(config::var_or(
+            &self.env,
+            "ROOM_IDLE_TIMEOUT_SECS",
+            DEFAULT_IDLE_TIMEOUT_SECS,
+        ))
+    }
+
+    /** Pushes the expiration back, as long as the activity came from a privileged peer */
+    async fn keep_alive(&self, peer_id: &str) -> w::Result<()> {
+        if !self
+            .get_privileged_peers()
+            .await
+            .iter()
+            .any(|v| v == peer_id)
+        {
+            return Ok(());
+        }
+        let expires_at = w::Date::now().as_millis() + self.idle_timeout().as_millis() as u64;
+        self.state.storage().put("expires_at", expires_at).await?;
+        self.schedule_alarm(expires_at).await
+    }
+
+    /** The alarm fires on expiration, or earlier if history needs compacting in the meantime */
+    async fn schedule_alarm(&self, expires_at: u64) -> w::Result<()> {
+        let now = w::Date::now().as_millis();
+        let next = match self.get_history_retention().await.max_age_secs {
+            Some(_) => std::cmp::min(expires_at, now + COMPACTION_INTERVAL_MS),
+            None => expires_at,
+        };
+        self.state
+            .storage()
+            .set_alarm(Duration::from_millis(next.saturating_sub(now)))
+            .await
+    }
+
+    fn close_subscriptions(&self, reason: api::RoomClosedReason) {
+        if let Err(err) = self.send_to_subscribers(&FromRoomMessage::RoomClosed(reason), |_| true) {
+            log_warn!("Failed to notify subscribers of room closure. {}", err);
+        }
+        for sub in self.subscriptions.borrow_mut().drain(..) {
+            sub.socket.close_with(reason.into());
+        }
+    }
+
+    /** Adds the peer as a member, unless it already has a role */
+    async fn add_privileged_peer(
+        &self,
+        adder_id: &str,
+        added: &api::PublicKeyWrapper,
+    ) -> w::Result<bool> {
+        let mut roles = self.get_peer_roles().await;
+        if !roles.contains_key(adder_id) {
+            return Ok(false);
+        }
+        let added_id = added.to_string();
+        if !roles.contains_key(&added_id) {
+            roles.insert(added_id, api::PeerRole::Member);
+            self.state.storage().put("peer_roles", roles).await?;
+            self.on_privilege_changed(added, true);
+        }
+        Ok(true)
+    }
+
+    async fn set_peer_role(
+        &self,
+        setter_id: &str,
+        peer: &api::PublicKeyWrapper,
+        role: Option<api::PeerRole>,
+    ) -> w::Result<bool> {
+        let peer_id = peer.to_string();
+        let peer_id = peer_id.as_str();
+        let mut roles = self.get_peer_roles().await;
+        let setter_role = match roles.get(setter_id) {
+            Some(role) => *role,
+            None => return Ok(false),
+        };
+        let current = roles.get(peer_id).copied();
+        // Anyone may give up their own role, apart from the checks below
+        let is_leaving = setter_id == peer_id && role.is_none();
+        if !is_leaving && !setter_role.may_change_role(current, role) {
+            return Ok(false);
+        }
+        match role {
+            Some(role) => roles.insert(peer_id.to_string(), role),
+            None => roles.remove(peer_id),
+        };
+        // A room without owners could never be deleted or have its moderators changed again
+        if !roles.values().any(|v| *v == api::PeerRole::Owner) {
+            return Ok(false);
+        }
+        self.state.storage().put("peer_roles", roles).await?;
+        if current.is_some() != role.is_some() {
+            self.on_privilege_changed(peer, role.is_some());
+        }
+        Ok(true)
+    }
+
+    fn send_to_subscribers<F: Fn(&Subscription) -> bool>(
+        &self,
+        message: &FromRoomMessage,
+        filter: F,
+    ) -> w::Result<()> {
+        send_to(&self.subscriptions, message, filter)
+    }
+
+    /** Called when a peer gains or loses its role (not when it changes to another role).
+    Keeps the peer's subscriptions in sync and lets everyone else know. */
+    fn on_privilege_changed(&self, peer_id: &api::PublicKeyWrapper, privileged: bool) {
+        let peer_id_string = peer_id.to_string();
+        for sub in self.subscriptions.borrow_mut().iter_mut() {
+            if sub.subscriber_id == peer_id_string {
+                sub.privileged = privileged;
+            }
+        }
+        let kind = match privileged {
+            true => api::PresenceKind::Joined,
+            false => api::PresenceKind::Left,
+        };
+        send_presence(&self.subscriptions, peer_id, kind);
+    }
+
+    /** `is_new` is false for resumed subscriptions, which other subscribers aren't told about */
+    fn track_subscription(
+        &self,
+        subscriber: api::PublicKeyWrapper,
+        subscription: Subscription,
+        is_new: bool,
+    ) -> w::Result<()> {
+        let mut event_stream = subscription.socket.events()?;
+        // Resumed subscriptions share their ID with the one they replace, so match by socket
+        let socket: web_sys::WebSocket = subscription.socket.as_ref().clone();
+        self.subscriptions.borrow_mut().push(subscription);
+        if is_new {
+            send_presence(
+                &self.subscriptions,
+                &subscriber,
+                api::PresenceKind::Subscribed,
+            );
+        }
+        let subscriptions = self.subscriptions.clone();
+        let pending_acks = self.pending_acks.clone();
+        w::wasm_bindgen_futures::spawn_local(async move {
+            // Subscribers only ever acknowledge data, otherwise this waits for the socket to go away
+            while let Some(Ok(w::WebsocketEvent::Message(message))) = event_stream.next().await {
+                let message = message
+                    .text()
+                    .and_then(|text| serde_json::from_str::<FromSubscriberMessage>(&text).ok());
+                if let Some(FromSubscriberMessage::Ack(ack)) = message {
+                    acknowledge_delivery(&subscriptions, &pending_acks, &subscriber, ack);
+                }
+            }
+            let mut subs = subscriptions.borrow_mut();
+            // Subscriptions removed on purpose (closed rooms, resumed subscriptions) don't count
+            let removed = subs
+                .iter()
+                .position(|sub| sub.socket.as_ref() == &socket)
+                .map(|index| subs.remove(index));
+            drop(subs);
+            let removed = match removed {
+                Some(removed) => removed,
+                None => return,
+            };
+            if let Some(last_will) = removed.last_will {
+                let data_message = room_api::SubscriptionDataMessage {
+                    seq: 0,
+                    received_at: w::Date::now().as_millis(),
+                    sender_id: subscriber.clone(),
+                    nonce: last_will.nonce,
+                    data: last_will.data,
+                    require_ack: false,
+                };
+                send_data_to(&subscriptions, data_message, |sub| {
+                    sub.privileged && sub.filter.accepts(&removed.subscriber_id, false)
+                });
+            }
+            send_presence(&subscriptions, &subscriber, api::PresenceKind::Unsubscribed);
+        });
+        Ok(())
+    }
+
+    async fn handle_message(&mut self, message: ToRoomMessage) -> w::Result<w::Response> {
+        match message {
+            ToRoomMessage::Echo(_) => w::Response::from_json(&true),
+            ToRoomMessage::Initialise(message) => {
+                if self.exists().await {
+                    return w::Response::from_json(&None::<api::HistoryRetention>);
+                }
+                let initial_peer_id = message.initial_peer_id.to_string();
+                let history_retention = self.default_history_retention();
+                self.state
+                    .storage()
+                    .put_multiple(RoomStorageUpdate {
+                        peer_roles: Some(PeerRoles::from([(
+                            initial_peer_id.clone(),
+                            api::PeerRole::Owner,
+                        )])),
+                        history_chunks: Some(vec![]),
+                        history_retention: Some(history_retention),
+                        created_at: Some(w::Date::now().as_millis() / 1000),
+                    })
+                    .await?;
+                self.keep_alive(&initial_peer_id).await?;
+                w::Response::from_json(&Some(history_retention))
+            }
+            ToRoomMessage::Subscribe(message) => {
+                let subscription_id = match &message.resume {
+                    Some(resume) => resume.subscription_id,
+                    None => self.get_next_sub_id().await?,
+                };
+                let mut headers = w::Headers::new();
+                headers.set("Subscription-Id", &subscription_id.to_string())?;
+                if !self.exists().await {
+                    return Ok(w::Response::empty()?.with_headers(headers));
+                }
+                let pair = w::WebSocketPair::new()?;
+                let server = pair.server;
+                server.accept()?;
+                server.send_with_str(serde_json::to_string(&FromRoomMessage::SubscriptionId(
+                    subscription_id,
+                ))?)?;
+                let subscriber_id = message.subscriber_id.to_string();
+                let is_new = message.resume.is_none();
+                let mut subscription = Subscription {
+                    socket: server,
+                    privileged: self.get_peer_roles().await.contains_key(&subscriber_id),
+                    subscriber_id: subscriber_id.clone(),
+                    subscription_id,
+                    filter: BroadcastFilter::from(message.filter),
+                    ignore_presence: message.ignore_presence,
+                    next_seq: 0,
+                    last_will: message.last_will,
+                };
+                if let Some(resume) = message.resume {
+                    // The old socket may not have noticed that it's gone yet
+                    self.subscriptions
+                        .borrow_mut()
+                        .retain(|sub| sub.subscription_id != subscription_id);
+                    subscription.next_seq = resume.next_seq;
+                    let since = resume
+                        .after
+                        .as_ref()
+                        .map_or(resume.started_at, |v| v.received_at);
+                    let mut history = self.get_history_since(since).await?;
+                    match &resume.after {
+                        Some(cursor) => {
+                            history.drain(..history_position_after(&history, cursor));
+                        }
+                        // Nothing was received before the connection was lost, so all of it is new
+                        None => history.retain(|v| v.received_at_ms() >= resume.started_at),
+                    }
+                    subscription.replay_history(history)?;
+                }
+                self.keep_alive(&subscriber_id).await?;
+                // Sent without awaiting anything in between, so queued data arrives before live data
+                let unicast_queue = self.get_unicast_queue(&subscriber_id).await;
+                for entry in unicast_queue.iter().flatten() {
+                    // Resumed subscriptions already got these from the history
+                    if !is_new && entry.write_history {
+                        continue;
+                    }
+                    if entry.require_ack {
+                        self.track_pending_ack(
+                            entry.sender_id.to_string(),
+                            subscriber_id.clone(),
+                            entry.nonce,
+                        );
+                    }
+                    subscription.send_data(room_api::SubscriptionDataMessage {
+                        seq: 0,
+                        received_at: entry.queued_at,
+                        sender_id: entry.sender_id.clone(),
+                        nonce: entry.nonce,
+                        data: entry.data.clone(),
+                        require_ack: entry.require_ack,
+                    })?;
+                }
+                let queue_key = unicast_queue_key(&subscriber_id);
+                self.track_subscription(message.subscriber_id, subscription, is_new)?;
+                if unicast_queue.is_some() {
+                    self.state.storage().delete(&queue_key).await?;
+                }
+                Ok(w::Response::from_websocket(pair.client)?.with_headers(headers))
+            }
+            ToRoomMessage::Unsubscribe(message) => {
+                if !self.exists().await {
+                    return w::Response::from_json(&());
+                }
+                let subscriber_id = message.subscriber_id.to_string();
+                // Same rule as for resyncing
+                let is_unsubscribed = |sub: &Subscription| {
+                    sub.subscription_id == message.subscription_id
+                        && sub.subscriber_id == subscriber_id
+                };
+                for sub in self.subscriptions.borrow_mut().iter_mut() {
+                    if is_unsubscribed(sub) {
+                        sub.last_will = None;
+                    }
+                }
+                self.send_to_subscribers(&FromRoomMessage::Close, is_unsubscribed)?;
+                w::Response::from_json(&())
+            }
+            ToRoomMessage::ResyncSubscription(message) => {
+                let mut history = self.get_history().await?;
+                if let Some(after_nonce) = message.after_nonce {
+                    history.retain(|v| v.nonce > after_nonce);
+                }
+                let subscriber_id = message.subscriber_id.to_string();
+                let mut subscriptions = self.subscriptions.borrow_mut();
+                // Subscription IDs only count together with the subscriber they belong to
+                let subscription = subscriptions.iter_mut().find(|sub| {
+                    sub.subscription_id == message.subscription_id
+                        && sub.subscriber_id == subscriber_id
+                });
+                match subscription {
+                    Some(subscription) => {
+                        subscription.replay_history(history)?;
+                        bool_response(true)
+                    }
+                    None => bool_response(false),
+                }
+            }
+            ToRoomMessage::AddPrivilegedPeer(message) => bool_response(
+                self.add_privileged_peer(&message.adder_id.to_string(), &message.added_id)
+                    .await?,
+            ),
+            ToRoomMessage::SetPeerRole(message) => bool_response(
+                self.set_peer_role(
+                    &message.setter_id.to_string(),
+                    &message.peer_id,
+                    message.role,
+                )
+                .await?,
+            ),
+            ToRoomMessage::GetPeers(message) => {
+                let roles = self.get_peer_roles().await;
+                // Only members get to see who else is in the room
+                if !roles.contains_key(&message.requester_id.to_string()) {
+                    return w::Response::from_json(&None::<Vec<PeerInfo>>);
+                }
+                let subscriptions = self.subscriptions.borrow();
+                let is_subscribed =
+                    |peer_id: &str| subscriptions.iter().any(|sub| sub.subscriber_id == peer_id);
+                let mut peers: Vec<PeerInfo> = roles
+                    .iter()
+                    .map(|(peer_id, role)| PeerInfo {
+                        peer_id: peer_id.clone(),
+                        privileged: true,
+                        role: Some(*role),
+                        subscribed: is_subscribed(peer_id),
+                    })
+                    .collect();
+                for sub in subscriptions.iter() {
+                    if !peers.iter().any(|peer| peer.peer_id == sub.subscriber_id) {
+                        peers.push(PeerInfo {
+                            peer_id: sub.subscriber_id.clone(),
+                            privileged: false,
+                            role: None,
+                            subscribed: true,
+                        });
+                    }
+                }
+                w::Response::from_json(&Some(peers))
+            }
+            ToRoomMessage::GetInfo(message) => {
+                let privileged_peers = self.get_privileged_peers().await;
+                // Same rule as for listing peers
+                if !privileged_peers.contains(&message.requester_id.to_string()) {
+                    return w::Response::from_json(&None::<api::RoomInfo>);
+                }
+                let storage = self.state.storage();
+                let mut subscribers: Vec<String> = self
+                    .subscriptions
+                    .borrow()
+                    .iter()
+                    .map(|sub| sub.subscriber_id.clone())
+                    .collect();
+                subscribers.sort_unstable();
+                subscribers.dedup();
+                let info = api::RoomInfo {
+                    created_at: storage.get("created_at").await.ok(),
+                    peer_count: privileged_peers.len() as u64,
+                    subscriber_count: subscribers.len() as u64,
+                    history_entry_count: self.get_history_entry_count().await? as u64,
+                    history_retention: self.get_history_retention().await,
+                    idle_timeout_secs: self.idle_timeout().as_secs(),
+                    max_data_bytes: config::max_data_bytes(&self.env) as u64,
+                };
+                w::Response::from_json(&Some(info))
+            }
+            ToRoomMessage::GetHistory(message) => {
+                let requester_id = message.requester_id.to_string();
+                // Same rule as for listing peers
+                if !self.get_privileged_peers().await.contains(&requester_id) {
+                    return w::Response::from_json(&api::GetRoomDataHistorySuccess {
+                        entries: vec![],
+                        truncated: false,
+                    });
+                }
+                let sender_id = message.sender_id.map(|v| v.to_string());
+                let is_visible = |v: &HistoryEntry| {
+                    sender_id.as_ref().map_or(true, |id| v.sender_id == *id)
+                        && match v.is_addressed_to(&requester_id) {
+                            Some(addressed) => addressed || v.sender_id == requester_id,
+                            None => true,
+                        }
+                };
+                let is_cursor = |v: &HistoryEntry| match &message.after {
+                    Some(after) => {
+                        v.nonce == after.nonce && v.sender_id == after.sender_id.to_string()
+                    }
+                    None => false,
+                };
+                let chunks = self.get_history_chunks().await?;
+                // Chunks are in the order the room received their entries, so the ones before
+                // the range can be skipped without loading them
+                let start =
+                    chunks.partition_point(|v| v.last_received_at / 1000 < message.from_timestamp);
+                let mut matching: Vec<HistoryEntry> = Vec::new();
+                let mut looking_for_cursor = message.after.is_some();
+                'chunks: for chunk in &chunks[start..] {
+                    for entry in self.get_history_chunk(chunk.id).await {
+                        let timestamp = entry.received_at_ms() / 1000;
+                        if timestamp < message.from_timestamp {
+                            continue;
+                        }
+                        if message
+                            .until_timestamp
+                            .map_or(false, |until| timestamp > until)
+                        {
+                            break 'chunks;
+                        }
+                        if looking_for_cursor {
+                            if is_cursor(&entry) {
+                                looking_for_cursor = false;
+                                matching.clear();
+                                continue;
+                            }
+                            // It's gone, as it would have been received at `from_timestamp`, so
+                            // everything from then on is returned
+                            if timestamp > message.from_timestamp {
+                                looking_for_cursor = false;
+                            }
+                        }
+                        if is_visible(&entry) {
+                            matching.push(entry);
+                        }
+                        // One more than the limit, to tell whether there are more
+                        if !looking_for_cursor && matching.len() > message.limit {
+                            break 'chunks;
+                        }
+                    }
+                }
+                let truncated = matching.len() > message.limit;
+                let entries = matching
+                    .into_iter()
+                    .take(message.limit)
+                    .filter_map(|v| {
+                        Some(api::RoomDataHistoryEntry {
+                            timestamp: v.received_at_ms() / 1000,
+                            sender_id: api::PublicKeyWrapper::try_from(v.sender_id).ok()?,
+                            nonce: v.nonce,
+                            data: v.data,
+                        })
+                    })
+                    .collect();
+                w::Response::from_json(&api::GetRoomDataHistorySuccess { entries, truncated })
+            }
+            ToRoomMessage::Inspect(_) => {
+                if !self.exists().await {
+                    return w::Response::from_json(&None::<RoomInspection>);
+                }
+                let inspection = RoomInspection {
+                    peer_roles: self.get_peer_roles().await,
+                    history_entries: self.get_history_entry_count().await?,
+                    history_retention: self.get_history_retention().await,
+                    expires_at: get_or_default(&self.state.storage(), "expires_at").await,
+                    subscriptions: self
+                        .subscriptions
+                        .borrow()
+                        .iter()
+                        .map(|sub| SubscriptionInspection {
+                            subscriber_id: sub.subscriber_id.clone(),
+                            subscription_id: sub.subscription_id,
+                        })
+                        .collect(),
+                };
+                w::Response::from_json(&Some(inspection))
+            }
+            ToRoomMessage::Delete(message) => {
+                if let Some(deleter_id) = message.deleter_id {
+                    let deleter_role = self
+                        .get_peer_roles()
+                        .await
+                        .get(&deleter_id.to_string())
+                        .copied();
+                    if deleter_role != Some(api::PeerRole::Owner) {
+                        return bool_response(false);
+                    }
+                }
+                if !self.exists().await {
+                    return bool_response(false);
+                }
+                self.close_subscriptions(api::RoomClosedReason::Deleted);
+                let mut storage = self.state.storage();
+                storage.delete_all().await?;
+                storage.delete_alarm().await?;
+                bool_response(true)
+            }
+            ToRoomMessage::BroadcastData(message) => {
+                let received_at = w::Date::now().as_millis();
+                if !self.exists().await {
+                    return bool_response(false);
+                }
+                let sender_id = message.sender_id.to_string();
+                let privileged_peers = self.get_privileged_peers().await;
+                if message.write_history {
+                    let write = self
+                        .append_history(HistoryEntry {
+                            receiver_id: None,
+                            receiver_ids: None,
+                            timestamp: message.nonce.timestamp,
+                            received_at: Some(received_at),
+                            data: message.data.clone(),
+                            sender_id: sender_id.clone(),
+                            nonce: message.nonce,
+                        })
+                        .await?;
+                    self.write_history(write).await?;
+                }
+                let data_message = room_api::SubscriptionDataMessage {
+                    seq: 0,
+                    received_at,
+                    sender_id: message.sender_id,
+                    nonce: message.nonce,
+                    data: message.data,
+                    require_ack: false,
+                };
+                send_data_to(&self.subscriptions, data_message, |sub| {
+                    privileged_peers.contains(&sub.subscriber_id)
+                        && sub.filter.accepts(&sender_id, message.write_history)
+                });
+                self.keep_alive(&sender_id).await?;
+                bool_response(true)
+            }
+            ToRoomMessage::UnicastData(message) => {
+                let received_at = w::Date::now().as_millis();
+                if !self.exists().await {
+                    return bool_response(false);
+                }
+                let sender_id = message.sender_id.to_string();
+                let receiver_id = message.receiver_id.to_string();
+                let mut roles = self.get_peer_roles().await;
+                let sender_has_role = roles.contains_key(&sender_id);
+                let mut update = RoomStorageUpdate::default();
+                if message.make_receiver_privileged {
+                    // Reject the whole send rather than delivering data whose privilege grant failed
+                    if !sender_has_role {
+                        return bool_response(false);
+                    }
+                    if !roles.contains_key(&receiver_id) {
+                        roles.insert(receiver_id.clone(), api::PeerRole::Member);
+                        update.peer_roles = Some(roles);
+                    }
+                }
+                let receiver_subscribed = self
+                    .subscriptions
+                    .borrow()
+                    .iter()
+                    .any(|sub| sub.subscriber_id == receiver_id);
+                // Only peers with a role can queue, so strangers can't fill anyone's queue.
+                // That also keeps the error below from revealing whether the room exists.
+                let unicast_queue = if !receiver_subscribed && sender_has_role {
+                    let mut queue = self
+                        .get_unicast_queue(&receiver_id)
+                        .await
+                        .unwrap_or_default();
+                    queue.push(QueuedUnicast {
+                        sender_id: message.sender_id.clone(),
+                        nonce: message.nonce,
+                        data: message.data.clone(),
+                        write_history: message.write_history,
+                        require_ack: message.require_ack,
+                        queued_at: received_at,
+                    });
+                    if !self.unicast_queue_fits(&queue)? {
+                        return w::Response::error("Queue full", 507);
+                    }
+                    Some(queue)
+                } else {
+                    None
+                };
+                let history_write = match message.write_history {
+                    false => None,
+                    true => Some(
+                        self.append_history(HistoryEntry {
+                            receiver_id: Some(receiver_id.clone()),
+                            receiver_ids: None,
+                            timestamp: message.nonce.timestamp,
+                            received_at: Some(received_at),
+                            data: message.data.clone(),
+                            sender_id: sender_id.clone(),
+                            nonce: message.nonce,
+                        })
+                        .await?,
+                    ),
+                };
+                // Started without awaiting anything in between, so storage writes the privilege
+                // grant and the history entry together
+                let receiver_joined = update.peer_roles.is_some();
+                let mut storage = self.state.storage();
+                futures::try_join!(
+                    async {
+                        match receiver_joined {
+                            true => storage.put_multiple(update).await,
+                            false => Ok(()),
+                        }
+                    },
+                    async {
+                        match history_write {
+                            Some(write) => self.write_history(write).await,
+                            None => Ok(()),
+                        }
+                    },
+                )?;
+                if let Some(queue) = unicast_queue {
+                    let key = unicast_queue_key(&receiver_id);
+                    self.state.storage().put(&key, queue).await?;
+                }
+                if receiver_joined {
+                    self.on_privilege_changed(&message.receiver_id, true);
+                }
+                // Queued data is tracked once it's flushed to the receiver
+                if message.require_ack && receiver_subscribed {
+                    self.track_pending_ack(sender_id.clone(), receiver_id.clone(), message.nonce);
+                }
+                let data_message = room_api::SubscriptionDataMessage {
+                    seq: 0,
+                    received_at,
+                    sender_id: message.sender_id,
+                    nonce: message.nonce,
+                    data: message.data,
+                    require_ack: message.require_ack,
+                };
+                send_data_to(&self.subscriptions, data_message, |sub| {
+                    sub.subscriber_id == receiver_id
+                });
+                self.keep_alive(&sender_id).await?;
+                bool_response(true)
+            }
+            ToRoomMessage::MulticastData(message) => {
+                let received_at = w::Date::now().as_millis();
+                // Only existing rooms have peers with roles, so this covers the room not existing.
+                // It also keeps strangers from learning who is subscribed.
+                let sender_id = message.sender_id.to_string();
+                if !self.get_privileged_peers().await.contains(&sender_id) {
+                    return w::Response::from_json(&Vec::<api::PublicKeyWrapper>::new());
+                }
+                let mut receiver_ids = message.receiver_ids;
+                receiver_ids.sort_by_key(|v| v.to_string());
+                receiver_ids.dedup_by(|a, b| a.to_string() == b.to_string());
+                let receiver_id_strings: Vec<String> =
+                    receiver_ids.iter().map(|v| v.to_string()).collect();
+                if message.write_history {
+                    let write = self
+                        .append_history(HistoryEntry {
+                            receiver_id: None,
+                            receiver_ids: Some(receiver_id_strings.clone()),
+                            timestamp: message.nonce.timestamp,
+                            received_at: Some(received_at),
+                            data: message.data.clone(),
+                            sender_id: sender_id.clone(),
+                            nonce: message.nonce,
+                        })
+                        .await?;
+                    self.write_history(write).await?;
+                }
+                let delivered_to: Vec<api::PublicKeyWrapper> = {
+                    let subscriptions = self.subscriptions.borrow();
+                    receiver_ids
+                        .into_iter()
+                        .filter(|receiver_id| {
+                            let receiver_id = receiver_id.to_string();
+                            subscriptions
+                                .iter()
+                                .any(|sub| sub.subscriber_id == receiver_id)
+                        })
+                        .collect()
+                };
+                let data_message = room_api::SubscriptionDataMessage {
+                    seq: 0,
+                    received_at,
+                    sender_id: message.sender_id,
+                    nonce: message.nonce,
+                    data: message.data,
+                    require_ack: false,
+                };
+                // Addressed data isn't filtered, same as unicast data
+                send_data_to(&self.subscriptions, data_message, |sub| {
+                    receiver_id_strings.contains(&sub.subscriber_id)
+                });
+                self.keep_alive(&sender_id).await?;
+                w::Response::from_json(&delivered_to)
+            }
+            ToRoomMessage::SendEphemeral(message) => {
+                // Never written anywhere, and doesn't count as activity keeping the room alive.
+                // Only existing rooms have peers with roles, so there's no separate existence check.
+                let sender_id = message.sender_id.to_string();
+                let privileged_peers = self.get_privileged_peers().await;
+                if !privileged_peers.contains(&sender_id) {
+                    return bool_response(false);
+                }
+                // Only reported to senders with a role, so it doesn't reveal whether the room exists
+                if !self.ephemeral_limiter.check(&sender_id) {
+                    let mut headers = w::Headers::new();
+                    headers.set(
+                        "Retry-After-Ms",
+                        &self.ephemeral_limiter.retry_ms().to_string(),
+                    )?;
+                    return Ok(w::Response::error("Rate limited", 429)?.with_headers(headers));
+                }
+                let ephemeral_message = FromRoomMessage::Ephemeral(room_api::EphemeralMessage {
+                    sender_id: message.sender_id,
+                    data: message.data,
+                    max_age_ms: message.max_age_ms,
+                    timestamp: w::Date::now().as_millis(),
+                });
+                self.send_to_subscribers(&ephemeral_message, |sub| {
+                    sub.privileged && sub.filter.accepts(&sender_id, false)
+                })?;
+                bool_response(true)
+            }
+            ToRoomMessage::DeleteData(message) => {
+                if !self.exists().await {
+                    return bool_response(false);
+                }
+                let roles = self.get_peer_roles().await;
+                let deleter_id = message.deleter_id.to_string();
+                let data_sender_id = message.data_sender_id.to_string();
+                // Peers may always retract their own data, moderators may retract anyone's
+                let is_moderator = roles
+                    .get(&deleter_id)
+                    .map_or(false, |v| *v >= api::PeerRole::Moderator);
+                if deleter_id != data_sender_id && !is_moderator {
+                    return bool_response(false);
+                }
+                // Nothing to tell anyone about, and nothing to write, for data that isn't there
+                if !self
+                    .delete_history_entry(&data_sender_id, message.data_nonce)
+                    .await?
+                {
+                    return bool_response(false);
+                }
+                let deleted_message = FromRoomMessage::DataDeleted(room_api::DataDeletedMessage {
+                    deleter_id: message.deleter_id,
+                    data_sender_id: message.data_sender_id,
+                    data_nonce: message.data_nonce,
+                });
+                self.send_to_subscribers(&deleted_message, |sub| {
+                    roles.contains_key(&sub.subscriber_id)
+                })?;
+                bool_response(true)
+            }
+        }
+    }
+}
+
+#[durable_object]
+impl DurableObject for Room {
+    fn new(state: w::State, env: w::Env) -> Self {
+        config::init_logging(&env);
+        metrics::init(&env);
+        let ephemeral_limiter = KeyedRateLimiter::new(RateLimitConfig::from_env(
+            &env,
+            "RATE_LIMIT_EPHEMERAL",
+            RateLimitConfig {
+                burst: 10.0,
+                per_sec: 2.0,
+            },
+        ));
+        Self {
+            state,
+            env,
+            subscriptions: Rc::new(RefCell::new(Vec::new())),
+            pending_acks: Rc::new(RefCell::new(VecDeque::new())),
+            ephemeral_limiter,
+        }
+    }
+
+    async fn fetch(&mut self, mut req: w::Request) -> w::Result<w::Response> {
+        let message: ToRoomMessage = req.json().await?;
+        self.handle_message(message).await
+    }
+
+    async fn alarm(&mut self) -> w::Result<w::Response> {
+        let expires_at: u64 = get_or_default(&self.state.storage(), "expires_at").await;
+        if w::Date::now().as_millis() < expires_at {
+            let mut write = HistoryWrite::new(self.get_history_chunks().await?);
+            self.compact_history(&mut write).await?;
+            if !write.is_empty() {
+                self.write_history(write).await?;
+            }
+            self.schedule_alarm(expires_at).await?;
+            return w::Response::empty();
+        }
+        // Expiration is pushed back on every bit of activity, so reaching it means the room went idle
+        self.close_subscriptions(api::RoomClosedReason::Expired);
+        // Wiping everything makes the room ID available to CreateRoom again
+        self.state.storage().delete_all().await?;
+        w::Response::empty()
+    }
+}
@@ -1,72 +1,258 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use worker as w;
 use zend_common::{api, enum_convert::EnumConvert};
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct InitialiseMessage {
-    pub initial_peer_id: api::EcdsaPublicKeyWrapper,
+    pub initial_peer_id: api::PublicKeyWrapper,
 }
 
-#[derive(Serialize)]
+/** The last data forwarded over a subscription. Nonces are only ordered per sender, so the
+sender is needed to find it in the history, and `received_at` where it isn't in the history. */
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HistoryCursor {
+    pub sender_id: api::PublicKeyWrapper,
+    pub nonce: api::Nonce,
+    /** In milliseconds */
+    pub received_at: u64,
+}
+
+/** Continues a subscription whose connection to the room was lost */
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ResumeSubscription {
+    pub subscription_id: u64,
+    /** History entries the room received after this one are replayed to the subscriber */
+    #[serde(default)]
+    pub after: Option<HistoryCursor>,
+    /** Where the subscription's data numbering carries on from */
+    #[serde(default)]
+    pub next_seq: u64,
+    /** When the subscription was first opened, in milliseconds. Without an `after` cursor,
+    history entries the room received since then are replayed. */
+    #[serde(default)]
+    pub started_at: u64,
+}
+
+/** Sent to everyone with a role when a subscription closes without being unsubscribed */
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LastWill {
+    pub nonce: api::Nonce,
+    pub data: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct SubscribeMessage {
-    pub subscriber_id: api::EcdsaPublicKeyWrapper,
+    pub subscriber_id: api::PublicKeyWrapper,
+    #[serde(default)]
+    pub resume: Option<ResumeSubscription>,
+    #[serde(default)]
+    pub filter: api::SubscriptionFilter,
+    #[serde(default)]
+    pub ignore_presence: bool,
+    #[serde(default)]
+    pub last_will: Option<LastWill>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct UnsubscribeMessage {
+    pub subscriber_id: api::PublicKeyWrapper,
     pub subscription_id: u64,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
+pub struct ResyncSubscriptionMessage {
+    pub subscriber_id: api::PublicKeyWrapper,
+    pub subscription_id: u64,
+    pub after_nonce: Option<api::Nonce>,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct AddPrivilegedPeerMessage {
-    pub adder_id: api::EcdsaPublicKeyWrapper,
-    pub added_id: api::EcdsaPublicKeyWrapper,
+    pub adder_id: api::PublicKeyWrapper,
+    pub added_id: api::PublicKeyWrapper,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
+pub struct SetPeerRoleMessage {
+    pub setter_id: api::PublicKeyWrapper,
+    pub peer_id: api::PublicKeyWrapper,
+    pub role: Option<api::PeerRole>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetPeersMessage {
+    pub requester_id: api::PublicKeyWrapper,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetInfoMessage {
+    pub requester_id: api::PublicKeyWrapper,
+}
+
+/** Answered with an `api::GetRoomDataHistorySuccess`. Times are in seconds. */
+#[derive(Serialize, Deserialize)]
+pub struct GetHistoryMessage {
+    pub requester_id: api::PublicKeyWrapper,
+    pub sender_id: Option<api::PublicKeyWrapper>,
+    pub from_timestamp: u64,
+    pub until_timestamp: Option<u64>,
+    pub after: Option<api::HistoryPosition>,
+    pub limit: usize,
+}
+
+/** Answered without touching storage, used by health checks */
+#[derive(Serialize, Deserialize)]
+pub struct EchoMessage {}
+
+/** Only sent by the admin API, so it isn't checked against anyone's privileges */
+#[derive(Serialize, Deserialize)]
+pub struct InspectMessage {}
+
+#[derive(Serialize, Deserialize)]
 pub struct DeleteMessage {
-    pub deleter_id: Option<api::EcdsaPublicKeyWrapper>,
+    pub deleter_id: Option<api::PublicKeyWrapper>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct BroadcastDataMessage {
     pub data: serde_json::Value,
-    pub sender_id: api::EcdsaPublicKeyWrapper,
+    pub sender_id: api::PublicKeyWrapper,
     pub nonce: api::Nonce,
     pub write_history: bool,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 pub struct UnicastDataMessage {
     pub data: serde_json::Value,
-    pub sender_id: api::EcdsaPublicKeyWrapper,
-    pub receiver_id: api::EcdsaPublicKeyWrapper,
+    pub sender_id: api::PublicKeyWrapper,
+    pub receiver_id: api::PublicKeyWrapper,
     pub nonce: api::Nonce,
     pub write_history: bool,
     pub make_receiver_privileged: bool,
+    pub require_ack: bool,
 }
 
-#[derive(Serialize)]
+/** Answered with the receivers that are subscribed, which the data was sent to */
+#[derive(Serialize, Deserialize)]
+pub struct MulticastDataMessage {
+    pub data: serde_json::Value,
+    pub sender_id: api::PublicKeyWrapper,
+    pub receiver_ids: Vec<api::PublicKeyWrapper>,
+    pub nonce: api::Nonce,
+    pub write_history: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SendEphemeralMessage {
+    pub data: serde_json::Value,
+    pub sender_id: api::PublicKeyWrapper,
+    pub max_age_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct DeleteDataMessage {
-    pub deleter_id: api::EcdsaPublicKeyWrapper,
-    pub data_sender_id: api::EcdsaPublicKeyWrapper,
+    pub deleter_id: api::PublicKeyWrapper,
+    pub data_sender_id: api::PublicKeyWrapper,
     pub data_nonce: api::Nonce,
 }
 
-#[derive(Serialize, EnumConvert)]
+#[derive(Serialize, Deserialize, EnumConvert)]
 #[enum_convert(from, into)]
 #[serde(rename_all = "snake_case", tag = "message_type")]
 pub enum ToRoomMessage {
+    Echo(EchoMessage),
     Initialise(InitialiseMessage),
     // CheckExists,
     Subscribe(SubscribeMessage),
+    Unsubscribe(UnsubscribeMessage),
+    ResyncSubscription(ResyncSubscriptionMessage),
     AddPrivilegedPeer(AddPrivilegedPeerMessage),
+    SetPeerRole(SetPeerRoleMessage),
+    GetPeers(GetPeersMessage),
+    GetInfo(GetInfoMessage),
+    GetHistory(GetHistoryMessage),
+    Inspect(InspectMessage),
     Delete(DeleteMessage),
     BroadcastData(BroadcastDataMessage),
     UnicastData(UnicastDataMessage),
+    MulticastData(MulticastDataMessage),
+    SendEphemeral(SendEphemeralMessage),
     DeleteData(DeleteDataMessage),
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SubscriptionDataMessage {
+    /** Assigned per subscription when the room sends the data */
+    #[serde(default)]
+    pub seq: u64,
+    /** When the room received the data, in milliseconds */
+    #[serde(default)]
+    pub received_at: u64,
+    pub sender_id: api::PublicKeyWrapper,
+    pub nonce: api::Nonce,
+    pub data: serde_json::Value,
+    /** The subscriber should answer with `FromSubscriberMessage::Ack` once it forwarded the data */
+    #[serde(default)]
+    pub require_ack: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EphemeralMessage {
+    pub sender_id: api::PublicKeyWrapper,
+    pub data: serde_json::Value,
+    pub max_age_ms: Option<u64>,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PresenceMessage {
+    pub peer_id: api::PublicKeyWrapper,
+    pub kind: api::PresenceKind,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeliveryReceiptMessage {
+    pub receiver_id: api::PublicKeyWrapper,
+    pub nonce: api::Nonce,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DataDeletedMessage {
+    pub deleter_id: api::PublicKeyWrapper,
+    pub data_sender_id: api::PublicKeyWrapper,
+    pub data_nonce: api::Nonce,
+}
+
+/** Messages sent by the room over a subscriber's websocket */
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "message_type", content = "message_content")]
+pub enum FromRoomMessage {
+    Close,
+    Data(SubscriptionDataMessage),
+    Ephemeral(EphemeralMessage),
+    DataDeleted(DataDeletedMessage),
+    Presence(PresenceMessage),
+    DeliveryReceipt(DeliveryReceiptMessage),
+    RoomClosed(api::RoomClosedReason),
+    SubscriptionId(u64),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AckMessage {
+    pub sender_id: api::PublicKeyWrapper,
+    pub nonce: api::Nonce,
+}
+
+/** Messages sent by a subscriber over its websocket to the room */
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "message_type", content = "message_content")]
+pub enum FromSubscriberMessage {
+    Ack(AckMessage),
+}
+
 pub fn make_request<T: Into<ToRoomMessage>>(message: T) -> Result<w::Request, w::Error> {
     let message: ToRoomMessage = message.into();
     w::Request::new_with_init(
@@ -0,0 +1,68 @@
+use serde::{Deserialize, Serialize};
+use worker as w;
+use zend_common::{api, util};
+
+/** Size of session keys, which are used for HMAC-SHA256 */
+const SESSION_KEY_BYTES: usize = 32;
+/** Size of the challenges a session is created with */
+pub const SESSION_CHALLENGE_BYTES: usize = 16;
+
+/** Base64 of cryptographically secure random bytes */
+pub fn random_base64(len: usize) -> Result<String, w::Error> {
+    let mut bytes = vec![0u8; len];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|err| w::Error::RustError(format!("Failed to get random bytes. {}", err)))?;
+    Ok(util::encode_base64(&bytes))
+}
+
+/** Lets one caller authenticate its calls with a key instead of a signature. Bound to the
+connection it was created on, and stored in its attachment so it survives hibernation. */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    caller_id: api::PublicKeyWrapper,
+    /** Base64 */
+    key: String,
+    /** Unix timestamp in seconds */
+    expires_at: u64,
+    /** Calls have to use increasing nonces, so reuse can be checked without asking the peer */
+    last_nonce: Option<api::Nonce>,
+}
+impl Session {
+    pub fn new(caller_id: api::PublicKeyWrapper, expires_at: u64) -> Result<Self, w::Error> {
+        Ok(Self {
+            caller_id,
+            key: random_base64(SESSION_KEY_BYTES)?,
+            expires_at,
+            last_nonce: None,
+        })
+    }
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+    pub fn is_expired(&self, now_secs: u64) -> bool {
+        now_secs >= self.expires_at
+    }
+    pub fn is_for(&self, caller_id: &api::PublicKeyWrapper) -> bool {
+        self.caller_id.to_string() == caller_id.to_string()
+    }
+
+    /** Checks everything but the call's timestamp, and remembers its nonce if it's accepted */
+    pub fn check_call(&mut self, call: &api::SessionMethodCall) -> Result<(), api::ErrorId> {
+        let common_args = &call.session_call.call.common_arguments;
+        if !self.is_for(&common_args.caller_id) {
+            return Err(api::ErrorId::InvalidSession);
+        }
+        let key = util::decode_base64(&self.key).map_err(|_| api::ErrorId::InternalError)?;
+        if !call.validate_mac(&key) {
+            return Err(api::ErrorId::InvalidSignature);
+        }
+        if self
+            .last_nonce
+            .is_some_and(|last| common_args.nonce <= last)
+        {
+            return Err(api::ErrorId::NonceReused);
+        }
+        self.last_nonce = Some(common_args.nonce);
+        Ok(())
+    }
+}
@@ -1,8 +1,19 @@
-use crate::peer_api;
-use futures::StreamExt;
-use std::{fmt::Display, rc::Rc};
+use crate::{
+    config, metrics, peer_api,
+    rate_limit::RateLimiter,
+    session::{self, Session},
+};
+use futures::future::{AbortHandle, AbortRegistration, Abortable};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt::Display,
+    rc::Rc,
+};
 use worker as w;
-use zend_common::{api, log};
+use zend_common::{
+    api, codec::Encoding, log_debug, log_error, log_info, log_warn, logging::LogContext,
+};
 
 pub trait WebSocketExt {
     /** (n)o (f)ail (send) (j)son, given a less-than-readable name as it's
@@ -10,53 +21,375 @@ pub trait WebSocketExt {
     fn nfsendj<T: serde::Serialize>(&self, data: &T);
     /** (n)o (f)ail (send) (j)son + unwrap, given a less-than-readable name as it's
     frequently used in places with already busy syntax  */
-    fn nfsendj_unwrap<T: serde::Serialize, U: Display>(&self, data: &Result<T, U>);
+    fn nfsendj_unwrap<T: serde::Serialize, U: Display>(&self, result: &Result<T, U>) {
+        match result {
+            Ok(data) => self.nfsendj(data),
+            Err(err) => log_warn!("Failed to unwrap a result. {}", err),
+        }
+    }
+    /** Closes with one of the codes clients know how to interpret, logging failures */
+    fn close_with(&self, code: api::CloseCode);
+    /** Tells the client when to reconnect, then closes. For closes initiated by the server. */
+    fn go_away(&self, code: api::CloseCode, retry_after_secs: Option<u64>) {
+        self.nfsendj(&api::GoingAway { retry_after_secs }.into_message());
+        self.close_with(code);
+    }
 }
 impl WebSocketExt for w::WebSocket {
     fn nfsendj<T: serde::Serialize>(&self, data: &T) {
         match serde_json::to_string(data) {
             Ok(json) => match self.send_with_str(json) {
-                Ok(_) => log!("Successfully sent a message."),
-                Err(err) => log!("Failed to send a message. {}", err),
+                Ok(_) => log_debug!("Successfully sent a message."),
+                Err(err) => log_warn!("Failed to send a message. {}", err),
             },
-            Err(err) => log!("Failed to serialise a message. {}", err),
+            Err(err) => log_error!("Failed to serialise a message. {}", err),
         }
     }
-    fn nfsendj_unwrap<T: serde::Serialize, U: Display>(&self, result: &Result<T, U>) {
+    fn close_with(&self, code: api::CloseCode) {
+        if let Err(err) = self.close(Some(code.code()), Some(code.reason())) {
+            log_warn!("Failed to close a websocket. {}", err);
+        }
+    }
+}
+
+/** Told to clients in the `ServerHello` */
+const SERVER_FEATURES: [api::ProtocolFeature; 10] = [
+    api::ProtocolFeature::Cbor,
+    api::ProtocolFeature::Compression,
+    api::ProtocolFeature::DeliveryReceipts,
+    api::ProtocolFeature::Ed25519,
+    api::ProtocolFeature::EphemeralData,
+    api::ProtocolFeature::Multicast,
+    api::ProtocolFeature::Presence,
+    api::ProtocolFeature::Sessions,
+    api::ProtocolFeature::SubscriptionSeq,
+    api::ProtocolFeature::UnicastQueue,
+];
+
+/** A connection's session and challenge. Messages are handled concurrently, so they share one
+of these, which is checked and updated without awaiting anything in between. */
+#[derive(Debug, Default)]
+pub struct SessionState {
+    pub session: Option<Session>,
+    /** The last challenge sent to the client, until it's used to create a session */
+    pub challenge: Option<String>,
+}
+
+/** A client's websocket, which sends everything in the encoding the client asked for */
+pub struct ClientSocket {
+    socket: w::WebSocket,
+    /** Can be changed with the client's hello */
+    encoding: Cell<Encoding>,
+    /** None until the client said hello */
+    protocol_version: Cell<Option<u32>>,
+    /** Whether the client listed compression in its hello */
+    compression: Cell<bool>,
+    session_state: Rc<RefCell<SessionState>>,
+    /** Whether the session or challenge changed and has to be stored again */
+    session_changed: Cell<bool>,
+    /** Sockets are wrapped for every message, so this is when the message being handled arrived */
+    received_at: u64,
+}
+impl ClientSocket {
+    pub fn new(socket: w::WebSocket, encoding: Encoding, protocol_version: Option<u32>) -> Self {
+        Self {
+            socket,
+            encoding: Cell::new(encoding),
+            protocol_version: Cell::new(protocol_version),
+            compression: Cell::new(false),
+            session_state: Default::default(),
+            session_changed: Cell::new(false),
+            received_at: w::Date::now().as_millis(),
+        }
+    }
+    pub fn with_compression(self, compression: bool) -> Self {
+        self.compression.set(compression);
+        self
+    }
+    /** Shared with the other messages of the connection that are being handled */
+    pub fn with_session_state(mut self, session_state: Rc<RefCell<SessionState>>) -> Self {
+        self.session_state = session_state;
+        self
+    }
+    /** The session and challenge to store, if either changed. That includes changes made while
+    handling other messages, so storing them last doesn't undo anything. */
+    pub fn changed_session_state(&self) -> Option<(Option<Session>, Option<String>)> {
+        if !self.session_changed.get() {
+            return None;
+        }
+        let state = self.session_state.borrow();
+        Some((state.session.clone(), state.challenge.clone()))
+    }
+    fn set_session_challenge(&self, challenge: String) {
+        self.session_state.borrow_mut().challenge = Some(challenge);
+        self.session_changed.set(true);
+    }
+    /** Challenges can only be used once, even if they don't match */
+    pub fn take_session_challenge(&self, challenge: &str) -> bool {
+        let expected = self.session_state.borrow_mut().challenge.take();
+        self.session_changed.set(true);
+        expected.is_some_and(|expected| expected == challenge)
+    }
+    /** Replaces any previous session */
+    pub fn set_session(&self, session: Session) {
+        self.session_state.borrow_mut().session = Some(session);
+        self.session_changed.set(true);
+    }
+    /** Revokes the session if it belongs to the caller */
+    pub fn end_session(&self, caller_id: &api::PublicKeyWrapper) {
+        let mut state = self.session_state.borrow_mut();
+        let session = &mut state.session;
+        if session.as_ref().is_some_and(|v| v.is_for(caller_id)) {
+            *session = None;
+            self.session_changed.set(true);
+        }
+    }
+    fn check_session_call(
+        &self,
+        call: &api::SessionMethodCall,
+        now_secs: u64,
+    ) -> Result<(), api::ErrorId> {
+        let mut state = self.session_state.borrow_mut();
+        let session = &mut state.session;
+        let result = match session.as_mut() {
+            None => return Err(api::ErrorId::InvalidSession),
+            Some(v) if v.is_expired(now_secs) => {
+                *session = None;
+                Err(api::ErrorId::InvalidSession)
+            }
+            Some(v) => v.check_call(call),
+        };
+        // Either the nonce was remembered or the expired session dropped
+        self.session_changed.set(true);
+        result
+    }
+    pub fn protocol_version(&self) -> Option<u32> {
+        self.protocol_version.get()
+    }
+    pub fn received_at(&self) -> u64 {
+        self.received_at
+    }
+    pub fn encoding(&self) -> Encoding {
+        self.encoding.get()
+    }
+    pub fn compression(&self) -> bool {
+        self.compression.get()
+    }
+    pub fn is_open(&self) -> bool {
+        let socket: &web_sys::WebSocket = self.socket.as_ref();
+        socket.ready_state() == web_sys::WebSocket::OPEN
+    }
+}
+impl WebSocketExt for ClientSocket {
+    fn nfsendj<T: serde::Serialize>(&self, data: &T) {
+        let encoding = self.encoding.get();
+        if !encoding.is_binary() && !self.compression.get() {
+            return self.socket.nfsendj(data);
+        }
+        let encoded = match self.compression.get() {
+            true => encoding.encode_compressed(data, deflate),
+            false => encoding.encode(data),
+        };
+        let bytes = match encoded {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                log_error!("Failed to serialise a message. {}", err);
+                return;
+            }
+        };
+        let result = match encoding.is_binary() {
+            true => self.socket.send_with_bytes(bytes),
+            // Text encodings always produce valid UTF-8
+            false => self.socket.send_with_str(String::from_utf8_lossy(&bytes)),
+        };
         match result {
-            Ok(data) => self.nfsendj(data),
-            Err(err) => log!("Failed to unwrap a result. {}", err),
+            Ok(_) => log_debug!("Successfully sent a message."),
+            Err(err) => log_warn!("Failed to send a message. {}", err),
+        }
+    }
+    fn close_with(&self, code: api::CloseCode) {
+        self.socket.close_with(code)
+    }
+}
+
+fn deflate(bytes: &[u8]) -> Vec<u8> {
+    miniz_oxide::deflate::compress_to_vec(bytes, 6)
+}
+
+/** Compressed messages can't inflate beyond `max_bytes`, same as the limit for plain ones */
+fn inflate(bytes: &[u8], max_bytes: usize) -> Result<Vec<u8>, String> {
+    miniz_oxide::inflate::decompress_to_vec_with_limit(bytes, max_bytes)
+        .map_err(|err| format!("Failed to decompress a message. {:?}", err))
+}
+
+/** A message as received from a client. Text frames hold JSON, binary frames hold CBOR. */
+pub enum ClientFrame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+impl ClientFrame {
+    fn len(&self) -> usize {
+        match self {
+            ClientFrame::Text(text) => text.len(),
+            ClientFrame::Binary(bytes) => bytes.len(),
+        }
+    }
+    /** Compressed messages are only accepted once the client said it supports compression */
+    fn parse(
+        &self,
+        protocol_version: u32,
+        compression: bool,
+        max_bytes: usize,
+    ) -> Result<api::ClientToServerMessage, String> {
+        let (encoding, bytes) = match self {
+            ClientFrame::Text(text) => (Encoding::Json, text.as_bytes()),
+            ClientFrame::Binary(bytes) => (Encoding::Cbor, bytes.as_slice()),
+        };
+        match protocol_version {
+            1 if compression => {
+                encoding.decode_compressed(bytes, |deflated| inflate(deflated, max_bytes))
+            }
+            1 => encoding.decode(bytes),
+            version => Err(format!("Unsupported protocol version {}", version)),
         }
     }
 }
 
+/** Room websockets of the subscriptions made over one client connection, so they can be closed
+along with it. Each connection has its own durable object, which owns its registry. */
+#[derive(Default)]
+pub struct SubscriptionRegistry {
+    room_clients: HashMap<(api::RoomId, u64), w::WebSocket>,
+    /** Set once the client connection is gone, nothing is registered after that */
+    closed: bool,
+}
+impl SubscriptionRegistry {
+    /** Rooms drop subscriptions whose websocket closes, which also updates their subscriber counts */
+    pub fn close_all(&mut self) {
+        self.closed = true;
+        for (_, room_client) in self.room_clients.drain() {
+            room_client.close_with(api::CloseCode::Normal);
+        }
+    }
+
+    pub fn contains(&self, room_id: api::RoomId, subscription_id: u64) -> bool {
+        self.room_clients.contains_key(&(room_id, subscription_id))
+    }
+
+    /** Closes the room websocket instead if the client connection is already gone */
+    fn insert(&mut self, key: (api::RoomId, u64), room_client: &w::WebSocket) -> bool {
+        if self.closed {
+            room_client.close_with(api::CloseCode::Normal);
+            return false;
+        }
+        self.room_clients.insert(key, room_client.clone());
+        true
+    }
+}
+
+/** Method calls that haven't returned yet on one client connection, so they can be cancelled */
+#[derive(Default)]
+pub struct InFlightCalls {
+    calls: HashMap<u64, AbortHandle>,
+}
+impl InFlightCalls {
+    fn register(&mut self, call_id: u64) -> AbortRegistration {
+        let (handle, registration) = AbortHandle::new_pair();
+        self.calls.insert(call_id, handle);
+        registration
+    }
+    fn finish(&mut self, call_id: u64) {
+        self.calls.remove(&call_id);
+    }
+    fn cancel(&mut self, call_id: u64) {
+        if let Some(handle) = self.calls.remove(&call_id) {
+            handle.abort();
+        }
+    }
+    /** Nobody is left to receive the returns once the client connection is gone */
+    pub fn cancel_all(&mut self) {
+        for (_, handle) in self.calls.drain() {
+            handle.abort();
+        }
+    }
+}
+
+/** Keeps a subscription's current room websocket registered until it's dropped */
+pub struct SubscriptionHandle {
+    registry: Rc<RefCell<SubscriptionRegistry>>,
+    room_id: api::RoomId,
+    subscription_id: u64,
+}
+impl SubscriptionHandle {
+    /** None if the client connection is already gone, in which case the websocket is closed */
+    pub fn register(
+        registry: &Rc<RefCell<SubscriptionRegistry>>,
+        room_id: api::RoomId,
+        subscription_id: u64,
+        room_client: &w::WebSocket,
+    ) -> Option<Self> {
+        if !registry
+            .borrow_mut()
+            .insert((room_id, subscription_id), room_client)
+        {
+            return None;
+        }
+        Some(Self {
+            registry: registry.clone(),
+            room_id,
+            subscription_id,
+        })
+    }
+
+    pub fn subscription_id(&self) -> u64 {
+        self.subscription_id
+    }
+
+    /** Swaps in the websocket of a resumed subscription, false if the client connection is gone */
+    pub fn replace(&self, room_client: &w::WebSocket) -> bool {
+        self.registry
+            .borrow_mut()
+            .insert((self.room_id, self.subscription_id), room_client)
+    }
+}
+impl Drop for SubscriptionHandle {
+    fn drop(&mut self) {
+        self.registry
+            .borrow_mut()
+            .room_clients
+            .remove(&(self.room_id, self.subscription_id));
+    }
+}
+
 #[derive(Debug)]
 enum CheckSignedMethodCallError {
     WorkerError(w::Error),
-    CheckFail,
+    /** The call was rejected for the given reason */
+    CheckFail(api::ErrorId),
 }
 impl From<w::Error> for CheckSignedMethodCallError {
     fn from(value: w::Error) -> Self {
         Self::WorkerError(value)
     }
 }
-impl From<()> for CheckSignedMethodCallError {
-    fn from(_: ()) -> Self {
-        Self::CheckFail
+impl From<api::ErrorId> for CheckSignedMethodCallError {
+    fn from(value: api::ErrorId) -> Self {
+        Self::CheckFail(value)
     }
 }
 async fn check_signed_method_call(
     env: &w::Env,
     signed_call: &api::SignedMethodCall,
+    log_ctx: &LogContext,
 ) -> Result<(), CheckSignedMethodCallError> {
     if let Err(err) = signed_call.validate_signature() {
-        log!("Call signature validation failed. {}", err);
-        return Err(().into());
+        log_info!(ctx: log_ctx, "Call signature validation failed. {}", err);
+        return Err(api::ErrorId::InvalidSignature.into());
     }
     let current_time_secs = w::Date::now().as_millis() / 1000;
-    if !signed_call.validate_timestamp(current_time_secs) {
-        log!("Call timestamp validation failed.");
-        return Err(().into());
+    if !signed_call.validate_timestamp(current_time_secs, &config::protocol_config(env)) {
+        log_info!(ctx: log_ctx, "Call timestamp validation failed.");
+        return Err(api::ErrorId::TimestampOutOfRange.into());
     }
     let peer = env
         .durable_object("PEER")?
@@ -79,56 +412,258 @@ async fn check_signed_method_call(
     let is_used: bool =
         serde_json::from_str(&response.text().await?).map_err(Into::<w::Error>::into)?;
     if is_used {
-        return Err(().into());
+        return Err(api::ErrorId::NonceReused.into());
     }
     return Ok(());
 }
 
+/** Tells the client how long to back off for */
+pub fn rate_limited_error(retry_after_ms: u64) -> api::MethodCallError {
+    api::ErrorId::RateLimited
+        .with_default_message()
+        .with_data(api::MethodCallErrorData::RateLimited { retry_after_ms })
+}
+
+/** The metrics label for calls rejected before they're handled */
+fn rejection_reason(error_id: api::ErrorId) -> &'static str {
+    match error_id {
+        api::ErrorId::InternalError => "internal_error",
+        api::ErrorId::NonceReused => "nonce_reused",
+        api::ErrorId::TimestampOutOfRange => "timestamp_out_of_range",
+        api::ErrorId::InvalidSession => "invalid_session",
+        _ => "invalid_signature",
+    }
+}
+
+/** Only compiles if a handler returns what its method is declared to return */
+fn method_return<M: api::ApiMethod>(
+    result: Result<M::Success, crate::websocket_api_handlers::Error>,
+) -> Result<api::MethodCallSuccess, crate::websocket_api_handlers::Error> {
+    result.map(Into::into)
+}
+
 async fn handle_signed_method_call(
     env: Rc<w::Env>,
     signed_call: api::SignedMethodCall,
-    server: Rc<w::WebSocket>,
+    server: Rc<ClientSocket>,
+    rate_limiter: Rc<RefCell<RateLimiter>>,
+    subscriptions: Rc<RefCell<SubscriptionRegistry>>,
+    calls: Rc<RefCell<InFlightCalls>>,
+    log_ctx: LogContext,
 ) -> Result<(), ()> {
-    if let Err(e) = check_signed_method_call(env.as_ref(), &signed_call).await {
-        log!("Error when checking signed method call: {:?}", e);
-        server.nfsendj(&api::ServerToClientMessage::call_error(
+    let log_ctx = log_ctx.with_call(signed_call.call_id);
+    if let Err(e) = check_signed_method_call(env.as_ref(), &signed_call, &log_ctx).await {
+        log_info!(ctx: log_ctx, "Error when checking signed method call: {:?}", e);
+        let error_id = match e {
+            CheckSignedMethodCallError::WorkerError(_) => api::ErrorId::InternalError,
+            CheckSignedMethodCallError::CheckFail(error_id) => error_id,
+        };
+        metrics::increment(&format!(
+            "method_calls_rejected{{reason={}}}",
+            rejection_reason(error_id)
+        ));
+        server.nfsendj(&api::ServerToClientMessage::from_error(
             signed_call.call_id,
-            api::ErrorId::InvalidSignature,
-            None,
+            server.received_at(),
+            error_id.with_default_message(),
+        ));
+        return Err(());
+    }
+    handle_method_call(
+        env,
+        signed_call.call_id,
+        signed_call.signed_call.call,
+        CallAuth::Signature,
+        server,
+        rate_limiter,
+        subscriptions,
+        calls,
+        log_ctx,
+    )
+    .await
+}
+
+async fn handle_session_method_call(
+    env: Rc<w::Env>,
+    session_call: api::SessionMethodCall,
+    server: Rc<ClientSocket>,
+    rate_limiter: Rc<RefCell<RateLimiter>>,
+    subscriptions: Rc<RefCell<SubscriptionRegistry>>,
+    calls: Rc<RefCell<InFlightCalls>>,
+    log_ctx: LogContext,
+) -> Result<(), ()> {
+    let log_ctx = log_ctx.with_call(session_call.call_id);
+    let current_time_secs = w::Date::now().as_millis() / 1000;
+    let protocol_config = config::protocol_config(env.as_ref());
+    let checked = if session_call.validate_timestamp(current_time_secs, &protocol_config) {
+        server.check_session_call(&session_call, current_time_secs)
+    } else {
+        Err(api::ErrorId::TimestampOutOfRange)
+    };
+    if let Err(error_id) = checked {
+        log_info!(ctx: log_ctx, "Rejected a session method call: {:?}", error_id);
+        metrics::increment(&format!(
+            "method_calls_rejected{{reason={}}}",
+            rejection_reason(error_id)
+        ));
+        server.nfsendj(&api::ServerToClientMessage::from_error(
+            session_call.call_id,
+            server.received_at(),
+            error_id.with_default_message(),
+        ));
+        return Err(());
+    }
+    handle_method_call(
+        env,
+        session_call.call_id,
+        session_call.session_call.call,
+        CallAuth::Session,
+        server,
+        rate_limiter,
+        subscriptions,
+        calls,
+        log_ctx,
+    )
+    .await
+}
+
+/** How the caller of a method call was verified */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallAuth {
+    Signature,
+    Session,
+}
+
+/** Handles a call whose caller was already verified */
+async fn handle_method_call(
+    env: Rc<w::Env>,
+    call_id: u64,
+    call: api::MethodCallContent,
+    auth: CallAuth,
+    server: Rc<ClientSocket>,
+    rate_limiter: Rc<RefCell<RateLimiter>>,
+    subscriptions: Rc<RefCell<SubscriptionRegistry>>,
+    calls: Rc<RefCell<InFlightCalls>>,
+    log_ctx: LogContext,
+) -> Result<(), ()> {
+    let caller_id = call.common_arguments.caller_id.to_string();
+    let limiter = rate_limiter.borrow();
+    if !limiter.check_caller(&caller_id) {
+        metrics::increment("method_calls_rejected{reason=caller_rate_limited}");
+        server.nfsendj(&api::ServerToClientMessage::from_error(
+            call_id,
+            server.received_at(),
+            rate_limited_error(limiter.caller_retry_ms()),
         ));
         return Err(());
     }
+    drop(limiter);
 
     use crate::websocket_api_handlers as h;
     use api::MethodCallArgsVariants as Method;
-    let common_args = signed_call.signed_call.call.common_arguments;
-    let variant_args = signed_call.signed_call.call.variant_arguments;
-    let result = match variant_args {
-        Method::CreateRoom => h::create_room(env, common_args).await,
-        Method::SubscribeToRoom(args) => {
-            h::subscribe_to_room(env, server.clone(), common_args, args).await
-        }
-        Method::UnsubscribeFromRoom(_) => h::unsubscribe_from_room().await,
-        Method::AddPrivilegedPeer(args) => {
-            h::add_privileged_peer(env.as_ref(), common_args, args).await
-        }
-        Method::GetRoomDataHistory(_) => h::get_room_data_history().await,
-        Method::DeleteData(_) => h::delete_data().await,
-        Method::BroadcastData(args) => h::broadcast_data(env.as_ref(), common_args, args).await,
-        Method::UnicastData(_) => h::unicast_data().await,
+    let common_args = call.common_arguments;
+    let variant_args = call.variant_arguments;
+    let method_name = variant_args.method_name();
+    let start = w::Date::now().as_millis();
+    let registration = calls.borrow_mut().register(call_id);
+    let result = Abortable::new(
+        async {
+            match variant_args {
+                Method::CreateRoom => {
+                    method_return::<api::CreateRoomArgs>(h::create_room(env, common_args).await)
+                }
+                Method::SubscribeToRoom(args) => method_return::<api::SubscribeToRoomArgs>(
+                    h::subscribe_to_room(
+                        env,
+                        server.clone(),
+                        subscriptions,
+                        common_args,
+                        args,
+                        log_ctx.clone(),
+                    )
+                    .await,
+                ),
+                Method::UnsubscribeFromRoom(args) => method_return::<api::UnsubscribeFromRoomArgs>(
+                    h::unsubscribe_from_room(env.as_ref(), &subscriptions, common_args, args).await,
+                ),
+                Method::ResyncSubscription(args) => method_return::<api::ResyncSubscriptionArgs>(
+                    h::resync_subscription(env.as_ref(), &subscriptions, common_args, args).await,
+                ),
+                Method::AddPrivilegedPeer(args) => method_return::<api::AddPrivilegedPeerArgs>(
+                    h::add_privileged_peer(env.as_ref(), common_args, args).await,
+                ),
+                Method::SetPeerRole(args) => method_return::<api::SetPeerRoleArgs>(
+                    h::set_peer_role(env.as_ref(), common_args, args).await,
+                ),
+                Method::DeleteRoom(args) => method_return::<api::DeleteRoomArgs>(
+                    h::delete_room(env.as_ref(), common_args, args).await,
+                ),
+                Method::GetRoomPeers(args) => method_return::<api::GetRoomPeersArgs>(
+                    h::get_room_peers(env.as_ref(), common_args, args).await,
+                ),
+                Method::GetRoomInfo(args) => method_return::<api::GetRoomInfoArgs>(
+                    h::get_room_info(env.as_ref(), common_args, args).await,
+                ),
+                Method::GetRoomDataHistory(args) => method_return::<api::GetRoomDataHistoryArgs>(
+                    h::get_room_data_history(env.as_ref(), common_args, args).await,
+                ),
+                Method::DeleteData(args) => method_return::<api::DeleteDataArgs>(
+                    h::delete_data(env.as_ref(), common_args, args).await,
+                ),
+                Method::BroadcastData(args) => method_return::<api::BroadcastDataArgs>(
+                    h::broadcast_data(env.as_ref(), common_args, args).await,
+                ),
+                Method::UnicastData(args) => method_return::<api::UnicastDataArgs>(
+                    h::unicast_data(env.as_ref(), common_args, args).await,
+                ),
+                Method::MulticastData(args) => method_return::<api::MulticastDataArgs>(
+                    h::multicast_data(env.as_ref(), common_args, args).await,
+                ),
+                Method::SendEphemeral(args) => method_return::<api::SendEphemeralArgs>(
+                    h::send_ephemeral(env.as_ref(), common_args, args).await,
+                ),
+                Method::CreateSession(args) => method_return::<api::CreateSessionArgs>(
+                    h::create_session(env.as_ref(), &server, auth, common_args, args).await,
+                ),
+                Method::EndSession => {
+                    method_return::<api::EndSessionArgs>(h::end_session(&server, common_args).await)
+                }
+            }
+        },
+        registration,
+    )
+    .await;
+    calls.borrow_mut().finish(call_id);
+    let result =
+        result.unwrap_or_else(|_| Err(api::ErrorId::Cancelled.with_default_message().into()));
+    metrics::observe(
+        &format!("method_call_ms{{method={}}}", method_name),
+        w::Date::now().as_millis().saturating_sub(start) as f64,
+    );
+    let outcome = match &result {
+        Ok(_) => "ok",
+        Err(h::Error::WorkerError(_)) => "internal_error",
+        Err(h::Error::MethodError(_)) => "method_error",
     };
+    metrics::increment(&format!(
+        "method_calls{{method={},outcome={}}}",
+        method_name, outcome
+    ));
     let to_send = match result {
-        Ok(result) => api::ServerToClientMessage::from_success(signed_call.call_id, result),
+        Ok(result) => {
+            api::ServerToClientMessage::from_success(call_id, server.received_at(), result)
+        }
         Err(err) => match err {
             h::Error::WorkerError(err) => {
-                log!("An internal error occured: {}", err);
+                log_error!(ctx: log_ctx, "An internal error occured: {}", err);
                 api::ServerToClientMessage::from_error(
-                    signed_call.call_id,
+                    call_id,
+                    server.received_at(),
                     api::ErrorId::InternalError.with_default_message(),
                 )
             }
             h::Error::MethodError(err) => {
-                api::ServerToClientMessage::from_error(signed_call.call_id, err)
+                api::ServerToClientMessage::from_error(call_id, server.received_at(), err)
             }
         },
     };
@@ -136,82 +671,211 @@ async fn handle_signed_method_call(
     Ok(())
 }
 
+/** Answers the call with an error if the connection is rate limited, closing the connection
+if the client doesn't back off */
+fn check_connection_rate(
+    server: &ClientSocket,
+    rate_limiter: &RefCell<RateLimiter>,
+    call_id: u64,
+) -> bool {
+    let mut limiter = rate_limiter.borrow_mut();
+    if limiter.check_connection() {
+        return true;
+    }
+    metrics::increment("method_calls_rejected{reason=connection_rate_limited}");
+    server.nfsendj(&api::ServerToClientMessage::from_error(
+        call_id,
+        server.received_at(),
+        rate_limited_error(limiter.connection_retry_ms()),
+    ));
+    if limiter.is_ignored() {
+        server.go_away(
+            api::CloseCode::RateLimited,
+            Some(limiter.retry_after_secs()),
+        );
+    }
+    false
+}
+
 async fn handle_parsed_message(
     env: Rc<w::Env>,
     message: api::ClientToServerMessage,
-    server: Rc<w::WebSocket>,
+    server: Rc<ClientSocket>,
+    rate_limiter: Rc<RefCell<RateLimiter>>,
+    subscriptions: Rc<RefCell<SubscriptionRegistry>>,
+    calls: Rc<RefCell<InFlightCalls>>,
+    log_ctx: LogContext,
 ) {
-    log!("{:?}", message);
+    log_debug!(ctx: log_ctx, "{:?}", message);
     match message {
+        api::ClientToServerMessage::Hello(hello) => {
+            if server.protocol_version().is_some() {
+                log_info!(ctx: log_ctx, "Client said hello twice");
+                server.go_away(api::CloseCode::ProtocolError, None);
+                return;
+            }
+            let protocol_version = match hello.choose_version() {
+                Some(protocol_version) => protocol_version,
+                None => {
+                    log_info!(
+                        ctx: log_ctx,
+                        "No common protocol version in {:?}",
+                        hello.protocol_versions
+                    );
+                    server.go_away(api::CloseCode::UnsupportedProtocol, None);
+                    return;
+                }
+            };
+            server.protocol_version.set(Some(protocol_version));
+            let encoding = hello.encoding.unwrap_or_else(|| server.encoding());
+            server.nfsendj(
+                &api::ServerHello {
+                    protocol_version,
+                    features: SERVER_FEATURES.to_vec(),
+                    encoding,
+                    protocol_config: config::protocol_config(env.as_ref()),
+                }
+                .into_message(),
+            );
+            server.encoding.set(encoding);
+            server
+                .compression
+                .set(hello.features.contains(&api::ProtocolFeature::Compression));
+        }
         api::ClientToServerMessage::Ping => {
             server.nfsendj(&api::ServerToClientMessage::pong());
         }
-        api::ClientToServerMessage::SignedMethodCall(signed_call) => match signed_call {
-            api::SignedMethodCallOrPartial::Partial(call_id) => {
-                server.nfsendj(&api::ServerToClientMessage::from_error(
-                    call_id,
-                    api::ErrorId::ParseError.with_default_message(),
-                ))
+        api::ClientToServerMessage::CancelCall(cancel) => {
+            calls.borrow_mut().cancel(cancel.call_id);
+        }
+        api::ClientToServerMessage::RequestSessionChallenge => {
+            match session::random_base64(session::SESSION_CHALLENGE_BYTES) {
+                Ok(challenge) => {
+                    server.set_session_challenge(challenge.clone());
+                    server.nfsendj(&api::SessionChallenge { challenge }.into_message());
+                }
+                Err(err) => log_error!(ctx: log_ctx, "Failed to make a challenge. {}", err),
             }
-            api::SignedMethodCallOrPartial::Full(signed_call) => {
-                let _ = handle_signed_method_call(env, signed_call, server).await;
+        }
+        api::ClientToServerMessage::SessionMethodCall(session_call) => {
+            if !check_connection_rate(&server, &rate_limiter, session_call.call_id) {
+                return;
             }
-        },
+            let _ = handle_session_method_call(
+                env,
+                session_call,
+                server,
+                rate_limiter,
+                subscriptions,
+                calls,
+                log_ctx,
+            )
+            .await;
+        }
+        api::ClientToServerMessage::SignedMethodCall(signed_call) => {
+            let call_id = match &signed_call {
+                api::SignedMethodCallOrPartial::Partial(call_id) => *call_id,
+                api::SignedMethodCallOrPartial::Full(signed_call) => signed_call.call_id,
+            };
+            // Checked before signature validation so floods don't cost us ECDSA verifications
+            if !check_connection_rate(&server, &rate_limiter, call_id) {
+                return;
+            }
+            match signed_call {
+                api::SignedMethodCallOrPartial::Partial(call_id) => {
+                    server.nfsendj(&api::ServerToClientMessage::from_error(
+                        call_id,
+                        server.received_at(),
+                        api::ErrorId::ParseError.with_default_message(),
+                    ))
+                }
+                api::SignedMethodCallOrPartial::Full(signed_call) => {
+                    let _ = handle_signed_method_call(
+                        env,
+                        signed_call,
+                        server,
+                        rate_limiter,
+                        subscriptions,
+                        calls,
+                        log_ctx,
+                    )
+                    .await;
+                }
+            }
+        }
     }
 }
 
-async fn handle_message(env: Rc<w::Env>, text: String, server: Rc<w::WebSocket>) {
-    // log!("{:?}", text);
-    match serde_json::from_str::<api::ClientToServerMessage>(&text) {
-        Ok(message) => handle_parsed_message(env, message, server).await,
+pub async fn handle_message(
+    env: Rc<w::Env>,
+    frame: ClientFrame,
+    server: Rc<ClientSocket>,
+    rate_limiter: Rc<RefCell<RateLimiter>>,
+    subscriptions: Rc<RefCell<SubscriptionRegistry>>,
+    calls: Rc<RefCell<InFlightCalls>>,
+    log_ctx: LogContext,
+) {
+    let max_bytes = config::max_message_bytes(env.as_ref());
+    if frame.len() > max_bytes {
+        // Not even parsed for a call ID, handling oversized messages should stay cheap
+        log_info!(ctx: log_ctx, "Dropped a message of {} bytes.", frame.len());
+        reject_message(
+            &server,
+            &rate_limiter,
+            api::ErrorId::PayloadTooLarge,
+            api::CloseCode::MessageTooLarge,
+        );
+        return;
+    }
+    let protocol_version = server
+        .protocol_version()
+        .unwrap_or(api::DEFAULT_PROTOCOL_VERSION);
+    match frame.parse(protocol_version, server.compression(), max_bytes) {
+        Ok(message) => {
+            rate_limiter.borrow_mut().check_message(true);
+            handle_parsed_message(
+                env,
+                message,
+                server,
+                rate_limiter,
+                subscriptions,
+                calls,
+                log_ctx,
+            )
+            .await
+        }
         Err(err) => {
-            server.nfsendj(&api::ServerToClientMessage::info(
-                "A message failed to be parsed.",
-            ));
-            log!("Failed to parse a message. {}", err);
+            // Messages that fail to parse at all aren't even partial method calls
+            log_info!(ctx: log_ctx, "Failed to parse a message. {}", err);
+            reject_message(
+                &server,
+                &rate_limiter,
+                api::ErrorId::ParseError,
+                api::CloseCode::ProtocolError,
+            );
         }
     }
 }
 
-pub async fn handle_ws_server(env: w::Env, server: w::WebSocket) {
-    let server = Rc::new(server);
-    let env = Rc::new(env);
-
-    let mut event_stream = match server.events() {
-        Ok(stream) => stream,
-        Err(err) => {
-            log!("Could not open a websocket stream: {}", err);
-            return;
-        }
-    };
-
-    while let Some(result) = event_stream.next().await {
-        let event = match result {
-            Err(err) => {
-                log!(
-                    "{} - Error in websocket: {}",
-                    w::Date::now().as_millis(),
-                    err
-                );
-                break;
-            }
-            Ok(event) => event,
-        };
-        let message_event = match event {
-            w::WebsocketEvent::Close(event) => {
-                log!("{} - {:#?}", w::Date::now().as_millis(), event);
-                break;
-            }
-            w::WebsocketEvent::Message(message_event) => message_event,
-        };
-        match message_event.text() {
-            None => log!("no text :("),
-            Some(text) => w::wasm_bindgen_futures::spawn_local(handle_message(
-                env.clone(),
-                text,
-                server.clone(),
-            )),
-        }
+/** Tells the client why its message was dropped, closing with `close_code` once it keeps
+sending messages like that */
+fn reject_message(
+    server: &ClientSocket,
+    rate_limiter: &RefCell<RateLimiter>,
+    error_id: api::ErrorId,
+    close_code: api::CloseCode,
+) {
+    metrics::increment(match error_id {
+        api::ErrorId::PayloadTooLarge => "messages_rejected{reason=too_large}",
+        _ => "messages_rejected{reason=unparseable}",
+    });
+    server.nfsendj(
+        &api::ServerNotice::from(api::MessageRejectedNotice {
+            error: error_id.with_default_message(),
+        })
+        .into_message(),
+    );
+    if rate_limiter.borrow_mut().check_message(false) {
+        server.go_away(close_code, None);
     }
-    log!("closed :)");
 }
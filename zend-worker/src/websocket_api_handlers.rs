@@ -1,32 +1,83 @@
 use crate::{
-    room_api::{self, IntoRequest},
-    websocket::WebSocketExt,
+    config, metrics,
+    room_api::{self, FromRoomMessage, FromSubscriberMessage, IntoRequest},
+    session::Session,
+    websocket::{
+        self, CallAuth, ClientSocket, SubscriptionHandle, SubscriptionRegistry, WebSocketExt,
+    },
 };
 use async_std::stream::StreamExt;
-use serde::Deserialize;
-use std::rc::Rc;
+use std::{cell::RefCell, collections::HashSet, rc::Rc, time::Duration};
 use worker::{self as w};
-use zend_common::{api, enum_convert::EnumConvert, log, util};
+use zend_common::{api, enum_convert::EnumConvert, log_info, log_warn, logging::LogContext, util};
 
-#[derive(Deserialize)]
-struct SubscriptionDataMessage {
-    sender_id: api::EcdsaPublicKeyWrapper,
-    nonce: api::Nonce,
-    data: serde_json::Value,
+struct DataShapeLimits {
+    max_depth: usize,
+    max_string_bytes: usize,
+    max_keys: usize,
 }
 
-#[derive(Deserialize)]
-#[serde(rename_all = "snake_case")]
-#[serde(tag = "message_type", content = "message_content")]
-enum FromRoomMessage {
-    Close,
-    Data(SubscriptionDataMessage),
-    SubscriptionId(u64),
+/** Walks the whole value, returning what's wrong with it if it exceeds a limit */
+fn check_data_shape(
+    value: &serde_json::Value,
+    limits: &DataShapeLimits,
+    depth: usize,
+    key_count: &mut usize,
+) -> Result<(), &'static str> {
+    use serde_json::Value;
+    let children: Box<dyn Iterator<Item = &Value>> = match value {
+        Value::String(string) if string.len() > limits.max_string_bytes => {
+            return Err("A string in the data is too long.")
+        }
+        Value::Array(array) => Box::new(array.iter()),
+        Value::Object(object) => {
+            *key_count += object.len();
+            if *key_count > limits.max_keys {
+                return Err("The data has too many object keys.");
+            }
+            if object.keys().any(|key| key.len() > limits.max_string_bytes) {
+                return Err("An object key in the data is too long.");
+            }
+            Box::new(object.values())
+        }
+        _ => return Ok(()),
+    };
+    if depth >= limits.max_depth {
+        return Err("The data is nested too deeply.");
+    }
+    for child in children {
+        check_data_shape(child, limits, depth + 1, key_count)?;
+    }
+    Ok(())
+}
+
+/** Keeps pathological payloads away from rooms, which would fan them out to every subscriber */
+fn check_data(env: &w::Env, data: &serde_json::Value) -> Result<(), Error> {
+    let max_bytes = config::max_data_bytes(env);
+    if serde_json::to_string(data)?.len() > max_bytes {
+        return Err(api::ErrorId::PayloadTooLarge
+            .with_default_message()
+            .with_data(api::MethodCallErrorData::PayloadTooLarge {
+                max_bytes: max_bytes as u64,
+            })
+            .into());
+    }
+    let limits = DataShapeLimits {
+        max_depth: config::max_data_depth(env),
+        max_string_bytes: config::max_data_string_bytes(env),
+        max_keys: config::max_data_keys(env),
+    };
+    if let Err(message) = check_data_shape(data, &limits, 0, &mut 0) {
+        return Err(api::ErrorId::InvalidPayload
+            .with_message(message.to_string())
+            .into());
+    }
+    Ok(())
 }
 
 fn get_room_stub(env: &w::Env, room_id: api::RoomId) -> Result<w::Stub, w::Error> {
     env.durable_object("ROOM")?
-        .id_from_name(&room_id.to_string())?
+        .id_from_name(&room_id.legacy_code())?
         .get_stub()
 }
 
@@ -38,149 +89,408 @@ pub enum Error {
 }
 impl From<serde_json::Error> for Error {
     fn from(value: serde_json::Error) -> Self {
-        value.into()
+        Self::WorkerError(value.into())
     }
 }
 
 pub async fn create_room(
     env: Rc<w::Env>,
     common_args: api::MethodCallCommonArgs,
-) -> Result<api::MethodCallSuccess, Error> {
+) -> Result<api::CreateRoomSuccess, Error> {
     let namespace = env.durable_object("ROOM")?;
-    let room_id = loop {
-        let tmp_id = api::RoomId::from_random(
-            util::math_random().map_err(|_| api::ErrorId::InternalError.with_default_message())?,
-        );
-        let tmp_stub = namespace.id_from_name(&tmp_id.to_string())?.get_stub()?;
+    let (room_id, history_retention) = loop {
+        let tmp_id = util::math_random()
+            .ok()
+            .and_then(|random| api::RoomId::from_random(random).ok())
+            .ok_or_else(|| api::ErrorId::InternalError.with_default_message())?;
+        let tmp_stub = namespace.id_from_name(&tmp_id.legacy_code())?.get_stub()?;
         let request = room_api::InitialiseMessage {
             initial_peer_id: common_args.caller_id.clone(),
         }
         .into_request()?;
         let mut response = tmp_stub.fetch_with_request(request).await?;
-        let success = serde_json::from_str(&response.text().await?)?;
-        if success {
-            break tmp_id;
+        // The room answers with its history retention if initialising it succeeded
+        let retention: Option<api::HistoryRetention> =
+            serde_json::from_str(&response.text().await?)?;
+        if let Some(retention) = retention {
+            break (tmp_id, retention);
         }
     };
-    Ok(api::CreateRoomSuccess { room_id }.into())
+    Ok(api::CreateRoomSuccess {
+        room_id,
+        history_retention,
+    })
 }
 
-// TODO possibly reconnect to the room object if the connection dies?
-// if this turns out to be a rare occurence, this work could be offloaded to the client
-async fn subscriber_background_future(
-    _env: Rc<w::Env>,
-    server: Rc<w::WebSocket>,
-    room_client: w::WebSocket,
-    subscription_id: u64,
-    _common_args: api::MethodCallCommonArgs,
-    args: api::SubscribeToRoomArgs,
-) -> Result<(), Error> {
-    let room_id = args.room_id;
+/** How many times re-subscribing is attempted after the connection to a room drops */
+const RESUME_ATTEMPTS: u32 = 3;
 
+/** Asks the room for a subscription. The websocket is missing if the room doesn't exist. */
+async fn open_room_subscription(
+    env: &w::Env,
+    args: &api::SubscribeToRoomArgs,
+    common_args: &api::MethodCallCommonArgs,
+    resume: Option<room_api::ResumeSubscription>,
+) -> Result<(u64, Option<w::WebSocket>), Error> {
+    let request = room_api::SubscribeMessage {
+        subscriber_id: common_args.caller_id.clone(),
+        resume,
+        filter: args.filter.clone(),
+        ignore_presence: args.ignore_presence,
+        last_will: args.last_will.clone().map(|data| room_api::LastWill {
+            nonce: common_args.nonce,
+            data,
+        }),
+    }
+    .into_request()?;
+    let stub = get_room_stub(env, args.room_id)?;
+    let response = stub.fetch_with_request(request).await?;
+    let subscription_id: u64 = response
+        .headers()
+        .get("Subscription-Id")?
+        .ok_or(api::MethodCallError::internal())?
+        .parse()
+        .map_err(|_| api::MethodCallError::internal())?;
+    let ws_client = response.websocket();
+    if let Some(ws_client) = &ws_client {
+        ws_client.accept()?;
+    }
+    Ok((subscription_id, ws_client))
+}
+
+enum RoomStreamEnd {
+    /** The room ended the subscription on purpose */
+    Closed,
+    /** The connection went away without the room saying so */
+    Dropped,
+}
+
+async fn forward_room_events(
+    server: &ClientSocket,
+    room_client: &w::WebSocket,
+    subscription_id: u64,
+    room_id: api::RoomId,
+    last_forwarded: &mut Option<room_api::HistoryCursor>,
+    next_seq: &mut u64,
+    log_ctx: &LogContext,
+) -> Result<RoomStreamEnd, Error> {
     let mut event_stream = room_client.events()?;
 
     while let Some(result) = event_stream.next().await {
         let event = match result {
             Err(err) => {
-                log!("Error in connection to room: {}", err);
-                break;
+                log_warn!(ctx: log_ctx, "Error in connection to room: {}", err);
+                return Ok(RoomStreamEnd::Dropped);
             }
             Ok(event) => event,
         };
         let message = match event {
             w::WebsocketEvent::Close(event) => {
-                log!("(Connection to room closed) {:#?}", event);
-                break;
+                log_info!(ctx: log_ctx, "Connection to room closed. {:?}", event);
+                return Ok(RoomStreamEnd::Dropped);
             }
             w::WebsocketEvent::Message(message) => message,
         };
         let text = match message.text() {
-            None => break,
+            None => continue,
             Some(text) => text,
         };
         let message = serde_json::from_str::<FromRoomMessage>(&text)?;
-        let data_message = match message {
+        let mut ack = None;
+        let to_send = match message {
             FromRoomMessage::Close => {
-                room_client.close(None, None::<&str>)?;
-                break;
+                room_client.close_with(api::CloseCode::Normal);
+                return Ok(RoomStreamEnd::Closed);
+            }
+            FromRoomMessage::Data(data_message) => {
+                // Data arrives in the order the room received it, so a resumed subscription
+                // continues after the last of it
+                *last_forwarded = Some(room_api::HistoryCursor {
+                    sender_id: data_message.sender_id.clone(),
+                    nonce: data_message.nonce,
+                    received_at: data_message.received_at,
+                });
+                *next_seq = data_message.seq + 1;
+                if data_message.require_ack {
+                    ack = Some(FromSubscriberMessage::Ack(room_api::AckMessage {
+                        sender_id: data_message.sender_id.clone(),
+                        nonce: data_message.nonce,
+                    }));
+                }
+                api::SubscriptionData {
+                    subscription_id,
+                    room_id,
+                    seq: data_message.seq,
+                    received_at: data_message.received_at,
+                    sender_id: data_message.sender_id,
+                    nonce: data_message.nonce,
+                    data: data_message.data,
+                }
+                .into_message()
+            }
+            // Doesn't advance last_forwarded, as ephemeral data can't be replayed anyway
+            FromRoomMessage::Ephemeral(ephemeral_message) => api::EphemeralData {
+                subscription_id,
+                room_id,
+                sender_id: ephemeral_message.sender_id,
+                data: ephemeral_message.data,
+                max_age_ms: ephemeral_message.max_age_ms,
+                timestamp: ephemeral_message.timestamp,
+            }
+            .into_message(),
+            FromRoomMessage::DeliveryReceipt(receipt_message) => api::DeliveryReceipt {
+                subscription_id,
+                room_id,
+                receiver_id: receipt_message.receiver_id,
+                nonce: receipt_message.nonce,
+            }
+            .into_message(),
+            FromRoomMessage::DataDeleted(deleted_message) => api::SubscriptionDataDeleted {
+                subscription_id,
+                room_id,
+                deleter_id: deleted_message.deleter_id,
+                data_sender_id: deleted_message.data_sender_id,
+                data_nonce: deleted_message.data_nonce,
+            }
+            .into_message(),
+            FromRoomMessage::Presence(presence_message) => api::PresenceEvent {
+                subscription_id,
+                room_id,
+                peer_id: presence_message.peer_id,
+                kind: presence_message.kind,
+                timestamp: presence_message.timestamp,
+            }
+            .into_message(),
+            FromRoomMessage::RoomClosed(reason) => {
+                server.nfsendj(
+                    &api::RoomClosed {
+                        subscription_id,
+                        room_id,
+                        reason,
+                    }
+                    .into_message(),
+                );
+                return Ok(RoomStreamEnd::Closed);
             }
-            FromRoomMessage::Data(data_message) => data_message,
             _ => continue,
         };
+        metrics::increment("subscription_messages_forwarded");
+        server.nfsendj(&to_send);
+        // Acknowledged once it's handed to the client's socket
+        if let Some(ack) = ack.filter(|_| server.is_open()) {
+            room_client.nfsendj(&ack);
+        }
+    }
+    Ok(RoomStreamEnd::Dropped)
+}
+
+async fn resume_subscription(
+    env: &w::Env,
+    args: &api::SubscribeToRoomArgs,
+    common_args: &api::MethodCallCommonArgs,
+    resume: room_api::ResumeSubscription,
+    log_ctx: &LogContext,
+) -> Option<w::WebSocket> {
+    let subscription_id = resume.subscription_id;
+    for attempt in 0..RESUME_ATTEMPTS {
+        w::Delay::from(Duration::from_millis(250 << attempt)).await;
+        match open_room_subscription(env, args, common_args, Some(resume.clone())).await {
+            // The room doesn't exist anymore, so there is nothing to resume
+            Ok((_, None)) => return None,
+            Ok((_, Some(ws_client))) => return Some(ws_client),
+            Err(err) => log_warn!(
+                ctx: log_ctx,
+                "Failed to resume subscription {}. {:?}",
+                subscription_id,
+                err
+            ),
+        }
+    }
+    None
+}
+
+async fn subscriber_background_future(
+    env: Rc<w::Env>,
+    server: Rc<ClientSocket>,
+    room_client: w::WebSocket,
+    subscription: SubscriptionHandle,
+    common_args: api::MethodCallCommonArgs,
+    args: api::SubscribeToRoomArgs,
+    started_at: u64,
+    log_ctx: LogContext,
+) -> Result<(), Error> {
+    let room_id = args.room_id;
+    let subscription_id = subscription.subscription_id();
+    let mut room_client = room_client;
+    let mut last_forwarded = None;
+    let mut next_seq = 0;
+
+    loop {
+        let end = forward_room_events(
+            &server,
+            &room_client,
+            subscription_id,
+            room_id,
+            &mut last_forwarded,
+            &mut next_seq,
+            &log_ctx,
+        )
+        .await?;
+        if let RoomStreamEnd::Closed = end {
+            return Ok(());
+        }
+        // Nobody is left to receive the data if the client went away as well
+        if !server.is_open() {
+            return Ok(());
+        }
+        let resume = room_api::ResumeSubscription {
+            subscription_id,
+            after: last_forwarded.clone(),
+            next_seq,
+            started_at,
+        };
+        room_client = match resume_subscription(&env, &args, &common_args, resume, &log_ctx).await {
+            Some(room_client) => room_client,
+            None => {
+                metrics::increment("subscription_resumes{outcome=failed}");
+                return Err(api::MethodCallError::internal().into());
+            }
+        };
+        metrics::increment("subscription_resumes{outcome=ok}");
+        if !subscription.replace(&room_client) {
+            return Ok(());
+        }
         server.nfsendj(
-            &api::SubscriptionData {
+            &api::SubscriptionResumed {
                 subscription_id,
                 room_id,
-                sender_id: data_message.sender_id,
-                nonce: data_message.nonce,
-                data: data_message.data,
             }
             .into_message(),
-        )
+        );
     }
-    Ok(())
 }
 
 pub async fn subscribe_to_room(
     env: Rc<w::Env>,
-    server: Rc<w::WebSocket>,
+    server: Rc<ClientSocket>,
+    subscriptions: Rc<RefCell<SubscriptionRegistry>>,
     common_args: api::MethodCallCommonArgs,
     args: api::SubscribeToRoomArgs,
-) -> Result<api::MethodCallSuccess, Error> {
-    let room_id = args.room_id;
-    let request = room_api::SubscribeMessage {
-        subscriber_id: common_args.caller_id.clone(),
+    log_ctx: LogContext,
+) -> Result<api::SubscribeSuccess, Error> {
+    if let Some(last_will) = &args.last_will {
+        check_data(&env, last_will)?;
     }
-    .into_request()?;
-    let stub = get_room_stub(env.as_ref(), room_id)?;
-    let response = stub.fetch_with_request(request).await?;
-    let subscription_id: u64 = response
-        .headers()
-        .get("Subscription-Id")?
-        .ok_or(api::MethodCallError::internal())?
-        .parse()
-        .map_err(|_| api::MethodCallError::internal())?;
-    let ws_client = match response.websocket() {
+    // Taken before subscribing, so resuming replays anything the room received in between
+    let started_at = w::Date::now().as_millis();
+    let (subscription_id, ws_client) =
+        open_room_subscription(&env, &args, &common_args, None).await?;
+    let ws_client = match ws_client {
         Some(ws_client) => ws_client,
         None => {
-            return Ok(api::SubscribeSuccess { subscription_id }.into());
+            return Ok(api::SubscribeSuccess { subscription_id });
         }
     };
-    ws_client.accept()?;
+    let subscription = match SubscriptionHandle::register(
+        &subscriptions,
+        args.room_id,
+        subscription_id,
+        &ws_client,
+    ) {
+        Some(subscription) => subscription,
+        // The client went away while the subscription was being opened
+        None => return Ok(api::SubscribeSuccess { subscription_id }),
+    };
 
+    let room_id = args.room_id;
     w::wasm_bindgen_futures::spawn_local(async move {
         let result = subscriber_background_future(
             env,
             server.clone(),
             ws_client,
-            subscription_id,
+            subscription,
             common_args,
             args,
+            started_at,
+            log_ctx.clone(),
         )
         .await;
-        // TODO actual handling?
         match result {
             Ok(_) => {
-                log!("A websocket ended")
+                log_info!(ctx: log_ctx, "Subscription {} ended", subscription_id)
             }
+            // Only this subscription is gone, so the client subscribes again rather than reconnecting
             Err(_) => {
-                server.nfsendj(&api::ServerToClientMessage::Info("Closed :(".to_string()));
+                log_warn!(ctx: log_ctx, "Subscription {} failed", subscription_id);
+                server.nfsendj(
+                    &api::ServerNotice::from(api::SubscriptionClosedNotice {
+                        subscription_id,
+                        room_id,
+                    })
+                    .into_message(),
+                );
             }
         }
     });
 
-    Ok(api::SubscribeSuccess { subscription_id }.into())
+    Ok(api::SubscribeSuccess { subscription_id })
 }
 
-pub async fn unsubscribe_from_room() -> Result<api::MethodCallSuccess, Error> {
-    todo!();
+/** The room closes the subscription without sending its last will, which also ends the task
+forwarding its data */
+pub async fn unsubscribe_from_room(
+    env: &w::Env,
+    subscriptions: &RefCell<SubscriptionRegistry>,
+    common_args: api::MethodCallCommonArgs,
+    args: api::UnsubscribeFromRoomArgs,
+) -> Result<api::AckSuccess, Error> {
+    // Only subscriptions made over this connection can be ended over it
+    if !subscriptions
+        .borrow()
+        .contains(args.room_id, args.subscription_id)
+    {
+        return Err(api::ErrorId::NotFound.with_default_message().into());
+    }
+    let request = room_api::UnsubscribeMessage {
+        subscriber_id: common_args.caller_id,
+        subscription_id: args.subscription_id,
+    }
+    .into_request()?;
+    let stub = get_room_stub(env, args.room_id)?;
+    stub.fetch_with_request(request).await?;
+    Ok(api::AckSuccess)
+}
+
+pub async fn resync_subscription(
+    env: &w::Env,
+    subscriptions: &RefCell<SubscriptionRegistry>,
+    common_args: api::MethodCallCommonArgs,
+    args: api::ResyncSubscriptionArgs,
+) -> Result<api::AckSuccess, Error> {
+    // Same as for unsubscribing
+    if !subscriptions
+        .borrow()
+        .contains(args.room_id, args.subscription_id)
+    {
+        return Err(api::ErrorId::NotFound.with_default_message().into());
+    }
+    let request = room_api::ResyncSubscriptionMessage {
+        subscriber_id: common_args.caller_id,
+        subscription_id: args.subscription_id,
+        after_nonce: args.after_nonce,
+    }
+    .into_request()?;
+    let stub = get_room_stub(env, args.room_id)?;
+    // Whether the subscription exists is not revealed, same as for the room itself
+    let _ = serde_json::from_str::<bool>(&stub.fetch_with_request(request).await?.text().await?);
+    Ok(api::AckSuccess)
 }
 
 pub async fn add_privileged_peer(
     env: &w::Env,
     common_args: api::MethodCallCommonArgs,
     args: api::AddPrivilegedPeerArgs,
-) -> Result<api::MethodCallSuccess, Error> {
+) -> Result<api::AckSuccess, Error> {
     let room_id = args.room_id;
     let request = room_api::AddPrivilegedPeerMessage {
         adder_id: common_args.caller_id,
@@ -191,22 +501,122 @@ pub async fn add_privileged_peer(
     // Make sure that the room returns a boolean to determine that it didn't fail in an unexpected way,
     // but don't care about the actual result to hide info from clients
     let _ = serde_json::from_str::<bool>(&stub.fetch_with_request(request).await?.text().await?);
-    Ok(api::MethodCallSuccess::Ack)
+    Ok(api::AckSuccess)
 }
 
-pub async fn get_room_data_history() -> Result<api::MethodCallSuccess, Error> {
-    todo!();
+pub async fn set_peer_role(
+    env: &w::Env,
+    common_args: api::MethodCallCommonArgs,
+    args: api::SetPeerRoleArgs,
+) -> Result<api::AckSuccess, Error> {
+    let request = room_api::SetPeerRoleMessage {
+        setter_id: common_args.caller_id,
+        peer_id: args.peer_id,
+        role: args.role,
+    }
+    .into_request()?;
+    let stub = get_room_stub(env, args.room_id)?;
+    // As with adding privileged peers, whether the change was allowed is not revealed
+    let _ = serde_json::from_str::<bool>(&stub.fetch_with_request(request).await?.text().await?);
+    Ok(api::AckSuccess)
 }
-pub async fn delete_data() -> Result<api::MethodCallSuccess, Error> {
-    todo!();
+
+pub async fn delete_room(
+    env: &w::Env,
+    common_args: api::MethodCallCommonArgs,
+    args: api::DeleteRoomArgs,
+) -> Result<api::AckSuccess, Error> {
+    let request = room_api::DeleteMessage {
+        deleter_id: Some(common_args.caller_id),
+    }
+    .into_request()?;
+    let stub = get_room_stub(env, args.room_id)?;
+    // The room checks that the caller is privileged, the outcome is not revealed to the client
+    let _ = serde_json::from_str::<bool>(&stub.fetch_with_request(request).await?.text().await?);
+    Ok(api::AckSuccess)
+}
+
+pub async fn get_room_peers(
+    env: &w::Env,
+    common_args: api::MethodCallCommonArgs,
+    args: api::GetRoomPeersArgs,
+) -> Result<api::GetRoomPeersSuccess, Error> {
+    let request = room_api::GetPeersMessage {
+        requester_id: common_args.caller_id,
+    }
+    .into_request()?;
+    let stub = get_room_stub(env, args.room_id)?;
+    let peers: Option<Vec<api::RoomPeerInfo>> =
+        serde_json::from_str(&stub.fetch_with_request(request).await?.text().await?)?;
+    // Non-members get the same answer as for a room that doesn't exist
+    Ok(api::GetRoomPeersSuccess {
+        peers: peers.unwrap_or_default(),
+    })
+}
+
+pub async fn get_room_info(
+    env: &w::Env,
+    common_args: api::MethodCallCommonArgs,
+    args: api::GetRoomInfoArgs,
+) -> Result<api::GetRoomInfoSuccess, Error> {
+    let request = room_api::GetInfoMessage {
+        requester_id: common_args.caller_id,
+    }
+    .into_request()?;
+    let stub = get_room_stub(env, args.room_id)?;
+    let info: Option<api::RoomInfo> =
+        serde_json::from_str(&stub.fetch_with_request(request).await?.text().await?)?;
+    Ok(api::GetRoomInfoSuccess { info })
+}
+
+pub async fn get_room_data_history(
+    env: &w::Env,
+    common_args: api::MethodCallCommonArgs,
+    args: api::GetRoomDataHistoryArgs,
+) -> Result<api::GetRoomDataHistorySuccess, Error> {
+    let max_entries = config::max_history_query_entries(env);
+    let request = room_api::GetHistoryMessage {
+        requester_id: common_args.caller_id,
+        sender_id: args.sender_id,
+        from_timestamp: args.from_timestamp,
+        until_timestamp: args.until_timestamp,
+        after: args.after,
+        limit: args
+            .limit
+            .map_or(max_entries, |v| std::cmp::min(v as usize, max_entries)),
+    }
+    .into_request()?;
+    let stub = get_room_stub(env, args.room_id)?;
+    // Non-members get the same answer as for a room that doesn't exist
+    Ok(serde_json::from_str(
+        &stub.fetch_with_request(request).await?.text().await?,
+    )?)
+}
+pub async fn delete_data(
+    env: &w::Env,
+    common_args: api::MethodCallCommonArgs,
+    args: api::DeleteDataArgs,
+) -> Result<api::AckSuccess, Error> {
+    let request = room_api::DeleteDataMessage {
+        deleter_id: common_args.caller_id,
+        data_sender_id: args.data_sender_id,
+        data_nonce: args.data_nonce,
+    }
+    .into_request()?;
+    let stub = get_room_stub(env, args.room_id)?;
+    // As with adding privileged peers, the room decides whether the caller may delete the data,
+    // but the outcome is not revealed to the client
+    let _ = serde_json::from_str::<bool>(&stub.fetch_with_request(request).await?.text().await?);
+    Ok(api::AckSuccess)
 }
 
 pub async fn broadcast_data(
     env: &w::Env,
     common_args: api::MethodCallCommonArgs,
     args: api::BroadcastDataArgs,
-) -> Result<api::MethodCallSuccess, Error> {
+) -> Result<api::AckSuccess, Error> {
     let args = args.common_args;
+    check_data(env, &args.data)?;
     let request = room_api::BroadcastDataMessage {
         data: args.data,
         sender_id: common_args.caller_id,
@@ -216,9 +626,135 @@ pub async fn broadcast_data(
     .into_request()?;
     let stub = get_room_stub(env, args.room_id)?;
     let _ = serde_json::from_str::<bool>(&stub.fetch_with_request(request).await?.text().await?);
-    Ok(api::MethodCallSuccess::Ack)
+    Ok(api::AckSuccess)
+}
+
+pub async fn unicast_data(
+    env: &w::Env,
+    common_args: api::MethodCallCommonArgs,
+    args: api::UnicastDataArgs,
+) -> Result<api::AckSuccess, Error> {
+    let receiver_id = args.receiver_id;
+    let make_receiver_privileged = args.make_receiver_privileged;
+    let require_ack = args.require_ack;
+    let args = args.common_args;
+    check_data(env, &args.data)?;
+    let request = room_api::UnicastDataMessage {
+        data: args.data,
+        sender_id: common_args.caller_id,
+        receiver_id,
+        nonce: common_args.nonce,
+        write_history: args.write_history,
+        make_receiver_privileged,
+        require_ack,
+    }
+    .into_request()?;
+    let stub = get_room_stub(env, args.room_id)?;
+    let response = stub.fetch_with_request(request).await?;
+    if response.status_code() == 507 {
+        return Err(api::ErrorId::QueueFull.with_default_message().into());
+    }
+    Ok(api::AckSuccess)
+}
+
+pub async fn multicast_data(
+    env: &w::Env,
+    common_args: api::MethodCallCommonArgs,
+    args: api::MulticastDataArgs,
+) -> Result<api::MulticastDataSuccess, Error> {
+    let max_receivers = config::max_multicast_receivers(env);
+    if args.receiver_ids.len() > max_receivers {
+        return Err(api::ErrorId::InvalidPayload
+            .with_message(format!("At most {} receivers are allowed.", max_receivers))
+            .into());
+    }
+    let receiver_ids = args.receiver_ids;
+    let args = args.common_args;
+    check_data(env, &args.data)?;
+    let request = room_api::MulticastDataMessage {
+        data: args.data,
+        sender_id: common_args.caller_id,
+        receiver_ids: receiver_ids.clone(),
+        nonce: common_args.nonce,
+        write_history: args.write_history,
+    }
+    .into_request()?;
+    let stub = get_room_stub(env, args.room_id)?;
+    let delivered_to: Vec<api::PublicKeyWrapper> =
+        serde_json::from_str(&stub.fetch_with_request(request).await?.text().await?)?;
+    // The room already skipped duplicates in what it answers with
+    let mut seen: HashSet<String> = delivered_to.iter().map(|v| v.to_string()).collect();
+    let not_delivered_to = receiver_ids
+        .into_iter()
+        .filter(|receiver_id| seen.insert(receiver_id.to_string()))
+        .collect();
+    Ok(api::MulticastDataSuccess {
+        delivered_to,
+        not_delivered_to,
+    })
 }
 
-pub async fn unicast_data() -> Result<api::MethodCallSuccess, Error> {
-    todo!();
+pub async fn send_ephemeral(
+    env: &w::Env,
+    common_args: api::MethodCallCommonArgs,
+    args: api::SendEphemeralArgs,
+) -> Result<api::AckSuccess, Error> {
+    check_data(env, &args.data)?;
+    let request = room_api::SendEphemeralMessage {
+        data: args.data,
+        sender_id: common_args.caller_id,
+        max_age_ms: args.max_age_ms,
+    }
+    .into_request()?;
+    let stub = get_room_stub(env, args.room_id)?;
+    let response = stub.fetch_with_request(request).await?;
+    if response.status_code() == 429 {
+        let retry_after_ms = response
+            .headers()
+            .get("Retry-After-Ms")?
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_default();
+        return Err(websocket::rate_limited_error(retry_after_ms).into());
+    }
+    Ok(api::AckSuccess)
+}
+
+pub async fn create_session(
+    env: &w::Env,
+    server: &ClientSocket,
+    auth: CallAuth,
+    common_args: api::MethodCallCommonArgs,
+    args: api::CreateSessionArgs,
+) -> Result<api::CreateSessionSuccess, Error> {
+    // A leaked session key must not be enough to keep a session going forever
+    if auth == CallAuth::Session {
+        return Err(api::ErrorId::NotAuthorized
+            .with_message("Sessions can only be created with signed calls.".to_string())
+            .into());
+    }
+    if !server.take_session_challenge(&args.challenge) {
+        return Err(api::ErrorId::NotAuthorized
+            .with_message("The challenge is unknown or was already used.".to_string())
+            .into());
+    }
+    let ttl_secs = args
+        .ttl_secs
+        .unwrap_or_else(|| config::session_ttl_secs(env))
+        .min(config::max_session_ttl_secs(env));
+    let expires_at = w::Date::now().as_millis() / 1000 + ttl_secs;
+    let session = Session::new(common_args.caller_id, expires_at)?;
+    let session_key = session.key().to_string();
+    server.set_session(session);
+    Ok(api::CreateSessionSuccess {
+        session_key,
+        expires_at,
+    })
+}
+
+pub async fn end_session(
+    server: &ClientSocket,
+    common_args: api::MethodCallCommonArgs,
+) -> Result<api::AckSuccess, Error> {
+    server.end_session(&common_args.caller_id);
+    Ok(api::AckSuccess)
 }